@@ -20,15 +20,16 @@ use safe_transmute::{
 use sloggers::file::FileLoggerBuilder;
 use sloggers::types::Severity;
 use sloggers::Build;
+use solana_client::client_error::{ClientError, ClientErrorKind};
 use solana_client::rpc_client::RpcClient;
 use solana_client::rpc_config::RpcSendTransactionConfig;
 use solana_sdk::commitment_config::CommitmentConfig;
-use solana_sdk::instruction::{AccountMeta, Instruction};
+use solana_sdk::instruction::{AccountMeta, Instruction, InstructionError};
 use solana_sdk::program_pack::Pack;
 use solana_sdk::pubkey::Pubkey;
 use solana_sdk::signature::Signature;
 use solana_sdk::signature::{Keypair, Signer};
-use solana_sdk::transaction::Transaction;
+use solana_sdk::transaction::{Transaction, TransactionError};
 use spl_token::instruction as token_instruction;
 use warp::Filter;
 
@@ -36,15 +37,23 @@ use serum_common::client::rpc::{
     create_and_init_mint, create_token_account, mint_to_new_account, send_txn, simulate_transaction,
 };
 use serum_common::client::Cluster;
+use serum_dex::audit::MarketAudit;
+use serum_dex::client::{
+    decode_event_queue, decode_fee_split, decode_market_state, decode_open_orders, decode_orders,
+    decode_request_queue, decode_trigger_orders,
+};
+use serum_dex::error::DecodedError;
 use serum_dex::instruction::{MarketInstruction, NewOrderInstructionV1};
 use serum_dex::matching::{OrderType, Side};
 use serum_dex::state::gen_vault_signer_key;
 use serum_dex::state::Event;
 use serum_dex::state::EventQueueHeader;
 use serum_dex::state::MarketState;
+use serum_dex::state::MarketStateV2;
 use serum_dex::state::QueueHeader;
 use serum_dex::state::Request;
 use serum_dex::state::RequestQueueHeader;
+use serum_dex::state::ToAlignedBytes;
 
 pub fn with_logging<F: FnOnce()>(_to: &str, fnc: F) {
     fnc();
@@ -55,6 +64,28 @@ fn read_keypair_file(s: &str) -> Result<Keypair> {
         .map_err(|_| format_err!("failed to read keypair from {}", s))
 }
 
+/// Describes a failed transaction, decoding custom program errors into the
+/// dex error they stand for.
+fn describe_transaction_error(err: &TransactionError) -> String {
+    match err {
+        TransactionError::InstructionError(index, InstructionError::Custom(code)) => format!(
+            "instruction {} failed: {}",
+            index,
+            DecodedError::from_custom(*code)
+        ),
+        err => format!("{:?}", err),
+    }
+}
+
+/// Adds the decoded reason to errors of transactions that failed on chain.
+fn decode_txn_error(err: anyhow::Error) -> anyhow::Error {
+    let description = match err.downcast_ref::<ClientError>().map(ClientError::kind) {
+        Some(ClientErrorKind::TransactionError(txn_err)) => describe_transaction_error(txn_err),
+        _ => return err,
+    };
+    err.context(description)
+}
+
 #[derive(Clap, Debug)]
 pub struct Opts {
     #[clap(default_value = "mainnet")]
@@ -187,6 +218,16 @@ pub enum Command {
         mint: Pubkey,
         owner_account: String,
     },
+    MigrateMarket {
+        payer: String,
+        authority: String,
+        dex_program_id: Pubkey,
+        market: Pubkey,
+    },
+    AuditMarket {
+        dex_program_id: Pubkey,
+        market: Pubkey,
+    },
 }
 
 impl Opts {
@@ -362,6 +403,23 @@ pub fn start(opts: Opts) -> Result<()> {
             let initialized_account = initialize_token_account(&client, mint, &owner)?;
             debug_println!("Initialized account: {}", initialized_account.pubkey());
         }
+        Command::MigrateMarket {
+            ref payer,
+            ref authority,
+            ref dex_program_id,
+            ref market,
+        } => {
+            let payer = read_keypair_file(payer)?;
+            let authority = read_keypair_file(authority)?;
+            let new_market = migrate_market(&client, dex_program_id, &payer, &authority, market)?;
+            println!("Migrated market: {}", new_market);
+        }
+        Command::AuditMarket {
+            ref dex_program_id,
+            ref market,
+        } => {
+            audit_market(&client, dex_program_id, market)?;
+        }
     }
     Ok(())
 }
@@ -415,16 +473,19 @@ fn get_keys_for_market<'a>(
 ) -> Result<MarketPubkeys> {
     let account_data: Vec<u8> = client.get_account_data(&market)?;
     let words: Cow<[u64]> = remove_dex_account_padding(&account_data)?;
-    let market_state: MarketState =
-        transmute_one_pedantic::<MarketState>(transmute_to_bytes(&words))
-            .map_err(|e| e.without_src())?;
+    if words.len() < size_of::<MarketState>() >> 3 {
+        return Err(format_err!("market account {} is too small", market));
+    }
+    let market_state: MarketState = transmute_one_pedantic::<MarketState>(transmute_to_bytes(
+        &words[..size_of::<MarketState>() >> 3],
+    ))
+    .map_err(|e| e.without_src())?;
     market_state.check_flags()?;
+    // Migrated markets live at a new address but keep their original
+    // `own_address`, which the vault signer is derived from.
+    let own_address = Pubkey::new(transmute_to_bytes(&market_state.own_address));
     let vault_signer_key =
-        gen_vault_signer_key(market_state.vault_signer_nonce, market, program_id)?;
-    assert_eq!(
-        transmute_to_bytes(&market_state.own_address),
-        market.as_ref()
-    );
+        gen_vault_signer_key(market_state.vault_signer_nonce, &own_address, program_id)?;
     Ok(MarketPubkeys {
         market: Box::new(*market),
         req_q: Box::new(Pubkey::new(transmute_one_to_bytes(&market_state.req_q))),
@@ -662,13 +723,15 @@ fn consume_events_once(
     );
 
     info!("Consuming events ...");
-    let signature = client.send_transaction_with_config(
-        &txn,
-        RpcSendTransactionConfig {
-            skip_preflight: true,
-            ..RpcSendTransactionConfig::default()
-        },
-    )?;
+    let signature = client
+        .send_transaction_with_config(
+            &txn,
+            RpcSendTransactionConfig {
+                skip_preflight: true,
+                ..RpcSendTransactionConfig::default()
+            },
+        )
+        .map_err(|err| decode_txn_error(err.into()))?;
     Ok(signature)
 }
 
@@ -726,7 +789,7 @@ fn consume_events(
         recent_hash,
     );
     info!("Consuming events ...");
-    send_txn(client, &txn, false)?;
+    send_txn(client, &txn, false).map_err(decode_txn_error)?;
     Ok(())
 }
 
@@ -854,21 +917,14 @@ fn place_order(
     new_order: NewOrderInstructionV1,
 ) -> Result<()> {
     let mut instructions = Vec::new();
-    let orders_keypair;
     let mut signers = Vec::new();
     let orders_pubkey = match *orders {
         Some(pk) => pk,
         None => {
-            let (orders_key, instruction) = create_dex_account(
-                client,
-                program_id,
-                &payer.pubkey(),
-                size_of::<serum_dex::state::OpenOrders>(),
-            )?;
-            orders_keypair = orders_key;
-            signers.push(&orders_keypair);
+            let (orders_pubkey, instruction) =
+                init_open_orders(client, program_id, payer, state.market.as_ref())?;
             instructions.push(instruction);
-            orders_keypair.pubkey()
+            orders_pubkey
         }
     };
     *orders = Some(orders_pubkey);
@@ -899,10 +955,47 @@ fn place_order(
         &signers,
         recent_hash,
     );
-    send_txn(client, &txn, false)?;
+    send_txn(client, &txn, false).map_err(decode_txn_error)?;
     Ok(())
 }
 
+// The number of OpenOrders account indices searched for an unused one.
+const MAX_OPEN_ORDERS_INDEX: u64 = 256;
+
+/// Builds an instruction creating the owner's next unused OpenOrders account
+/// on the market, at its program derived address.
+fn init_open_orders(
+    client: &RpcClient,
+    program_id: &Pubkey,
+    owner: &Keypair,
+    market: &Pubkey,
+) -> Result<(Pubkey, Instruction)> {
+    for index in 0..MAX_OPEN_ORDERS_INDEX {
+        let (orders_pubkey, instruction) = serum_dex::instruction::init_open_orders(
+            program_id,
+            market,
+            &owner.pubkey(),
+            &owner.pubkey(),
+            index,
+            128,
+        )?;
+        let account = client
+            .get_account_with_commitment(&orders_pubkey, CommitmentConfig::recent())?
+            .value;
+        // InitOpenOrders also takes over addresses that were only sent lamports
+        match account {
+            Some(account) if account.owner == *program_id => continue,
+            _ => return Ok((orders_pubkey, instruction)),
+        }
+    }
+    Err(format_err!(
+        "{} already has {} OpenOrders accounts on market {}",
+        owner.pubkey(),
+        MAX_OPEN_ORDERS_INDEX,
+        market
+    ))
+}
+
 fn settle_funds(
     client: &RpcClient,
     program_id: &Pubkey,
@@ -947,7 +1040,10 @@ fn settle_funds(
         debug_println!("Simulating SettleFunds instruction ...");
         let result = simulate_transaction(client, &txn, true, CommitmentConfig::single())?;
         if let Some(e) = result.value.err {
-            return Err(format_err!("simulate_transaction error: {:?}", e));
+            return Err(format_err!(
+                "simulate_transaction error: {}",
+                describe_transaction_error(&e)
+            ));
         }
         debug_println!("{:#?}", result.value);
         if result.value.err.is_none() {
@@ -955,7 +1051,7 @@ fn settle_funds(
         }
     }
     debug_println!("Settling ...");
-    send_txn(client, &txn, false)?;
+    send_txn(client, &txn, false).map_err(decode_txn_error)?;
     Ok(())
 }
 
@@ -1032,11 +1128,14 @@ fn list_market(
     debug_println!("txn:\n{:#x?}", txn);
     let result = simulate_transaction(client, &txn, true, CommitmentConfig::single())?;
     if let Some(e) = result.value.err {
-        return Err(format_err!("simulate_transaction error: {:?}", e));
+        return Err(format_err!(
+            "simulate_transaction error: {}",
+            describe_transaction_error(&e)
+        ));
     }
     debug_println!("{:#?}", result.value);
     debug_println!("Listing {} ...", market_key.pubkey());
-    send_txn(client, &txn, false)?;
+    send_txn(client, &txn, false).map_err(decode_txn_error)?;
 
     Ok(MarketPubkeys {
         market: Box::new(market_key.pubkey()),
@@ -1067,7 +1166,8 @@ fn gen_listing_params(
     _coin_mint: &Pubkey,
     _pc_mint: &Pubkey,
 ) -> Result<(ListingKeys, Vec<Instruction>)> {
-    let (market_key, create_market) = create_dex_account(client, program_id, payer, 376)?;
+    let (market_key, create_market) =
+        create_dex_account(client, program_id, payer, size_of::<MarketStateV2>())?;
     let (req_q_key, create_req_q) = create_dex_account(client, program_id, payer, 640)?;
     let (event_q_key, create_event_q) = create_dex_account(client, program_id, payer, 1 << 20)?;
     let (bids_key, create_bids) = create_dex_account(client, program_id, payer, 1 << 16)?;
@@ -1154,12 +1254,111 @@ fn match_orders(
     debug_println!("Simulating order matching ...");
     let result = simulate_transaction(&client, &txn, true, CommitmentConfig::single())?;
     if let Some(e) = result.value.err {
-        return Err(format_err!("simulate_transaction error: {:?}", e));
+        return Err(format_err!(
+            "simulate_transaction error: {}",
+            describe_transaction_error(&e)
+        ));
     }
     debug_println!("{:#?}", result.value);
     if result.value.err.is_none() {
         debug_println!("Matching orders ...");
-        send_txn(client, &txn, false)?;
+        send_txn(client, &txn, false).map_err(decode_txn_error)?;
+    }
+    Ok(())
+}
+
+fn migrate_market(
+    client: &RpcClient,
+    program_id: &Pubkey,
+    payer: &Keypair,
+    authority: &Keypair,
+    market: &Pubkey,
+) -> Result<Pubkey> {
+    let (new_market_key, create_new_market) = create_dex_account(
+        client,
+        program_id,
+        &payer.pubkey(),
+        size_of::<MarketStateV2>(),
+    )?;
+    let instruction =
+        serum_dex::instruction::migrate_market(market, &new_market_key.pubkey(), program_id)?;
+
+    let (recent_hash, _fee_calc) = client.get_recent_blockhash()?;
+    let txn = Transaction::new_signed_with_payer(
+        &[create_new_market, instruction],
+        Some(&payer.pubkey()),
+        &[payer, &new_market_key, authority],
+        recent_hash,
+    );
+
+    debug_println!("Migrating market ...");
+    send_txn(client, &txn, false).map_err(decode_txn_error)?;
+    Ok(new_market_key.pubkey())
+}
+
+fn audit_market(client: &RpcClient, program_id: &Pubkey, market: &Pubkey) -> Result<()> {
+    let market_keys = get_keys_for_market(client, program_id, market)?;
+    let market_data = client.get_account_data(market)?;
+    let market_state = decode_market_state(&market_data)?;
+    let fee_split = decode_fee_split(&market_data)?;
+    let vault_balance = |vault: &Pubkey| -> Result<u64> {
+        let data = client.get_account_data(vault)?;
+        Ok(spl_token::state::Account::unpack(&data)?.amount)
+    };
+    let bids = decode_orders(&client.get_account_data(&market_keys.bids)?, Side::Bid)?;
+    let asks = decode_orders(&client.get_account_data(&market_keys.asks)?, Side::Ask)?;
+    let request_queue = decode_request_queue(&client.get_account_data(&market_keys.req_q)?)?;
+    let event_queue = decode_event_queue(&client.get_account_data(&market_keys.event_q)?)?;
+    let program_accounts = client.get_program_accounts(program_id)?;
+    let open_orders: Vec<_> = program_accounts
+        .iter()
+        .filter_map(|(address, account)| {
+            let open_orders = decode_open_orders(&account.data).ok()?;
+            if open_orders.open_orders.market != market_state.own_address {
+                return None;
+            }
+            Some((address.to_aligned_bytes(), open_orders))
+        })
+        .collect();
+    let trigger_orders = program_accounts
+        .iter()
+        .filter_map(|(_, account)| decode_trigger_orders(&account.data).ok())
+        .find(|(market, _)| *market == market_state.own_address)
+        .map_or(vec![], |(_, trigger_orders)| trigger_orders);
+    let open_orders: Vec<_> = open_orders
+        .iter()
+        .map(|(address, open_orders)| (*address, open_orders.view()))
+        .collect();
+
+    let violations = MarketAudit {
+        market: &market_state,
+        coin_vault_balance: vault_balance(&market_keys.coin_vault)?,
+        pc_vault_balance: vault_balance(&market_keys.pc_vault)?,
+        coin_staking_fees: fee_split.map_or(0, |fee_split| fee_split.staking_coin_fees),
+        pc_staking_fees: fee_split.map_or(0, |fee_split| fee_split.staking_pc_fees),
+        bids: &bids,
+        asks: &asks,
+        request_queue_len: request_queue.len() as u64,
+        event_queue_len: event_queue.len() as u64,
+        open_orders: &open_orders,
+        trigger_orders: &trigger_orders,
+    }
+    .run();
+    println!(
+        "Audited {} open orders accounts, {} bids, {} asks, {} trigger orders",
+        open_orders.len(),
+        bids.len(),
+        asks.len(),
+        trigger_orders.len()
+    );
+    if request_queue.len() + event_queue.len() > 0 {
+        println!("The queues are not drained, so some checks were skipped");
+    }
+    for violation in &violations {
+        println!("{:?}", violation);
+    }
+    if !violations.is_empty() {
+        return Err(format_err!("found {} violations", violations.len()));
     }
     Ok(())
 }
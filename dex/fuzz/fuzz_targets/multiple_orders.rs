@@ -154,6 +154,7 @@ fn run_actions(actions: Vec<Action>) {
 
     for action in actions {
         run_action(action, &market_accounts, &mut owners, &mut referrers, &bump);
+        audit_market(&market_accounts, &owners);
         if *VERBOSE >= 4 {
             run_action(
                 Action::MatchOrders(100),
@@ -202,6 +203,7 @@ fn run_actions(actions: Vec<Action>) {
     actions.push(Action::SweepFees);
     for action in actions {
         run_action(action, &market_accounts, &mut owners, &mut referrers, &bump);
+        audit_market(&market_accounts, &owners);
     }
 
     for owner in owners.values() {
@@ -322,6 +324,20 @@ fn run_actions(actions: Vec<Action>) {
     }
 }
 
+fn audit_market(market_accounts: &MarketAccounts, owners: &HashMap<OwnerId, Owner>) {
+    let open_orders_accounts: Vec<&AccountInfo> = owners
+        .values()
+        .filter(|owner| {
+            owner
+                .open_orders()
+                .map_or(false, |orders| orders.account_flags != 0)
+        })
+        .map(|owner| &owner.orders_account)
+        .collect();
+    let violations = market_accounts.audit(&open_orders_accounts);
+    assert!(violations.is_empty(), "{:#?}", violations);
+}
+
 fn run_action<'bump>(
     action: Action,
     market_accounts: &MarketAccounts<'bump>,
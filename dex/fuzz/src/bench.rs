@@ -0,0 +1,378 @@
+//! Measures how the cost of the dex instructions scales with the depth of the
+//! order book and the length of the queues, to tune the limits passed to
+//! `MatchOrders` and `ConsumeEvents` and to catch regressions in the critbit
+//! tree and the matching engine.
+//!
+//! Every case builds a market in some state and then times `State::process` on
+//! a single instruction, restoring the accounts between samples. The times are
+//! wall clock on the host rather than compute units, so only how they scale and
+//! how they compare between runs on the same machine is meaningful.
+
+use std::mem::size_of;
+use std::num::NonZeroU64;
+use std::time::{Duration, Instant};
+
+use bumpalo::Bump;
+use solana_sdk::account_info::AccountInfo;
+
+use serum_dex::instruction::{
+    CancelOrderInstruction, MarketInstruction, NewOrderInstructionV2, SelfTradeBehavior,
+};
+use serum_dex::matching::{OrderType, Side};
+use serum_dex::state::{
+    strip_header, EventQueue, OpenOrders, OpenOrdersMut, Queue, Request, RequestQueue,
+    RequestQueueHeader, State, ToAlignedBytes,
+};
+
+use crate::{
+    new_dex_owned_account_with_lamports, new_sol_account, new_token_account, process_instruction,
+    setup_market_with_sizes, MarketAccounts,
+};
+
+const MAX_DEPTH: usize = 4096;
+const REQUEST_QUEUE_CAPACITY: usize = 1024;
+// Leaves room for the orders a trader has in flight.
+const ORDERS_PER_MAKER: usize = 120;
+const BALANCE: u64 = 1 << 50;
+
+pub const DEPTHS: [usize; 5] = [16, 64, 256, 1024, MAX_DEPTH];
+
+#[derive(Clone, Debug)]
+pub struct Measurement {
+    pub instruction: &'static str,
+    /// What `value` counts, e.g. the number of orders in the book.
+    pub parameter: &'static str,
+    pub value: usize,
+    pub median: Duration,
+}
+
+struct Trader<'bump> {
+    signer: AccountInfo<'bump>,
+    orders: AccountInfo<'bump>,
+    coin: AccountInfo<'bump>,
+    pc: AccountInfo<'bump>,
+}
+
+impl<'bump> Trader<'bump> {
+    fn new(market_accounts: &MarketAccounts<'bump>, bump: &'bump Bump) -> Self {
+        let signer = new_sol_account(10, bump);
+        let orders = new_dex_owned_account_with_lamports(
+            size_of::<OpenOrders>(),
+            10_000_000_000,
+            market_accounts.market.owner,
+            bump,
+        );
+        let coin = new_token_account(market_accounts.coin_mint.key, signer.key, BALANCE, bump);
+        let pc = new_token_account(market_accounts.pc_mint.key, signer.key, BALANCE, bump);
+        Trader {
+            signer,
+            orders,
+            coin,
+            pc,
+        }
+    }
+}
+
+/// The trader placing the `order_index`th order of a book.
+fn maker(order_index: usize) -> usize {
+    1 + order_index / ORDERS_PER_MAKER
+}
+
+/// An instruction ready to be processed.
+struct Call<'bump> {
+    accounts: Vec<AccountInfo<'bump>>,
+    data: Vec<u8>,
+}
+
+/// A market large enough for every case. Trader 0 is the taker; the makers
+/// follow.
+struct BenchMarket<'bump> {
+    accounts: MarketAccounts<'bump>,
+    traders: Vec<Trader<'bump>>,
+    bump: &'bump Bump,
+}
+
+impl<'bump> BenchMarket<'bump> {
+    fn new(bump: &'bump Bump) -> Self {
+        let slab_len = 1 << 20;
+        let req_q_len =
+            size_of::<RequestQueueHeader>() + REQUEST_QUEUE_CAPACITY * size_of::<Request>();
+        let event_q_len = 2 << 20;
+        BenchMarket {
+            accounts: setup_market_with_sizes(slab_len, req_q_len, event_q_len, bump),
+            traders: vec![],
+            bump,
+        }
+    }
+
+    fn trader(&mut self, index: usize) -> &Trader<'bump> {
+        while self.traders.len() <= index {
+            let trader = Trader::new(&self.accounts, self.bump);
+            self.traders.push(trader);
+        }
+        &self.traders[index]
+    }
+
+    fn new_order(&self, trader: usize, side: Side, price: u64, qty: u64) -> Call<'bump> {
+        let market_accounts = &self.accounts;
+        let trader = &self.traders[trader];
+        let instruction = NewOrderInstructionV2 {
+            side,
+            limit_price: NonZeroU64::new(price).unwrap(),
+            max_qty: NonZeroU64::new(qty).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        };
+        Call {
+            accounts: vec![
+                market_accounts.market.clone(),
+                trader.orders.clone(),
+                market_accounts.req_q.clone(),
+                match side {
+                    Side::Bid => trader.pc.clone(),
+                    Side::Ask => trader.coin.clone(),
+                },
+                trader.signer.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+                market_accounts.spl_token_program.clone(),
+                market_accounts.rent_sysvar.clone(),
+            ],
+            data: MarketInstruction::NewOrderV2(instruction).pack(),
+        }
+    }
+
+    fn cancel_order(&self, trader: usize, slot: u8) -> Call<'bump> {
+        let market_accounts = &self.accounts;
+        let trader = &self.traders[trader];
+        let (side, order_id) = {
+            let open_orders = OpenOrdersMut::load(&trader.orders, trader.orders.owner).unwrap();
+            (
+                open_orders.slot_side(slot).unwrap(),
+                open_orders.order_id(slot).unwrap(),
+            )
+        };
+        let instruction = CancelOrderInstruction {
+            side,
+            order_id,
+            owner: [0; 4],
+            owner_slot: slot,
+        };
+        Call {
+            accounts: vec![
+                market_accounts.market.clone(),
+                trader.orders.clone(),
+                market_accounts.req_q.clone(),
+                trader.signer.clone(),
+            ],
+            data: MarketInstruction::CancelOrder(instruction).pack(),
+        }
+    }
+
+    fn match_orders(&self, limit: u16) -> Call<'bump> {
+        let market_accounts = &self.accounts;
+        Call {
+            accounts: vec![
+                market_accounts.market.clone(),
+                market_accounts.req_q.clone(),
+                market_accounts.event_q.clone(),
+                market_accounts.bids.clone(),
+                market_accounts.asks.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+            ],
+            data: MarketInstruction::MatchOrders(limit).pack(),
+        }
+    }
+
+    fn consume_events(&self, limit: u16) -> Call<'bump> {
+        let market_accounts = &self.accounts;
+        let mut accounts: Vec<AccountInfo> = self
+            .traders
+            .iter()
+            .map(|trader| trader.orders.clone())
+            .collect();
+        accounts.sort_by_key(|account| account.key.to_aligned_bytes());
+        accounts.extend_from_slice(&[
+            market_accounts.market.clone(),
+            market_accounts.event_q.clone(),
+            market_accounts.coin_vault.clone(),
+            market_accounts.pc_vault.clone(),
+        ]);
+        Call {
+            accounts,
+            data: MarketInstruction::ConsumeEvents(limit).pack(),
+        }
+    }
+
+    fn run(&self, call: Call) {
+        process_instruction(self.accounts.market.owner, &call.accounts, &call.data).unwrap();
+    }
+
+    fn request_queue_len(&self) -> usize {
+        let (header, buf) = strip_header(&self.accounts.req_q, false).unwrap();
+        let requests: RequestQueue = Queue::new(header, buf);
+        requests.len() as usize
+    }
+
+    fn event_queue_len(&self) -> usize {
+        let (header, buf) = strip_header(&self.accounts.event_q, false).unwrap();
+        let events: EventQueue = Queue::new(header, buf);
+        events.len() as usize
+    }
+
+    fn drain_requests(&self) {
+        while self.request_queue_len() > 0 {
+            self.run(self.match_orders(u16::MAX));
+        }
+    }
+
+    /// Rests `count` orders of quantity 1 on `side`, one per price starting at
+    /// 1 lot.
+    fn rest_orders(&mut self, side: Side, count: usize) {
+        for i in 0..count {
+            let maker = maker(i);
+            self.trader(maker);
+            let call = self.new_order(maker, side, i as u64 + 1, 1);
+            self.run(call);
+            if self.request_queue_len() == REQUEST_QUEUE_CAPACITY {
+                self.drain_requests();
+            }
+        }
+        self.drain_requests();
+    }
+
+    /// Times `call` over `samples` runs from the current state and returns
+    /// the median. The state is left as it was.
+    fn time(&self, call: Call, samples: usize) -> Duration {
+        let saved: Vec<Vec<u8>> = call
+            .accounts
+            .iter()
+            .map(|account| account.try_borrow_data().unwrap().to_vec())
+            .collect();
+        let restore = || {
+            for (account, data) in call.accounts.iter().zip(&saved) {
+                account.try_borrow_mut_data().unwrap().copy_from_slice(data);
+            }
+        };
+        let program_id = self.accounts.market.owner;
+        let mut times: Vec<Duration> = (0..samples.max(1))
+            .map(|_| {
+                let start = Instant::now();
+                State::process(program_id, &call.accounts, &call.data).unwrap();
+                let elapsed = start.elapsed();
+                restore();
+                elapsed
+            })
+            .collect();
+        times.sort();
+        times[times.len() / 2]
+    }
+}
+
+/// `NewOrder` only enqueues a request, so it shouldn't depend on how many are
+/// already queued.
+fn bench_new_order(queued: usize, samples: usize) -> Measurement {
+    let bump = Bump::new();
+    let mut market = BenchMarket::new(&bump);
+    market.trader(0);
+    for i in 0..queued {
+        market.trader(maker(i));
+        market.run(market.new_order(maker(i), Side::Ask, 1, 1));
+    }
+    let call = market.new_order(0, Side::Bid, 1, 1);
+    Measurement {
+        instruction: "NewOrder",
+        parameter: "queued requests",
+        value: queued,
+        median: market.time(call, samples),
+    }
+}
+
+/// Matches a single order that rests in a book of `depth` bids.
+fn bench_insert_order(depth: usize, samples: usize) -> Measurement {
+    let bump = Bump::new();
+    let mut market = BenchMarket::new(&bump);
+    market.rest_orders(Side::Bid, depth);
+    market.trader(0);
+    market.run(market.new_order(0, Side::Bid, depth as u64 / 2 + 1, 1));
+    Measurement {
+        instruction: "MatchOrders",
+        parameter: "book depth (insert)",
+        value: depth,
+        median: market.time(market.match_orders(1), samples),
+    }
+}
+
+/// Matches a single cancel of the lowest of `depth` bids.
+fn bench_cancel_order(depth: usize, samples: usize) -> Measurement {
+    let bump = Bump::new();
+    let mut market = BenchMarket::new(&bump);
+    market.rest_orders(Side::Bid, depth);
+    market.run(market.cancel_order(1, 0));
+    Measurement {
+        instruction: "MatchOrders",
+        parameter: "book depth (cancel)",
+        value: depth,
+        median: market.time(market.match_orders(1), samples),
+    }
+}
+
+/// Matches a bid that fills each of `fills` asks in turn.
+fn bench_sweep(fills: usize, samples: usize) -> Measurement {
+    let bump = Bump::new();
+    let mut market = BenchMarket::new(&bump);
+    market.rest_orders(Side::Ask, fills);
+    market.trader(0);
+    market.run(market.new_order(0, Side::Bid, fills as u64, fills as u64));
+    Measurement {
+        instruction: "MatchOrders",
+        parameter: "fills",
+        value: fills,
+        median: market.time(market.match_orders(u16::MAX), samples),
+    }
+}
+
+/// Consumes the events of a bid that filled `fills` asks of different makers.
+fn bench_consume_events(fills: usize, samples: usize) -> Measurement {
+    let bump = Bump::new();
+    let mut market = BenchMarket::new(&bump);
+    market.rest_orders(Side::Ask, fills);
+    market.trader(0);
+    market.run(market.new_order(0, Side::Bid, fills as u64, fills as u64));
+    market.drain_requests();
+    let events = market.event_queue_len();
+    Measurement {
+        instruction: "ConsumeEvents",
+        parameter: "events",
+        value: events,
+        median: market.time(market.consume_events(u16::MAX), samples),
+    }
+}
+
+/// Runs every case at every depth in `depths`, taking the median of `samples`
+/// runs.
+pub fn run_all(depths: &[usize], samples: usize) -> Vec<Measurement> {
+    let mut measurements = vec![];
+    for &depth in depths {
+        assert!(depth >= 1 && depth <= MAX_DEPTH);
+        measurements.push(bench_new_order(
+            depth.min(REQUEST_QUEUE_CAPACITY - 1),
+            samples,
+        ));
+    }
+    for &depth in depths {
+        measurements.push(bench_insert_order(depth, samples));
+    }
+    for &depth in depths {
+        measurements.push(bench_cancel_order(depth, samples));
+    }
+    for &depth in depths {
+        measurements.push(bench_sweep(depth, samples));
+    }
+    for &depth in depths {
+        measurements.push(bench_consume_events(depth, samples));
+    }
+    measurements
+}
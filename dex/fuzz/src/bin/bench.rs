@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::fs;
+use std::process::exit;
+
+use serum_dex_fuzz::bench::{run_all, DEPTHS};
+
+const DEFAULT_SAMPLES: usize = 21;
+
+/// Reads the medians from the output of a previous run, keyed by instruction,
+/// parameter and value.
+fn read_baseline(path: &str) -> Result<HashMap<(String, String, String), f64>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut baseline = HashMap::new();
+    for line in contents.lines().skip(1) {
+        let columns: Vec<&str> = line.split('\t').collect();
+        if columns.len() < 4 {
+            return Err(format!("{}: malformed line {:?}", path, line));
+        }
+        let nanos = columns[3]
+            .parse()
+            .map_err(|e| format!("{}: malformed line {:?}: {}", path, line, e))?;
+        let key = (
+            columns[0].to_string(),
+            columns[1].to_string(),
+            columns[2].to_string(),
+        );
+        baseline.insert(key, nanos);
+    }
+    Ok(baseline)
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    if args.len() > 2 {
+        return Err("usage: bench [samples] [baseline.tsv]".into());
+    }
+    let samples = match args.get(0) {
+        Some(samples) => samples
+            .parse()
+            .map_err(|e| format!("invalid sample count: {}", e))?,
+        None => DEFAULT_SAMPLES,
+    };
+    let baseline = args.get(1).map(|path| read_baseline(path)).transpose()?;
+
+    println!("instruction\tparameter\tvalue\tmedian_ns\tns_per_unit\tvs_baseline");
+    for measurement in run_all(&DEPTHS, samples) {
+        let nanos = measurement.median.as_nanos() as f64;
+        let value = measurement.value.to_string();
+        let vs_baseline = baseline
+            .as_ref()
+            .and_then(|baseline| {
+                baseline.get(&(
+                    measurement.instruction.to_string(),
+                    measurement.parameter.to_string(),
+                    value.clone(),
+                ))
+            })
+            .map(|baseline_nanos| format!("{:.2}x", nanos / baseline_nanos))
+            .unwrap_or_default();
+        println!(
+            "{}\t{}\t{}\t{:.0}\t{:.1}\t{}",
+            measurement.instruction,
+            measurement.parameter,
+            value,
+            nanos,
+            nanos / measurement.value.max(1) as f64,
+            vs_baseline
+        );
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
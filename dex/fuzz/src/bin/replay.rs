@@ -0,0 +1,38 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::process::exit;
+use std::str::FromStr;
+
+use solana_sdk::pubkey::Pubkey;
+
+use serum_dex_fuzz::replay::{replay, AccountSnapshot, ReplayInstruction};
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &str) -> Result<T, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    if args.len() < 3 {
+        return Err("usage: replay <program id> <instructions.json> <account.json>...".into());
+    }
+    let program_id =
+        Pubkey::from_str(&args[0]).map_err(|e| format!("invalid program id: {:?}", e))?;
+    let instructions: Vec<ReplayInstruction> = read_json(&args[1])?;
+    let snapshots = args[2..]
+        .iter()
+        .map(|path| read_json(path))
+        .collect::<Result<Vec<AccountSnapshot>, _>>()?;
+    for line in replay(program_id, &snapshots, &instructions)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
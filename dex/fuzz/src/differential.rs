@@ -0,0 +1,469 @@
+//! Runs sequences of market actions against both the program and the
+//! reference order book, and checks after every action that the two agree on
+//! the book, the queues and every owner's balances and slots. Driven by the
+//! `differential` fuzz target.
+
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::num::NonZeroU64;
+
+use arbitrary::Arbitrary;
+use bumpalo::Bump;
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use solana_sdk::account_info::AccountInfo;
+
+use serum_dex::critbit::Slab;
+use serum_dex::error::DexError;
+use serum_dex::instruction::{
+    CancelOrderInstruction, MarketInstruction, NewOrderInstructionV2, SelfTradeBehavior,
+};
+use serum_dex::matching::{OrderType, Side};
+use serum_dex::state::{
+    strip_header, Event, EventQueue, EventQueueHeader, EventView, MarketState, OpenOrders,
+    OpenOrdersMut, Queue, RequestQueue, ToAlignedBytes,
+};
+
+use crate::reference::{Balances, Fill, MarketTotals, ReferenceBook, RestingOrder};
+use crate::{
+    get_token_account_balance, new_dex_owned_account_with_lamports, new_sol_account,
+    new_token_account, process_instruction, setup_market, MarketAccounts, COIN_LOT_SIZE,
+    PC_LOT_SIZE,
+};
+
+const OWNERS: usize = 4;
+// keeps the number of resting orders well below what the slabs can hold,
+// since the reference book never boots orders out
+const MAX_ACTIONS: usize = 256;
+const INITIAL_COIN_BALANCE: u64 = 1_000_000_000;
+const INITIAL_PC_BALANCE: u64 = 1_000_000_000;
+// a maker fill and out, then a taker fill and out
+const MAX_EVENTS_PER_STEP: usize = 4;
+
+#[derive(Debug, Arbitrary, Clone)]
+pub enum Action {
+    NewOrder {
+        owner: u8,
+        side: Side,
+        limit_price: u8,
+        max_qty: u8,
+        order_type: OrderType,
+        client_id: u8,
+        self_trade_behavior: SelfTradeBehavior,
+    },
+    CancelOrder {
+        owner: u8,
+        slot: u8,
+    },
+    MatchOrders(u8),
+    ConsumeEvents(u8),
+}
+
+struct Owner<'bump> {
+    signer_account: AccountInfo<'bump>,
+    orders_account: AccountInfo<'bump>,
+    coin_account: AccountInfo<'bump>,
+    pc_account: AccountInfo<'bump>,
+}
+
+impl<'bump> Owner<'bump> {
+    fn new(market_accounts: &MarketAccounts<'bump>, bump: &'bump Bump) -> Self {
+        let signer_account = new_sol_account(10, &bump);
+        let orders_account = new_dex_owned_account_with_lamports(
+            size_of::<OpenOrders>(),
+            10000000000,
+            market_accounts.market.owner,
+            &bump,
+        );
+        let coin_account = new_token_account(
+            market_accounts.coin_mint.key,
+            signer_account.key,
+            INITIAL_COIN_BALANCE,
+            &bump,
+        );
+        let pc_account = new_token_account(
+            market_accounts.pc_mint.key,
+            signer_account.key,
+            INITIAL_PC_BALANCE,
+            &bump,
+        );
+        Self {
+            signer_account,
+            orders_account,
+            coin_account,
+            pc_account,
+        }
+    }
+
+    fn open_orders(&self) -> Option<OpenOrdersMut> {
+        let is_initialized = {
+            let (orders, _) = strip_header::<OpenOrders, u8>(&self.orders_account, false).unwrap();
+            orders.account_flags != 0
+        };
+        if !is_initialized {
+            return None;
+        }
+        Some(OpenOrdersMut::load(&self.orders_account, self.orders_account.owner).unwrap())
+    }
+}
+
+lazy_static! {
+    static ref VERBOSE: u32 = std::env::var("FUZZ_VERBOSE")
+        .map(|s| s.parse())
+        .ok()
+        .transpose()
+        .ok()
+        .flatten()
+        .unwrap_or(0);
+}
+
+/// Runs `actions` against the program and the reference book, comparing the
+/// two after each one.
+pub fn run_actions(actions: Vec<Action>) {
+    if *VERBOSE >= 1 {
+        println!("{:#?}", actions);
+    }
+
+    let bump = Bump::new();
+    let market_accounts = setup_market(&bump);
+    let owners: Vec<Owner> = (0..OWNERS)
+        .map(|_| Owner::new(&market_accounts, &bump))
+        .collect();
+    let mut reference = ReferenceBook::new(
+        COIN_LOT_SIZE,
+        PC_LOT_SIZE,
+        OWNERS,
+        INITIAL_COIN_BALANCE,
+        INITIAL_PC_BALANCE,
+    );
+
+    for action in actions.into_iter().take(MAX_ACTIONS) {
+        if *VERBOSE >= 2 {
+            println!("{:?}", action);
+        }
+        run_action(action, &market_accounts, &owners, &mut reference);
+        compare(&market_accounts, &owners, &reference);
+    }
+}
+
+fn run_action<'bump>(
+    action: Action,
+    market_accounts: &MarketAccounts<'bump>,
+    owners: &[Owner<'bump>],
+    reference: &mut ReferenceBook,
+) {
+    match action {
+        Action::NewOrder {
+            owner,
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+        } => {
+            if request_queue_is_full(market_accounts) {
+                return;
+            }
+            let owner_index = owner as usize % OWNERS;
+            let owner = &owners[owner_index];
+            let instruction = NewOrderInstructionV2 {
+                side,
+                limit_price: NonZeroU64::new(limit_price as u64 + 1).unwrap(),
+                max_qty: NonZeroU64::new(max_qty as u64 + 1).unwrap(),
+                order_type,
+                client_id: client_id as u64,
+                self_trade_behavior,
+            };
+            let mut accounts = vec![
+                market_accounts.market.clone(),
+                owner.orders_account.clone(),
+                market_accounts.req_q.clone(),
+                match side {
+                    Side::Bid => owner.pc_account.clone(),
+                    Side::Ask => owner.coin_account.clone(),
+                },
+                owner.signer_account.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+                market_accounts.spl_token_program.clone(),
+                market_accounts.rent_sysvar.clone(),
+            ];
+            if order_type == OrderType::PostOnlySlide {
+                accounts.push(market_accounts.bids.clone());
+                accounts.push(market_accounts.asks.clone());
+            }
+            let result = process_instruction(
+                market_accounts.market.owner,
+                &accounts,
+                &MarketInstruction::NewOrderV2(instruction.clone()).pack(),
+            );
+            let expected = reference.new_order(
+                owner_index,
+                side,
+                instruction.limit_price.get(),
+                instruction.max_qty.get(),
+                order_type,
+                instruction.client_id,
+                self_trade_behavior,
+            );
+            match (result, expected) {
+                (Ok(()), Ok(())) => {}
+                (Err(DexError::ErrorCode(code)), Err(expected)) if code == expected => {}
+                (result, expected) => panic!(
+                    "NewOrderV2 returned {:?}, the reference book {:?}",
+                    result, expected
+                ),
+            }
+        }
+
+        Action::CancelOrder { owner, slot } => {
+            let owner_index = owner as usize % OWNERS;
+            let owner = &owners[owner_index];
+            let slot = slot % 128;
+            if reference.slot(owner_index, slot).is_none() || request_queue_is_full(market_accounts)
+            {
+                return;
+            }
+            let (side, order_id) = {
+                let open_orders = owner.open_orders().unwrap();
+                (
+                    open_orders.slot_side(slot).unwrap(),
+                    open_orders.order_id(slot).unwrap(),
+                )
+            };
+            let instruction = MarketInstruction::CancelOrder(CancelOrderInstruction {
+                side,
+                order_id,
+                owner: [0; 4],
+                owner_slot: slot,
+            });
+            process_instruction(
+                market_accounts.market.owner,
+                &[
+                    market_accounts.market.clone(),
+                    owner.orders_account.clone(),
+                    market_accounts.req_q.clone(),
+                    owner.signer_account.clone(),
+                ],
+                &instruction.pack(),
+            )
+            .unwrap();
+            reference.cancel_order(owner_index, slot);
+        }
+
+        Action::MatchOrders(limit) => {
+            let free_events = {
+                let (header, buf) =
+                    strip_header::<EventQueueHeader, Event>(&market_accounts.event_q, false)
+                        .unwrap();
+                let capacity = buf.len();
+                let event_q: EventQueue = Queue::new(header, buf);
+                capacity - event_q.len() as usize
+            };
+            // the reference book never fills its event queue
+            let limit = (limit as usize).min(free_events / MAX_EVENTS_PER_STEP) as u16;
+            if limit == 0 {
+                return;
+            }
+            process_instruction(
+                market_accounts.market.owner,
+                &[
+                    market_accounts.market.clone(),
+                    market_accounts.req_q.clone(),
+                    market_accounts.event_q.clone(),
+                    market_accounts.bids.clone(),
+                    market_accounts.asks.clone(),
+                    market_accounts.coin_vault.clone(),
+                    market_accounts.pc_vault.clone(),
+                ],
+                &MarketInstruction::MatchOrders(limit).pack(),
+            )
+            .unwrap();
+            reference.match_orders(limit);
+        }
+
+        Action::ConsumeEvents(limit) => {
+            let mut accounts: Vec<AccountInfo> = owners
+                .iter()
+                .filter(|owner| owner.open_orders().is_some())
+                .map(|owner| owner.orders_account.clone())
+                .sorted_by_key(|account_info| account_info.key.to_aligned_bytes())
+                .collect();
+            if accounts.is_empty() {
+                return;
+            }
+            accounts.extend_from_slice(&[
+                market_accounts.market.clone(),
+                market_accounts.event_q.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+            ]);
+            process_instruction(
+                market_accounts.market.owner,
+                &accounts,
+                &MarketInstruction::ConsumeEvents(limit as u16).pack(),
+            )
+            .unwrap();
+            reference.consume_events(limit as u16);
+        }
+    }
+}
+
+fn request_queue_is_full(market_accounts: &MarketAccounts) -> bool {
+    let (header, buf) = strip_header(&market_accounts.req_q, false).unwrap();
+    let req_q: RequestQueue = Queue::new(header, buf);
+    req_q.full()
+}
+
+fn compare(market_accounts: &MarketAccounts, owners: &[Owner], reference: &ReferenceBook) {
+    let owner_indices: HashMap<[u64; 4], usize> = owners
+        .iter()
+        .enumerate()
+        .map(|(i, owner)| (owner.orders_account.key.to_aligned_bytes(), i))
+        .collect();
+
+    {
+        let market =
+            MarketState::load(&market_accounts.market, market_accounts.market.owner).unwrap();
+        let totals = MarketTotals {
+            coin_deposits_total: market.coin_deposits_total,
+            pc_deposits_total: market.pc_deposits_total,
+            pc_fees_accrued: market.pc_fees_accrued,
+            referrer_rebates_accrued: market.referrer_rebates_accrued,
+        };
+        assert_eq!(totals, reference.totals());
+        assert_eq!(market.coin_fees_accrued, 0);
+    }
+
+    {
+        let (header, buf) = strip_header(&market_accounts.req_q, false).unwrap();
+        let req_q: RequestQueue = Queue::new(header, buf);
+        assert_eq!(req_q.len() as usize, reference.request_count());
+    }
+
+    {
+        let (header, buf) = strip_header(&market_accounts.event_q, false).unwrap();
+        let event_q: EventQueue = Queue::new(header, buf);
+        assert_eq!(event_q.len() as usize, reference.event_count());
+        let fills: Vec<Fill> = event_q
+            .iter()
+            .filter_map(|event| match event.as_view().unwrap() {
+                EventView::Fill {
+                    side,
+                    maker,
+                    native_qty_paid,
+                    native_qty_received,
+                    native_fee_or_rebate,
+                    owner,
+                    owner_slot,
+                    ..
+                } => Some(Fill {
+                    owner: owner_indices[owner],
+                    owner_slot,
+                    side,
+                    maker,
+                    native_qty_paid,
+                    native_qty_received,
+                    native_fee_or_rebate,
+                }),
+                EventView::Out { .. } | EventView::Triggered { .. } => None,
+            })
+            .collect();
+        assert_eq!(fills, reference.pending_fills());
+    }
+
+    for (side, account) in &[
+        (Side::Bid, &market_accounts.bids),
+        (Side::Ask, &market_accounts.asks),
+    ] {
+        let (_, mut buf) = strip_header::<u64, u8>(account, false).unwrap();
+        let slab = Slab::new(&mut *buf);
+        let to_resting_order = |order: &serum_dex::critbit::LeafNode| RestingOrder {
+            owner: owner_indices[order.owner()],
+            owner_slot: order.owner_slot(),
+            price: order.price().get(),
+            quantity: order.quantity(),
+            client_order_id: order.client_order_id(),
+        };
+        match side {
+            Side::Bid => assert_eq!(
+                slab.iter_rev().map(to_resting_order).collect::<Vec<_>>(),
+                reference.bids()
+            ),
+            Side::Ask => assert_eq!(
+                slab.iter().map(to_resting_order).collect::<Vec<_>>(),
+                reference.asks()
+            ),
+        }
+    }
+
+    for (i, owner) in owners.iter().enumerate() {
+        assert_eq!(
+            (
+                get_token_account_balance(&owner.coin_account),
+                get_token_account_balance(&owner.pc_account)
+            ),
+            reference.wallets(i)
+        );
+        let open_orders = match owner.open_orders() {
+            Some(open_orders) => open_orders,
+            None => {
+                assert_eq!(reference.balances(i), Balances::default());
+                assert!((0..128).all(|slot| reference.slot(i, slot).is_none()));
+                continue;
+            }
+        };
+        let balances = Balances {
+            native_coin_free: open_orders.native_coin_free,
+            native_coin_total: open_orders.native_coin_total,
+            native_pc_free: open_orders.native_pc_free,
+            native_pc_total: open_orders.native_pc_total,
+            referrer_rebates_accrued: open_orders.referrer_rebates_accrued,
+        };
+        assert_eq!(balances, reference.balances(i), "owner {}", i);
+        for slot in 0..128u8 {
+            let expected = reference
+                .slot(i, slot)
+                .map(|slot| (slot.side, slot.client_order_id));
+            let actual = open_orders
+                .slot_side(slot)
+                .map(|side| (side, open_orders.client_order_id(slot)));
+            assert_eq!(actual, expected, "owner {} slot {}", i, slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_order(owner: u8, side: Side, limit_price: u8) -> Action {
+        Action::NewOrder {
+            owner,
+            side,
+            limit_price,
+            max_qty: 0,
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        }
+    }
+
+    #[test]
+    fn test_cancels_ahead_of_new_orders() {
+        run_actions(vec![
+            new_order(0, Side::Bid, 10),
+            Action::MatchOrders(10),
+            // the cancel of the resting bid goes ahead of the ask that would
+            // fill it
+            new_order(1, Side::Ask, 10),
+            Action::CancelOrder { owner: 0, slot: 0 },
+            // the cancel of a queued order stays behind it
+            new_order(2, Side::Bid, 5),
+            Action::CancelOrder { owner: 2, slot: 0 },
+            Action::MatchOrders(10),
+            Action::ConsumeEvents(10),
+        ]);
+    }
+}
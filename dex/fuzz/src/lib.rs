@@ -5,6 +5,8 @@ use safe_transmute::to_bytes::{transmute_to_bytes, transmute_to_bytes_mut};
 use solana_sdk::account_info::AccountInfo;
 use solana_sdk::bpf_loader;
 use solana_sdk::clock::Epoch;
+use solana_sdk::native_loader;
+use solana_sdk::program_option::COption;
 use solana_sdk::pubkey::Pubkey;
 use solana_sdk::rent::Rent;
 use solana_sdk::system_program;
@@ -14,12 +16,20 @@ use spl_token::pack::Pack;
 use spl_token::state::Account as SplAccount;
 use spl_token::state::Mint;
 
+use serum_dex::audit::{MarketAudit, Violation};
+use serum_dex::critbit::{LeafNode, Slab};
 use serum_dex::error::DexResult;
 use serum_dex::instruction::{fee_sweeper, initialize_market};
 use serum_dex::state::{
-    gen_vault_signer_key, strip_header, EventQueue, MarketState, Queue, RequestQueue, State,
+    gen_vault_signer_key, strip_header, EventQueue, MarketState, MarketStateV2, OpenOrdersMut,
+    OpenOrdersView, Queue, RequestQueue, State, ToAlignedBytes,
 };
 
+pub mod bench;
+pub mod differential;
+pub mod reference;
+pub mod replay;
+
 fn random_pubkey(bump: &Bump) -> &Pubkey {
     bump.alloc(Pubkey::new(transmute_to_bytes(&rand::random::<[u64; 4]>())))
 }
@@ -95,6 +105,25 @@ pub fn new_token_mint(bump: &Bump) -> AccountInfo {
     )
 }
 
+/// The mint of wrapped SOL, at its well-known address.
+pub fn new_native_mint(bump: &Bump) -> AccountInfo {
+    let data = bump.alloc_slice_fill_copy(Mint::LEN, 0u8);
+    let mut mint = Mint::default();
+    mint.is_initialized = true;
+    mint.decimals = spl_token::native_mint::DECIMALS;
+    Mint::pack(mint, data).unwrap();
+    AccountInfo::new(
+        bump.alloc(spl_token::native_mint::ID),
+        false,
+        false,
+        bump.alloc(0),
+        data,
+        &spl_token::ID,
+        false,
+        Epoch::default(),
+    )
+}
+
 pub fn new_token_account<'bump, 'a, 'b>(
     mint_pubkey: &'a Pubkey,
     owner_pubkey: &'b Pubkey,
@@ -120,6 +149,63 @@ pub fn new_token_account<'bump, 'a, 'b>(
     )
 }
 
+/// A wrapped SOL account, which holds `balance` lamports on top of its rent
+/// exempt reserve.
+pub fn new_native_token_account<'bump, 'a>(
+    owner_pubkey: &'a Pubkey,
+    balance: u64,
+    bump: &'bump Bump,
+) -> AccountInfo<'bump> {
+    let data = bump.alloc_slice_fill_copy(SplAccount::LEN, 0u8);
+    let rent_exempt_reserve = Rent::default().minimum_balance(SplAccount::LEN);
+    let mut account = SplAccount::default();
+    account.state = spl_token::state::AccountState::Initialized;
+    account.mint = spl_token::native_mint::ID;
+    account.owner = *owner_pubkey;
+    account.amount = balance;
+    account.is_native = COption::Some(rent_exempt_reserve);
+    SplAccount::pack(account, data).unwrap();
+    AccountInfo::new(
+        random_pubkey(bump),
+        false,
+        true,
+        bump.alloc(rent_exempt_reserve + balance),
+        data,
+        &spl_token::ID,
+        false,
+        Epoch::default(),
+    )
+}
+
+/// A fresh account for the temporary wrapped SOL account of an instruction
+/// paying or settling in native SOL, already sized and assigned to the token
+/// program, since that can't be done outside of the runtime.
+pub fn new_temp_token_account(bump: &Bump) -> AccountInfo {
+    AccountInfo::new(
+        random_pubkey(bump),
+        true,
+        true,
+        bump.alloc(0),
+        bump.alloc_slice_fill_copy(SplAccount::LEN, 0u8),
+        &spl_token::ID,
+        false,
+        Epoch::default(),
+    )
+}
+
+pub fn new_system_program(bump: &Bump) -> AccountInfo {
+    AccountInfo::new(
+        &system_program::ID,
+        false,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &native_loader::ID,
+        false,
+        Epoch::default(),
+    )
+}
+
 pub fn new_spl_token_program(bump: &Bump) -> AccountInfo {
     AccountInfo::new(
         &spl_token::ID,
@@ -196,22 +282,79 @@ pub const PC_LOT_SIZE: u64 = 100;
 pub const PC_DUST_THRESHOLD: u64 = 500;
 
 pub fn setup_market(bump: &Bump) -> MarketAccounts {
+    setup_market_with_sizes(1 << 16, 640, 65536, bump)
+}
+
+/// Like `setup_market`, with the unpadded sizes in bytes of the order book
+/// sides and the queues.
+pub fn setup_market_with_sizes(
+    slab_len: usize,
+    req_q_len: usize,
+    event_q_len: usize,
+    bump: &Bump,
+) -> MarketAccounts {
+    setup_market_with_pc_mint(
+        size_of::<MarketStateV2>(),
+        slab_len,
+        req_q_len,
+        event_q_len,
+        new_token_mint(bump),
+        bump,
+    )
+}
+
+/// Like `setup_market`, with the version 1 layout markets had before
+/// `MarketStateV2`, for exercising `MigrateMarket`.
+pub fn setup_market_v1(bump: &Bump) -> MarketAccounts {
+    setup_market_with_pc_mint(
+        size_of::<MarketState>(),
+        1 << 16,
+        640,
+        65536,
+        new_token_mint(bump),
+        bump,
+    )
+}
+
+/// Like `setup_market`, with wrapped SOL as the price currency.
+pub fn setup_native_pc_market(bump: &Bump) -> MarketAccounts {
+    setup_market_with_pc_mint(
+        size_of::<MarketStateV2>(),
+        1 << 16,
+        640,
+        65536,
+        new_native_mint(bump),
+        bump,
+    )
+}
+
+fn setup_market_with_pc_mint<'bump>(
+    market_len: usize,
+    slab_len: usize,
+    req_q_len: usize,
+    event_q_len: usize,
+    pc_mint: AccountInfo<'bump>,
+    bump: &'bump Bump,
+) -> MarketAccounts<'bump> {
     let program_id = random_pubkey(bump);
-    let market = new_dex_owned_account(size_of::<MarketState>(), program_id, bump);
-    let bids = new_dex_owned_account(1 << 16, program_id, bump);
-    let asks = new_dex_owned_account(1 << 16, program_id, bump);
-    let req_q = new_dex_owned_account(640, program_id, bump);
-    let event_q = new_dex_owned_account(65536, program_id, bump);
+    let market = new_dex_owned_account(market_len, program_id, bump);
+    let bids = new_dex_owned_account(slab_len, program_id, bump);
+    let asks = new_dex_owned_account(slab_len, program_id, bump);
+    let req_q = new_dex_owned_account(req_q_len, program_id, bump);
+    let event_q = new_dex_owned_account(event_q_len, program_id, bump);
 
     let coin_mint = new_token_mint(bump);
-    let pc_mint = new_token_mint(bump);
 
     let rent_sysvar = new_rent_sysvar_account(100000, Rent::default(), bump);
 
     let (vault_signer_nonce, vault_signer) = new_vault_signer_account(&market, program_id, bump);
 
     let coin_vault = new_token_account(coin_mint.key, vault_signer.key, 0, bump);
-    let pc_vault = new_token_account(pc_mint.key, vault_signer.key, 0, bump);
+    let pc_vault = if *pc_mint.key == spl_token::native_mint::ID {
+        new_native_token_account(vault_signer.key, 0, bump)
+    } else {
+        new_token_account(pc_mint.key, vault_signer.key, 0, bump)
+    };
     let fee_receiver = new_token_account(pc_mint.key, random_pubkey(bump), 0, bump);
     let sweep_authority = new_sol_account_with_pubkey(bump.alloc(fee_sweeper::ID), 0, bump);
 
@@ -314,6 +457,62 @@ impl<'bump> MarketAccounts<'bump> {
         }
         println!("]");
     }
+
+    /// Audits the market against `open_orders_accounts`, which must include
+    /// every initialized `OpenOrders` account of the market.
+    pub fn audit(&self, open_orders_accounts: &[&AccountInfo<'bump>]) -> Vec<Violation> {
+        let (coin_staking_fees, pc_staking_fees) =
+            match MarketStateV2::load(&self.market, self.market.owner) {
+                Ok(market) => (
+                    market.fee_split.staking_coin_fees,
+                    market.fee_split.staking_pc_fees,
+                ),
+                Err(_) => (0, 0),
+            };
+        let market = MarketState::load(&self.market, self.market.owner).unwrap();
+        let load_orders = |account: &AccountInfo| -> Vec<LeafNode> {
+            let (_, mut buf) = strip_header::<u64, u8>(account, false).unwrap();
+            let slab = Slab::new(&mut *buf);
+            slab.iter().copied().collect()
+        };
+        let bids = load_orders(&self.bids);
+        let asks = load_orders(&self.asks);
+        let request_queue_len = {
+            let (header, buf) = strip_header(&self.req_q, false).unwrap();
+            let requests: RequestQueue = Queue::new(header, buf);
+            requests.len()
+        };
+        let event_queue_len = {
+            let (header, buf) = strip_header(&self.event_q, false).unwrap();
+            let events: EventQueue = Queue::new(header, buf);
+            events.len()
+        };
+        let open_orders: Vec<([u64; 4], OpenOrdersMut)> = open_orders_accounts
+            .iter()
+            .map(|account| {
+                let open_orders = OpenOrdersMut::load(account, self.market.owner).unwrap();
+                (account.key.to_aligned_bytes(), open_orders)
+            })
+            .collect();
+        let open_orders: Vec<([u64; 4], OpenOrdersView)> = open_orders
+            .iter()
+            .map(|(address, open_orders)| (*address, open_orders.view()))
+            .collect();
+        MarketAudit {
+            market: &market,
+            coin_vault_balance: get_token_account_balance(&self.coin_vault),
+            pc_vault_balance: get_token_account_balance(&self.pc_vault),
+            coin_staking_fees,
+            pc_staking_fees,
+            bids: &bids,
+            asks: &asks,
+            request_queue_len,
+            event_queue_len,
+            open_orders: &open_orders,
+            trigger_orders: &[],
+        }
+        .run()
+    }
 }
 
 pub fn get_token_account_balance(account: &AccountInfo) -> u64 {
@@ -322,3 +521,501 @@ pub fn get_token_account_balance(account: &AccountInfo) -> u64 {
     let unpacked = SplAccount::unpack(&data).unwrap();
     return unpacked.amount;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU64;
+
+    use serum_dex::error::DexErrorCode;
+    use serum_dex::instruction::{
+        CancelOrderInstruction, MarketInstruction, NewOrderInstructionV2, SelfTradeBehavior,
+        SetFeeSplitInstruction,
+    };
+    use serum_dex::matching::{OrderType, Side};
+    use serum_dex::state::OpenOrders;
+
+    use super::*;
+
+    const WALLET_LAMPORTS: u64 = 10_000_000_000;
+
+    fn temp_account_state(temp_account: &AccountInfo) -> SplAccount {
+        SplAccount::unpack_unchecked(&temp_account.try_borrow_data().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_native_sol_new_order_and_settle() {
+        let bump = Bump::new();
+        let market_accounts = setup_native_pc_market(&bump);
+        let program_id = market_accounts.market.owner;
+        let rent_exempt_reserve = Rent::default().minimum_balance(SplAccount::LEN);
+
+        let wallet = new_sol_account(WALLET_LAMPORTS, &bump);
+        let coin_wallet = new_token_account(market_accounts.coin_mint.key, wallet.key, 0, &bump);
+        let orders_account = new_dex_owned_account_with_lamports(
+            size_of::<OpenOrders>(),
+            10_000_000_000,
+            program_id,
+            &bump,
+        );
+        let native_mint = new_native_mint(&bump);
+        let system_program = new_system_program(&bump);
+
+        // pay for a bid from the system wallet
+        let temp_account = new_temp_token_account(&bump);
+        let instruction = MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(1_000).unwrap(),
+            max_qty: NonZeroU64::new(10).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        });
+        process_instruction(
+            program_id,
+            &[
+                market_accounts.market.clone(),
+                orders_account.clone(),
+                market_accounts.req_q.clone(),
+                wallet.clone(),
+                wallet.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+                market_accounts.spl_token_program.clone(),
+                market_accounts.rent_sysvar.clone(),
+                temp_account.clone(),
+                native_mint.clone(),
+                market_accounts.vault_signer.clone(),
+                system_program.clone(),
+            ],
+            &instruction.pack(),
+        )
+        .unwrap();
+
+        let deposit = {
+            let open_orders = OpenOrdersMut::load(&orders_account, program_id).unwrap();
+            assert_eq!(open_orders.native_pc_free, 0);
+            open_orders.native_pc_total
+        };
+        assert!(deposit > 0);
+        assert_eq!(
+            get_token_account_balance(&market_accounts.pc_vault),
+            deposit
+        );
+        assert_eq!(
+            market_accounts.pc_vault.lamports(),
+            rent_exempt_reserve + deposit
+        );
+        // the wrapped account was the vault signer's, and was closed with its
+        // rent refunded to the wallet
+        let temp_state = temp_account_state(&temp_account);
+        assert_eq!(temp_state.owner, *market_accounts.vault_signer.key);
+        assert_eq!(temp_state.mint, spl_token::native_mint::ID);
+        assert_eq!(temp_state.amount, 0);
+        assert_eq!(temp_account.lamports(), 0);
+        assert_eq!(wallet.lamports(), WALLET_LAMPORTS - deposit);
+
+        // cancel the bid so its funds are free again
+        let order_id = OpenOrdersMut::load(&orders_account, program_id)
+            .unwrap()
+            .order_id(0)
+            .unwrap();
+        process_instruction(
+            program_id,
+            &[
+                market_accounts.market.clone(),
+                orders_account.clone(),
+                market_accounts.req_q.clone(),
+                wallet.clone(),
+            ],
+            &MarketInstruction::CancelOrder(CancelOrderInstruction {
+                side: Side::Bid,
+                order_id,
+                owner: [0; 4],
+                owner_slot: 0,
+            })
+            .pack(),
+        )
+        .unwrap();
+        process_instruction(
+            program_id,
+            &[
+                market_accounts.market.clone(),
+                market_accounts.req_q.clone(),
+                market_accounts.event_q.clone(),
+                market_accounts.bids.clone(),
+                market_accounts.asks.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+            ],
+            &MarketInstruction::MatchOrders(10).pack(),
+        )
+        .unwrap();
+        process_instruction(
+            program_id,
+            &[
+                orders_account.clone(),
+                market_accounts.market.clone(),
+                market_accounts.event_q.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+            ],
+            &MarketInstruction::ConsumeEvents(10).pack(),
+        )
+        .unwrap();
+        assert_eq!(
+            OpenOrdersMut::load(&orders_account, program_id)
+                .unwrap()
+                .native_pc_free,
+            deposit
+        );
+
+        // settle the price currency back to the system wallet
+        let temp_account = new_temp_token_account(&bump);
+        process_instruction(
+            program_id,
+            &[
+                market_accounts.market.clone(),
+                orders_account.clone(),
+                wallet.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+                coin_wallet.clone(),
+                wallet.clone(),
+                market_accounts.vault_signer.clone(),
+                market_accounts.spl_token_program.clone(),
+                temp_account.clone(),
+                native_mint.clone(),
+                system_program.clone(),
+                market_accounts.rent_sysvar.clone(),
+            ],
+            &MarketInstruction::SettleFunds.pack(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            OpenOrdersMut::load(&orders_account, program_id)
+                .unwrap()
+                .native_pc_total,
+            0
+        );
+        assert_eq!(get_token_account_balance(&market_accounts.pc_vault), 0);
+        assert_eq!(market_accounts.pc_vault.lamports(), rent_exempt_reserve);
+        let temp_state = temp_account_state(&temp_account);
+        assert_eq!(temp_state.owner, *market_accounts.vault_signer.key);
+        assert_eq!(temp_account.lamports(), 0);
+        assert_eq!(wallet.lamports(), WALLET_LAMPORTS);
+    }
+
+    #[test]
+    fn test_native_sol_new_order_insufficient_lamports() {
+        let bump = Bump::new();
+        let market_accounts = setup_native_pc_market(&bump);
+        let program_id = market_accounts.market.owner;
+
+        // enough for the bid, but not for the wrapped account's rent on top
+        let lamports = 1_000 * 10 * PC_LOT_SIZE;
+        let wallet = new_sol_account(lamports, &bump);
+        let orders_account = new_dex_owned_account_with_lamports(
+            size_of::<OpenOrders>(),
+            10_000_000_000,
+            program_id,
+            &bump,
+        );
+        let temp_account = new_temp_token_account(&bump);
+        let instruction = MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(1_000).unwrap(),
+            max_qty: NonZeroU64::new(10).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        });
+        let result = process_instruction(
+            program_id,
+            &[
+                market_accounts.market.clone(),
+                orders_account.clone(),
+                market_accounts.req_q.clone(),
+                wallet.clone(),
+                wallet.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+                market_accounts.spl_token_program.clone(),
+                market_accounts.rent_sysvar.clone(),
+                temp_account.clone(),
+                new_native_mint(&bump),
+                market_accounts.vault_signer.clone(),
+                new_system_program(&bump),
+            ],
+            &instruction.pack(),
+        );
+        assert_eq!(result, Err(DexErrorCode::InsufficientFunds.into()));
+        assert_eq!(wallet.lamports(), lamports);
+        assert_eq!(temp_account.lamports(), 0);
+    }
+
+    #[test]
+    fn test_migrate_v1_market() {
+        let bump = Bump::new();
+        let market_accounts = setup_market_v1(&bump);
+        let program_id = market_accounts.market.owner;
+
+        let owner = new_sol_account(WALLET_LAMPORTS, &bump);
+        let pc_wallet = new_token_account(market_accounts.pc_mint.key, owner.key, 1_000_000, &bump);
+        let orders_account = new_dex_owned_account_with_lamports(
+            size_of::<OpenOrders>(),
+            10_000_000_000,
+            program_id,
+            &bump,
+        );
+
+        // a bid resting on the version 1 market
+        let instruction = MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(1_000).unwrap(),
+            max_qty: NonZeroU64::new(10).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        });
+        process_instruction(
+            program_id,
+            &[
+                market_accounts.market.clone(),
+                orders_account.clone(),
+                market_accounts.req_q.clone(),
+                pc_wallet.clone(),
+                owner.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+                market_accounts.spl_token_program.clone(),
+                market_accounts.rent_sysvar.clone(),
+            ],
+            &instruction.pack(),
+        )
+        .unwrap();
+        let match_orders = |market| {
+            process_instruction(
+                program_id,
+                &[
+                    market,
+                    market_accounts.req_q.clone(),
+                    market_accounts.event_q.clone(),
+                    market_accounts.bids.clone(),
+                    market_accounts.asks.clone(),
+                    market_accounts.coin_vault.clone(),
+                    market_accounts.pc_vault.clone(),
+                ],
+                &MarketInstruction::MatchOrders(10).pack(),
+            )
+        };
+        match_orders(market_accounts.market.clone()).unwrap();
+        let deposit = get_token_account_balance(&market_accounts.pc_vault);
+        assert!(deposit > 0);
+
+        let new_market = new_dex_owned_account_with_lamports(
+            size_of::<MarketStateV2>(),
+            10_000_000_000,
+            program_id,
+            &bump,
+        );
+        process_instruction(
+            program_id,
+            &[
+                market_accounts.market.clone(),
+                new_market.clone(),
+                market_accounts.sweep_authority.clone(),
+                market_accounts.rent_sysvar.clone(),
+            ],
+            &MarketInstruction::MigrateMarket.pack(),
+        )
+        .unwrap();
+        assert_eq!(
+            match_orders(market_accounts.market.clone()),
+            Err(DexErrorCode::InvalidMarketFlags.into())
+        );
+
+        // the old OpenOrders account cancels its bid through the new account
+        let order_id = OpenOrdersMut::load(&orders_account, program_id)
+            .unwrap()
+            .order_id(0)
+            .unwrap();
+        process_instruction(
+            program_id,
+            &[
+                new_market.clone(),
+                orders_account.clone(),
+                market_accounts.req_q.clone(),
+                owner.clone(),
+            ],
+            &MarketInstruction::CancelOrder(CancelOrderInstruction {
+                side: Side::Bid,
+                order_id,
+                owner: [0; 4],
+                owner_slot: 0,
+            })
+            .pack(),
+        )
+        .unwrap();
+        match_orders(new_market.clone()).unwrap();
+        process_instruction(
+            program_id,
+            &[
+                orders_account.clone(),
+                new_market.clone(),
+                market_accounts.event_q.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+            ],
+            &MarketInstruction::ConsumeEvents(10).pack(),
+        )
+        .unwrap();
+        assert_eq!(
+            OpenOrdersMut::load(&orders_account, program_id)
+                .unwrap()
+                .native_pc_free,
+            deposit
+        );
+
+        let migrated = MarketAccounts {
+            market: new_market,
+            ..market_accounts
+        };
+        assert!(migrated.audit(&[&orders_account]).is_empty());
+    }
+
+    #[test]
+    fn test_sweep_fees_split() {
+        let bump = Bump::new();
+        let market_accounts = setup_market(&bump);
+        let program_id = market_accounts.market.owner;
+
+        let owner = new_sol_account(WALLET_LAMPORTS, &bump);
+        let coin_wallet =
+            new_token_account(market_accounts.coin_mint.key, owner.key, 10_000_000, &bump);
+        let pc_wallet =
+            new_token_account(market_accounts.pc_mint.key, owner.key, 10_000_000, &bump);
+        let new_orders_account = || {
+            new_dex_owned_account_with_lamports(
+                size_of::<OpenOrders>(),
+                10_000_000_000,
+                program_id,
+                &bump,
+            )
+        };
+        let orders_accounts = [new_orders_account(), new_orders_account()];
+
+        // an ask, and a bid taking it, so that pc fees accrue
+        let orders = [(&coin_wallet, Side::Ask), (&pc_wallet, Side::Bid)];
+        for (orders_account, &(payer, side)) in orders_accounts.iter().zip(orders.iter()) {
+            let instruction = MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                side,
+                limit_price: NonZeroU64::new(1_000).unwrap(),
+                max_qty: NonZeroU64::new(10).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: 0,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            });
+            process_instruction(
+                program_id,
+                &[
+                    market_accounts.market.clone(),
+                    orders_account.clone(),
+                    market_accounts.req_q.clone(),
+                    payer.clone(),
+                    owner.clone(),
+                    market_accounts.coin_vault.clone(),
+                    market_accounts.pc_vault.clone(),
+                    market_accounts.spl_token_program.clone(),
+                    market_accounts.rent_sysvar.clone(),
+                ],
+                &instruction.pack(),
+            )
+            .unwrap();
+            process_instruction(
+                program_id,
+                &[
+                    market_accounts.market.clone(),
+                    market_accounts.req_q.clone(),
+                    market_accounts.event_q.clone(),
+                    market_accounts.bids.clone(),
+                    market_accounts.asks.clone(),
+                    market_accounts.coin_vault.clone(),
+                    market_accounts.pc_vault.clone(),
+                ],
+                &MarketInstruction::MatchOrders(10).pack(),
+            )
+            .unwrap();
+        }
+        let pc_fees = MarketState::load(&market_accounts.market, program_id)
+            .unwrap()
+            .pc_fees_accrued;
+        assert!(pc_fees > 0);
+
+        let treasury = random_pubkey(&bump);
+        let referrer = random_pubkey(&bump);
+        let treasury_pc_wallet = new_token_account(market_accounts.pc_mint.key, treasury, 0, &bump);
+        let referrer_pc_wallet = new_token_account(market_accounts.pc_mint.key, referrer, 0, &bump);
+        process_instruction(
+            program_id,
+            &[
+                market_accounts.market.clone(),
+                market_accounts.sweep_authority.clone(),
+                new_token_account(market_accounts.coin_mint.key, treasury, 0, &bump),
+                treasury_pc_wallet.clone(),
+                new_token_account(market_accounts.coin_mint.key, referrer, 0, &bump),
+                referrer_pc_wallet.clone(),
+            ],
+            &MarketInstruction::SetFeeSplit(SetFeeSplitInstruction {
+                staking_bps: 2_000,
+                treasury_bps: 5_000,
+                referrer_bps: 3_000,
+                route_staking_to_treasury: false,
+            })
+            .pack(),
+        )
+        .unwrap();
+
+        // nobody signs the sweep
+        let sweep_accounts = [
+            market_accounts.market.clone(),
+            market_accounts.pc_vault.clone(),
+            treasury_pc_wallet.clone(),
+            referrer_pc_wallet.clone(),
+            market_accounts.vault_signer.clone(),
+            market_accounts.spl_token_program.clone(),
+        ];
+        assert!(!sweep_accounts[2..4].iter().any(|account| account.is_signer));
+        let vault_balance = get_token_account_balance(&market_accounts.pc_vault);
+        process_instruction(
+            program_id,
+            &sweep_accounts,
+            &MarketInstruction::SweepFees.pack(),
+        )
+        .unwrap();
+
+        let staking_fees = pc_fees * 2_000 / 10_000;
+        let referrer_fees = pc_fees * 3_000 / 10_000;
+        assert_eq!(
+            get_token_account_balance(&referrer_pc_wallet),
+            referrer_fees
+        );
+        assert_eq!(
+            get_token_account_balance(&treasury_pc_wallet),
+            pc_fees - staking_fees - referrer_fees
+        );
+        assert_eq!(
+            get_token_account_balance(&market_accounts.pc_vault),
+            vault_balance - pc_fees + staking_fees
+        );
+        assert_eq!(
+            MarketStateV2::load(&market_accounts.market, program_id)
+                .unwrap()
+                .fee_split
+                .staking_pc_fees,
+            staking_fees
+        );
+        let orders_accounts: Vec<_> = orders_accounts.iter().collect();
+        assert!(market_accounts.audit(&orders_accounts).is_empty());
+    }
+}
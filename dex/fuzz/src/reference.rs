@@ -0,0 +1,768 @@
+//! A deliberately simple order book, used as an oracle for the program's
+//! matching in the `differential` fuzz target.
+//!
+//! Orders rest in `BTreeMap`s keyed by price and arrival, so price-time
+//! priority falls out of the map ordering. Requests and events go through
+//! plain queues like the program's, so that `MatchOrders` and `ConsumeEvents`
+//! limits cut the work at the same points, and each owner's balances are kept
+//! in a ledger that events are applied to. Fees are always charged in pc at
+//! the base tier, and the book is assumed never to fill up.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+
+use serum_dex::error::DexErrorCode;
+use serum_dex::fees::{self, FeeTier};
+use serum_dex::instruction::SelfTradeBehavior;
+use serum_dex::matching::{OrderType, Side};
+
+const FEE_TIER: FeeTier = FeeTier::Base;
+const SLOTS: usize = 128;
+// as in the program's matching
+const QUEUED_CANCELS_WINDOW: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RestingOrder {
+    pub owner: usize,
+    pub owner_slot: u8,
+    pub price: u64,
+    pub quantity: u64,
+    pub client_order_id: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Fill {
+    pub owner: usize,
+    pub owner_slot: u8,
+    pub side: Side,
+    pub maker: bool,
+    pub native_qty_paid: u64,
+    pub native_qty_received: u64,
+    pub native_fee_or_rebate: u64,
+}
+
+/// The `OpenOrders` balances of an owner.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Balances {
+    pub native_coin_free: u64,
+    pub native_coin_total: u64,
+    pub native_pc_free: u64,
+    pub native_pc_total: u64,
+    pub referrer_rebates_accrued: u64,
+}
+
+/// The market totals the program keeps in `MarketState`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MarketTotals {
+    pub coin_deposits_total: u64,
+    pub pc_deposits_total: u64,
+    pub pc_fees_accrued: u64,
+    pub referrer_rebates_accrued: u64,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Event {
+    Fill(Fill),
+    Out {
+        owner: usize,
+        owner_slot: u8,
+        side: Side,
+        native_qty_unlocked: u64,
+        /// Whether the order is gone, which frees its slot.
+        done: bool,
+    },
+}
+
+#[derive(Copy, Clone, Debug)]
+struct PendingOrder {
+    owner: usize,
+    owner_slot: u8,
+    side: Side,
+    order_type: OrderType,
+    self_trade_behavior: SelfTradeBehavior,
+    limit_price: u64,
+    seq_num: u64,
+    client_order_id: u64,
+    coin_qty_remaining: u64,
+    /// The pc a bid still has locked, fees included.
+    native_pc_qty_locked: u64,
+}
+
+impl PendingOrder {
+    fn post_only(&self) -> bool {
+        self.order_type == OrderType::PostOnly || self.order_type == OrderType::PostOnlySlide
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Request {
+    NewOrder(PendingOrder),
+    Cancel {
+        owner: usize,
+        owner_slot: u8,
+        side: Side,
+        price: u64,
+        seq_num: u64,
+    },
+}
+
+impl Request {
+    /// Whether this is the new order that `cancel` cancels.
+    fn is_cancelled_by(&self, cancel: &Request) -> bool {
+        match (self, cancel) {
+            (
+                Request::NewOrder(order),
+                Request::Cancel {
+                    owner,
+                    owner_slot,
+                    seq_num,
+                    ..
+                },
+            ) => {
+                order.owner == *owner
+                    && order.owner_slot == *owner_slot
+                    && order.seq_num == *seq_num
+            }
+            _ => false,
+        }
+    }
+}
+
+/// An order's position in its owner's slots.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Slot {
+    pub side: Side,
+    pub client_order_id: u64,
+    price: u64,
+    seq_num: u64,
+}
+
+struct Owner {
+    coin_wallet: u64,
+    pc_wallet: u64,
+    balances: Balances,
+    slots: [Option<Slot>; SLOTS],
+}
+
+pub struct ReferenceBook {
+    coin_lot_size: u64,
+    pc_lot_size: u64,
+    next_seq_num: u64,
+    // best first: lowest price, then oldest
+    asks: BTreeMap<(u64, u64), RestingOrder>,
+    // best first: highest price, then oldest
+    bids: BTreeMap<(Reverse<u64>, u64), RestingOrder>,
+    requests: VecDeque<Request>,
+    events: VecDeque<Event>,
+    owners: Vec<Owner>,
+    totals: MarketTotals,
+}
+
+impl ReferenceBook {
+    pub fn new(
+        coin_lot_size: u64,
+        pc_lot_size: u64,
+        owners: usize,
+        coin_wallet: u64,
+        pc_wallet: u64,
+    ) -> Self {
+        ReferenceBook {
+            coin_lot_size,
+            pc_lot_size,
+            next_seq_num: 0,
+            asks: BTreeMap::new(),
+            bids: BTreeMap::new(),
+            requests: VecDeque::new(),
+            events: VecDeque::new(),
+            owners: (0..owners)
+                .map(|_| Owner {
+                    coin_wallet,
+                    pc_wallet,
+                    balances: Balances::default(),
+                    slots: [None; SLOTS],
+                })
+                .collect(),
+            totals: MarketTotals::default(),
+        }
+    }
+
+    /// Locks the funds for an order and queues it, or returns the error the
+    /// program should fail `NewOrderV2` with.
+    pub fn new_order(
+        &mut self,
+        owner: usize,
+        side: Side,
+        limit_price: u64,
+        max_qty: u64,
+        order_type: OrderType,
+        client_order_id: u64,
+        self_trade_behavior: SelfTradeBehavior,
+    ) -> Result<(), DexErrorCode> {
+        let limit_price = match order_type {
+            OrderType::PostOnlySlide => self.slide_price(side, limit_price),
+            _ => limit_price,
+        };
+        let lock = match side {
+            Side::Bid => {
+                let before_fee = max_qty
+                    .checked_mul(limit_price)
+                    .and_then(|lots| lots.checked_mul(self.pc_lot_size))
+                    .ok_or(DexErrorCode::InsufficientFunds)?;
+                before_fee
+                    .checked_add(FEE_TIER.taker_fee(before_fee))
+                    .ok_or(DexErrorCode::InsufficientFunds)?
+            }
+            Side::Ask => max_qty
+                .checked_mul(self.coin_lot_size)
+                .ok_or(DexErrorCode::InsufficientFunds)?,
+        };
+        let owner_state = &mut self.owners[owner];
+        let (free, total, wallet, deposits_total) = match side {
+            Side::Bid => (
+                &mut owner_state.balances.native_pc_free,
+                &mut owner_state.balances.native_pc_total,
+                &mut owner_state.pc_wallet,
+                &mut self.totals.pc_deposits_total,
+            ),
+            Side::Ask => (
+                &mut owner_state.balances.native_coin_free,
+                &mut owner_state.balances.native_coin_total,
+                &mut owner_state.coin_wallet,
+                &mut self.totals.coin_deposits_total,
+            ),
+        };
+        let from_free = lock.min(*free);
+        let deposit = lock - from_free;
+        if deposit > *wallet {
+            return Err(DexErrorCode::InsufficientFunds);
+        }
+        let owner_slot = owner_state
+            .slots
+            .iter()
+            .position(Option::is_none)
+            .ok_or(DexErrorCode::TooManyOpenOrders)?;
+
+        *free -= from_free;
+        *total += deposit;
+        *wallet -= deposit;
+        *deposits_total += deposit;
+
+        let seq_num = self.gen_seq_num();
+        self.owners[owner].slots[owner_slot] = Some(Slot {
+            side,
+            client_order_id,
+            price: limit_price,
+            seq_num,
+        });
+        self.requests.push_back(Request::NewOrder(PendingOrder {
+            owner,
+            owner_slot: owner_slot as u8,
+            side,
+            order_type,
+            self_trade_behavior,
+            limit_price,
+            seq_num,
+            client_order_id,
+            coin_qty_remaining: max_qty,
+            native_pc_qty_locked: match side {
+                Side::Bid => lock,
+                Side::Ask => 0,
+            },
+        }));
+        Ok(())
+    }
+
+    /// Queues a cancel of the order currently in `owner_slot`, which must be
+    /// in use.
+    pub fn cancel_order(&mut self, owner: usize, owner_slot: u8) {
+        let slot = self.owners[owner].slots[owner_slot as usize].unwrap();
+        self.gen_seq_num();
+        self.requests.push_back(Request::Cancel {
+            owner,
+            owner_slot,
+            side: slot.side,
+            price: slot.price,
+            seq_num: slot.seq_num,
+        });
+    }
+
+    /// Processes requests, spending one unit of `limit` per cancel and per
+    /// maker an order trades with (or per order that trades with none).
+    ///
+    /// Like the program, the cancels among the first `QUEUED_CANCELS_WINDOW`
+    /// requests go first, except those of orders still queued ahead of them.
+    pub fn match_orders(&mut self, limit: u16) {
+        let mut limit = limit;
+        let window = self
+            .requests
+            .len()
+            .min(QUEUED_CANCELS_WINDOW)
+            .min(limit as usize);
+        let mut kept = Vec::new();
+        for request in self.requests.drain(..window).collect::<Vec<_>>() {
+            let cancel = match request {
+                Request::Cancel { .. } => request,
+                Request::NewOrder(_) => {
+                    kept.push(request);
+                    continue;
+                }
+            };
+            limit -= 1;
+            if kept.iter().any(|queued| queued.is_cancelled_by(&cancel)) {
+                kept.push(cancel);
+            } else {
+                self.cancel(cancel);
+            }
+        }
+        for request in kept.into_iter().rev() {
+            self.requests.push_front(request);
+        }
+
+        while limit > 0 {
+            let request = match self.requests.front().copied() {
+                Some(request) => request,
+                None => break,
+            };
+            match request {
+                Request::Cancel { .. } => {
+                    limit -= 1;
+                    self.requests.pop_front();
+                    self.cancel(request);
+                }
+                Request::NewOrder(mut order) => loop {
+                    limit -= 1;
+                    let finished = match order.side {
+                        Side::Bid => self.bid_step(&mut order),
+                        Side::Ask => self.ask_step(&mut order),
+                    };
+                    if finished {
+                        self.requests.pop_front();
+                        break;
+                    }
+                    if limit == 0 {
+                        *self.requests.front_mut().unwrap() = Request::NewOrder(order);
+                        break;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Removes the order a cancel request names from the book, if it rests
+    /// there.
+    fn cancel(&mut self, request: Request) {
+        let (owner, owner_slot, side, price, seq_num) = match request {
+            Request::Cancel {
+                owner,
+                owner_slot,
+                side,
+                price,
+                seq_num,
+            } => (owner, owner_slot, side, price, seq_num),
+            Request::NewOrder(_) => unreachable!(),
+        };
+        let removed = match side {
+            Side::Bid => self.bids.remove(&(Reverse(price), seq_num)),
+            Side::Ask => self.asks.remove(&(price, seq_num)),
+        };
+        if let Some(order) = removed {
+            assert_eq!((order.owner, order.owner_slot), (owner, owner_slot));
+            let native_qty_unlocked = match side {
+                Side::Bid => order.quantity * order.price * self.pc_lot_size,
+                Side::Ask => order.quantity * self.coin_lot_size,
+            };
+            self.events.push_back(Event::Out {
+                owner,
+                owner_slot,
+                side,
+                native_qty_unlocked,
+                done: true,
+            });
+        }
+    }
+
+    /// Trades an ask with the best bid, or rests or cancels what's left of it
+    /// once it no longer crosses. Returns whether the ask is finished.
+    fn ask_step(&mut self, order: &mut PendingOrder) -> bool {
+        let (coin_lot_size, pc_lot_size) = (self.coin_lot_size, self.pc_lot_size);
+        let best_bid = self.bids.iter().next().map(|(key, bid)| (*key, *bid));
+        let crossing_bid = best_bid.filter(|(_, bid)| bid.price >= order.limit_price);
+
+        let (key, bid) = match crossing_bid {
+            Some(crossing_bid) if !order.post_only() => crossing_bid,
+            _ => {
+                let rests =
+                    crossing_bid.is_none() && order.order_type != OrderType::ImmediateOrCancel;
+                if rests {
+                    self.asks.insert(
+                        (order.limit_price, order.seq_num),
+                        resting_order(order, order.limit_price),
+                    );
+                } else {
+                    self.out(
+                        order,
+                        Side::Ask,
+                        order.coin_qty_remaining * coin_lot_size,
+                        true,
+                    );
+                }
+                return true;
+            }
+        };
+
+        let trade_qty = bid.quantity.min(order.coin_qty_remaining);
+        if bid.owner == order.owner {
+            let (cancelled_provide, cancelled_take) = match order.self_trade_behavior {
+                SelfTradeBehavior::DecrementTake => (trade_qty, trade_qty),
+                SelfTradeBehavior::CancelProvide => (bid.quantity, 0),
+            };
+            let provide_left = self.reduce_bid(key, cancelled_provide);
+            self.events.push_back(Event::Out {
+                owner: bid.owner,
+                owner_slot: bid.owner_slot,
+                side: Side::Bid,
+                native_qty_unlocked: cancelled_provide * bid.price * pc_lot_size,
+                done: provide_left == 0,
+            });
+            order.coin_qty_remaining -= cancelled_take;
+            let done = order.coin_qty_remaining == 0;
+            self.out(order, Side::Ask, cancelled_take * coin_lot_size, done);
+            return done;
+        }
+
+        let native_pc = trade_qty * bid.price * pc_lot_size;
+        let maker_rebate = FEE_TIER.maker_rebate(native_pc);
+        self.events.push_back(Event::Fill(Fill {
+            owner: bid.owner,
+            owner_slot: bid.owner_slot,
+            side: Side::Bid,
+            maker: true,
+            native_qty_paid: native_pc - maker_rebate,
+            native_qty_received: trade_qty * coin_lot_size,
+            native_fee_or_rebate: maker_rebate,
+        }));
+        if self.reduce_bid(key, trade_qty) == 0 {
+            self.events.push_back(Event::Out {
+                owner: bid.owner,
+                owner_slot: bid.owner_slot,
+                side: Side::Bid,
+                native_qty_unlocked: 0,
+                done: true,
+            });
+        }
+
+        let taker_fee = FEE_TIER.taker_fee(native_pc);
+        self.events.push_back(Event::Fill(Fill {
+            owner: order.owner,
+            owner_slot: order.owner_slot,
+            side: Side::Ask,
+            maker: false,
+            native_qty_paid: trade_qty * coin_lot_size,
+            native_qty_received: native_pc - taker_fee,
+            native_fee_or_rebate: taker_fee,
+        }));
+        self.collect_fees(taker_fee, maker_rebate);
+
+        order.coin_qty_remaining -= trade_qty;
+        if order.coin_qty_remaining > 0 {
+            return false;
+        }
+        self.out(order, Side::Ask, 0, true);
+        true
+    }
+
+    /// Trades a bid with the best ask, or rests or cancels what's left of it
+    /// once it no longer crosses or can't afford another lot. Returns whether
+    /// the bid is finished.
+    fn bid_step(&mut self, order: &mut PendingOrder) -> bool {
+        let (coin_lot_size, pc_lot_size) = (self.coin_lot_size, self.pc_lot_size);
+        // the price lots the locked pc pays for, once taker fees are taken out
+        let affordable_pc_lots =
+            FEE_TIER.remove_taker_fee(order.native_pc_qty_locked) / pc_lot_size;
+        let best_ask = self.asks.iter().next().map(|(key, ask)| (*key, *ask));
+        let crossing_ask = best_ask.filter(|(_, ask)| ask.price <= order.limit_price);
+
+        let trade = match crossing_ask {
+            Some((key, ask)) if !order.post_only() => {
+                let trade_qty = ask
+                    .quantity
+                    .min(order.coin_qty_remaining)
+                    .min(affordable_pc_lots / ask.price);
+                if trade_qty > 0 {
+                    Some((key, ask, trade_qty))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let (key, ask, trade_qty) = match trade {
+            Some(trade) => trade,
+            None => {
+                let rest_qty =
+                    if crossing_ask.is_none() && order.order_type != OrderType::ImmediateOrCancel {
+                        order
+                            .coin_qty_remaining
+                            .min(order.native_pc_qty_locked / pc_lot_size / order.limit_price)
+                    } else {
+                        0
+                    };
+                let native_pc_to_keep = rest_qty * order.limit_price * pc_lot_size;
+                self.out(
+                    order,
+                    Side::Bid,
+                    order.native_pc_qty_locked - native_pc_to_keep,
+                    rest_qty == 0,
+                );
+                if rest_qty > 0 {
+                    let mut resting = resting_order(order, order.limit_price);
+                    resting.quantity = rest_qty;
+                    self.bids
+                        .insert((Reverse(order.limit_price), order.seq_num), resting);
+                }
+                return true;
+            }
+        };
+
+        if ask.owner == order.owner {
+            let (cancelled_provide, cancelled_take) = match order.self_trade_behavior {
+                SelfTradeBehavior::DecrementTake => (trade_qty, trade_qty),
+                SelfTradeBehavior::CancelProvide => (ask.quantity, 0),
+            };
+            let provide_left = self.reduce_ask(key, cancelled_provide);
+            self.events.push_back(Event::Out {
+                owner: ask.owner,
+                owner_slot: ask.owner_slot,
+                side: Side::Ask,
+                native_qty_unlocked: cancelled_provide * coin_lot_size,
+                done: provide_left == 0,
+            });
+            let native_pc_unlocked = cancelled_take * ask.price * pc_lot_size;
+            order.coin_qty_remaining -= cancelled_take;
+            order.native_pc_qty_locked -= native_pc_unlocked;
+            if order.coin_qty_remaining > 0 && order.native_pc_qty_locked > 0 {
+                self.out(order, Side::Bid, native_pc_unlocked, false);
+                return false;
+            }
+            self.out(
+                order,
+                Side::Bid,
+                order.native_pc_qty_locked + native_pc_unlocked,
+                true,
+            );
+            return true;
+        }
+
+        let native_pc = trade_qty * ask.price * pc_lot_size;
+        let maker_rebate = FEE_TIER.maker_rebate(native_pc);
+        self.events.push_back(Event::Fill(Fill {
+            owner: ask.owner,
+            owner_slot: ask.owner_slot,
+            side: Side::Ask,
+            maker: true,
+            native_qty_paid: trade_qty * coin_lot_size,
+            native_qty_received: native_pc + maker_rebate,
+            native_fee_or_rebate: maker_rebate,
+        }));
+        if self.reduce_ask(key, trade_qty) == 0 {
+            self.events.push_back(Event::Out {
+                owner: ask.owner,
+                owner_slot: ask.owner_slot,
+                side: Side::Ask,
+                native_qty_unlocked: 0,
+                done: true,
+            });
+        }
+
+        let taker_fee = FEE_TIER.taker_fee(native_pc);
+        self.events.push_back(Event::Fill(Fill {
+            owner: order.owner,
+            owner_slot: order.owner_slot,
+            side: Side::Bid,
+            maker: false,
+            native_qty_paid: native_pc + taker_fee,
+            native_qty_received: trade_qty * coin_lot_size,
+            native_fee_or_rebate: taker_fee,
+        }));
+        self.collect_fees(taker_fee, maker_rebate);
+
+        order.coin_qty_remaining -= trade_qty;
+        order.native_pc_qty_locked -= native_pc + taker_fee;
+        if order.coin_qty_remaining > 0 && order.native_pc_qty_locked > 0 {
+            return false;
+        }
+        let native_pc_left = order.native_pc_qty_locked;
+        self.out(order, Side::Bid, native_pc_left, true);
+        true
+    }
+
+    fn out(&mut self, order: &PendingOrder, side: Side, native_qty_unlocked: u64, done: bool) {
+        self.events.push_back(Event::Out {
+            owner: order.owner,
+            owner_slot: order.owner_slot,
+            side,
+            native_qty_unlocked,
+            done,
+        });
+    }
+
+    /// The price a post-only slide order is placed at: one tick behind the
+    /// best opposite order if it would cross it, and there's such a price.
+    fn slide_price(&self, side: Side, limit_price: u64) -> u64 {
+        let slid_price = match side {
+            Side::Bid => self
+                .asks
+                .values()
+                .next()
+                .filter(|ask| ask.price <= limit_price)
+                .map(|ask| ask.price - 1),
+            Side::Ask => self
+                .bids
+                .values()
+                .next()
+                .filter(|bid| bid.price >= limit_price)
+                .and_then(|bid| bid.price.checked_add(1)),
+        };
+        slid_price.filter(|&price| price > 0).unwrap_or(limit_price)
+    }
+
+    /// Takes `qty` off a resting bid and returns what's left of it.
+    fn reduce_bid(&mut self, key: (Reverse<u64>, u64), qty: u64) -> u64 {
+        let bid = self.bids.get_mut(&key).unwrap();
+        bid.quantity -= qty;
+        let left = bid.quantity;
+        if left == 0 {
+            self.bids.remove(&key);
+        }
+        left
+    }
+
+    /// Takes `qty` off a resting ask and returns what's left of it.
+    fn reduce_ask(&mut self, key: (u64, u64), qty: u64) -> u64 {
+        let ask = self.asks.get_mut(&key).unwrap();
+        ask.quantity -= qty;
+        let left = ask.quantity;
+        if left == 0 {
+            self.asks.remove(&key);
+        }
+        left
+    }
+
+    fn collect_fees(&mut self, taker_fee: u64, maker_rebate: u64) {
+        let referrer_rebate = fees::referrer_rebate(taker_fee);
+        self.totals.referrer_rebates_accrued += referrer_rebate;
+        self.totals.pc_fees_accrued += taker_fee - maker_rebate - referrer_rebate;
+        self.totals.pc_deposits_total -= taker_fee - maker_rebate;
+    }
+
+    /// Applies up to `limit` events to the owners' balances.
+    pub fn consume_events(&mut self, limit: u16) {
+        for _ in 0..limit {
+            let event = match self.events.pop_front() {
+                Some(event) => event,
+                None => break,
+            };
+            match event {
+                Event::Fill(fill) => {
+                    let balances = &mut self.owners[fill.owner].balances;
+                    match fill.side {
+                        Side::Bid => {
+                            balances.native_pc_total -= fill.native_qty_paid;
+                            balances.native_coin_total += fill.native_qty_received;
+                            balances.native_coin_free += fill.native_qty_received;
+                            if fill.maker {
+                                balances.native_pc_free += fill.native_fee_or_rebate;
+                            }
+                        }
+                        Side::Ask => {
+                            balances.native_coin_total -= fill.native_qty_paid;
+                            balances.native_pc_total += fill.native_qty_received;
+                            balances.native_pc_free += fill.native_qty_received;
+                        }
+                    }
+                    if !fill.maker {
+                        balances.referrer_rebates_accrued +=
+                            fees::referrer_rebate(fill.native_fee_or_rebate);
+                    }
+                }
+                Event::Out {
+                    owner,
+                    owner_slot,
+                    side,
+                    native_qty_unlocked,
+                    done,
+                } => {
+                    let owner = &mut self.owners[owner];
+                    match side {
+                        Side::Bid => owner.balances.native_pc_free += native_qty_unlocked,
+                        Side::Ask => owner.balances.native_coin_free += native_qty_unlocked,
+                    }
+                    if done {
+                        owner.slots[owner_slot as usize] = None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn gen_seq_num(&mut self) -> u64 {
+        self.next_seq_num += 1;
+        self.next_seq_num
+    }
+
+    /// The fills still in the event queue, oldest first.
+    pub fn pending_fills(&self) -> Vec<Fill> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Fill(fill) => Some(*fill),
+                Event::Out { .. } => None,
+            })
+            .collect()
+    }
+
+    pub fn event_count(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn request_count(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// The resting bids, best first.
+    pub fn bids(&self) -> Vec<RestingOrder> {
+        self.bids.values().copied().collect()
+    }
+
+    /// The resting asks, best first.
+    pub fn asks(&self) -> Vec<RestingOrder> {
+        self.asks.values().copied().collect()
+    }
+
+    pub fn balances(&self, owner: usize) -> Balances {
+        self.owners[owner].balances
+    }
+
+    /// The coin and pc wallet balances of an owner.
+    pub fn wallets(&self, owner: usize) -> (u64, u64) {
+        (self.owners[owner].coin_wallet, self.owners[owner].pc_wallet)
+    }
+
+    pub fn slot(&self, owner: usize, owner_slot: u8) -> Option<Slot> {
+        self.owners[owner].slots[owner_slot as usize]
+    }
+
+    pub fn totals(&self) -> MarketTotals {
+        self.totals
+    }
+}
+
+fn resting_order(order: &PendingOrder, price: u64) -> RestingOrder {
+    RestingOrder {
+        owner: order.owner,
+        owner_slot: order.owner_slot,
+        price,
+        quantity: order.coin_qty_remaining,
+        client_order_id: order.client_order_id,
+    }
+}
@@ -0,0 +1,446 @@
+//! Replays DEX instructions against captured account states.
+//!
+//! Account snapshots use the format of `solana account <pubkey> --output json`.
+//! Instructions are a JSON list of `{ "accounts": [{ "pubkey", "isSigner",
+//! "isWritable" }], "data": "<base64>" }`. SPL token instructions issued by the
+//! DEX are executed directly by the token program, as in the fuzz targets.
+//! CPIs to other programs (e.g. system account creation) are not supported.
+//!
+//! `fixtures/replay` holds a small trace in these formats, which the tests
+//! replay, e.g. `replay <program id> fixtures/replay/instructions.json
+//! fixtures/replay/accounts/*.json` with the program id from the tests.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use bumpalo::Bump;
+use safe_transmute::to_bytes::transmute_to_bytes_mut;
+use serde::Deserialize;
+use solana_sdk::account_info::AccountInfo;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::rent::Rent;
+use solana_sdk::sysvar;
+use spl_token::pack::Pack;
+use spl_token::state::Account as SplAccount;
+
+use serum_dex::critbit::Slab;
+use serum_dex::error::DexResult;
+use serum_dex::instruction::MarketInstruction;
+use serum_dex::state::{
+    gen_vault_signer_seeds, strip_header, AccountFlag, Event, EventQueue, EventQueueHeader,
+    MarketState, OpenOrdersMut, Queue, Request, RequestQueue, RequestQueueHeader,
+    ACCOUNT_HEAD_PADDING,
+};
+
+use crate::{
+    new_rent_sysvar_account, new_sol_account_with_pubkey, new_spl_token_program,
+    process_instruction,
+};
+
+#[derive(Deserialize)]
+pub struct AccountSnapshot {
+    pub pubkey: String,
+    pub account: SnapshotAccount,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotAccount {
+    pub lamports: u64,
+    // (data, encoding)
+    pub data: (String, String),
+    pub owner: String,
+    pub executable: bool,
+    pub rent_epoch: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayAccountMeta {
+    pub pubkey: String,
+    pub is_signer: bool,
+    pub is_writable: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ReplayInstruction {
+    pub accounts: Vec<ReplayAccountMeta>,
+    pub data: String,
+}
+
+fn parse_pubkey(s: &str) -> Result<Pubkey, String> {
+    Pubkey::from_str(s).map_err(|e| format!("invalid pubkey {}: {:?}", s, e))
+}
+
+fn decode_base64(s: &str) -> Result<Vec<u8>, String> {
+    base64::decode(s).map_err(|e| format!("invalid base64 data: {}", e))
+}
+
+/// Copies `data` so that the words after the dex account head padding are
+/// aligned, as the runtime lays out account data.
+fn allocate_account_data<'bump>(data: &[u8], bump: &'bump Bump) -> &'bump mut [u8] {
+    let offset = 8 - ACCOUNT_HEAD_PADDING.len();
+    let u64_data = bump.alloc_slice_fill_copy((data.len() + offset) / 8 + 1, 0u64);
+    let bytes = &mut transmute_to_bytes_mut(u64_data)[offset..data.len() + offset];
+    bytes.copy_from_slice(data);
+    bytes
+}
+
+pub struct Replay<'bump> {
+    program_id: &'bump Pubkey,
+    accounts: BTreeMap<Pubkey, AccountInfo<'bump>>,
+    original_accounts: BTreeMap<Pubkey, AccountInfo<'bump>>,
+    // The vault signers of the captured markets. Outside of the program, every
+    // market's vault signer is the default pubkey, so they are remapped to it.
+    vault_signers: Vec<Pubkey>,
+    vault_signer: AccountInfo<'bump>,
+    spl_token_program: AccountInfo<'bump>,
+    rent_sysvar: AccountInfo<'bump>,
+}
+
+impl<'bump> Replay<'bump> {
+    pub fn load(
+        program_id: Pubkey,
+        snapshots: &[AccountSnapshot],
+        bump: &'bump Bump,
+    ) -> Result<Self, String> {
+        let program_id: &'bump Pubkey = bump.alloc(program_id);
+        let mut accounts = BTreeMap::new();
+        for snapshot in snapshots {
+            let pubkey = parse_pubkey(&snapshot.pubkey)?;
+            let (data, encoding) = &snapshot.account.data;
+            if encoding != "base64" {
+                return Err(format!(
+                    "account {}: unsupported encoding {}",
+                    pubkey, encoding
+                ));
+            }
+            let data = decode_base64(data)?;
+            let info = AccountInfo::new(
+                bump.alloc(pubkey),
+                false,
+                true,
+                bump.alloc(snapshot.account.lamports),
+                allocate_account_data(&data, bump),
+                bump.alloc(parse_pubkey(&snapshot.account.owner)?),
+                snapshot.account.executable,
+                snapshot.account.rent_epoch,
+            );
+            accounts.insert(pubkey, info);
+        }
+
+        let mut vault_signers = vec![];
+        for (pubkey, info) in accounts.iter() {
+            if let Ok(market) = MarketState::load(info, program_id) {
+                let nonce = market.vault_signer_nonce;
+                let seeds = gen_vault_signer_seeds(&nonce, pubkey);
+                let vault_signer = Pubkey::create_program_address(&seeds, program_id)
+                    .map_err(|e| format!("market {}: bad vault signer nonce: {:?}", pubkey, e))?;
+                vault_signers.push(vault_signer);
+            }
+        }
+        for (pubkey, info) in accounts.iter_mut() {
+            if vault_signers.contains(pubkey) {
+                info.key = bump.alloc(Pubkey::default());
+            }
+            if *info.owner == spl_token::ID && info.data_len() == SplAccount::LEN {
+                let mut data = info.try_borrow_mut_data().unwrap();
+                if let Ok(mut token_account) = SplAccount::unpack(&data) {
+                    if vault_signers.contains(&token_account.owner) {
+                        token_account.owner = Pubkey::default();
+                        SplAccount::pack(token_account, &mut data).unwrap();
+                    }
+                }
+            }
+        }
+
+        let original_accounts = accounts
+            .iter()
+            .map(|(pubkey, info)| {
+                let data = info.try_borrow_data().unwrap();
+                let original = AccountInfo::new(
+                    info.key,
+                    false,
+                    false,
+                    bump.alloc(info.lamports()),
+                    allocate_account_data(&data, bump),
+                    info.owner,
+                    info.executable,
+                    info.rent_epoch,
+                );
+                (*pubkey, original)
+            })
+            .collect();
+
+        Ok(Replay {
+            program_id,
+            accounts,
+            original_accounts,
+            vault_signers,
+            vault_signer: new_sol_account_with_pubkey(bump.alloc(Pubkey::default()), 0, bump),
+            spl_token_program: new_spl_token_program(bump),
+            rent_sysvar: new_rent_sysvar_account(0, Rent::default(), bump),
+        })
+    }
+
+    fn account_info(&self, meta: &ReplayAccountMeta) -> Result<AccountInfo<'bump>, String> {
+        let pubkey = parse_pubkey(&meta.pubkey)?;
+        let mut info = match self.accounts.get(&pubkey) {
+            Some(info) => info.clone(),
+            None if self.vault_signers.contains(&pubkey) => self.vault_signer.clone(),
+            None if pubkey == spl_token::ID => self.spl_token_program.clone(),
+            None if pubkey == sysvar::rent::ID => self.rent_sysvar.clone(),
+            None => return Err(format!("no snapshot for account {}", pubkey)),
+        };
+        // the vault signer signs through invoke_signed
+        info.is_signer = meta.is_signer || self.vault_signers.contains(&pubkey);
+        info.is_writable = meta.is_writable;
+        Ok(info)
+    }
+
+    pub fn execute(&self, instruction: &ReplayInstruction) -> Result<DexResult, String> {
+        let data = decode_base64(&instruction.data)?;
+        let accounts = instruction
+            .accounts
+            .iter()
+            .map(|meta| self.account_info(meta))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(process_instruction(self.program_id, &accounts, &data))
+    }
+
+    /// Describes the changes to every account since it was loaded.
+    pub fn state_diff(&self) -> Vec<String> {
+        let mut lines = vec![];
+        for (pubkey, info) in self.accounts.iter() {
+            let before = describe_account(&self.original_accounts[pubkey], self.program_id);
+            let after = describe_account(info, self.program_id);
+            if before == after {
+                continue;
+            }
+            lines.push(format!("account {}", pubkey));
+            for line in before.iter().filter(|line| !after.contains(line)) {
+                lines.push(format!("  - {}", line));
+            }
+            for line in after.iter().filter(|line| !before.contains(line)) {
+                lines.push(format!("  + {}", line));
+            }
+        }
+        lines
+    }
+}
+
+fn describe_account(info: &AccountInfo, program_id: &Pubkey) -> Vec<String> {
+    let mut lines = vec![format!("lamports: {}", info.lamports())];
+    if *info.owner == spl_token::ID && info.data_len() == SplAccount::LEN {
+        if let Ok(token_account) = SplAccount::unpack(&info.try_borrow_data().unwrap()) {
+            lines.push(format!("amount: {}", token_account.amount));
+            lines.push(format!(
+                "delegated_amount: {}",
+                token_account.delegated_amount
+            ));
+        }
+    } else if info.owner == program_id {
+        if let Ok(dex_lines) = describe_dex_account(info, program_id) {
+            lines.extend(dex_lines);
+        }
+    }
+    lines
+}
+
+fn describe_dex_account(info: &AccountInfo, program_id: &Pubkey) -> DexResult<Vec<String>> {
+    let flags = {
+        let (flags, _) = strip_header::<u64, u8>(info, false)?;
+        *flags
+    };
+    let has_flag = |flag: AccountFlag| flags & (flag as u64) != 0;
+    let mut lines = vec![];
+    if has_flag(AccountFlag::Market) {
+        let market = MarketState::load(info, program_id)?;
+        lines.push(format!("account_flags: {:#x}", market.account_flags));
+        lines.push(format!(
+            "coin_deposits_total: {}",
+            market.coin_deposits_total
+        ));
+        lines.push(format!("coin_fees_accrued: {}", market.coin_fees_accrued));
+        lines.push(format!("pc_deposits_total: {}", market.pc_deposits_total));
+        lines.push(format!("pc_fees_accrued: {}", market.pc_fees_accrued));
+        lines.push(format!(
+            "referrer_rebates_accrued: {}",
+            market.referrer_rebates_accrued
+        ));
+    } else if has_flag(AccountFlag::OpenOrders) {
+        let open_orders = OpenOrdersMut::load(info, program_id)?;
+        lines.push(format!(
+            "native_coin_free: {}",
+            open_orders.native_coin_free
+        ));
+        lines.push(format!(
+            "native_coin_total: {}",
+            open_orders.native_coin_total
+        ));
+        lines.push(format!("native_pc_free: {}", open_orders.native_pc_free));
+        lines.push(format!("native_pc_total: {}", open_orders.native_pc_total));
+        lines.push(format!(
+            "referrer_rebates_accrued: {}",
+            open_orders.referrer_rebates_accrued
+        ));
+        let open_orders = open_orders.view();
+        for slot in open_orders.slots() {
+            if !open_orders.slot_is_free(slot) {
+                lines.push(format!(
+                    "order[{}]: {:#x} client_order_id {}",
+                    slot,
+                    open_orders.order_id(slot).unwrap_or(0),
+                    open_orders.client_order_id(slot)
+                ));
+            }
+        }
+    } else if has_flag(AccountFlag::Bids) || has_flag(AccountFlag::Asks) {
+        let (_, mut buf) = strip_header::<u64, u8>(info, false)?;
+        let slab = Slab::new(&mut *buf);
+        for order in slab.iter() {
+            lines.push(format!(
+                "order {:#x}: quantity {} owner {:?} slot {}",
+                order.order_id(),
+                order.quantity(),
+                order.owner(),
+                order.owner_slot()
+            ));
+        }
+    } else if has_flag(AccountFlag::EventQueue) {
+        let (header, buf) = strip_header::<EventQueueHeader, Event>(info, false)?;
+        let events: EventQueue = Queue::new(header, buf);
+        for event in events.iter() {
+            lines.push(format!("event {:?}", event.as_view()?));
+        }
+    } else if has_flag(AccountFlag::RequestQueue) {
+        let (header, buf) = strip_header::<RequestQueueHeader, Request>(info, false)?;
+        let requests: RequestQueue = Queue::new(header, buf);
+        for request in requests.iter() {
+            lines.push(format!("request {:?}", request.as_view()?));
+        }
+    }
+    Ok(lines)
+}
+
+/// Replays `instructions` in order and returns a log of their results followed
+/// by the resulting state diff.
+pub fn replay(
+    program_id: Pubkey,
+    snapshots: &[AccountSnapshot],
+    instructions: &[ReplayInstruction],
+) -> Result<Vec<String>, String> {
+    let bump = Bump::new();
+    let replay = Replay::load(program_id, snapshots, &bump)?;
+    let mut log = vec![];
+    for (i, instruction) in instructions.iter().enumerate() {
+        let data = decode_base64(&instruction.data)?;
+        log.push(format!(
+            "instruction {}: {:?}",
+            i,
+            MarketInstruction::unpack(&data)
+        ));
+        log.push(format!("  result: {:?}", replay.execute(instruction)?));
+    }
+    log.extend(replay.state_diff());
+    Ok(log)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serum_dex::fees::FeeTier;
+
+    use super::*;
+
+    const PROGRAM_ID: &str = "8zkT6jpAR1HLS9cAGgZDRVydw4bC1RAfpDyUtE9eNee6";
+    const MARKET: &str = "7d3x75iqpYufxHwA3dvn5g2qEnZUBoZYFGfBWkLZZFch";
+    const BIDS: &str = "5xPZLB7k1bN9puhoEPXgcQPW8adn3B5W8fMokjvKNQEL";
+    const OPEN_ORDERS: &str = "BrRvp6AwwzJgyuZFb51eF387dnxAiCwBeAgPCCJJWfxz";
+    const PC_VAULT: &str = "2Gn5k6XwqaNJbSNMyoLWJCUPKcXAuCxmm4XbxT49PxTZ";
+    const PC_WALLET: &str = "6HhxMCmeqSQqjGnwQ5uxkJqk3vzJRArr5kHk5ZAc6dzL";
+
+    // A freshly initialized market, an OpenOrders account that hasn't been
+    // used yet and an owner with 1_000_000_000 in their pc wallet. The trace
+    // places a bid for one lot at 1_000, client id 7, and matches it onto
+    // the empty book.
+    const ACCOUNTS: &[&str] = &[
+        include_str!("../fixtures/replay/accounts/market.json"),
+        include_str!("../fixtures/replay/accounts/request_queue.json"),
+        include_str!("../fixtures/replay/accounts/event_queue.json"),
+        include_str!("../fixtures/replay/accounts/bids.json"),
+        include_str!("../fixtures/replay/accounts/asks.json"),
+        include_str!("../fixtures/replay/accounts/open_orders.json"),
+        include_str!("../fixtures/replay/accounts/coin_vault.json"),
+        include_str!("../fixtures/replay/accounts/pc_vault.json"),
+        include_str!("../fixtures/replay/accounts/pc_wallet.json"),
+        include_str!("../fixtures/replay/accounts/owner.json"),
+    ];
+    const INSTRUCTIONS: &str = include_str!("../fixtures/replay/instructions.json");
+
+    /// Groups the lines of the state diff by account.
+    fn diff_by_account(lines: &[String]) -> HashMap<String, Vec<String>> {
+        let mut diff: HashMap<String, Vec<String>> = HashMap::new();
+        let mut account = None;
+        for line in lines {
+            match line.strip_prefix("account ") {
+                Some(pubkey) => account = Some(pubkey.to_string()),
+                None => diff
+                    .entry(account.clone().unwrap())
+                    .or_default()
+                    .push(line.clone()),
+            }
+        }
+        diff
+    }
+
+    #[test]
+    fn test_replay_fixture() {
+        let snapshots: Vec<AccountSnapshot> = ACCOUNTS
+            .iter()
+            .map(|json| serde_json::from_str(json).unwrap())
+            .collect();
+        let instructions: Vec<ReplayInstruction> = serde_json::from_str(INSTRUCTIONS).unwrap();
+        let log = replay(
+            Pubkey::from_str(PROGRAM_ID).unwrap(),
+            &snapshots,
+            &instructions,
+        )
+        .unwrap();
+
+        assert!(log[0].starts_with("instruction 0: Some(NewOrderV2("));
+        assert_eq!(log[1], "  result: Ok(())");
+        assert_eq!(log[2], "instruction 1: Some(MatchOrders(10))");
+        assert_eq!(log[3], "  result: Ok(())");
+
+        let native_pc_locked = 100_000 + FeeTier::Base.taker_fee(100_000);
+        let diff = diff_by_account(&log[4..]);
+        let market = &diff[MARKET];
+        assert!(market.contains(&"  - pc_deposits_total: 0".to_string()));
+        assert!(market.contains(&format!("  + pc_deposits_total: {}", native_pc_locked)));
+        let open_orders = &diff[OPEN_ORDERS];
+        assert!(open_orders.contains(&format!("  + native_pc_total: {}", native_pc_locked)));
+        assert!(open_orders.contains(&"  + native_pc_free: 0".to_string()));
+        assert!(open_orders
+            .iter()
+            .any(|line| line.starts_with("  + order[0]: ") && line.ends_with("client_order_id 7")));
+        assert_eq!(
+            diff[PC_WALLET],
+            vec![
+                "  - amount: 1000000000".to_string(),
+                format!("  + amount: {}", 1_000_000_000 - native_pc_locked),
+            ]
+        );
+        assert_eq!(
+            diff[PC_VAULT],
+            vec![
+                "  - amount: 0".to_string(),
+                format!("  + amount: {}", native_pc_locked),
+            ]
+        );
+        let bids = &diff[BIDS];
+        assert_eq!(bids.len(), 1);
+        assert!(bids[0].contains(": quantity 1 owner ") && bids[0].ends_with(" slot 0"));
+    }
+}
@@ -0,0 +1,474 @@
+//! Checks that a market's accounting reconciles with its vaults, its
+//! `OpenOrders` accounts and the orders resting in its book.
+
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU64;
+
+use crate::{
+    critbit::LeafNode,
+    matching::Side,
+    state::{MarketState, OpenOrders, OpenOrdersView, RequestView, TriggerOrder},
+};
+
+/// The accounts of a market, decoded. `open_orders` must hold every
+/// `OpenOrders` account of the market, keyed by address.
+pub struct MarketAudit<'a> {
+    pub market: &'a MarketState,
+    pub coin_vault_balance: u64,
+    pub pc_vault_balance: u64,
+    /// The staking share of the swept fees, which a `FeeSplit` holds in the
+    /// vaults.
+    pub coin_staking_fees: u64,
+    pub pc_staking_fees: u64,
+    pub bids: &'a [LeafNode],
+    pub asks: &'a [LeafNode],
+    pub request_queue_len: u64,
+    pub event_queue_len: u64,
+    pub open_orders: &'a [([u64; 4], OpenOrdersView<'a>)],
+    /// The orders waiting in the market's trigger orders account, if any.
+    pub trigger_orders: &'a [TriggerOrder],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The coin vault doesn't hold exactly the coin deposits and fees.
+    CoinVault {
+        vault_balance: u64,
+        expected: u64,
+    },
+    /// The pc vault doesn't hold exactly the pc deposits, fees and referrer
+    /// rebates.
+    PcVault {
+        vault_balance: u64,
+        expected: u64,
+    },
+    CoinDepositsTotal {
+        market: u64,
+        open_orders: u64,
+    },
+    PcDepositsTotal {
+        market: u64,
+        open_orders: u64,
+    },
+    ReferrerRebatesAccrued {
+        market: u64,
+        open_orders: u64,
+    },
+    WrongOpenOrdersMarket {
+        open_orders: [u64; 4],
+    },
+    OpenOrdersBalance {
+        open_orders: [u64; 4],
+    },
+    /// A resting or trigger order whose owner isn't among the audited
+    /// `OpenOrders`.
+    UnknownOrderOwner {
+        side: Side,
+        order_id: u128,
+        owner: [u64; 4],
+    },
+    /// A resting or trigger order that its `OpenOrders` doesn't list in its
+    /// slot.
+    OrderNotInOpenOrders {
+        side: Side,
+        order_id: u128,
+        owner: [u64; 4],
+    },
+    /// An `OpenOrders` slot without a matching resting or trigger order.
+    StaleOpenOrdersSlot {
+        open_orders: [u64; 4],
+        slot: u8,
+        order_id: u128,
+    },
+    LockedCoin {
+        open_orders: [u64; 4],
+        locked: u64,
+        resting: u64,
+    },
+    LockedPc {
+        open_orders: [u64; 4],
+        locked: u64,
+        resting: u64,
+    },
+}
+
+#[derive(Default)]
+struct RestingOrders {
+    native_coin: u64,
+    native_pc: u64,
+    slots: HashSet<u8>,
+}
+
+impl<'a> MarketAudit<'a> {
+    /// Reports every invariant violation.
+    ///
+    /// The vault invariants always hold. The `OpenOrders` totals only
+    /// reconcile with the market once the event queue is empty, and the locked
+    /// balances only match the book once both queues are empty, so those
+    /// checks are skipped otherwise.
+    pub fn run(&self) -> Vec<Violation> {
+        let mut violations = vec![];
+        let market = self.market;
+
+        let expected_coin =
+            market.coin_deposits_total + market.coin_fees_accrued + self.coin_staking_fees;
+        if self.coin_vault_balance != expected_coin {
+            violations.push(Violation::CoinVault {
+                vault_balance: self.coin_vault_balance,
+                expected: expected_coin,
+            });
+        }
+        let expected_pc = market.pc_deposits_total
+            + market.pc_fees_accrued
+            + market.referrer_rebates_accrued
+            + self.pc_staking_fees;
+        if self.pc_vault_balance != expected_pc {
+            violations.push(Violation::PcVault {
+                vault_balance: self.pc_vault_balance,
+                expected: expected_pc,
+            });
+        }
+
+        for (address, open_orders) in self.open_orders {
+            if open_orders.market != market.own_address {
+                violations.push(Violation::WrongOpenOrdersMarket {
+                    open_orders: *address,
+                });
+            }
+            if open_orders.native_coin_free > open_orders.native_coin_total
+                || open_orders.native_pc_free > open_orders.native_pc_total
+            {
+                violations.push(Violation::OpenOrdersBalance {
+                    open_orders: *address,
+                });
+            }
+        }
+
+        if self.event_queue_len == 0 {
+            let sum = |f: fn(&OpenOrders) -> u64| {
+                self.open_orders
+                    .iter()
+                    .map(|(_, open_orders)| f(open_orders.inner))
+                    .sum::<u64>()
+            };
+            let coin_total = sum(|open_orders| open_orders.native_coin_total);
+            if coin_total != market.coin_deposits_total {
+                violations.push(Violation::CoinDepositsTotal {
+                    market: market.coin_deposits_total,
+                    open_orders: coin_total,
+                });
+            }
+            let pc_total = sum(|open_orders| open_orders.native_pc_total);
+            if pc_total != market.pc_deposits_total {
+                violations.push(Violation::PcDepositsTotal {
+                    market: market.pc_deposits_total,
+                    open_orders: pc_total,
+                });
+            }
+            let rebates_total = sum(|open_orders| open_orders.referrer_rebates_accrued);
+            if rebates_total != market.referrer_rebates_accrued {
+                violations.push(Violation::ReferrerRebatesAccrued {
+                    market: market.referrer_rebates_accrued,
+                    open_orders: rebates_total,
+                });
+            }
+        }
+
+        if self.event_queue_len == 0 && self.request_queue_len == 0 {
+            self.audit_resting_orders(&mut violations);
+        }
+        violations
+    }
+
+    fn audit_resting_orders(&self, violations: &mut Vec<Violation>) {
+        let open_orders_by_address: HashMap<[u64; 4], OpenOrdersView> =
+            self.open_orders.iter().copied().collect();
+        let mut resting: HashMap<[u64; 4], RestingOrders> = HashMap::new();
+
+        let market = self.market;
+        let sides = [(Side::Bid, self.bids), (Side::Ask, self.asks)];
+        let book_orders = sides.iter().flat_map(|&(side, orders)| {
+            orders.iter().map(move |order| {
+                let native_locked = match side {
+                    Side::Bid => order.quantity() * order.price().get() * market.pc_lot_size,
+                    Side::Ask => order.quantity() * market.coin_lot_size,
+                };
+                (
+                    side,
+                    *order.order_id(),
+                    *order.owner(),
+                    order.owner_slot(),
+                    native_locked,
+                )
+            })
+        });
+        // Trigger orders hold their slot and locked funds until they're
+        // triggered or cancelled.
+        let trigger_orders = self.trigger_orders.iter().filter_map(|trigger_order| {
+            match trigger_order.request().as_view().ok()? {
+                RequestView::NewOrder {
+                    side,
+                    order_id,
+                    owner,
+                    owner_slot,
+                    max_coin_qty,
+                    native_pc_qty_locked,
+                    ..
+                } => {
+                    let native_locked = match side {
+                        Side::Bid => native_pc_qty_locked.map_or(0, NonZeroU64::get),
+                        Side::Ask => max_coin_qty.get() * market.coin_lot_size,
+                    };
+                    Some((side, *order_id, *owner, owner_slot, native_locked))
+                }
+                RequestView::CancelOrder { .. } => None,
+            }
+        });
+
+        for (side, order_id, owner, slot, native_locked) in book_orders.chain(trigger_orders) {
+            let open_orders = match open_orders_by_address.get(&owner) {
+                Some(open_orders) => open_orders,
+                None => {
+                    violations.push(Violation::UnknownOrderOwner {
+                        side,
+                        order_id,
+                        owner,
+                    });
+                    continue;
+                }
+            };
+            let (is_free, is_bid) = open_orders.slot_bits(slot).unwrap_or((true, false));
+            if is_free
+                || open_orders.order_id(slot) != Some(order_id)
+                || is_bid != (side == Side::Bid)
+            {
+                violations.push(Violation::OrderNotInOpenOrders {
+                    side,
+                    order_id,
+                    owner,
+                });
+                continue;
+            }
+            let resting = resting.entry(owner).or_default();
+            resting.slots.insert(slot);
+            match side {
+                Side::Bid => resting.native_pc += native_locked,
+                Side::Ask => resting.native_coin += native_locked,
+            }
+        }
+
+        for (address, open_orders) in self.open_orders {
+            let resting = resting.remove(address).unwrap_or_default();
+            for slot in open_orders.slots() {
+                if !open_orders.slot_is_free(slot) && !resting.slots.contains(&slot) {
+                    violations.push(Violation::StaleOpenOrdersSlot {
+                        open_orders: *address,
+                        slot,
+                        order_id: open_orders.order_id(slot).unwrap_or(0),
+                    });
+                }
+            }
+            let locked_coin = open_orders.native_coin_total - open_orders.native_coin_free;
+            if locked_coin != resting.native_coin {
+                violations.push(Violation::LockedCoin {
+                    open_orders: *address,
+                    locked: locked_coin,
+                    resting: resting.native_coin,
+                });
+            }
+            let locked_pc = open_orders.native_pc_total - open_orders.native_pc_free;
+            if locked_pc != resting.native_pc {
+                violations.push(Violation::LockedPc {
+                    open_orders: *address,
+                    locked: locked_pc,
+                    resting: resting.native_pc,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::FeeTier;
+    use crate::instruction::{SelfTradeBehavior, TriggerDirection};
+    use crate::matching::OrderType;
+    use crate::state::{OpenOrdersSlots, Request, MAX_OPEN_ORDERS_SLOTS};
+    use bytemuck::Zeroable;
+
+    fn order_id(price: u64, seq_num: u64) -> u128 {
+        ((price as u128) << 64) | seq_num as u128
+    }
+
+    #[test]
+    fn test_audit() {
+        let mut market = MarketState::zeroed();
+        market.own_address = [7; 4];
+        market.coin_lot_size = 100;
+        market.pc_lot_size = 10;
+        market.coin_deposits_total = 300;
+        market.pc_deposits_total = 150;
+
+        let address = [1; 4];
+        let mut open_orders = OpenOrders::zeroed();
+        open_orders.market = [7; 4];
+        open_orders.native_coin_total = 300;
+        open_orders.native_pc_total = 150;
+        open_orders.native_pc_free = 50;
+        open_orders.free_slot_bits = !0b11;
+        open_orders.is_bid_bits = 0b01;
+        open_orders.orders[0] = order_id(5, 0);
+        open_orders.orders[1] = order_id(6, 1);
+
+        let bids = [LeafNode::new(
+            0,
+            &order_id(5, 0),
+            &address,
+            2,
+            FeeTier::Base,
+            0,
+        )];
+        let asks = [LeafNode::new(
+            1,
+            &order_id(6, 1),
+            &address,
+            3,
+            FeeTier::Base,
+            0,
+        )];
+        // the ask, before it was triggered
+        let trigger_orders = [TriggerOrder::new(
+            Request::new(RequestView::NewOrder {
+                side: Side::Ask,
+                order_type: OrderType::Limit,
+                owner_slot: 1,
+                fee_tier: FeeTier::Base,
+                order_id: &order_id(6, 1),
+                max_coin_qty: NonZeroU64::new(3).unwrap(),
+                native_pc_qty_locked: None,
+                owner: &address,
+                client_order_id: None,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            }),
+            NonZeroU64::new(10).unwrap(),
+            TriggerDirection::Above,
+        )];
+        let mut audit = MarketAudit {
+            market: &market,
+            coin_vault_balance: 300,
+            pc_vault_balance: 150,
+            coin_staking_fees: 0,
+            pc_staking_fees: 0,
+            bids: &bids,
+            asks: &asks,
+            request_queue_len: 0,
+            event_queue_len: 0,
+            open_orders: &[(
+                address,
+                OpenOrdersView {
+                    inner: &open_orders,
+                    extra_slots: &[],
+                },
+            )],
+            trigger_orders: &[],
+        };
+        assert_eq!(audit.run(), vec![]);
+
+        audit.pc_vault_balance = 149;
+        audit.asks = &[];
+        assert_eq!(
+            audit.run(),
+            vec![
+                Violation::PcVault {
+                    vault_balance: 149,
+                    expected: 150,
+                },
+                Violation::StaleOpenOrdersSlot {
+                    open_orders: address,
+                    slot: 1,
+                    order_id: order_id(6, 1),
+                },
+                Violation::LockedCoin {
+                    open_orders: address,
+                    locked: 300,
+                    resting: 0,
+                },
+            ]
+        );
+
+        // the book is only reconciled once the queues have been drained
+        audit.request_queue_len = 1;
+        assert_eq!(audit.run().len(), 1);
+
+        audit.request_queue_len = 0;
+        audit.pc_vault_balance = 150;
+        audit.trigger_orders = &trigger_orders;
+        assert_eq!(audit.run(), vec![]);
+    }
+
+    #[test]
+    fn test_audit_extra_slots() {
+        let mut market = MarketState::zeroed();
+        market.own_address = [7; 4];
+        market.coin_lot_size = 100;
+        market.pc_lot_size = 10;
+        market.coin_deposits_total = 500;
+
+        let address = [1; 4];
+        let mut open_orders = OpenOrders::zeroed();
+        open_orders.market = [7; 4];
+        open_orders.native_coin_total = 500;
+        open_orders.free_slot_bits = !0;
+        let mut extra_slots = [OpenOrdersSlots::zeroed(); 2];
+        for chunk in extra_slots.iter_mut() {
+            chunk.free_slot_bits = !0;
+        }
+        // asks in slots 200 and 255
+        extra_slots[1].free_slot_bits = !(1 << 8 | 1 << 63);
+        extra_slots[1].orders[8] = order_id(5, 0);
+        extra_slots[1].orders[63] = order_id(6, 1);
+        let view = OpenOrdersView {
+            inner: &open_orders,
+            extra_slots: &extra_slots,
+        };
+        assert_eq!(view.capacity(), MAX_OPEN_ORDERS_SLOTS);
+
+        let asks = [
+            LeafNode::new(200, &order_id(5, 0), &address, 2, FeeTier::Base, 0),
+            LeafNode::new(255, &order_id(6, 1), &address, 3, FeeTier::Base, 0),
+        ];
+        let open_orders_accounts = [(address, view)];
+        let mut audit = MarketAudit {
+            market: &market,
+            coin_vault_balance: 500,
+            pc_vault_balance: 0,
+            coin_staking_fees: 0,
+            pc_staking_fees: 0,
+            bids: &[],
+            asks: &asks,
+            request_queue_len: 0,
+            event_queue_len: 0,
+            open_orders: &open_orders_accounts,
+            trigger_orders: &[],
+        };
+        assert_eq!(audit.run(), vec![]);
+
+        audit.asks = &asks[..1];
+        assert_eq!(
+            audit.run(),
+            vec![
+                Violation::StaleOpenOrdersSlot {
+                    open_orders: address,
+                    slot: 255,
+                    order_id: order_id(6, 1),
+                },
+                Violation::LockedCoin {
+                    open_orders: address,
+                    locked: 500,
+                    resting: 200,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,573 @@
+//! Decoding of raw market account data into owned types, for clients that
+//! read the order book over RPC.
+
+use std::mem::size_of;
+
+use bytemuck::{bytes_of_mut, cast_slice, cast_slice_mut, try_from_bytes, Pod};
+
+use crate::{
+    critbit::{LeafNode, Slab, SLAB_HEADER_LEN},
+    error::{DexErrorCode, DexResult},
+    matching::Side,
+    state::{
+        remove_slop, AccountFlag, Event, EventQueueHeader, FeeSplit, MarketState, MarketStateV2,
+        OpenOrders, OpenOrdersSlots, OpenOrdersV2, OpenOrdersView, QueueHeader, ReferredOpenOrders,
+        Referrer, Request, RequestQueueHeader, TriggerOrder, TriggerOrdersHeader,
+        ACCOUNT_HEAD_PADDING, ACCOUNT_TAIL_PADDING,
+    },
+};
+
+/// Copies the data between the account padding into aligned words.
+fn remove_account_padding(data: &[u8]) -> DexResult<Vec<u64>> {
+    let padding_len = ACCOUNT_HEAD_PADDING.len() + ACCOUNT_TAIL_PADDING.len();
+    if data.len() < padding_len || (data.len() - padding_len) % 8 != 0 {
+        Err(DexErrorCode::WrongAccountDataPaddingLength)?
+    }
+    let (head, rest) = data.split_at(ACCOUNT_HEAD_PADDING.len());
+    let (inner, tail) = rest.split_at(rest.len() - ACCOUNT_TAIL_PADDING.len());
+    if head != ACCOUNT_HEAD_PADDING {
+        Err(DexErrorCode::WrongAccountHeadPadding)?
+    }
+    if tail != ACCOUNT_TAIL_PADDING {
+        Err(DexErrorCode::WrongAccountTailPadding)?
+    }
+    let mut words = vec![0u64; inner.len() / 8];
+    cast_slice_mut(&mut words).copy_from_slice(inner);
+    Ok(words)
+}
+
+fn check_account_flags(words: &[u64], expected: u64, err: DexErrorCode) -> DexResult {
+    match words.first() {
+        Some(&flags) if flags == expected => Ok(()),
+        _ => Err(err.into()),
+    }
+}
+
+pub fn decode_market_state(data: &[u8]) -> DexResult<MarketState> {
+    let words = remove_account_padding(data)?;
+    let market_words = size_of::<MarketState>() / 8;
+    if words.len() < market_words {
+        Err(DexErrorCode::MarketAccountTooSmall)?
+    }
+    let market: MarketState = *try_from_bytes(cast_slice(&words[..market_words]))
+        .map_err(|_| DexErrorCode::MarketAccountTooSmall)?;
+    let flags = market.account_flags
+        & !(AccountFlag::LayoutV2 as u64
+            | AccountFlag::CoinFees as u64
+            | AccountFlag::HasTriggerOrders as u64);
+    if flags & !(AccountFlag::Disabled as u64)
+        != (AccountFlag::Initialized | AccountFlag::Market).bits()
+    {
+        Err(DexErrorCode::InvalidMarketFlags)?
+    }
+    Ok(market)
+}
+
+/// The fee split of a market, which only `LayoutV2` markets have.
+pub fn decode_fee_split(data: &[u8]) -> DexResult<Option<FeeSplit>> {
+    let market = decode_market_state(data)?;
+    if market.layout_version() != 2 {
+        return Ok(None);
+    }
+    let words = remove_account_padding(data)?;
+    if words.len() < size_of::<MarketStateV2>() / 8 {
+        Err(DexErrorCode::MarketAccountTooSmall)?
+    }
+    let market: &MarketStateV2 =
+        try_from_bytes(cast_slice(&words[..size_of::<MarketStateV2>() / 8]))
+            .map_err(|_| DexErrorCode::MarketAccountTooSmall)?;
+    Ok(Some(market.fee_split))
+}
+
+/// An OpenOrders account of any layout.
+#[derive(Clone)]
+pub struct DecodedOpenOrders {
+    pub open_orders: OpenOrders,
+    /// Only set for accounts flagged `Referred`.
+    pub referrer: Option<[u64; 4]>,
+    pub delegate: Option<[u64; 4]>,
+    /// The index and nonce of accounts created by InitOpenOrders, which are
+    /// flagged `Derived`.
+    pub seeds: Option<(u64, u64)>,
+    pub extra_slots: Vec<OpenOrdersSlots>,
+}
+
+impl DecodedOpenOrders {
+    pub fn view(&self) -> OpenOrdersView {
+        OpenOrdersView {
+            inner: &self.open_orders,
+            extra_slots: &self.extra_slots,
+        }
+    }
+}
+
+/// Copies a value out of words, since types holding a `u128` need stricter
+/// alignment than the words have.
+fn copy_from_words<T: Pod>(words: &[u64]) -> T {
+    let mut value = T::zeroed();
+    bytes_of_mut(&mut value).copy_from_slice(cast_slice(&words[..size_of::<T>() / 8]));
+    value
+}
+
+pub fn decode_open_orders(data: &[u8]) -> DexResult<DecodedOpenOrders> {
+    let words = remove_account_padding(data)?;
+    let flags = words
+        .first()
+        .map(|&flags| flags & !(AccountFlag::Referred as u64 | AccountFlag::Derived as u64));
+    if flags != Some((AccountFlag::Initialized | AccountFlag::OpenOrders).bits()) {
+        Err(DexErrorCode::WrongOrdersAccount)?
+    }
+    let data_len = words.len() * 8;
+    let chunks =
+        if data_len == size_of::<OpenOrders>() || data_len == size_of::<ReferredOpenOrders>() {
+            0
+        } else {
+            OpenOrdersSlots::chunks(data_len).ok_or(DexErrorCode::WrongOrdersAccount)?
+        };
+    let open_orders: OpenOrders = copy_from_words(&words);
+    let referrer = if data_len >= size_of::<ReferredOpenOrders>() && open_orders.is_referred() {
+        Some(copy_from_words::<ReferredOpenOrders>(&words).referrer)
+    } else {
+        None
+    };
+    let (delegate, seeds) = if data_len >= size_of::<OpenOrdersV2>() {
+        let open_orders_v2: OpenOrdersV2 = copy_from_words(&words);
+        (
+            Some(open_orders_v2.delegate).filter(|&delegate| delegate != [0; 4]),
+            Some((open_orders_v2.index, open_orders_v2.nonce)).filter(|_| open_orders.is_derived()),
+        )
+    } else {
+        (None, None)
+    };
+    let extra_slots = words[size_of::<OpenOrdersV2>().min(data_len) / 8..]
+        .chunks_exact(size_of::<OpenOrdersSlots>() / 8)
+        .take(chunks)
+        .map(copy_from_words)
+        .collect();
+    Ok(DecodedOpenOrders {
+        open_orders,
+        referrer,
+        delegate,
+        seeds,
+        extra_slots,
+    })
+}
+
+pub fn decode_referrer(data: &[u8]) -> DexResult<Referrer> {
+    let words = remove_account_padding(data)?;
+    check_account_flags(
+        &words,
+        (AccountFlag::Initialized | AccountFlag::Referrer).bits(),
+        DexErrorCode::WrongReferrerAccount,
+    )?;
+    let referrer_words = size_of::<Referrer>() / 8;
+    if words.len() != referrer_words {
+        Err(DexErrorCode::WrongReferrerAccount)?
+    }
+    Ok(*try_from_bytes(cast_slice(&words)).map_err(|_| DexErrorCode::WrongReferrerAccount)?)
+}
+
+/// Decodes a bids or asks account into its orders, best price first and in
+/// time priority within a price.
+pub fn decode_orders(data: &[u8], side: Side) -> DexResult<Vec<LeafNode>> {
+    let mut words = remove_account_padding(data)?;
+    let (flag, err) = match side {
+        Side::Bid => (AccountFlag::Bids, DexErrorCode::InvalidBidFlags),
+        Side::Ask => (AccountFlag::Asks, DexErrorCode::InvalidAskFlags),
+    };
+    check_account_flags(&words, (AccountFlag::Initialized | flag).bits(), err)?;
+    if (words.len() - 1) * 8 < SLAB_HEADER_LEN {
+        Err(DexErrorCode::SlabTooSmall)?
+    }
+    let slab = Slab::new(cast_slice_mut(&mut words[1..]));
+    let orders = match side {
+        Side::Bid => slab.iter_rev().copied().collect(),
+        Side::Ask => slab.iter().copied().collect(),
+    };
+    Ok(orders)
+}
+
+fn decode_queue<H: QueueHeader>(data: &[u8], flag: AccountFlag) -> DexResult<Vec<H::Item>> {
+    let words = remove_account_padding(data)?;
+    check_account_flags(
+        &words,
+        (AccountFlag::Initialized | flag).bits(),
+        DexErrorCode::InvalidQueueLength,
+    )?;
+    let bytes: &[u8] = cast_slice(&words);
+    if bytes.len() < size_of::<H>() {
+        Err(DexErrorCode::InvalidQueueLength)?
+    }
+    let (header_bytes, item_bytes) = bytes.split_at(size_of::<H>());
+    let header: &H = try_from_bytes(header_bytes).map_err(|_| DexErrorCode::InvalidQueueLength)?;
+    let items: &[H::Item] = remove_slop(item_bytes);
+    if items.is_empty() || header.count() as usize > items.len() {
+        Err(DexErrorCode::InvalidQueueLength)?
+    }
+    Ok((0..header.count())
+        .map(|i| items[((header.head() + i) as usize) % items.len()])
+        .collect())
+}
+
+/// The pending requests, oldest first.
+pub fn decode_request_queue(data: &[u8]) -> DexResult<Vec<Request>> {
+    decode_queue::<RequestQueueHeader>(data, AccountFlag::RequestQueue)
+}
+
+/// The unconsumed events, oldest first.
+pub fn decode_event_queue(data: &[u8]) -> DexResult<Vec<Event>> {
+    decode_queue::<EventQueueHeader>(data, AccountFlag::EventQueue)
+}
+
+/// The market a trigger orders account belongs to, and its waiting orders.
+pub fn decode_trigger_orders(data: &[u8]) -> DexResult<([u64; 4], Vec<TriggerOrder>)> {
+    let words = remove_account_padding(data)?;
+    let bytes: &[u8] = cast_slice(&words);
+    if bytes.len() < size_of::<TriggerOrdersHeader>() {
+        Err(DexErrorCode::WrongTriggerOrdersAccount)?
+    }
+    let (header_bytes, order_bytes) = bytes.split_at(size_of::<TriggerOrdersHeader>());
+    let header: &TriggerOrdersHeader =
+        try_from_bytes(header_bytes).map_err(|_| DexErrorCode::WrongTriggerOrdersAccount)?;
+    let orders: &[TriggerOrder] = remove_slop(order_bytes);
+    if !header.is_initialized() || header.len() > orders.len() {
+        Err(DexErrorCode::WrongTriggerOrdersAccount)?
+    }
+    Ok((header.market, orders[..header.len()].to_vec()))
+}
+
+/// Converts prices and quantities in lots into UI units.
+#[derive(Copy, Clone, Debug)]
+pub struct UiConverter {
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+    pub coin_decimals: u8,
+    pub pc_decimals: u8,
+}
+
+impl UiConverter {
+    pub fn new(market: &MarketState, coin_decimals: u8, pc_decimals: u8) -> Self {
+        UiConverter {
+            coin_lot_size: market.coin_lot_size,
+            pc_lot_size: market.pc_lot_size,
+            coin_decimals,
+            pc_decimals,
+        }
+    }
+
+    pub fn price_to_ui(&self, price_lots: u64) -> f64 {
+        (price_lots as f64) * (self.pc_lot_size as f64) * 10f64.powi(self.coin_decimals as i32)
+            / ((self.coin_lot_size as f64) * 10f64.powi(self.pc_decimals as i32))
+    }
+
+    pub fn quantity_to_ui(&self, quantity_lots: u64) -> f64 {
+        (quantity_lots as f64) * (self.coin_lot_size as f64) / 10f64.powi(self.coin_decimals as i32)
+    }
+}
+
+/// The total size resting at one price. `price` and `quantity` are in UI
+/// units; the `_lots` fields are exact.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PriceLevel {
+    pub price_lots: u64,
+    pub quantity_lots: u64,
+    pub price: f64,
+    pub quantity: f64,
+}
+
+/// Aggregates orders, sorted best price first as `decode_orders` returns them,
+/// into price levels.
+pub fn aggregate_levels(orders: &[LeafNode], converter: &UiConverter) -> Vec<PriceLevel> {
+    let mut levels: Vec<PriceLevel> = vec![];
+    for order in orders {
+        let price_lots = order.price().get();
+        match levels.last_mut() {
+            Some(level) if level.price_lots == price_lots => {
+                level.quantity_lots += order.quantity();
+            }
+            _ => levels.push(PriceLevel {
+                price_lots,
+                quantity_lots: order.quantity(),
+                price: 0.,
+                quantity: 0.,
+            }),
+        }
+    }
+    for level in levels.iter_mut() {
+        level.price = converter.price_to_ui(level.price_lots);
+        level.quantity = converter.quantity_to_ui(level.quantity_lots);
+    }
+    levels
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderBookSnapshot {
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+/// A price level whose total size changed between two snapshots. A
+/// `quantity_lots` of zero means the level was removed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LevelChange {
+    pub side: Side,
+    pub level: PriceLevel,
+}
+
+impl OrderBookSnapshot {
+    pub fn decode(bids_data: &[u8], asks_data: &[u8], converter: &UiConverter) -> DexResult<Self> {
+        Ok(OrderBookSnapshot {
+            bids: aggregate_levels(&decode_orders(bids_data, Side::Bid)?, converter),
+            asks: aggregate_levels(&decode_orders(asks_data, Side::Ask)?, converter),
+        })
+    }
+
+    /// The level changes that turn `self` into `newer`, bids first.
+    pub fn diff(&self, newer: &OrderBookSnapshot, converter: &UiConverter) -> Vec<LevelChange> {
+        let mut changes = vec![];
+        diff_side(Side::Bid, &self.bids, &newer.bids, converter, &mut changes);
+        diff_side(Side::Ask, &self.asks, &newer.asks, converter, &mut changes);
+        changes
+    }
+}
+
+fn diff_side(
+    side: Side,
+    old: &[PriceLevel],
+    new: &[PriceLevel],
+    converter: &UiConverter,
+    changes: &mut Vec<LevelChange>,
+) {
+    let removed = |level: &PriceLevel| PriceLevel {
+        price_lots: level.price_lots,
+        quantity_lots: 0,
+        price: converter.price_to_ui(level.price_lots),
+        quantity: 0.,
+    };
+    // both sides are sorted best price first
+    let is_better = |a: u64, b: u64| match side {
+        Side::Bid => a > b,
+        Side::Ask => a < b,
+    };
+    let (mut old_iter, mut new_iter) = (old.iter().peekable(), new.iter().peekable());
+    loop {
+        let level = match (old_iter.peek().copied(), new_iter.peek().copied()) {
+            (None, None) => break,
+            (Some(old_level), Some(new_level)) if old_level.price_lots == new_level.price_lots => {
+                old_iter.next();
+                new_iter.next();
+                if old_level.quantity_lots == new_level.quantity_lots {
+                    continue;
+                }
+                *new_level
+            }
+            (Some(old_level), Some(new_level))
+                if is_better(new_level.price_lots, old_level.price_lots) =>
+            {
+                new_iter.next();
+                *new_level
+            }
+            (None, Some(new_level)) => {
+                new_iter.next();
+                *new_level
+            }
+            (Some(old_level), _) => {
+                old_iter.next();
+                removed(old_level)
+            }
+        };
+        changes.push(LevelChange { side, level });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::{bytes_of, Zeroable};
+
+    use super::*;
+    use crate::error::DexError;
+    use crate::fees::FeeTier;
+
+    /// Pads the data the way the program lays out its accounts.
+    fn account_data(unpadded: &[u8]) -> Vec<u8> {
+        [
+            &ACCOUNT_HEAD_PADDING[..],
+            unpadded,
+            &ACCOUNT_TAIL_PADDING[..],
+        ]
+        .concat()
+    }
+
+    /// An account holding a bid in slot 3.
+    fn open_orders(flags: u64) -> OpenOrders {
+        let mut open_orders = OpenOrders::zeroed();
+        open_orders.account_flags = flags;
+        open_orders.market = [1; 4];
+        open_orders.owner = [2; 4];
+        open_orders.native_pc_free = 5;
+        open_orders.native_pc_total = 1_234;
+        open_orders.free_slot_bits = !(1 << 3);
+        open_orders.is_bid_bits = 1 << 3;
+        open_orders.orders[3] = 99;
+        open_orders.client_order_ids[3] = 7;
+        open_orders
+    }
+
+    fn open_orders_flags() -> u64 {
+        (AccountFlag::Initialized | AccountFlag::OpenOrders).bits()
+    }
+
+    #[test]
+    fn test_decode_open_orders() {
+        let unpadded = bytes_of(&open_orders(open_orders_flags())).to_vec();
+        // native_pc_total is the 13th word of the account
+        assert_eq!(unpadded[96..104], 1_234u64.to_le_bytes());
+
+        let decoded = decode_open_orders(&account_data(&unpadded)).unwrap();
+        assert_eq!(decoded.open_orders.owner, [2; 4]);
+        assert_eq!(decoded.open_orders.native_pc_free, 5);
+        assert_eq!(decoded.open_orders.native_pc_total, 1_234);
+        assert_eq!(decoded.referrer, None);
+        assert_eq!(decoded.delegate, None);
+        assert_eq!(decoded.seeds, None);
+        let view = decoded.view();
+        assert_eq!(view.capacity(), 128);
+        assert_eq!(view.slot_bits(3), Some((false, true)));
+        assert_eq!(view.order_id(3), Some(99));
+        assert_eq!(view.client_order_id(3), 7);
+        assert!(view.slot_is_free(4));
+
+        assert_eq!(
+            decode_open_orders(&unpadded).err(),
+            Some(DexError::ErrorCode(
+                DexErrorCode::WrongAccountDataPaddingLength
+            ))
+        );
+        let mut market_data = account_data(&unpadded);
+        market_data[5..13].copy_from_slice(&(AccountFlag::Market as u64).to_le_bytes());
+        assert_eq!(
+            decode_open_orders(&market_data).err(),
+            Some(DexError::ErrorCode(DexErrorCode::WrongOrdersAccount))
+        );
+    }
+
+    #[test]
+    fn test_decode_referred_open_orders() {
+        let mut referred = ReferredOpenOrders {
+            inner: open_orders(open_orders_flags() | AccountFlag::Referred as u64),
+            referrer: [3; 4],
+        };
+        let unpadded = bytes_of(&referred).to_vec();
+        assert_eq!(unpadded[size_of::<OpenOrders>()..], *bytes_of(&[3u64; 4]));
+
+        let decoded = decode_open_orders(&account_data(&unpadded)).unwrap();
+        assert_eq!(decoded.referrer, Some([3; 4]));
+        assert_eq!(decoded.delegate, None);
+        assert_eq!(decoded.seeds, None);
+        assert_eq!(decoded.open_orders.native_pc_total, 1_234);
+        assert!(decoded.extra_slots.is_empty());
+
+        // without the flag, the trailing words aren't a referrer
+        referred.inner.account_flags = open_orders_flags();
+        let decoded = decode_open_orders(&account_data(bytes_of(&referred))).unwrap();
+        assert_eq!(decoded.referrer, None);
+    }
+
+    #[test]
+    fn test_decode_open_orders_v2() {
+        let mut open_orders_v2 = OpenOrdersV2 {
+            inner: open_orders(open_orders_flags() | AccountFlag::Derived as u64),
+            referrer: [0; 4],
+            delegate: [4; 4],
+            index: 5,
+            nonce: 254,
+        };
+        // a bid in slot 136, the ninth of the first chunk of extra slots
+        let mut extra_slots = OpenOrdersSlots::zeroed();
+        extra_slots.free_slot_bits = !(1 << 8);
+        extra_slots.is_bid_bits = 1 << 8;
+        extra_slots.orders[8] = 123;
+        extra_slots.client_order_ids[8] = 11;
+        let mut unpadded = bytes_of(&open_orders_v2).to_vec();
+        unpadded.extend_from_slice(bytes_of(&extra_slots));
+        let seeds_offset = size_of::<OpenOrders>() + 64;
+        assert_eq!(
+            unpadded[seeds_offset..seeds_offset + 16],
+            [5u64.to_le_bytes(), 254u64.to_le_bytes()].concat()[..]
+        );
+
+        let decoded = decode_open_orders(&account_data(&unpadded)).unwrap();
+        assert_eq!(decoded.referrer, None);
+        assert_eq!(decoded.delegate, Some([4; 4]));
+        assert_eq!(decoded.seeds, Some((5, 254)));
+        assert_eq!(decoded.extra_slots.len(), 1);
+        let view = decoded.view();
+        assert_eq!(view.capacity(), 192);
+        assert_eq!(view.order_id(3), Some(99));
+        assert_eq!(view.slot_bits(136), Some((false, true)));
+        assert_eq!(view.order_id(136), Some(123));
+        assert_eq!(view.client_order_id(136), 11);
+        assert!(view.slot_is_free(137));
+        assert_eq!(view.order_id(192), None);
+
+        // only InitOpenOrders sets the seeds, and a zeroed delegate is unset
+        open_orders_v2.inner.account_flags = open_orders_flags();
+        open_orders_v2.delegate = [0; 4];
+        let decoded = decode_open_orders(&account_data(bytes_of(&open_orders_v2))).unwrap();
+        assert_eq!(decoded.delegate, None);
+        assert_eq!(decoded.seeds, None);
+        assert!(decoded.extra_slots.is_empty());
+
+        // a partial chunk of extra slots isn't a valid length
+        unpadded.truncate(unpadded.len() - 8);
+        assert_eq!(
+            decode_open_orders(&account_data(&unpadded)).err(),
+            Some(DexError::ErrorCode(DexErrorCode::WrongOrdersAccount))
+        );
+    }
+
+    fn order(price: u64, seq_num: u64, quantity: u64) -> LeafNode {
+        let key = ((price as u128) << 64) | seq_num as u128;
+        LeafNode::new(0, &key, &[0; 4], quantity, FeeTier::Base, 0)
+    }
+
+    #[test]
+    fn test_aggregate_and_diff() {
+        let converter = UiConverter {
+            coin_lot_size: 100_000,
+            pc_lot_size: 100,
+            coin_decimals: 6,
+            pc_decimals: 6,
+        };
+        let asks = aggregate_levels(
+            &[order(10, 0, 1), order(10, 1, 2), order(12, 2, 5)],
+            &converter,
+        );
+        assert_eq!(asks.len(), 2);
+        assert_eq!((asks[0].price_lots, asks[0].quantity_lots), (10, 3));
+        assert_eq!((asks[1].price_lots, asks[1].quantity_lots), (12, 5));
+        assert_eq!(asks[0].price, 0.01);
+        assert_eq!(asks[0].quantity, 0.3);
+
+        let old = OrderBookSnapshot { bids: vec![], asks };
+        let new = OrderBookSnapshot {
+            bids: aggregate_levels(&[order(9, 3, 4)], &converter),
+            asks: aggregate_levels(&[order(11, 4, 1), order(12, 2, 5)], &converter),
+        };
+        let changes: Vec<(Side, u64, u64)> = old
+            .diff(&new, &converter)
+            .iter()
+            .map(|change| {
+                (
+                    change.side,
+                    change.level.price_lots,
+                    change.level.quantity_lots,
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![(Side::Bid, 9, 4), (Side::Ask, 10, 0), (Side::Ask, 11, 1)]
+        );
+    }
+}
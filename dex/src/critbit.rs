@@ -183,7 +183,6 @@ impl AnyNode {
         }
     }
 
-    #[cfg(test)]
     fn prefix_len(&self) -> u32 {
         match self.case().unwrap() {
             NodeRef::Inner(&InnerNode { prefix_len, .. }) => prefix_len,
@@ -214,6 +213,14 @@ impl AnyNode {
         }
     }
 
+    #[inline]
+    pub fn as_leaf(&self) -> Option<&LeafNode> {
+        match self.case() {
+            Some(NodeRef::Leaf(leaf_ref)) => Some(leaf_ref),
+            _ => None,
+        }
+    }
+
     #[inline]
     pub fn as_leaf_mut(&mut self) -> Option<&mut LeafNode> {
         match self.case_mut() {
@@ -252,7 +259,7 @@ struct SlabHeader {
 unsafe impl Zeroable for SlabHeader {}
 unsafe impl Pod for SlabHeader {}
 
-const SLAB_HEADER_LEN: usize = size_of::<SlabHeader>();
+pub const SLAB_HEADER_LEN: usize = size_of::<SlabHeader>();
 
 #[cfg(debug_assertions)]
 unsafe fn invariant(check: bool) {
@@ -588,8 +595,7 @@ impl Slab {
         }
     }
 
-    #[cfg(test)]
-    fn find_by_key(&self, search_key: &u128) -> Option<NodeHandle> {
+    pub fn find_by_key(&self, search_key: &u128) -> Option<NodeHandle> {
         let mut node_handle: NodeHandle = self.root()?;
         loop {
             let node_ref = self.get(node_handle).unwrap();
@@ -659,6 +665,40 @@ impl Slab {
         Some(cast(self.remove(child_h).unwrap()))
     }
 
+    /// Looks up an order by its id, which is also its key in the tree.
+    #[inline]
+    pub fn find_by_order_id(&self, order_id: &u128) -> Option<&LeafNode> {
+        self.get(self.find_by_key(order_id)?)?.as_leaf()
+    }
+
+    /// Iterates over the orders in ascending key order, i.e. by ascending price.
+    /// On the asks side, orders at the same price come in time priority.
+    #[inline]
+    pub fn iter(&self) -> SlabIter {
+        SlabIter::new(self, 0, std::u128::MAX, false)
+    }
+
+    /// Iterates over the orders in descending key order, i.e. by descending
+    /// price. On the bids side, orders at the same price come in time priority.
+    #[inline]
+    pub fn iter_rev(&self) -> SlabIter {
+        SlabIter::new(self, 0, std::u128::MAX, true)
+    }
+
+    /// Like `iter`, restricted to orders priced within `min_price..=max_price`.
+    #[inline]
+    pub fn price_range(&self, min_price: u64, max_price: u64) -> SlabIter {
+        let (lower, upper) = price_range_keys(min_price, max_price);
+        SlabIter::new(self, lower, upper, false)
+    }
+
+    /// Like `iter_rev`, restricted to orders priced within `min_price..=max_price`.
+    #[inline]
+    pub fn price_range_rev(&self, min_price: u64, max_price: u64) -> SlabIter {
+        let (lower, upper) = price_range_keys(min_price, max_price);
+        SlabIter::new(self, lower, upper, true)
+    }
+
     #[inline]
     pub fn remove_min(&mut self) -> Option<LeafNode> {
         self.remove_by_key(&self.get(self.find_min()?)?.key()?)
@@ -765,6 +805,90 @@ impl Slab {
     }
 }
 
+#[inline]
+fn price_range_keys(min_price: u64, max_price: u64) -> (u128, u128) {
+    let lower = (min_price as u128) << 64;
+    let upper = ((max_price as u128) << 64) | (std::u64::MAX as u128);
+    (lower, upper)
+}
+
+// Prefix lengths strictly increase going down the tree, so any path has at
+// most 128 inner nodes. Each of them leaves at most one sibling on the stack,
+// plus the child about to be visited.
+const SLAB_ITER_STACK_LEN: usize = 129;
+
+/// An in-order walk over the leaves of a `Slab` with keys in `lower..=upper`.
+/// The walk uses a fixed-size stack, so it never allocates.
+pub struct SlabIter<'a> {
+    slab: &'a Slab,
+    stack: [NodeHandle; SLAB_ITER_STACK_LEN],
+    stack_len: usize,
+    lower: u128,
+    upper: u128,
+    descending: bool,
+}
+
+impl<'a> SlabIter<'a> {
+    fn new(slab: &'a Slab, lower: u128, upper: u128, descending: bool) -> Self {
+        let mut iter = SlabIter {
+            slab,
+            stack: [0; SLAB_ITER_STACK_LEN],
+            stack_len: 0,
+            lower,
+            upper,
+            descending,
+        };
+        if let Some(root) = slab.root() {
+            iter.push_if_in_range(root);
+        }
+        iter
+    }
+
+    fn push_if_in_range(&mut self, handle: NodeHandle) {
+        let node = self.slab.get(handle).unwrap();
+        let prefix_len = node.prefix_len();
+        let key = node.key().unwrap();
+        let prefix_mask = match prefix_len {
+            0 => 0,
+            _ => std::u128::MAX << (128 - prefix_len),
+        };
+        let min_key = key & prefix_mask;
+        let max_key = key | !prefix_mask;
+        if min_key <= self.upper && max_key >= self.lower {
+            self.stack[self.stack_len] = handle;
+            self.stack_len += 1;
+        }
+    }
+}
+
+impl<'a> Iterator for SlabIter<'a> {
+    type Item = &'a LeafNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.stack_len > 0 {
+            self.stack_len -= 1;
+            let handle = self.stack[self.stack_len];
+            match self.slab.get(handle).unwrap().case().unwrap() {
+                NodeRef::Leaf(leaf) => {
+                    if self.lower <= leaf.key && leaf.key <= self.upper {
+                        return Some(leaf);
+                    }
+                }
+                NodeRef::Inner(&InnerNode { children, .. }) => {
+                    let [first, second] = if self.descending {
+                        [children[1], children[0]]
+                    } else {
+                        children
+                    };
+                    self.push_if_in_range(second);
+                    self.push_if_in_range(first);
+                }
+            }
+        }
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -941,6 +1065,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn simulate_iterators() {
+        use std::collections::BTreeMap;
+
+        for trial in 0..10u64 {
+            let mut aligned_buf = vec![0u64; 10_000];
+            let bytes: &mut [u8] = cast_slice_mut(aligned_buf.as_mut_slice());
+
+            let slab: &mut Slab = Slab::new(bytes);
+            let mut model: BTreeMap<u128, LeafNode> = BTreeMap::new();
+
+            let mut rng = StdRng::seed_from_u64(trial);
+
+            assert_eq!(slab.iter().next(), None);
+            assert_eq!(slab.iter_rev().next(), None);
+
+            for _ in 0..100 {
+                let price: u64 = rng.gen_range(1, 20);
+                let seq_num: u64 = rng.gen();
+                let key = ((price as u128) << 64) | (seq_num as u128);
+                let owner = rng.gen();
+                let leaf = LeafNode::new(0, &key, &owner, rng.gen(), FeeTier::Base, 0);
+                slab.insert_leaf(&leaf).unwrap();
+                model.insert(key, leaf);
+
+                assert!(slab.iter().eq(model.values()));
+                assert!(slab.iter_rev().eq(model.values().rev()));
+
+                let min_price = rng.gen_range(1, 20);
+                let max_price = rng.gen_range(min_price, 20);
+                let (lower, upper) = price_range_keys(min_price, max_price);
+                assert!(slab
+                    .price_range(min_price, max_price)
+                    .eq(model.range(lower..=upper).map(|(_, leaf)| leaf)));
+                assert!(slab
+                    .price_range_rev(min_price, max_price)
+                    .eq(model.range(lower..=upper).rev().map(|(_, leaf)| leaf)));
+
+                assert_eq!(slab.find_by_order_id(&key), Some(&leaf));
+                assert_eq!(slab.find_by_order_id(&(key ^ 1)), model.get(&(key ^ 1)));
+            }
+        }
+    }
+
     #[test]
     #[should_panic]
     fn panics_unaligned() {
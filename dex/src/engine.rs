@@ -0,0 +1,255 @@
+//! A standalone order book that owns its buffers, for running the on-chain
+//! matching logic without any Solana accounts (e.g. to backtest strategies
+//! against historical order flow).
+//!
+//! Requests go through the same `OrderBookState::process_requests` path as
+//! `MatchOrders`, so fills, fees and order ids match the program exactly.
+
+use std::cell::{RefCell, RefMut};
+use std::num::NonZeroU64;
+
+use bytemuck::{cast_slice_mut, Zeroable};
+
+use crate::{
+    critbit::Slab,
+    error::{DexErrorCode, DexResult},
+    instruction::FeeCurrency,
+    matching::{OrderBookState, Side},
+    state::{
+        AccountFlag, Event, EventQueue, EventQueueHeader, MarketState, Queue, Request,
+        RequestQueue, RequestQueueHeader, RequestView,
+    },
+};
+
+pub struct MatchingEngine {
+    market_state: MarketState,
+    bids: Vec<u64>,
+    asks: Vec<u64>,
+    req_q_header: RefCell<RequestQueueHeader>,
+    req_q_buf: RefCell<Vec<Request>>,
+    event_q_header: RefCell<EventQueueHeader>,
+    event_q_buf: RefCell<Vec<Event>>,
+}
+
+impl MatchingEngine {
+    /// `slab_len` is the size in bytes of each of the bids and asks slabs, the
+    /// same as the data length of the on-chain accounts without their headers.
+    /// `event_q_len` bounds the number of events a single request can emit.
+    pub fn new(
+        coin_lot_size: u64,
+        pc_lot_size: u64,
+        fee_currency: FeeCurrency,
+        slab_len: usize,
+        event_q_len: usize,
+    ) -> Self {
+        let mut market_state = MarketState::zeroed();
+        market_state.account_flags = (AccountFlag::Initialized | AccountFlag::Market).bits();
+        if fee_currency == FeeCurrency::Coin {
+            market_state.account_flags |= AccountFlag::CoinFees as u64;
+        }
+        market_state.coin_lot_size = coin_lot_size;
+        market_state.pc_lot_size = pc_lot_size;
+
+        let slab_words = (slab_len + 7) / 8;
+        MatchingEngine {
+            market_state,
+            bids: vec![0; slab_words],
+            asks: vec![0; slab_words],
+            req_q_header: RefCell::new(RequestQueueHeader::zeroed()),
+            req_q_buf: RefCell::new(vec![Request::zeroed(); 1]),
+            event_q_header: RefCell::new(EventQueueHeader::zeroed()),
+            event_q_buf: RefCell::new(vec![Event::zeroed(); event_q_len]),
+        }
+    }
+
+    /// The market totals (deposits, accrued fees and referrer rebates) as the
+    /// matching logic has updated them.
+    pub fn market_state(&self) -> &MarketState {
+        &self.market_state
+    }
+
+    pub fn order_book_state(&mut self) -> OrderBookState {
+        OrderBookState {
+            bids: Slab::new(cast_slice_mut(&mut self.bids)),
+            asks: Slab::new(cast_slice_mut(&mut self.asks)),
+            market_state: &mut self.market_state,
+        }
+    }
+
+    /// Generates the order id the program would assign to the next order.
+    pub fn gen_order_id(&mut self, limit_price: u64, side: Side) -> u128 {
+        self.request_queue().gen_order_id(limit_price, side)
+    }
+
+    fn request_queue(&self) -> RequestQueue {
+        Queue::new(
+            self.req_q_header.borrow_mut(),
+            RefMut::map(self.req_q_buf.borrow_mut(), Vec::as_mut_slice),
+        )
+    }
+
+    /// Matches a single request against the book and returns the events it
+    /// produced, in queue order. Use `Event::as_view` to inspect them.
+    ///
+    /// New orders are credited to the market deposits as `NewOrder` would.
+    /// Bids must set `native_pc_qty_locked`.
+    pub fn process_request(&mut self, request: RequestView) -> DexResult<Vec<Event>> {
+        if let RequestView::NewOrder {
+            side,
+            max_coin_qty,
+            native_pc_qty_locked,
+            ..
+        } = request
+        {
+            match side {
+                Side::Bid => {
+                    let native_pc_qty_locked = native_pc_qty_locked
+                        .map(NonZeroU64::get)
+                        .ok_or(DexErrorCode::InsufficientFunds)?;
+                    self.market_state.pc_deposits_total += native_pc_qty_locked;
+                }
+                Side::Ask => {
+                    let native_coin_qty_locked = max_coin_qty
+                        .get()
+                        .checked_mul(self.market_state.coin_lot_size)
+                        .ok_or(DexErrorCode::InsufficientFunds)?;
+                    self.market_state.coin_deposits_total += native_coin_qty_locked;
+                }
+            }
+        }
+
+        let MatchingEngine {
+            market_state,
+            bids,
+            asks,
+            req_q_header,
+            req_q_buf,
+            event_q_header,
+            event_q_buf,
+        } = self;
+        let mut order_book_state = OrderBookState {
+            bids: Slab::new(cast_slice_mut(bids)),
+            asks: Slab::new(cast_slice_mut(asks)),
+            market_state,
+        };
+        let mut req_q: RequestQueue = Queue::new(
+            req_q_header.borrow_mut(),
+            RefMut::map(req_q_buf.borrow_mut(), Vec::as_mut_slice),
+        );
+        let mut event_q: EventQueue = Queue::new(
+            event_q_header.borrow_mut(),
+            RefMut::map(event_q_buf.borrow_mut(), Vec::as_mut_slice),
+        );
+
+        req_q
+            .push_back(Request::new(request))
+            .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        let mut events = vec![];
+        while !req_q.empty() {
+            order_book_state.process_requests(&mut req_q, &mut event_q, std::u16::MAX)?;
+            while let Ok(event) = event_q.pop_front() {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::critbit::SlabView;
+    use crate::fees::FeeTier;
+    use crate::instruction::SelfTradeBehavior;
+    use crate::matching::OrderType;
+    use crate::state::EventView;
+
+    fn new_order(
+        engine: &mut MatchingEngine,
+        side: Side,
+        order_type: OrderType,
+        limit_price: u64,
+        max_coin_qty: u64,
+        owner: &[u64; 4],
+    ) -> Vec<Event> {
+        let order_id = engine.gen_order_id(limit_price, side);
+        let native_pc_qty_locked = match side {
+            Side::Bid => {
+                let native_pc_qty = max_coin_qty * limit_price * engine.market_state().pc_lot_size;
+                NonZeroU64::new(native_pc_qty + FeeTier::Base.taker_fee(native_pc_qty))
+            }
+            Side::Ask => None,
+        };
+        engine
+            .process_request(RequestView::NewOrder {
+                side,
+                order_type,
+                owner_slot: 0,
+                fee_tier: FeeTier::Base,
+                order_id: &order_id,
+                max_coin_qty: NonZeroU64::new(max_coin_qty).unwrap(),
+                native_pc_qty_locked,
+                owner,
+                client_order_id: None,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_matching_engine() {
+        let mut engine = MatchingEngine::new(100, 10, FeeCurrency::Pc, 1 << 14, 64);
+        let maker = [1; 4];
+        let taker = [2; 4];
+
+        let events = new_order(&mut engine, Side::Ask, OrderType::Limit, 50, 10, &maker);
+        assert_eq!(events.len(), 1);
+        match events[0].as_view().unwrap() {
+            EventView::Out {
+                native_qty_still_locked,
+                ..
+            } => assert_eq!(native_qty_still_locked, 1_000),
+            _ => panic!("expected the maker's order to be posted"),
+        }
+
+        let events = new_order(
+            &mut engine,
+            Side::Bid,
+            OrderType::ImmediateOrCancel,
+            50,
+            4,
+            &taker,
+        );
+        let mut maker_paid = 0;
+        let mut taker_received = 0;
+        for event in events.iter() {
+            if let EventView::Fill {
+                maker,
+                native_qty_paid,
+                native_qty_received,
+                ..
+            } = event.as_view().unwrap()
+            {
+                if maker {
+                    maker_paid += native_qty_paid;
+                } else {
+                    taker_received += native_qty_received;
+                }
+            }
+        }
+        assert_eq!(maker_paid, 400);
+        assert_eq!(taker_received, 400);
+        assert!(engine.market_state().pc_fees_accrued > 0);
+
+        let mut order_book_state = engine.order_book_state();
+        assert!(order_book_state.bids.find_max().is_none());
+        let best_ask = order_book_state.asks.find_min().unwrap();
+        let best_ask = order_book_state
+            .asks
+            .get_mut(best_ask)
+            .unwrap()
+            .as_leaf_mut()
+            .unwrap();
+        assert_eq!(best_ask.quantity(), 6);
+    }
+}
@@ -1,4 +1,7 @@
-use num_enum::{FromPrimitive, IntoPrimitive};
+use std::convert::TryFrom;
+use std::fmt;
+
+use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 use solana_sdk::program_error::ProgramError;
 use thiserror::Error;
 
@@ -107,6 +110,36 @@ pub enum DexErrorCode {
     RentNotProvided,
     OrdersNotRentExempt,
 
+    InvalidMarketLayoutVersion,
+    MarketAccountTooSmall,
+    CreateAccountFailed,
+    BadOpenOrdersNonce,
+    WrongNativeSolAccounts,
+
+    UnsupportedFeeCurrency,
+    RequestQueueNotEmpty,
+    RouteOutputTooSmall,
+    InvalidReferrerRebate,
+    WrongReferrerAccount,
+    WrongReferrerWallet,
+    ReferrerNotRentExempt,
+    DuplicateClientOrderId,
+    WrongTriggerOrdersAccount,
+    TriggerOrdersFull,
+    TriggerOrdersNotRentExempt,
+    TriggerOrdersUnsupported,
+    DelegateUnsupported,
+    WrongDelegateSettleWallet,
+    InsufficientCrankDeposit,
+    SettleAmountExceedsFree,
+    PostOnlySlideUnsupported,
+    MarketNotRentExempt,
+    InvalidOpenOrdersSlots,
+    OrderIdNotFound,
+    SystemProgramNotProvided,
+    InvalidFeeSplit,
+    WrongFeeWallet,
+
     Unknown = 1000,
 
     // This contains the line number in the lower 16 bits,
@@ -115,8 +148,157 @@ pub enum DexErrorCode {
     AssertionError,
 }
 
+impl DexErrorCode {
+    /// A readable description of the error, as `DecodedError` prints it.
+    pub fn message(self) -> &'static str {
+        match self {
+            DexErrorCode::InvalidMarketFlags => "the market account flags are invalid",
+            DexErrorCode::InvalidAskFlags => "the asks account flags are invalid",
+            DexErrorCode::InvalidBidFlags => "the bids account flags are invalid",
+            DexErrorCode::InvalidQueueLength => "the queue account has an invalid length",
+            DexErrorCode::OwnerAccountNotProvided => {
+                "the open orders owner account was not provided"
+            }
+            DexErrorCode::ConsumeEventsQueueFailure => {
+                "failed to pop an event while consuming events"
+            }
+            DexErrorCode::WrongCoinVault => "the coin vault doesn't belong to the market",
+            DexErrorCode::WrongPcVault => "the pc vault doesn't belong to the market",
+            DexErrorCode::WrongCoinMint => "the coin mint doesn't belong to the market",
+            DexErrorCode::WrongPcMint => "the pc mint doesn't belong to the market",
+            DexErrorCode::CoinVaultProgramId => "the coin vault isn't owned by the token program",
+            DexErrorCode::PcVaultProgramId => "the pc vault isn't owned by the token program",
+            DexErrorCode::CoinMintProgramId => "the coin mint isn't owned by the token program",
+            DexErrorCode::PcMintProgramId => "the pc mint isn't owned by the token program",
+            DexErrorCode::WrongCoinMintSize => "the coin mint account has the wrong size",
+            DexErrorCode::WrongPcMintSize => "the pc mint account has the wrong size",
+            DexErrorCode::WrongCoinVaultSize => "the coin vault account has the wrong size",
+            DexErrorCode::WrongPcVaultSize => "the pc vault account has the wrong size",
+            DexErrorCode::UninitializedVault => "the vault is not initialized",
+            DexErrorCode::UninitializedMint => "the mint is not initialized",
+            DexErrorCode::CoinMintUninitialized => "the coin mint is not initialized",
+            DexErrorCode::PcMintUninitialized => "the pc mint is not initialized",
+            DexErrorCode::WrongMint => "the token account has the wrong mint",
+            DexErrorCode::WrongVaultOwner => "the vault isn't owned by the vault signer",
+            DexErrorCode::VaultHasDelegate => "the vault has a delegate",
+            DexErrorCode::AlreadyInitialized => "the account is already initialized",
+            DexErrorCode::WrongAccountDataAlignment => "the account data is misaligned",
+            DexErrorCode::WrongAccountDataPaddingLength => {
+                "the account data has the wrong length for its padding"
+            }
+            DexErrorCode::WrongAccountHeadPadding => "the account data has the wrong head padding",
+            DexErrorCode::WrongAccountTailPadding => "the account data has the wrong tail padding",
+            DexErrorCode::RequestQueueEmpty => "the request queue is empty",
+            DexErrorCode::EventQueueTooSmall => "the event queue account is too small",
+            DexErrorCode::SlabTooSmall => "the order book account is too small",
+            DexErrorCode::BadVaultSignerNonce => {
+                "the vault signer nonce doesn't derive a valid address"
+            }
+            DexErrorCode::InsufficientFunds => "insufficient funds",
+            DexErrorCode::SplAccountProgramId => {
+                "the token account isn't owned by the token program"
+            }
+            DexErrorCode::SplAccountLen => "the token account has the wrong size",
+            DexErrorCode::WrongFeeDiscountAccountOwner => {
+                "the fee discount account isn't owned by the order owner"
+            }
+            DexErrorCode::WrongFeeDiscountMint => "the fee discount account has the wrong mint",
+            DexErrorCode::CoinPayerProgramId => "the coin payer isn't owned by the token program",
+            DexErrorCode::PcPayerProgramId => "the pc payer isn't owned by the token program",
+            DexErrorCode::ClientIdNotFound => "no open order has this client id",
+            DexErrorCode::TooManyOpenOrders => "the open orders account has no free slots",
+            DexErrorCode::FakeErrorSoWeDontChangeNumbers => "unused",
+            DexErrorCode::BorrowError => "an account is already borrowed",
+            DexErrorCode::WrongOrdersAccount => {
+                "the open orders account is invalid or belongs to another market or owner"
+            }
+            DexErrorCode::WrongBidsAccount => "the bids account doesn't belong to the market",
+            DexErrorCode::WrongAsksAccount => "the asks account doesn't belong to the market",
+            DexErrorCode::WrongRequestQueueAccount => {
+                "the request queue doesn't belong to the market"
+            }
+            DexErrorCode::WrongEventQueueAccount => "the event queue doesn't belong to the market",
+            DexErrorCode::RequestQueueFull => "the request queue is full",
+            DexErrorCode::EventQueueFull => "the event queue is full",
+            DexErrorCode::MarketIsDisabled => "the market is disabled",
+            DexErrorCode::WrongSigner => "the required signer didn't sign",
+            DexErrorCode::TransferFailed => "the token transfer failed",
+            DexErrorCode::ClientOrderIdIsZero => "the client order id is zero",
+            DexErrorCode::WrongRentSysvarAccount => "the rent sysvar account is wrong",
+            DexErrorCode::RentNotProvided => {
+                "the rent sysvar is needed to initialize the open orders account"
+            }
+            DexErrorCode::OrdersNotRentExempt => "the open orders account is not rent exempt",
+            DexErrorCode::InvalidMarketLayoutVersion => {
+                "the market has an unsupported layout version"
+            }
+            DexErrorCode::MarketAccountTooSmall => "the market account is too small",
+            DexErrorCode::CreateAccountFailed => "failed to create the account",
+            DexErrorCode::BadOpenOrdersNonce => {
+                "the open orders nonce doesn't derive the open orders address"
+            }
+            DexErrorCode::WrongNativeSolAccounts => "the native SOL accounts are wrong",
+            DexErrorCode::UnsupportedFeeCurrency => {
+                "the market's fee currency isn't supported by this instruction"
+            }
+            DexErrorCode::RequestQueueNotEmpty => "the request queue couldn't be drained",
+            DexErrorCode::RouteOutputTooSmall => "the route's output is below the minimum",
+            DexErrorCode::InvalidReferrerRebate => {
+                "the referrer rebate exceeds the share set aside for referrers"
+            }
+            DexErrorCode::WrongReferrerAccount => {
+                "the referrer account is invalid or doesn't match the open orders account"
+            }
+            DexErrorCode::WrongReferrerWallet => "the wallet isn't the referrer's payout wallet",
+            DexErrorCode::ReferrerNotRentExempt => "the referrer account is not rent exempt",
+            DexErrorCode::DuplicateClientOrderId => "a live order already has this client order id",
+            DexErrorCode::WrongTriggerOrdersAccount => {
+                "the trigger orders account is invalid or doesn't belong to the market"
+            }
+            DexErrorCode::TriggerOrdersFull => "the trigger orders account is full",
+            DexErrorCode::TriggerOrdersNotRentExempt => {
+                "the trigger orders account is not rent exempt"
+            }
+            DexErrorCode::TriggerOrdersUnsupported => {
+                "the instruction doesn't support markets with trigger orders"
+            }
+            DexErrorCode::DelegateUnsupported => {
+                "the open orders account is too small to have a delegate"
+            }
+            DexErrorCode::WrongDelegateSettleWallet => {
+                "a delegate can only settle to wallets of the open orders owner"
+            }
+            DexErrorCode::InsufficientCrankDeposit => "the owner can't pay the crank deposit",
+            DexErrorCode::SettleAmountExceedsFree => {
+                "the amount to settle is more than the open orders account has free"
+            }
+            DexErrorCode::PostOnlySlideUnsupported => {
+                "post-only slide orders can only be placed with NewOrder"
+            }
+            DexErrorCode::MarketNotRentExempt => "the new market account is not rent exempt",
+            DexErrorCode::InvalidOpenOrdersSlots => {
+                "the number of OpenOrders slots must be 128 plus a multiple of 64, up to 256"
+            }
+            DexErrorCode::OrderIdNotFound => "the slot doesn't hold an open order with this id",
+            DexErrorCode::SystemProgramNotProvided => "the system program account was not provided",
+            DexErrorCode::InvalidFeeSplit => "the fee split shares must add up to 10000 bps",
+            DexErrorCode::WrongFeeWallet => "the wallet is not the one the fee split pays",
+            DexErrorCode::Unknown => "unknown error",
+            DexErrorCode::AssertionError => "assertion failed",
+        }
+    }
+}
+
+impl std::fmt::Display for DexErrorCode {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        <Self as std::fmt::Debug>::fmt(self, fmt)
+    }
+}
+
+impl std::error::Error for DexErrorCode {}
+
 #[repr(u8)]
-#[derive(Error, Debug)]
+#[derive(Error, Debug, TryFromPrimitive, Clone, Copy, PartialEq, Eq)]
 pub enum SourceFileId {
     #[error("src/state.rs")]
     State = 1,
@@ -126,6 +308,70 @@ pub enum SourceFileId {
     Critbit = 3,
 }
 
+/// A `ProgramError::Custom` code returned by the program, decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodedError {
+    ErrorCode(DexErrorCode),
+    /// A failed `check_assert!` and friends.
+    Assertion {
+        file_id: SourceFileId,
+        line: u16,
+    },
+    Unknown(u32),
+}
+
+impl DecodedError {
+    pub fn from_custom(code: u32) -> Self {
+        let file_id = code >> 24;
+        if file_id != 0 {
+            return match SourceFileId::try_from(file_id as u8) {
+                Ok(file_id) if code & 0x00ff_0000 == 0 => DecodedError::Assertion {
+                    file_id,
+                    line: code as u16,
+                },
+                _ => DecodedError::Unknown(code),
+            };
+        }
+        match DexErrorCode::from(code) {
+            // the default for codes outside the enum
+            DexErrorCode::AssertionError => DecodedError::Unknown(code),
+            error_code => DecodedError::ErrorCode(error_code),
+        }
+    }
+}
+
+impl fmt::Display for DecodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodedError::ErrorCode(error_code) => write!(
+                f,
+                "{:?} ({}): {}",
+                error_code,
+                u32::from(*error_code),
+                error_code.message()
+            ),
+            DecodedError::Assertion { file_id, line } => {
+                write!(f, "assertion failed at {}:{}", file_id, line)
+            }
+            DecodedError::Unknown(code) => write!(f, "unknown error code {:#x}", code),
+        }
+    }
+}
+
+impl DexError {
+    /// Decodes the error as the program returns it to clients, or `None` if it
+    /// isn't a custom error.
+    pub fn decode(&self) -> Option<DecodedError> {
+        match self {
+            DexError::ErrorCode(error_code) => Some(DecodedError::ErrorCode(*error_code)),
+            DexError::ProgramError(ProgramError::Custom(code)) => {
+                Some(DecodedError::from_custom(*code))
+            }
+            DexError::ProgramError(_) => None,
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! declare_check_assert_macros {
     ($source_file_id:expr) => {
@@ -170,14 +416,6 @@ macro_rules! declare_check_assert_macros {
     };
 }
 
-impl std::fmt::Display for DexErrorCode {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        <Self as std::fmt::Debug>::fmt(self, fmt)
-    }
-}
-
-impl std::error::Error for DexErrorCode {}
-
 impl std::convert::From<DexError> for ProgramError {
     fn from(e: DexError) -> ProgramError {
         match e {
@@ -192,3 +430,57 @@ impl std::convert::From<std::cell::BorrowError> for DexError {
         DexError::ErrorCode(DexErrorCode::BorrowError)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_custom_errors() {
+        for code in 0..=1001 {
+            match DecodedError::from_custom(code) {
+                DecodedError::ErrorCode(error_code) => assert_eq!(u32::from(error_code), code),
+                DecodedError::Unknown(_) => {}
+                decoded => panic!("{} decoded as {:?}", code, decoded),
+            }
+        }
+        assert_eq!(
+            DecodedError::from_custom(DexErrorCode::RequestQueueFull.into()).to_string(),
+            "RequestQueueFull (50): the request queue is full"
+        );
+        assert_eq!(
+            DexErrorCode::RequestQueueFull.to_string(),
+            "RequestQueueFull"
+        );
+
+        let assertion: u32 = AssertionError {
+            line: 1234,
+            file_id: SourceFileId::Matching,
+        }
+        .into();
+        let decoded = DecodedError::from_custom(assertion);
+        assert_eq!(
+            decoded,
+            DecodedError::Assertion {
+                file_id: SourceFileId::Matching,
+                line: 1234
+            }
+        );
+        assert_eq!(
+            decoded.to_string(),
+            "assertion failed at src/matching.rs:1234"
+        );
+        assert_eq!(
+            DexError::from(AssertionError {
+                line: 1234,
+                file_id: SourceFileId::Matching,
+            })
+            .decode(),
+            Some(decoded)
+        );
+        assert_eq!(
+            DecodedError::from_custom(9 << 24),
+            DecodedError::Unknown(9 << 24)
+        );
+    }
+}
@@ -125,6 +125,18 @@ pub fn referrer_rebate(amount: u64) -> u64 {
     amount / 5
 }
 
+/// The most a registered referrer can earn, in basis points of the taker fee.
+/// This is the share set aside by `referrer_rebate`.
+pub const MAX_REFERRER_REBATE_BPS: u64 = 2_000;
+
+/// The part of the rebates set aside by `referrer_rebate` that is paid to a
+/// registered referrer earning `rebate_bps` of the taker fee.
+#[inline]
+pub fn registered_referrer_rebate(set_aside: u64, rebate_bps: u64) -> u64 {
+    debug_assert!(rebate_bps <= MAX_REFERRER_REBATE_BPS);
+    (set_aside as u128 * rebate_bps as u128 / MAX_REFERRER_REBATE_BPS as u128) as u64
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -142,6 +154,13 @@ mod tests {
             assert!(net_bps_u64f64 + dust_qty_u64f64 > three_bps, "{:x}, {:x}, {:x}", qty, net_bps_u64f64, three_bps);
         }
 
+        #[test]
+        fn registered_referrer_rebate_bounded(set_aside: u64, rebate_bps in 0..=MAX_REFERRER_REBATE_BPS) {
+            let rebate = registered_referrer_rebate(set_aside, rebate_bps);
+            assert!(rebate <= set_aside);
+            assert_eq!(registered_referrer_rebate(set_aside, MAX_REFERRER_REBATE_BPS), set_aside);
+        }
+
         #[test]
         fn fee_bps_approx(bps in 1..100u64) {
             let rate = fee_bps(bps);
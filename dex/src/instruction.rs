@@ -1,6 +1,7 @@
 #![cfg_attr(not(feature = "program"), allow(unused))]
-use crate::error::DexError;
+use crate::error::{DexError, DexErrorCode};
 use crate::matching::{OrderType, Side};
+use crate::state::find_open_orders_key;
 use bytemuck::cast;
 use serde::{Deserialize, Serialize};
 use solana_sdk::{
@@ -58,6 +59,66 @@ pub struct InitializeMarketInstruction {
     pub pc_dust_threshold: u64,
 }
 
+/// The currency taker fees are charged in.
+///
+/// With `Coin`, taker bids pay their fee out of the coin they receive, and the
+/// makers they trade against are rebated in coin as well. Taker asks always
+/// pay fees in the price currency, since that is what they receive.
+#[derive(
+    PartialEq, Eq, Copy, Clone, Debug, TryFromPrimitive, IntoPrimitive, Serialize, Deserialize,
+)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum FeeCurrency {
+    Pc = 0,
+    Coin = 1,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(test, proptest(no_params))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct InitializeMarketInstructionV2 {
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+    pub fee_rate_bps: u16,
+    pub vault_signer_nonce: u64,
+    pub pc_dust_threshold: u64,
+    pub fee_currency: FeeCurrency,
+}
+
+impl InitializeMarketInstruction {
+    pub fn add_fee_currency(self, fee_currency: FeeCurrency) -> InitializeMarketInstructionV2 {
+        let InitializeMarketInstruction {
+            coin_lot_size,
+            pc_lot_size,
+            fee_rate_bps,
+            vault_signer_nonce,
+            pc_dust_threshold,
+        } = self;
+        InitializeMarketInstructionV2 {
+            coin_lot_size,
+            pc_lot_size,
+            fee_rate_bps,
+            vault_signer_nonce,
+            pc_dust_threshold,
+            fee_currency,
+        }
+    }
+
+    fn unpack(data: &[u8; 34]) -> Self {
+        let fields = array_refs![data, 8, 8, 2, 8, 8];
+        InitializeMarketInstruction {
+            coin_lot_size: u64::from_le_bytes(*fields.0),
+            pc_lot_size: u64::from_le_bytes(*fields.1),
+            fee_rate_bps: u16::from_le_bytes(*fields.2),
+            vault_signer_nonce: u64::from_le_bytes(*fields.3),
+            pc_dust_threshold: u64::from_le_bytes(*fields.4),
+        }
+    }
+}
+
 #[derive(
     PartialEq, Eq, Copy, Clone, Debug, TryFromPrimitive, IntoPrimitive, Serialize, Deserialize,
 )]
@@ -69,6 +130,82 @@ pub enum SelfTradeBehavior {
     CancelProvide = 1,
 }
 
+/// Which way the last traded price has to move for a trigger order to enter
+/// the book.
+#[derive(
+    PartialEq, Eq, Copy, Clone, Debug, TryFromPrimitive, IntoPrimitive, Serialize, Deserialize,
+)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum TriggerDirection {
+    /// Once the last price rises to or above the trigger price, e.g. a
+    /// stop-loss bid or a take-profit ask.
+    Above = 0,
+    /// Once the last price falls to or below the trigger price, e.g. a
+    /// stop-loss ask or a take-profit bid.
+    Below = 1,
+}
+
+/// The most client order ids `CancelOrdersByClientIds` takes.
+pub const MAX_CANCEL_CLIENT_IDS: usize = 8;
+
+/// The most orders `NewOrders` places.
+pub const MAX_NEW_ORDERS: usize = 24;
+
+// A `NewOrders` with `MAX_NEW_ORDERS` orders is the longest instruction.
+const MAX_INSTRUCTION_LEN: usize = 5 + 17 + 28 * MAX_NEW_ORDERS;
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct NewOrderInstructionV3 {
+    pub side: Side,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub limit_price: NonZeroU64,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub max_qty: NonZeroU64,
+    pub order_type: OrderType,
+    pub client_id: u64,
+    pub self_trade_behavior: SelfTradeBehavior,
+    // Rejects the order if a live order of the OpenOrders account already
+    // has its (nonzero) client id.
+    pub reject_duplicate_client_id: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct NewOrderInstructionV4 {
+    pub side: Side,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub limit_price: NonZeroU64,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub max_qty: NonZeroU64,
+    pub order_type: OrderType,
+    pub client_id: u64,
+    pub self_trade_behavior: SelfTradeBehavior,
+    pub reject_duplicate_client_id: bool,
+    // The order stays out of the book until the last traded price reaches
+    // this price in the trigger direction.
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub trigger_price: NonZeroU64,
+    pub trigger_direction: TriggerDirection,
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub struct NewOrderInstructionV2 {
@@ -129,6 +266,85 @@ impl NewOrderInstructionV1 {
     }
 }
 
+impl NewOrderInstructionV2 {
+    pub fn add_reject_duplicate_client_id(
+        self,
+        reject_duplicate_client_id: bool,
+    ) -> NewOrderInstructionV3 {
+        let NewOrderInstructionV2 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+        } = self;
+        NewOrderInstructionV3 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+            reject_duplicate_client_id,
+        }
+    }
+}
+
+impl NewOrderInstructionV3 {
+    pub fn add_trigger(
+        self,
+        trigger_price: NonZeroU64,
+        trigger_direction: TriggerDirection,
+    ) -> NewOrderInstructionV4 {
+        let NewOrderInstructionV3 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+            reject_duplicate_client_id,
+        } = self;
+        NewOrderInstructionV4 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+            reject_duplicate_client_id,
+            trigger_price,
+            trigger_direction,
+        }
+    }
+}
+
+impl NewOrderInstructionV4 {
+    pub fn remove_trigger(self) -> NewOrderInstructionV3 {
+        let NewOrderInstructionV4 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+            reject_duplicate_client_id,
+            trigger_price: _,
+            trigger_direction: _,
+        } = self;
+        NewOrderInstructionV3 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+            reject_duplicate_client_id,
+        }
+    }
+}
+
 impl NewOrderInstructionV1 {
     fn unpack(data: &[u8; 32]) -> Option<Self> {
         let (&side_arr, &price_arr, &max_qty_arr, &otype_arr, &client_id_bytes) =
@@ -145,6 +361,7 @@ impl NewOrderInstructionV1 {
             0 => OrderType::Limit,
             1 => OrderType::ImmediateOrCancel,
             2 => OrderType::PostOnly,
+            3 => OrderType::PostOnlySlide,
             _ => return None,
         };
         Some(NewOrderInstructionV1 {
@@ -157,6 +374,103 @@ impl NewOrderInstructionV1 {
     }
 }
 
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct NewOrdersEntry {
+    pub side: Side,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub limit_price: NonZeroU64,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub max_qty: NonZeroU64,
+    pub client_id: u64,
+}
+
+impl NewOrdersEntry {
+    fn unpack(data: &[u8; 28]) -> Option<Self> {
+        let (&side_arr, &price_arr, &max_qty_arr, &client_id_arr) = array_refs![data, 4, 8, 8, 8];
+        let side = match u32::from_le_bytes(side_arr) {
+            0 => Side::Bid,
+            1 => Side::Ask,
+            _ => return None,
+        };
+        Some(NewOrdersEntry {
+            side,
+            limit_price: NonZeroU64::new(u64::from_le_bytes(price_arr))?,
+            max_qty: NonZeroU64::new(u64::from_le_bytes(max_qty_arr))?,
+            client_id: u64::from_le_bytes(client_id_arr),
+        })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct NewOrdersInstruction {
+    pub order_type: OrderType,
+    pub self_trade_behavior: SelfTradeBehavior,
+    // Fails the instruction unless all the orders fit in the OpenOrders
+    // account and the request queue, instead of dropping the last ones.
+    // Without it the instruction still fails if none of them fit.
+    pub all_or_nothing: bool,
+    // Between 1 and `MAX_NEW_ORDERS` orders.
+    #[cfg_attr(
+        test,
+        proptest(
+            strategy = "proptest::collection::vec(any::<NewOrdersEntry>(), 1..=MAX_NEW_ORDERS)"
+        )
+    )]
+    pub orders: Vec<NewOrdersEntry>,
+}
+
+impl NewOrdersInstruction {
+    fn unpack(data: &[u8]) -> Option<Self> {
+        if data.len() < 17 {
+            return None;
+        }
+        let (header, entries) = data.split_at(17);
+        let (&order_type_arr, &self_trade_behavior_arr, &[all_or_nothing], &len_arr) =
+            array_refs![array_ref![header, 0, 17], 4, 4, 1, 8];
+        let order_type = match u32::from_le_bytes(order_type_arr) {
+            0 => OrderType::Limit,
+            1 => OrderType::ImmediateOrCancel,
+            2 => OrderType::PostOnly,
+            3 => OrderType::PostOnlySlide,
+            _ => return None,
+        };
+        let self_trade_behavior = SelfTradeBehavior::try_from_primitive(
+            u32::from_le_bytes(self_trade_behavior_arr)
+                .try_into()
+                .ok()?,
+        )
+        .ok()?;
+        let all_or_nothing = match all_or_nothing {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        let len = u64::from_le_bytes(len_arr);
+        if len == 0 || len > MAX_NEW_ORDERS as u64 || entries.len() as u64 != 28 * len {
+            return None;
+        }
+        let orders = entries
+            .chunks(28)
+            .map(|entry| NewOrdersEntry::unpack(array_ref![entry, 0, 28]))
+            .collect::<Option<Vec<_>>>()?;
+        Some(NewOrdersInstruction {
+            order_type,
+            self_trade_behavior,
+            all_or_nothing,
+            orders,
+        })
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
@@ -167,6 +481,74 @@ pub struct CancelOrderInstruction {
     pub owner_slot: u8,
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct InitOpenOrdersInstruction {
+    // Lets an owner hold several OpenOrders accounts on the same market.
+    pub index: u64,
+    pub nonce: u64,
+    // 128 plus a multiple of 64, up to `state::MAX_OPEN_ORDERS_SLOTS`.
+    pub slots: u64,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct SettleFundsPartialInstruction {
+    // Native amounts, at most what the OpenOrders account has free.
+    pub native_coin_amount: u64,
+    pub native_pc_amount: u64,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct SetFeeSplitInstruction {
+    // Shares of the swept fees, adding up to 10000.
+    pub staking_bps: u16,
+    pub treasury_bps: u16,
+    pub referrer_bps: u16,
+    // Pays the staking share to the treasury instead of holding it in the
+    // vault, for as long as the registry can't take donations.
+    pub route_staking_to_treasury: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct RouteInstruction {
+    // The sides taken on the first and second market. A bid spends pc for
+    // coin and an ask spends coin for pc.
+    pub first_side: Side,
+    pub second_side: Side,
+    // Spent on the first market, in its input currency.
+    pub native_amount_in: u64,
+    // Received from the second market, in its output currency.
+    pub min_native_amount_out: u64,
+    // The most requests processed and resting orders matched on each market.
+    pub limit: u16,
+}
+
+impl RouteInstruction {
+    fn unpack(data: &[u8; 26]) -> Option<Self> {
+        let (&first_side, &second_side, &amount_in, &min_amount_out, &limit) =
+            array_refs![data, 4, 4, 8, 8, 2];
+        let unpack_side = |side| match u32::from_le_bytes(side) {
+            0 => Some(Side::Bid),
+            1 => Some(Side::Ask),
+            _ => None,
+        };
+        Some(RouteInstruction {
+            first_side: unpack_side(first_side)?,
+            second_side: unpack_side(second_side)?,
+            native_amount_in: u64::from_le_bytes(amount_in),
+            min_native_amount_out: u64::from_le_bytes(min_amount_out),
+            limit: u16::from_le_bytes(limit),
+        })
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
@@ -191,6 +573,25 @@ pub enum MarketInstruction {
     /// 7. `[]` spl token program
     /// 8. `[]` the rent sysvar
     /// 9. `[writable]` (optional) the (M)SRM account used for fee discounts
+    ///
+    /// A `PostOnlySlide` order is repriced against the order book, which goes
+    /// right after the rent sysvar, ahead of the optional accounts:
+    ///
+    /// ... `[]` bids
+    /// ... `[]` asks
+    ///
+    /// If the paying account is the owner's system account, the order is paid
+    /// in native SOL and these accounts follow:
+    ///
+    /// ... `[writable, signer]` fresh temporary account for the wrapped SOL
+    /// ... `[]` native mint
+    /// ... `[]` vault signer
+    /// ... `[]` system program
+    ///
+    /// Otherwise, if the market charges crank deposits, the system program
+    /// goes last:
+    ///
+    /// ... `[]` system program
     NewOrder(NewOrderInstructionV1),
     /// 0. `[writable]` market
     /// 1. `[writable]` req_q
@@ -199,13 +600,20 @@ pub enum MarketInstruction {
     /// 4. `[writable]` asks
     /// 5. `[writable]` coin fee receivable account
     /// 6. `[writable]` pc fee receivable account
+    /// 7. `[writable]` the market's trigger orders, if it has any
+    /// 7 or 8. `[writable]` (optional) the account paid the crank reward
     MatchOrders(u16),
+    /// 0. `[writable]` (optional) the account paid the crank reward, which
+    ///    can't be owned by the dex
     /// ... `[writable]` OpenOrders
     /// accounts.len() - 4 `[writable]` market
     /// accounts.len() - 3 `[writable]` event queue
     /// accounts.len() - 2 `[writable]` coin fee receivable account
     /// accounts.len() - 1 `[writable]` pc fee receivable account
     ConsumeEvents(u16),
+    /// Fails unless the OpenOrders slot holds an open order with this id on
+    /// this side.
+    ///
     /// 0. `[]` market
     /// 1. `[writable]` OpenOrders
     /// 2. `[writable]` the request queue
@@ -221,6 +629,18 @@ pub enum MarketInstruction {
     /// 7. `[]` vault signer
     /// 8. `[]` spl token program
     /// 9. `[writable]` (optional) referrer pc wallet
+    /// 10. `[]` the OpenOrders account's Referrer, if it was created with one
+    ///
+    /// OpenOrders accounts created with a Referrer only pay referrer rebates
+    /// to its pc wallet, and keep them until both accounts are passed.
+    ///
+    /// If the coin or pc wallet is the owner's system account, that side is
+    /// settled in native SOL and these accounts follow:
+    ///
+    /// ... `[writable, signer]` fresh temporary account for the wrapped SOL
+    /// ... `[]` native mint
+    /// ... `[]` system program
+    /// ... `[]` the rent sysvar
     SettleFunds,
     /// 0. `[]` market
     /// 1. `[writable]` OpenOrders
@@ -230,10 +650,22 @@ pub enum MarketInstruction {
     /// 0. `[writable]` market
     /// 1. `[signer]` disable authority
     DisableMarket,
+    /// Sweeps the fees accrued in whichever vault is passed.
+    ///
     /// 0. `[writable]` market
-    /// 1. `[writable]` pc vault
+    /// 1. `[writable]` pc vault, or coin vault
     /// 2. `[signer]` fee sweeping authority
-    /// 3. `[writable]` fee receivable account
+    /// 3. `[writable]` fee receivable account, in the currency of the vault
+    /// 4. `[]` vault signer
+    /// 5. `[]` spl token program
+    ///
+    /// Once the market has a fee split, anyone can sweep, and the fees are
+    /// divided as `SetFeeSplit` recorded:
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable]` pc vault, or coin vault
+    /// 2. `[writable]` the treasury wallet, in the currency of the vault
+    /// 3. `[writable]` the referrer wallet, in the currency of the vault
     /// 4. `[]` vault signer
     /// 5. `[]` spl token program
     SweepFees,
@@ -247,7 +679,197 @@ pub enum MarketInstruction {
     /// 7. `[]` spl token program
     /// 8. `[]` the rent sysvar
     /// 9. `[writable]` (optional) the (M)SRM account used for fee discounts
+    ///
+    /// A `PostOnlySlide` order is repriced against the order book, which goes
+    /// right after the rent sysvar, ahead of the optional accounts:
+    ///
+    /// ... `[]` bids
+    /// ... `[]` asks
+    ///
+    /// If the paying account is the owner's system account, the order is paid
+    /// in native SOL and these accounts follow:
+    ///
+    /// ... `[writable, signer]` fresh temporary account for the wrapped SOL
+    /// ... `[]` native mint
+    /// ... `[]` vault signer
+    /// ... `[]` system program
     NewOrderV2(NewOrderInstructionV2),
+    /// Copies a version 1 market into a new account with the version 2
+    /// layout, and disables the old account. Accounts can't be reallocated
+    /// on this version of the runtime, so the market can't be upgraded in
+    /// place.
+    ///
+    /// The new account keeps the market's `own_address`, which the vault
+    /// signer is derived from and existing OpenOrders accounts refer to, so
+    /// the queues, book, vaults and OpenOrders accounts carry over as they
+    /// are. Instructions take the new account from then on.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable]` the new market account, zeroed out, rent exempt and
+    ///    large enough for `MarketStateV2`
+    /// 2. `[signer]` fee sweeping authority
+    /// 3. `[]` the rent sysvar
+    MigrateMarket,
+    /// Creates an OpenOrders account with the given number of order slots at
+    /// the address derived from (market, owner, index, nonce), where market
+    /// is the address of the market account passed in. The account
+    /// has the `state::OpenOrdersV2` layout and is flagged `Derived`. If the
+    /// account is already owned by the program, it is only initialized.
+    ///
+    /// 0. `[writable]` the OpenOrders account to create
+    /// 1. `[signer]` owner of the OpenOrders account
+    /// 2. `[]` market
+    /// 3. `[writable, signer]` payer of the rent-exempt balance
+    /// 4. `[]` system program
+    /// 5. `[]` the rent sysvar
+    /// 6. `[]` (optional) a Referrer registered on the market, which is paid
+    ///    the account's referrer rebates from then on
+    InitOpenOrders(InitOpenOrdersInstruction),
+    /// Same accounts as `InitializeMarket`.
+    InitializeMarketV2(InitializeMarketInstructionV2),
+    /// Trades through two markets in one instruction: an immediate-or-cancel
+    /// order on the first market, whose whole output is spent by an
+    /// immediate-or-cancel order on the second. Fails unless the second order
+    /// receives at least the minimum output.
+    ///
+    /// Both orders match right away, without OpenOrders accounts, after the
+    /// pending requests of their market have been processed. The makers are
+    /// settled by ConsumeEvents as usual. Only markets charging fees in the
+    /// price currency are supported.
+    ///
+    /// 0. `[writable]` first market
+    /// 1. `[writable]` first market's request queue
+    /// 2. `[writable]` first market's event queue
+    /// 3. `[writable]` first market's bids
+    /// 4. `[writable]` first market's asks
+    /// 5. `[writable]` first market's coin vault
+    /// 6. `[writable]` first market's pc vault
+    /// 7. `[]` first market's vault signer
+    /// 8..15. the same accounts for the second market
+    /// 16. `[signer]` owner of the wallets
+    /// 17. `[writable]` wallet paying for the first order
+    /// 18. `[writable]` wallet receiving the first order's output, which pays
+    ///     for the second order
+    /// 19. `[writable]` wallet receiving the second order's output
+    /// 20. `[]` spl token program
+    /// 21. `[writable]` (optional) the (M)SRM account used for fee discounts
+    Route(RouteInstruction),
+    /// Registers a referrer on the market, earning the given basis points of
+    /// the taker fees paid through OpenOrders accounts created with it, up to
+    /// `fees::MAX_REFERRER_REBATE_BPS`.
+    ///
+    /// 0. `[writable]` the Referrer account, zeroed out and rent exempt
+    /// 1. `[]` market
+    /// 2. `[signer]` owner of the Referrer account
+    /// 3. `[]` pc wallet receiving the rebates
+    /// 4. `[signer]` fee sweeping authority, which sets the rebate
+    /// 5. `[]` the rent sysvar
+    InitReferrer(u16),
+    /// Same accounts as `NewOrderV2`.
+    NewOrderV3(NewOrderInstructionV3),
+    /// Cancels every live order whose client id is in the list. Zeros are
+    /// ignored, as are ids without a live order.
+    ///
+    /// 0. `[]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[writable]` the request queue
+    /// 3. `[signer]` the OpenOrders owner
+    CancelOrdersByClientIds([u64; MAX_CANCEL_CLIENT_IDS]),
+    /// Places a trigger order: its funds are locked in the OpenOrders account
+    /// right away, but it only enters the book as a new order once a fill
+    /// during MatchOrders moves the last traded price to the trigger price.
+    /// Until then CancelOrder and friends remove it as usual. Trigger orders
+    /// can't be `PostOnlySlide`.
+    ///
+    /// A triggered order gets a new order id, so that it queues behind the
+    /// orders placed before it was triggered. Its OpenOrders slot takes the
+    /// new id when the `Triggered` event is consumed; until then, cancels by
+    /// the previous id find it under the new one.
+    ///
+    /// The accounts of `NewOrderV2`, followed by:
+    ///
+    /// ... `[writable]` the market's trigger orders
+    NewOrderV4(NewOrderInstructionV4),
+    /// Adds a store for trigger orders to the market. From then on,
+    /// MatchOrders requires it.
+    ///
+    /// 0. `[writable]` the trigger orders account, zeroed out and rent exempt
+    /// 1. `[writable]` market
+    /// 2. `[signer]` fee sweeping authority
+    /// 3. `[]` the rent sysvar
+    InitTriggerOrders,
+    /// Places several orders from one OpenOrders account, with the same order
+    /// type and self trade behavior. Their funds are locked, and whatever
+    /// isn't free in the OpenOrders account deposited, once per side. The
+    /// order type can't be `PostOnlySlide`.
+    ///
+    /// Unless `all_or_nothing` is set, the orders that don't fit in the
+    /// OpenOrders account or the request queue are dropped from the end, and
+    /// the program logs how many were placed. The instruction fails if none
+    /// of them fit.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the coin account paying for the asks
+    /// 4. `[writable]` the pc account paying for the bids
+    /// 5. `[signer]` owner of the OpenOrders account
+    /// 6. `[writable]` coin vault
+    /// 7. `[writable]` pc vault
+    /// 8. `[]` spl token program
+    /// 9. `[]` the rent sysvar
+    /// 10. `[writable]` (optional) the (M)SRM account used for fee discounts
+    /// ... `[]` the system program, if the market charges crank deposits
+    NewOrders(NewOrdersInstruction),
+    /// Sets the delegate of an OpenOrders account, which may sign for its
+    /// owner to place and cancel orders, and settle to the owner's wallets.
+    /// Leaving out the delegate removes it. The account must be at least
+    /// `state::OpenOrdersV2` long.
+    ///
+    /// 0. `[writable]` OpenOrders
+    /// 1. `[signer]` the OpenOrders owner
+    /// 2. `[]` market
+    /// 3. `[]` (optional) the new delegate
+    SetOpenOrdersDelegate,
+    /// Consumes the events of the given OpenOrders accounts among the first
+    /// `limit` in the queue, instead of stopping at the first event of
+    /// another account. The other events stay at the front of the queue, in
+    /// order, so cranks can work through disjoint sets of accounts.
+    ///
+    /// 0. `[writable]` (optional) the account paid the crank reward, which
+    ///    can't be owned by the dex
+    /// ... `[writable]` OpenOrders
+    /// accounts.len() - 4 `[writable]` market
+    /// accounts.len() - 3 `[writable]` event queue
+    /// accounts.len() - 2 `[writable]` coin fee receivable account
+    /// accounts.len() - 1 `[writable]` pc fee receivable account
+    ConsumeEventsPermissive(u16),
+    /// Sets the lamports each new order deposits for the cranks, transferred
+    /// from the owner's system account. Deposits aren't refunded when orders
+    /// are cancelled or settled. The market must be `MarketStateV2`.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[signer]` fee sweeping authority
+    SetCrankReward(u64),
+    /// Settles the given amounts of each currency, leaving the rest of the
+    /// free funds in the OpenOrders account. Referrer rebates are paid out as
+    /// in `SettleFunds`.
+    ///
+    /// Same accounts as `SettleFunds`.
+    SettleFundsPartial(SettleFundsPartialInstruction),
+    /// Sets how `SweepFees` divides the market's fees, and the wallets it
+    /// pays. The registry's staking pool can't take donations yet, so its
+    /// share is held in the vault, or paid to the treasury if
+    /// `route_staking_to_treasury` is set. The market must be
+    /// `MarketStateV2`.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[signer]` fee sweeping authority
+    /// 2. `[]` the treasury's coin wallet
+    /// 3. `[]` the treasury's pc wallet
+    /// 4. `[]` the referrer's coin wallet
+    /// 5. `[]` the referrer's pc wallet
+    SetFeeSplit(SetFeeSplitInstruction),
 }
 
 impl MarketInstruction {
@@ -256,7 +878,7 @@ impl MarketInstruction {
     }
 
     pub fn unpack(versioned_bytes: &[u8]) -> Option<Self> {
-        if versioned_bytes.len() < 5 || versioned_bytes.len() > 58 {
+        if versioned_bytes.len() < 5 || versioned_bytes.len() > MAX_INSTRUCTION_LEN {
             return None;
         }
         let (&[version], &discrim, data) = array_refs![versioned_bytes, 1, 4; ..;];
@@ -267,14 +889,7 @@ impl MarketInstruction {
         Some(match (discrim, data.len()) {
             (0, 34) => MarketInstruction::InitializeMarket({
                 let data_array = array_ref![data, 0, 34];
-                let fields = array_refs![data_array, 8, 8, 2, 8, 8];
-                InitializeMarketInstruction {
-                    coin_lot_size: u64::from_le_bytes(*fields.0),
-                    pc_lot_size: u64::from_le_bytes(*fields.1),
-                    fee_rate_bps: u16::from_le_bytes(*fields.2),
-                    vault_signer_nonce: u64::from_le_bytes(*fields.3),
-                    pc_dust_threshold: u64::from_le_bytes(*fields.4),
-                }
+                InitializeMarketInstruction::unpack(data_array)
             }),
             (1, 32) => MarketInstruction::NewOrder({
                 let data_arr = array_ref![data, 0, 32];
@@ -323,6 +938,116 @@ impl MarketInstruction {
                 .ok()?;
                 v1_instr.add_self_trade_behavior(self_trade_behavior)
             }),
+            (10, 0) => MarketInstruction::MigrateMarket,
+            (11, 24) => MarketInstruction::InitOpenOrders({
+                let data_array = array_ref![data, 0, 24];
+                let (index, nonce, slots) = array_refs![data_array, 8, 8, 8];
+                InitOpenOrdersInstruction {
+                    index: u64::from_le_bytes(*index),
+                    nonce: u64::from_le_bytes(*nonce),
+                    slots: u64::from_le_bytes(*slots),
+                }
+            }),
+            (12, 38) => MarketInstruction::InitializeMarketV2({
+                let data_array = array_ref![data, 0, 38];
+                let (v1_data_array, v2_data_array) = array_refs![data_array, 34, 4];
+                let fee_currency = FeeCurrency::try_from_primitive(
+                    u32::from_le_bytes(*v2_data_array).try_into().ok()?,
+                )
+                .ok()?;
+                InitializeMarketInstruction::unpack(v1_data_array).add_fee_currency(fee_currency)
+            }),
+            (13, 26) => MarketInstruction::Route({
+                let data_array = array_ref![data, 0, 26];
+                RouteInstruction::unpack(data_array)?
+            }),
+            (14, 2) => {
+                let rebate_bps = array_ref![data, 0, 2];
+                MarketInstruction::InitReferrer(u16::from_le_bytes(*rebate_bps))
+            }
+            (15, 37) => MarketInstruction::NewOrderV3({
+                let data_arr = array_ref![data, 0, 37];
+                let (v1_data_arr, v2_data_arr, &[v3_data]) = array_refs![data_arr, 32, 4, 1];
+                let v1_instr = NewOrderInstructionV1::unpack(v1_data_arr)?;
+                let self_trade_behavior = SelfTradeBehavior::try_from_primitive(
+                    u32::from_le_bytes(*v2_data_arr).try_into().ok()?,
+                )
+                .ok()?;
+                let reject_duplicate_client_id = match v3_data {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                };
+                v1_instr
+                    .add_self_trade_behavior(self_trade_behavior)
+                    .add_reject_duplicate_client_id(reject_duplicate_client_id)
+            }),
+            (16, 64) => {
+                let data_array = array_ref![data, 0, 64];
+                let mut client_ids = [0; MAX_CANCEL_CLIENT_IDS];
+                for (client_id, bytes) in client_ids.iter_mut().zip(data_array.chunks(8)) {
+                    *client_id = u64::from_le_bytes(bytes.try_into().unwrap());
+                }
+                MarketInstruction::CancelOrdersByClientIds(client_ids)
+            }
+            (17, 49) => MarketInstruction::NewOrderV4({
+                let data_arr = array_ref![data, 0, 49];
+                let (v1_data_arr, v2_data_arr, &[v3_data], price_arr, direction_arr) =
+                    array_refs![data_arr, 32, 4, 1, 8, 4];
+                let v1_instr = NewOrderInstructionV1::unpack(v1_data_arr)?;
+                let self_trade_behavior = SelfTradeBehavior::try_from_primitive(
+                    u32::from_le_bytes(*v2_data_arr).try_into().ok()?,
+                )
+                .ok()?;
+                let reject_duplicate_client_id = match v3_data {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                };
+                let trigger_price = NonZeroU64::new(u64::from_le_bytes(*price_arr))?;
+                let trigger_direction = TriggerDirection::try_from_primitive(
+                    u32::from_le_bytes(*direction_arr).try_into().ok()?,
+                )
+                .ok()?;
+                v1_instr
+                    .add_self_trade_behavior(self_trade_behavior)
+                    .add_reject_duplicate_client_id(reject_duplicate_client_id)
+                    .add_trigger(trigger_price, trigger_direction)
+            }),
+            (18, 0) => MarketInstruction::InitTriggerOrders,
+            (19, _) => MarketInstruction::NewOrders(NewOrdersInstruction::unpack(data)?),
+            (20, 0) => MarketInstruction::SetOpenOrdersDelegate,
+            (21, 2) => {
+                let limit = array_ref![data, 0, 2];
+                MarketInstruction::ConsumeEventsPermissive(u16::from_le_bytes(*limit))
+            }
+            (22, 8) => {
+                let lamports_per_order = array_ref![data, 0, 8];
+                MarketInstruction::SetCrankReward(u64::from_le_bytes(*lamports_per_order))
+            }
+            (23, 16) => MarketInstruction::SettleFundsPartial({
+                let data_array = array_ref![data, 0, 16];
+                let (native_coin_amount, native_pc_amount) = array_refs![data_array, 8, 8];
+                SettleFundsPartialInstruction {
+                    native_coin_amount: u64::from_le_bytes(*native_coin_amount),
+                    native_pc_amount: u64::from_le_bytes(*native_pc_amount),
+                }
+            }),
+            (24, 7) => MarketInstruction::SetFeeSplit({
+                let data_array = array_ref![data, 0, 7];
+                let (staking_bps, treasury_bps, referrer_bps, &[route_staking_to_treasury]) =
+                    array_refs![data_array, 2, 2, 2, 1];
+                SetFeeSplitInstruction {
+                    staking_bps: u16::from_le_bytes(*staking_bps),
+                    treasury_bps: u16::from_le_bytes(*treasury_bps),
+                    referrer_bps: u16::from_le_bytes(*referrer_bps),
+                    route_staking_to_treasury: match route_staking_to_treasury {
+                        0 => false,
+                        1 => true,
+                        _ => return None,
+                    },
+                }
+            }),
             _ => return None,
         })
     }
@@ -398,6 +1123,289 @@ pub fn initialize_market(
     })
 }
 
+pub fn migrate_market(
+    market: &Pubkey,
+    new_market: &Pubkey,
+    program_id: &Pubkey,
+) -> Result<solana_sdk::instruction::Instruction, DexError> {
+    let data = MarketInstruction::MigrateMarket.pack();
+    let accounts = vec![
+        AccountMeta::new(*market, false),
+        AccountMeta::new(*new_market, false),
+        AccountMeta::new_readonly(fee_sweeper::ID, true),
+        AccountMeta::new_readonly(solana_sdk::sysvar::rent::ID, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        data,
+        accounts,
+    })
+}
+
+pub fn init_open_orders(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    owner: &Pubkey,
+    payer: &Pubkey,
+    index: u64,
+    slots: u64,
+) -> Result<(Pubkey, solana_sdk::instruction::Instruction), DexError> {
+    let (nonce, open_orders) = find_open_orders_key(index, market, owner, program_id)
+        .ok_or(DexErrorCode::BadOpenOrdersNonce)?;
+    let data = MarketInstruction::InitOpenOrders(InitOpenOrdersInstruction {
+        index,
+        nonce,
+        slots,
+    })
+    .pack();
+    let accounts = vec![
+        AccountMeta::new(open_orders, false),
+        AccountMeta::new_readonly(*owner, true),
+        AccountMeta::new_readonly(*market, false),
+        AccountMeta::new(*payer, true),
+        AccountMeta::new_readonly(solana_sdk::system_program::ID, false),
+        AccountMeta::new_readonly(solana_sdk::sysvar::rent::ID, false),
+    ];
+    Ok((
+        open_orders,
+        Instruction {
+            program_id: *program_id,
+            data,
+            accounts,
+        },
+    ))
+}
+
+/// Same as `init_open_orders`, but the OpenOrders account pays its referrer
+/// rebates to `referrer` from the start.
+pub fn init_open_orders_with_referrer(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    owner: &Pubkey,
+    payer: &Pubkey,
+    index: u64,
+    slots: u64,
+    referrer: &Pubkey,
+) -> Result<(Pubkey, solana_sdk::instruction::Instruction), DexError> {
+    let (open_orders, mut instruction) =
+        init_open_orders(program_id, market, owner, payer, index, slots)?;
+    instruction
+        .accounts
+        .push(AccountMeta::new_readonly(*referrer, false));
+    Ok((open_orders, instruction))
+}
+
+pub fn init_referrer(
+    program_id: &Pubkey,
+    referrer: &Pubkey,
+    market: &Pubkey,
+    owner: &Pubkey,
+    pc_wallet: &Pubkey,
+    rebate_bps: u16,
+) -> Result<Instruction, DexError> {
+    let data = MarketInstruction::InitReferrer(rebate_bps).pack();
+    let accounts = vec![
+        AccountMeta::new(*referrer, false),
+        AccountMeta::new_readonly(*market, false),
+        AccountMeta::new_readonly(*owner, true),
+        AccountMeta::new_readonly(*pc_wallet, false),
+        AccountMeta::new_readonly(fee_sweeper::ID, true),
+        AccountMeta::new_readonly(solana_sdk::sysvar::rent::ID, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        data,
+        accounts,
+    })
+}
+
+pub fn init_trigger_orders(
+    program_id: &Pubkey,
+    trigger_orders: &Pubkey,
+    market: &Pubkey,
+) -> Result<Instruction, DexError> {
+    let data = MarketInstruction::InitTriggerOrders.pack();
+    let accounts = vec![
+        AccountMeta::new(*trigger_orders, false),
+        AccountMeta::new(*market, false),
+        AccountMeta::new_readonly(fee_sweeper::ID, true),
+        AccountMeta::new_readonly(solana_sdk::sysvar::rent::ID, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        data,
+        accounts,
+    })
+}
+
+pub fn set_open_orders_delegate(
+    program_id: &Pubkey,
+    open_orders: &Pubkey,
+    owner: &Pubkey,
+    market: &Pubkey,
+    delegate: Option<&Pubkey>,
+) -> Result<Instruction, DexError> {
+    let data = MarketInstruction::SetOpenOrdersDelegate.pack();
+    let mut accounts = vec![
+        AccountMeta::new(*open_orders, false),
+        AccountMeta::new_readonly(*owner, true),
+        AccountMeta::new_readonly(*market, false),
+    ];
+    if let Some(delegate) = delegate {
+        accounts.push(AccountMeta::new_readonly(*delegate, false));
+    }
+    Ok(Instruction {
+        program_id: *program_id,
+        data,
+        accounts,
+    })
+}
+
+pub fn set_crank_reward(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    lamports_per_order: u64,
+) -> Result<Instruction, DexError> {
+    let data = MarketInstruction::SetCrankReward(lamports_per_order).pack();
+    let accounts = vec![
+        AccountMeta::new(*market, false),
+        AccountMeta::new_readonly(fee_sweeper::ID, true),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        data,
+        accounts,
+    })
+}
+
+pub fn set_fee_split(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    treasury_coin_wallet: &Pubkey,
+    treasury_pc_wallet: &Pubkey,
+    referrer_coin_wallet: &Pubkey,
+    referrer_pc_wallet: &Pubkey,
+    split: SetFeeSplitInstruction,
+) -> Result<Instruction, DexError> {
+    let data = MarketInstruction::SetFeeSplit(split).pack();
+    let accounts = vec![
+        AccountMeta::new(*market, false),
+        AccountMeta::new_readonly(fee_sweeper::ID, true),
+        AccountMeta::new_readonly(*treasury_coin_wallet, false),
+        AccountMeta::new_readonly(*treasury_pc_wallet, false),
+        AccountMeta::new_readonly(*referrer_coin_wallet, false),
+        AccountMeta::new_readonly(*referrer_pc_wallet, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        data,
+        accounts,
+    })
+}
+
+pub fn initialize_market_with_fee_currency(
+    market: &Pubkey,
+    program_id: &Pubkey,
+    coin_mint_pk: &Pubkey,
+    pc_mint_pk: &Pubkey,
+    coin_vault_pk: &Pubkey,
+    pc_vault_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    req_q_pk: &Pubkey,
+    event_q_pk: &Pubkey,
+    coin_lot_size: u64,
+    pc_lot_size: u64,
+    vault_signer_nonce: u64,
+    pc_dust_threshold: u64,
+    fee_currency: FeeCurrency,
+) -> Result<solana_sdk::instruction::Instruction, DexError> {
+    let mut instruction = initialize_market(
+        market,
+        program_id,
+        coin_mint_pk,
+        pc_mint_pk,
+        coin_vault_pk,
+        pc_vault_pk,
+        bids_pk,
+        asks_pk,
+        req_q_pk,
+        event_q_pk,
+        coin_lot_size,
+        pc_lot_size,
+        vault_signer_nonce,
+        pc_dust_threshold,
+    )?;
+    instruction.data = MarketInstruction::InitializeMarketV2(InitializeMarketInstructionV2 {
+        coin_lot_size,
+        pc_lot_size,
+        fee_rate_bps: 0,
+        vault_signer_nonce,
+        pc_dust_threshold,
+        fee_currency,
+    })
+    .pack();
+    Ok(instruction)
+}
+
+/// The accounts of one market of a `Route` instruction.
+pub struct RouteMarketKeys<'a> {
+    pub market: &'a Pubkey,
+    pub req_q: &'a Pubkey,
+    pub event_q: &'a Pubkey,
+    pub bids: &'a Pubkey,
+    pub asks: &'a Pubkey,
+    pub coin_vault: &'a Pubkey,
+    pub pc_vault: &'a Pubkey,
+    pub vault_signer: &'a Pubkey,
+}
+
+impl<'a> RouteMarketKeys<'a> {
+    fn account_metas(&self) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(*self.market, false),
+            AccountMeta::new(*self.req_q, false),
+            AccountMeta::new(*self.event_q, false),
+            AccountMeta::new(*self.bids, false),
+            AccountMeta::new(*self.asks, false),
+            AccountMeta::new(*self.coin_vault, false),
+            AccountMeta::new(*self.pc_vault, false),
+            AccountMeta::new_readonly(*self.vault_signer, false),
+        ]
+    }
+}
+
+pub fn route(
+    program_id: &Pubkey,
+    first_market: &RouteMarketKeys,
+    second_market: &RouteMarketKeys,
+    owner: &Pubkey,
+    source_wallet: &Pubkey,
+    intermediate_wallet: &Pubkey,
+    destination_wallet: &Pubkey,
+    fee_discount_account: Option<&Pubkey>,
+    instruction: RouteInstruction,
+) -> Result<Instruction, DexError> {
+    let data = MarketInstruction::Route(instruction).pack();
+    let mut accounts = first_market.account_metas();
+    accounts.extend(second_market.account_metas());
+    accounts.extend(vec![
+        AccountMeta::new_readonly(*owner, true),
+        AccountMeta::new(*source_wallet, false),
+        AccountMeta::new(*intermediate_wallet, false),
+        AccountMeta::new(*destination_wallet, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ]);
+    if let Some(key) = fee_discount_account {
+        accounts.push(AccountMeta::new(*key, false));
+    }
+    Ok(Instruction {
+        program_id: *program_id,
+        data,
+        accounts,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -510,6 +1518,41 @@ mod fuzzing {
         }
     }
 
+    impl arbitrary::Arbitrary for NewOrdersEntry {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            let side = <Side as arbitrary::Arbitrary>::arbitrary(u)?;
+            let limit_price = NonZeroU64::new(<u64 as arbitrary::Arbitrary>::arbitrary(u)?)
+                .ok_or(arbitrary::Error::IncorrectFormat)?;
+            let max_qty = NonZeroU64::new(<u64 as arbitrary::Arbitrary>::arbitrary(u)?)
+                .ok_or(arbitrary::Error::IncorrectFormat)?;
+            let client_id = <u64 as arbitrary::Arbitrary>::arbitrary(u)?;
+            Ok(NewOrdersEntry {
+                side,
+                limit_price,
+                max_qty,
+                client_id,
+            })
+        }
+    }
+
+    impl arbitrary::Arbitrary for NewOrderInstructionV4 {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            let v3_instr = <NewOrderInstructionV3 as arbitrary::Arbitrary>::arbitrary(u)?;
+            let trigger_price = NonZeroU64::new(<u64 as arbitrary::Arbitrary>::arbitrary(u)?)
+                .ok_or(arbitrary::Error::IncorrectFormat)?;
+            let trigger_direction = <TriggerDirection as arbitrary::Arbitrary>::arbitrary(u)?;
+            Ok(v3_instr.add_trigger(trigger_price, trigger_direction))
+        }
+    }
+
+    impl arbitrary::Arbitrary for NewOrderInstructionV3 {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            let v2_instr = <NewOrderInstructionV2 as arbitrary::Arbitrary>::arbitrary(u)?;
+            let reject_duplicate_client_id = <bool as arbitrary::Arbitrary>::arbitrary(u)?;
+            Ok(v2_instr.add_reject_duplicate_client_id(reject_duplicate_client_id))
+        }
+    }
+
     impl arbitrary::Arbitrary for NewOrderInstructionV2 {
         fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
             <NewOrderInstructionU64 as arbitrary::Arbitrary>::arbitrary(u)?
@@ -6,8 +6,13 @@ pub mod error;
 #[cfg(test)]
 mod tests;
 
+pub mod audit;
+#[cfg(any(test, feature = "client"))]
+pub mod client;
 pub mod critbit;
-mod fees;
+#[cfg(any(test, feature = "engine"))]
+pub mod engine;
+pub mod fees;
 pub mod instruction;
 pub mod matching;
 pub mod state;
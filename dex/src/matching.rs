@@ -1,6 +1,6 @@
 use std::num::NonZeroU64;
 
-use crate::instruction::SelfTradeBehavior;
+use crate::instruction::{FeeCurrency, SelfTradeBehavior};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 #[cfg(test)]
 use proptest_derive::Arbitrary;
@@ -14,7 +14,10 @@ use crate::{
     critbit::{LeafNode, NodeHandle, Slab, SlabView},
     error::DexError,
     fees::{self, FeeTier},
-    state::{Event, EventQueue, EventView, MarketState, Request, RequestQueue, RequestView},
+    state::{
+        Event, EventQueue, EventView, MarketState, Request, RequestQueue, RequestView,
+        TriggerOrders,
+    },
 };
 
 #[cfg(not(feature = "program"))]
@@ -44,12 +47,21 @@ pub enum OrderType {
     Limit = 0,
     ImmediateOrCancel = 1,
     PostOnly = 2,
+    /// A post-only order that would cross the best opposite order when it's
+    /// placed is repriced one tick behind that order instead, so its order id
+    /// carries the repriced limit from the start. If it still crosses once
+    /// it's matched, it's dropped like a post-only order.
+    PostOnlySlide = 3,
 }
 
 fn extract_price_from_order_id(order_id: &u128) -> u64 {
     (order_id >> 64) as u64
 }
 
+/// How many requests at the front of the request queue MatchOrders looks
+/// through for cancels to process ahead of the new orders.
+const QUEUED_CANCELS_WINDOW: usize = 32;
+
 pub struct OrderBookState<'a> {
     // first byte of a key is 0xaa or 0xbb, disambiguating bids and asks
     pub bids: &'a mut Slab,
@@ -72,35 +84,153 @@ impl<'ob> OrderBookState<'ob> {
         }
     }
 
+    /// The price a post-only slide order on `side` is placed at instead of
+    /// `limit_price`, if it would cross the best opposite order: one tick
+    /// behind that order. `None` if the order doesn't cross, or if there's no
+    /// price one tick behind.
+    pub(crate) fn slide_price(&self, side: Side, limit_price: u64) -> Option<NonZeroU64> {
+        let opposite_side = match side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+        let best_h = self.find_bbo(opposite_side)?;
+        let best_price = match opposite_side {
+            Side::Bid => self.bids.get(best_h),
+            Side::Ask => self.asks.get(best_h),
+        }?
+        .as_leaf()?
+        .price()
+        .get();
+        match side {
+            Side::Bid if best_price <= limit_price => NonZeroU64::new(best_price - 1),
+            Side::Ask if best_price >= limit_price => NonZeroU64::new(best_price.checked_add(1)?),
+            _ => None,
+        }
+    }
+
     pub fn process_requests(
         &mut self,
         req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
         limit: u16,
     ) -> Result<(), DexError> {
+        self.process_requests_with_triggers(req_q, event_q, None, limit)?;
+        Ok(())
+    }
+
+    /// Like `process_requests`, but the fills also move the last traded price
+    /// of the market's trigger orders. The orders triggered by a move go to the
+    /// back of the request queue, and are matched within the same limit.
+    /// Cancels of orders that haven't been triggered yet remove them from the
+    /// trigger orders. Returns how many requests were done with.
+    ///
+    /// Cancels near the front of the queue are processed ahead of the new
+    /// orders queued before them, so that takers can't fill orders whose
+    /// cancel is already queued.
+    pub fn process_requests_with_triggers(
+        &mut self,
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+        mut trigger_orders: Option<&mut TriggerOrders>,
+        limit: u16,
+    ) -> Result<u64, DexError> {
         let mut limit_remaining = limit;
+        let mut requests_processed = self.process_queued_cancels(
+            req_q,
+            event_q,
+            trigger_orders.as_deref_mut(),
+            &mut limit_remaining,
+        )?;
         while limit_remaining > 0 {
             let request = match req_q.peek_front_mut() {
                 Some(r) => r,
                 None => break,
             };
-            match self.process_orderbook_request(request, event_q, &mut limit_remaining)? {
+            let events_before = event_q.len();
+            match self.process_orderbook_request(
+                request,
+                event_q,
+                trigger_orders.as_deref_mut(),
+                &mut limit_remaining,
+            )? {
                 Some(remaining_request) => {
                     *request = remaining_request;
                 }
                 None => {
                     req_q.pop_front().unwrap();
+                    requests_processed += 1;
                 }
             };
+            if let Some(trigger_orders) = trigger_orders.as_deref_mut() {
+                if let Some(last_price) = last_fill_price(event_q, events_before)? {
+                    trigger_orders.set_last_price(last_price);
+                    self.activate_trigger_orders(trigger_orders, req_q, event_q)?;
+                }
+            }
         }
 
-        Ok(())
+        Ok(requests_processed)
+    }
+
+    /// Processes the cancels among the first `QUEUED_CANCELS_WINDOW` requests
+    /// and removes them, except those of orders still waiting in the queue,
+    /// which stay in place. Every cancel looked at counts against `limit`.
+    fn process_queued_cancels(
+        &mut self,
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+        mut trigger_orders: Option<&mut TriggerOrders>,
+        limit: &mut u16,
+    ) -> DexResult<u64> {
+        let window = req_q
+            .len()
+            .min(QUEUED_CANCELS_WINDOW as u64)
+            .min(*limit as u64);
+        // bit i is set once the i-th request of the window is processed
+        let mut processed_bits = 0u64;
+        for (index, request) in req_q.iter().take(window as usize).enumerate() {
+            if *limit == 0 {
+                break;
+            }
+            if let RequestView::CancelOrder {
+                order_id,
+                expected_owner,
+                expected_owner_slot,
+                client_order_id,
+                ..
+            } = request.as_view()?
+            {
+                if new_order_is_queued(
+                    req_q,
+                    index,
+                    order_id,
+                    expected_owner,
+                    expected_owner_slot,
+                    client_order_id,
+                )? {
+                    *limit -= 1;
+                    continue;
+                }
+                self.process_orderbook_request(
+                    request,
+                    event_q,
+                    trigger_orders.as_deref_mut(),
+                    limit,
+                )?;
+                processed_bits |= 1 << index;
+            }
+        }
+        if processed_bits == 0 {
+            return Ok(0);
+        }
+        req_q.remove_front_where(window, |index, _| processed_bits & (1 << index) != 0)
     }
 
     fn process_orderbook_request(
         &mut self,
         request: &Request,
         event_q: &mut EventQueue,
+        trigger_orders: Option<&mut TriggerOrders>,
         limit: &mut u16,
     ) -> DexResult<Option<Request>> {
         Ok(match request.as_view()? {
@@ -155,17 +285,258 @@ impl<'ob> OrderBookState<'ob> {
                 cancel_id: _,
             } => {
                 *limit -= 1;
-                self.cancel_order(
+                let has_trigger_orders = trigger_orders.is_some();
+                let trigger_order = trigger_orders.and_then(|trigger_orders| {
+                    let index = trigger_orders.position(order_id)?;
+                    Some((trigger_orders, index))
+                });
+                match trigger_order {
+                    Some((trigger_orders, index)) => self.cancel_trigger_order(
+                        trigger_orders,
+                        index,
+                        expected_owner,
+                        expected_owner_slot,
+                        event_q,
+                    )?,
+                    None => {
+                        let order_id = if has_trigger_orders {
+                            self.triggered_order_id(
+                                side,
+                                order_id,
+                                expected_owner,
+                                expected_owner_slot,
+                                client_order_id,
+                            )
+                        } else {
+                            *order_id
+                        };
+                        self.cancel_order(
+                            side,
+                            &order_id,
+                            expected_owner,
+                            expected_owner_slot,
+                            client_order_id,
+                            event_q,
+                        )?
+                    }
+                };
+                None
+            }
+        })
+    }
+}
+
+/// The price of the last fill among the events pushed after the first
+/// `events_before`.
+fn last_fill_price(event_q: &EventQueue, events_before: u64) -> DexResult<Option<u64>> {
+    let mut last_price = None;
+    for event in event_q.iter().skip(events_before as usize) {
+        if let EventView::Fill {
+            maker: true,
+            order_id,
+            ..
+        } = event.as_view()?
+        {
+            last_price = Some(extract_price_from_order_id(order_id));
+        }
+    }
+    Ok(last_price)
+}
+
+/// Whether the order is waiting in the request queue among its first `len`
+/// requests. A cancel also matches an order in the same slot with that client
+/// id, or at the same price for a cancel by order id, which is how a
+/// triggered order with a new id is found.
+fn new_order_is_queued(
+    req_q: &RequestQueue,
+    len: usize,
+    order_id: &u128,
+    owner: &[u64; 4],
+    owner_slot: u8,
+    client_order_id: Option<NonZeroU64>,
+) -> DexResult<bool> {
+    for request in req_q.iter().take(len) {
+        if let RequestView::NewOrder {
+            order_id: queued_order_id,
+            owner: queued_owner,
+            owner_slot: queued_owner_slot,
+            client_order_id: queued_client_order_id,
+            ..
+        } = request.as_view()?
+        {
+            let same_order = queued_order_id == order_id
+                || match client_order_id {
+                    Some(_) => queued_client_order_id == client_order_id,
+                    None => {
+                        extract_price_from_order_id(queued_order_id)
+                            == extract_price_from_order_id(order_id)
+                    }
+                };
+            if same_order && queued_owner == owner && queued_owner_slot == owner_slot {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Whether a cancel of the order is waiting in the request queue.
+fn cancel_is_queued(
+    req_q: &RequestQueue,
+    order_id: &u128,
+    owner: &[u64; 4],
+    owner_slot: u8,
+) -> DexResult<bool> {
+    for request in req_q.iter() {
+        if let RequestView::CancelOrder {
+            order_id: cancelled_order_id,
+            expected_owner,
+            expected_owner_slot,
+            ..
+        } = request.as_view()?
+        {
+            if cancelled_order_id == order_id
+                && expected_owner == owner
+                && expected_owner_slot == owner_slot
+            {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+impl<'ob> OrderBookState<'ob> {
+    fn activate_trigger_orders(
+        &mut self,
+        trigger_orders: &mut TriggerOrders,
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+    ) -> DexResult {
+        let last_price = trigger_orders.last_price();
+        let mut index = 0;
+        while let Some(&trigger_order) = trigger_orders.get(index) {
+            if !trigger_order.is_triggered(last_price)? {
+                index += 1;
+                continue;
+            }
+            let (side, order_id, owner, owner_slot, client_order_id) =
+                match trigger_order.request().as_view()? {
+                    RequestView::NewOrder {
+                        side,
+                        order_id,
+                        owner,
+                        owner_slot,
+                        client_order_id,
+                        ..
+                    } => (side, order_id, owner, owner_slot, client_order_id),
+                    RequestView::CancelOrder { .. } => check_unreachable!()?,
+                };
+            // The cancel would be processed before the order, and miss it.
+            if cancel_is_queued(req_q, order_id, owner, owner_slot)? {
+                self.cancel_trigger_order(trigger_orders, index, owner, owner_slot, event_q)?;
+                continue;
+            }
+            // Orders that don't fit stay until the next fill.
+            if req_q.full() || event_q.full() {
+                break;
+            }
+            // The order takes its place in the book's time priority now, so
+            // it gets a new sequence number. Its `OpenOrders` slot follows
+            // when the `Triggered` event is consumed.
+            let new_order_id = req_q.gen_order_id(extract_price_from_order_id(order_id), side);
+            event_q
+                .push_back(Event::new(EventView::Triggered {
+                    side,
+                    order_id: &new_order_id,
+                    previous_order_id: *order_id,
+                    owner,
+                    owner_slot,
+                    client_order_id,
+                }))
+                .map_err(|_| DexErrorCode::EventQueueFull)?;
+            req_q
+                .push_back(trigger_order.request().with_order_id(new_order_id))
+                .map_err(|_| DexErrorCode::RequestQueueFull)?;
+            trigger_orders.swap_remove(index)?;
+        }
+        Ok(())
+    }
+
+    /// The id a cancel should use: `order_id` itself, or the new id of the
+    /// same order if it was a trigger order that has been triggered since its
+    /// `OpenOrders` slot was read. A slot holds one order at a time, so a
+    /// cancel by order id finds it by its owner, slot and price alone.
+    fn triggered_order_id(
+        &self,
+        side: Side,
+        order_id: &u128,
+        owner: &[u64; 4],
+        owner_slot: u8,
+        client_order_id: Option<NonZeroU64>,
+    ) -> u128 {
+        let orders: &Slab = match side {
+            Side::Bid => &*self.bids,
+            Side::Ask => &*self.asks,
+        };
+        if orders.find_by_key(order_id).is_some() {
+            return *order_id;
+        }
+        let price = extract_price_from_order_id(order_id);
+        orders
+            .price_range(price, price)
+            .find(|order| {
+                order.owner() == owner
+                    && order.owner_slot() == owner_slot
+                    && client_order_id.map_or(true, |id| order.client_order_id() == id.get())
+            })
+            .map_or(*order_id, |order| *order.order_id())
+    }
+
+    fn cancel_trigger_order(
+        &mut self,
+        trigger_orders: &mut TriggerOrders,
+        index: usize,
+        expected_owner: &[u64; 4],
+        expected_owner_slot: u8,
+        event_q: &mut EventQueue,
+    ) -> DexResult {
+        let trigger_order = *trigger_orders.get(index).ok_or(assertion_error!())?;
+        let out = match trigger_order.request().as_view()? {
+            RequestView::NewOrder {
+                side,
+                order_id,
+                owner,
+                owner_slot,
+                max_coin_qty,
+                native_pc_qty_locked,
+                client_order_id,
+                ..
+            } => {
+                if owner != expected_owner || owner_slot != expected_owner_slot {
+                    return Ok(());
+                }
+                let native_qty_unlocked = match side {
+                    Side::Bid => native_pc_qty_locked.map_or(0, NonZeroU64::get),
+                    Side::Ask => max_coin_qty.get() * self.market_state.coin_lot_size,
+                };
+                Event::new(EventView::Out {
                     side,
+                    native_qty_unlocked,
+                    native_qty_still_locked: 0,
                     order_id,
-                    expected_owner,
-                    expected_owner_slot,
+                    owner,
+                    owner_slot,
                     client_order_id,
-                    event_q,
-                )?;
-                None
+                })
             }
-        })
+            RequestView::CancelOrder { .. } => check_unreachable!()?,
+        };
+        event_q
+            .push_back(out)
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        trigger_orders.swap_remove(index)?;
+        Ok(())
     }
 }
 
@@ -211,7 +582,7 @@ impl<'ob> OrderBookState<'ob> {
         let (post_only, post_allowed) = match order_type {
             OrderType::Limit => (false, true),
             OrderType::ImmediateOrCancel => (false, false),
-            OrderType::PostOnly => (true, true),
+            OrderType::PostOnly | OrderType::PostOnlySlide => (true, true),
         };
         let limit_price = extract_price_from_order_id(order_id);
         while *limit > 0 {
@@ -571,7 +942,12 @@ impl<'ob> OrderBookState<'ob> {
         let pc_lot_size = self.market_state.pc_lot_size;
         let coin_lot_size = self.market_state.coin_lot_size;
 
-        let max_pc_qty = fee_tier.remove_taker_fee(native_pc_qty_locked.get()) / pc_lot_size;
+        let fee_currency = self.market_state.fee_currency();
+
+        let max_pc_qty = match fee_currency {
+            FeeCurrency::Pc => fee_tier.remove_taker_fee(native_pc_qty_locked.get()) / pc_lot_size,
+            FeeCurrency::Coin => native_pc_qty_locked.get() / pc_lot_size,
+        };
 
         let mut coin_qty_remaining = max_coin_qty.get();
         let mut pc_qty_remaining = max_pc_qty;
@@ -696,14 +1072,29 @@ impl<'ob> OrderBookState<'ob> {
             }
             let maker_fee_tier = best_offer_ref.fee_tier();
             let native_maker_pc_qty = trade_qty * trade_price.get() * pc_lot_size;
-            let native_maker_rebate = maker_fee_tier.maker_rebate(native_maker_pc_qty);
+            let native_maker_coin_qty = trade_qty * coin_lot_size;
+            let native_maker_rebate;
+            let native_maker_qty_paid;
+            let native_maker_qty_received;
+            match fee_currency {
+                FeeCurrency::Pc => {
+                    native_maker_rebate = maker_fee_tier.maker_rebate(native_maker_pc_qty);
+                    native_maker_qty_paid = native_maker_coin_qty;
+                    native_maker_qty_received = native_maker_pc_qty + native_maker_rebate;
+                }
+                FeeCurrency::Coin => {
+                    native_maker_rebate = maker_fee_tier.maker_rebate(native_maker_coin_qty);
+                    native_maker_qty_paid = native_maker_coin_qty - native_maker_rebate;
+                    native_maker_qty_received = native_maker_pc_qty;
+                }
+            };
             accum_maker_rebates += native_maker_rebate;
 
             let maker_fill = Event::new(EventView::Fill {
                 side: Side::Ask,
                 maker: true,
-                native_qty_paid: trade_qty * coin_lot_size,
-                native_qty_received: native_maker_pc_qty + native_maker_rebate,
+                native_qty_paid: native_maker_qty_paid,
+                native_qty_received: native_maker_qty_received,
                 native_fee_or_rebate: native_maker_rebate,
                 order_id: best_offer_ref.order_id(),
                 owner: best_offer_ref.owner(),
@@ -741,16 +1132,30 @@ impl<'ob> OrderBookState<'ob> {
         };
 
         let native_accum_fill_price = (max_pc_qty - pc_qty_remaining) * pc_lot_size;
-        let native_taker_fee = fee_tier.taker_fee(native_accum_fill_price);
-        let native_pc_qty_remaining =
-            native_pc_qty_locked.get() - native_accum_fill_price - native_taker_fee;
+        let native_coin_qty_filled = (max_coin_qty.get() - coin_qty_remaining) * coin_lot_size;
+        let native_taker_fee;
+        let native_taker_qty_paid;
+        let native_taker_qty_received;
+        match fee_currency {
+            FeeCurrency::Pc => {
+                native_taker_fee = fee_tier.taker_fee(native_accum_fill_price);
+                native_taker_qty_paid = native_accum_fill_price + native_taker_fee;
+                native_taker_qty_received = native_coin_qty_filled;
+            }
+            FeeCurrency::Coin => {
+                native_taker_fee = fee_tier.taker_fee(native_coin_qty_filled);
+                native_taker_qty_paid = native_accum_fill_price;
+                native_taker_qty_received = native_coin_qty_filled - native_taker_fee;
+            }
+        };
+        let native_pc_qty_remaining = native_pc_qty_locked.get() - native_taker_qty_paid;
 
         if native_accum_fill_price > 0 {
             let taker_fill = Event::new(EventView::Fill {
                 side: Side::Bid,
                 maker: false,
-                native_qty_paid: native_accum_fill_price + native_taker_fee,
-                native_qty_received: (max_coin_qty.get() - coin_qty_remaining) * coin_lot_size,
+                native_qty_paid: native_taker_qty_paid,
+                native_qty_received: native_taker_qty_received,
                 native_fee_or_rebate: native_taker_fee,
                 order_id,
                 owner,
@@ -764,12 +1169,20 @@ impl<'ob> OrderBookState<'ob> {
         }
 
         let net_fees_before_referrer_rebate = native_taker_fee - accum_maker_rebates;
-        let referrer_rebate = fees::referrer_rebate(native_taker_fee);
-        let net_fees = net_fees_before_referrer_rebate - referrer_rebate;
-
-        self.market_state.referrer_rebates_accrued += referrer_rebate;
-        self.market_state.pc_fees_accrued += net_fees;
-        self.market_state.pc_deposits_total -= net_fees_before_referrer_rebate;
+        match fee_currency {
+            FeeCurrency::Pc => {
+                let referrer_rebate = fees::referrer_rebate(native_taker_fee);
+                let net_fees = net_fees_before_referrer_rebate - referrer_rebate;
+
+                self.market_state.referrer_rebates_accrued += referrer_rebate;
+                self.market_state.pc_fees_accrued += net_fees;
+                self.market_state.pc_deposits_total -= net_fees_before_referrer_rebate;
+            }
+            FeeCurrency::Coin => {
+                self.market_state.coin_fees_accrued += net_fees_before_referrer_rebate;
+                self.market_state.coin_deposits_total -= net_fees_before_referrer_rebate;
+            }
+        };
 
         if !done {
             if let Some(coin_qty_remaining) = NonZeroU64::new(coin_qty_remaining) {
@@ -886,3 +1299,151 @@ impl<'ob> OrderBookState<'ob> {
         Ok(())
     }
 }
+
+/// What a taker matched by `OrderBookState::send_take` paid and received.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TakeProceeds {
+    /// In pc for bids, including the taker fee, and in coin for asks.
+    pub native_qty_paid: u64,
+    /// In coin for bids, and in pc net of the taker fee for asks.
+    pub native_qty_received: u64,
+}
+
+impl<'ob> OrderBookState<'ob> {
+    /// Matches an immediate-or-cancel order against the book right away,
+    /// without an `OpenOrders` account or the request queue.
+    ///
+    /// The makers' fills are queued as events as usual, but no events are
+    /// queued for the taker: the caller moves the returned proceeds between
+    /// the taker's wallets and the vaults itself. The deposit totals are
+    /// adjusted as if that had already happened.
+    ///
+    /// A bid spends at most `max_native_pc_qty`, including the taker fee, and
+    /// an ask sells at most `max_coin_qty` lots. At most `limit` resting
+    /// orders are matched. Only markets that charge fees in pc are supported.
+    pub fn send_take(
+        &mut self,
+        side: Side,
+        max_coin_qty: u64,
+        max_native_pc_qty: u64,
+        fee_tier: FeeTier,
+        event_q: &mut EventQueue,
+        limit: u16,
+    ) -> DexResult<TakeProceeds> {
+        if self.market_state.fee_currency() != FeeCurrency::Pc {
+            Err(DexErrorCode::UnsupportedFeeCurrency)?
+        }
+        let pc_lot_size = self.market_state.pc_lot_size;
+        let coin_lot_size = self.market_state.coin_lot_size;
+        let max_pc_qty = match side {
+            Side::Bid => fee_tier.remove_taker_fee(max_native_pc_qty) / pc_lot_size,
+            Side::Ask => std::u64::MAX,
+        };
+        let maker_side = match side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+
+        let mut coin_qty_remaining = max_coin_qty;
+        let mut accum_fill_price: u64 = 0;
+        let mut accum_maker_rebates = 0;
+        for _ in 0..limit {
+            let best_h = match self.find_bbo(maker_side) {
+                None => break,
+                Some(h) => h,
+            };
+            let best_ref = self
+                .orders_mut(maker_side)
+                .get_mut(best_h)
+                .unwrap()
+                .as_leaf_mut()
+                .unwrap();
+
+            let trade_price = best_ref.price().get();
+            let trade_qty = best_ref
+                .quantity()
+                .min(coin_qty_remaining)
+                .min((max_pc_qty - accum_fill_price) / trade_price);
+            if trade_qty == 0 {
+                break;
+            }
+
+            let maker_fee_tier = best_ref.fee_tier();
+            let native_maker_pc_qty = trade_qty * trade_price * pc_lot_size;
+            let native_maker_rebate = maker_fee_tier.maker_rebate(native_maker_pc_qty);
+            accum_maker_rebates += native_maker_rebate;
+            let (native_qty_paid, native_qty_received) = match maker_side {
+                Side::Bid => (
+                    native_maker_pc_qty - native_maker_rebate,
+                    trade_qty * coin_lot_size,
+                ),
+                Side::Ask => (
+                    trade_qty * coin_lot_size,
+                    native_maker_pc_qty + native_maker_rebate,
+                ),
+            };
+            let maker_fill = Event::new(EventView::Fill {
+                side: maker_side,
+                maker: true,
+                native_qty_paid,
+                native_qty_received,
+                native_fee_or_rebate: native_maker_rebate,
+                order_id: best_ref.order_id(),
+                owner: best_ref.owner(),
+                owner_slot: best_ref.owner_slot(),
+                fee_tier: maker_fee_tier,
+                client_order_id: NonZeroU64::new(best_ref.client_order_id()),
+            });
+            event_q
+                .push_back(maker_fill)
+                .map_err(|_| DexErrorCode::EventQueueFull)?;
+
+            *best_ref.quantity_mut() -= trade_qty;
+            coin_qty_remaining -= trade_qty;
+            accum_fill_price += trade_qty * trade_price;
+
+            if best_ref.quantity() == 0 {
+                let best_id = *best_ref.order_id();
+                event_q
+                    .push_back(Event::new(EventView::Out {
+                        side: maker_side,
+                        native_qty_unlocked: 0,
+                        native_qty_still_locked: 0,
+                        order_id: &best_id,
+                        owner: best_ref.owner(),
+                        owner_slot: best_ref.owner_slot(),
+                        client_order_id: NonZeroU64::new(best_ref.client_order_id()),
+                    }))
+                    .map_err(|_| DexErrorCode::EventQueueFull)?;
+                self.orders_mut(maker_side).remove_by_key(&best_id).unwrap();
+            }
+        }
+
+        let native_pc_qty = accum_fill_price * pc_lot_size;
+        let native_coin_qty = (max_coin_qty - coin_qty_remaining) * coin_lot_size;
+        let native_taker_fee = fee_tier.taker_fee(native_pc_qty);
+
+        // There is no OpenOrders account to credit a referrer rebate to, so
+        // the market keeps the whole fee.
+        let market = &mut *self.market_state;
+        market.pc_fees_accrued += native_taker_fee - accum_maker_rebates;
+        Ok(match side {
+            Side::Bid => {
+                market.coin_deposits_total -= native_coin_qty;
+                market.pc_deposits_total += native_pc_qty + accum_maker_rebates;
+                TakeProceeds {
+                    native_qty_paid: native_pc_qty + native_taker_fee,
+                    native_qty_received: native_coin_qty,
+                }
+            }
+            Side::Ask => {
+                market.coin_deposits_total += native_coin_qty;
+                market.pc_deposits_total -= native_pc_qty - accum_maker_rebates;
+                TakeProceeds {
+                    native_qty_paid: native_coin_qty,
+                    native_qty_received: native_pc_qty - native_taker_fee,
+                }
+            }
+        })
+    }
+}
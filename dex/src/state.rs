@@ -1,12 +1,18 @@
 #![cfg_attr(not(feature = "program"), allow(unused))]
 use num_enum::TryFromPrimitive;
-use std::{cell::RefMut, convert::TryInto, mem::size_of, num::NonZeroU64, ops::DerefMut};
+use std::{
+    cell::RefMut,
+    convert::TryInto,
+    mem::size_of,
+    num::NonZeroU64,
+    ops::{Deref, DerefMut, RangeInclusive},
+};
 
 use arrayref::{array_ref, array_refs, mut_array_refs};
 
 use bytemuck::{
     bytes_of, bytes_of_mut, cast, cast_slice, cast_slice_mut, from_bytes_mut, try_cast_mut,
-    try_cast_slice_mut, try_from_bytes_mut, Pod, Zeroable,
+    try_cast_slice_mut, try_from_bytes, try_from_bytes_mut, Pod, Zeroable,
 };
 use enumflags2::BitFlags;
 use num_traits::FromPrimitive;
@@ -26,6 +32,7 @@ use solana_sdk::{
     program_pack::Pack,
     pubkey::Pubkey,
     rent::Rent,
+    system_program,
     sysvar::{Sysvar, SysvarId},
 };
 use spl_token::error::TokenError;
@@ -35,10 +42,13 @@ use crate::{
     error::{DexErrorCode, DexResult, SourceFileId},
     fees::{self, FeeTier},
     instruction::{
-        disable_authority, fee_sweeper, msrm_token, srm_token, CancelOrderInstruction,
-        InitializeMarketInstruction, MarketInstruction, NewOrderInstructionV2, SelfTradeBehavior,
+        disable_authority, fee_sweeper, msrm_token, srm_token, CancelOrderInstruction, FeeCurrency,
+        InitOpenOrdersInstruction, InitializeMarketInstructionV2, MarketInstruction,
+        NewOrderInstructionV3, NewOrderInstructionV4, NewOrdersInstruction, RouteInstruction,
+        SelfTradeBehavior, SetFeeSplitInstruction, SettleFundsPartialInstruction, TriggerDirection,
+        MAX_CANCEL_CLIENT_IDS,
     },
-    matching::{OrderBookState, OrderType, Side},
+    matching::{OrderBookState, OrderType, Side, TakeProceeds},
 };
 
 declare_check_assert_macros!(SourceFileId::State);
@@ -65,6 +75,13 @@ pub enum AccountFlag {
     Bids = 1u64 << 5,
     Asks = 1u64 << 6,
     Disabled = 1u64 << 7,
+    LayoutV2 = 1u64 << 8,
+    CoinFees = 1u64 << 9,
+    Referrer = 1u64 << 10,
+    Referred = 1u64 << 11,
+    TriggerOrders = 1u64 << 12,
+    HasTriggerOrders = 1u64 << 13,
+    Derived = 1u64 << 14,
 }
 
 #[cfg_attr(target_endian = "little", derive(Debug))]
@@ -128,6 +145,119 @@ unsafe impl Pod for MarketState {}
 #[cfg(target_endian = "little")]
 unsafe impl TriviallyTransmutable for MarketState {}
 
+#[cfg_attr(target_endian = "little", derive(Debug))]
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct MarketStateV2 {
+    // 0
+    pub inner: MarketState,
+
+    // 47
+    pub crank_rewards: CrankRewards,
+
+    // 50
+    pub fee_split: FeeSplit,
+
+    // 69
+    pub padding: [u64; 106],
+}
+#[cfg(target_endian = "little")]
+unsafe impl Zeroable for MarketStateV2 {}
+#[cfg(target_endian = "little")]
+unsafe impl Pod for MarketStateV2 {}
+#[cfg(target_endian = "little")]
+unsafe impl TriviallyTransmutable for MarketStateV2 {}
+
+/// The lamports paid to the cranks of a market. New orders deposit
+/// `lamports_per_order`, split between the pools paying for matching orders
+/// and consuming events. Instructions doing either take the share of the pool
+/// their work is of the work queued at the time.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct CrankRewards {
+    pub lamports_per_order: u64,
+    pub match_pool: u64,
+    pub consume_pool: u64,
+}
+unsafe impl Zeroable for CrankRewards {}
+unsafe impl Pod for CrankRewards {}
+
+impl CrankRewards {
+    fn deposit(&mut self, lamports: u64) {
+        let match_share = lamports / 2;
+        self.match_pool += match_share;
+        self.consume_pool += lamports - match_share;
+    }
+
+    /// Takes the share of `pool` earned by `work` units when `pending` more
+    /// are still queued.
+    fn take_reward(pool: &mut u64, work: u64, pending: u64) -> u64 {
+        if work == 0 {
+            return 0;
+        }
+        let reward = (*pool as u128 * work as u128 / (work as u128 + pending as u128)) as u64;
+        *pool -= reward;
+        reward
+    }
+}
+
+/// How `SweepFees` divides a market's fees. Once the shares are set, sweeping
+/// is permissionless: the treasury and referrer shares are paid to the
+/// wallets recorded here, and the staking share is held in the vault until
+/// the registry accepts donations, or paid to the treasury while
+/// `route_staking_to_treasury` is set.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct FeeSplit {
+    pub staking_bps: u16,
+    pub treasury_bps: u16,
+    pub referrer_bps: u16,
+    pub route_staking_to_treasury: u16,
+
+    pub treasury_coin_wallet: [u64; 4],
+    pub treasury_pc_wallet: [u64; 4],
+    pub referrer_coin_wallet: [u64; 4],
+    pub referrer_pc_wallet: [u64; 4],
+
+    // The staking share of the fees swept so far, still in the vaults.
+    pub staking_coin_fees: u64,
+    pub staking_pc_fees: u64,
+}
+unsafe impl Zeroable for FeeSplit {}
+unsafe impl Pod for FeeSplit {}
+
+impl FeeSplit {
+    #[inline]
+    pub fn is_set(&self) -> bool {
+        self.staking_bps as u32 + self.treasury_bps as u32 + self.referrer_bps as u32 != 0
+    }
+
+    /// Splits `amount` into its staking, treasury and referrer shares. The
+    /// treasury's share takes what rounding down the others leaves over.
+    pub fn split(&self, amount: u64) -> (u64, u64, u64) {
+        let share = |bps: u16| (amount as u128 * bps as u128 / 10_000) as u64;
+        let staking = share(self.staking_bps);
+        let referrer = share(self.referrer_bps);
+        (staking, amount - staking - referrer, referrer)
+    }
+}
+
+impl Deref for MarketStateV2 {
+    type Target = MarketState;
+
+    #[inline]
+    fn deref(&self) -> &MarketState {
+        &self.inner
+    }
+}
+
+impl DerefMut for MarketStateV2 {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut MarketState {
+        &mut self.inner
+    }
+}
+
 pub const ACCOUNT_HEAD_PADDING: &[u8; 5] = b"serum";
 pub const ACCOUNT_TAIL_PADDING: &[u8; 7] = b"padding";
 
@@ -204,42 +334,126 @@ impl MarketState {
         let state: RefMut<'a, Self>;
 
         account_data = RefMut::map(market_account.try_borrow_mut_data()?, |data| *data);
-        check_account_padding(&mut account_data)?;
+        let data_len = size_of::<u64>() * check_account_padding(&mut account_data)?.len();
+        check_assert!(data_len >= size_of::<Self>())?;
         state = RefMut::map(account_data, |data| {
-            from_bytes_mut(cast_slice_mut(
-                check_account_padding(data).unwrap_or_else(|_| unreachable!()),
-            ))
+            let words = check_account_padding(data).unwrap_or_else(|_| unreachable!());
+            from_bytes_mut(&mut cast_slice_mut::<u64, u8>(words)[..size_of::<Self>()])
         });
 
         state.check_flags()?;
+        if state.layout_version() == 2 {
+            check_assert!(data_len >= size_of::<MarketStateV2>())?;
+        }
         Ok(state)
     }
 
+    /// Loads a market along with its crank rewards, which only `LayoutV2`
+    /// markets have.
+    fn load_with_crank_rewards<'a>(
+        market_account: &'a AccountInfo,
+        program_id: &Pubkey,
+    ) -> DexResult<(RefMut<'a, Self>, Option<RefMut<'a, CrankRewards>>)> {
+        let has_crank_rewards = Self::load(market_account, program_id)?.layout_version() == 2;
+        let account_data: RefMut<'a, [u8]> =
+            RefMut::map(market_account.try_borrow_mut_data()?, |data| *data);
+        let (state, rest) = RefMut::map_split(account_data, |data| {
+            let words = check_account_padding(data).unwrap_or_else(|_| unreachable!());
+            let (state, rest) = cast_slice_mut::<u64, u8>(words).split_at_mut(size_of::<Self>());
+            (from_bytes_mut(state), rest)
+        });
+        let crank_rewards = if has_crank_rewards {
+            Some(RefMut::map(rest, |rest| {
+                from_bytes_mut(&mut rest[..size_of::<CrankRewards>()])
+            }))
+        } else {
+            None
+        };
+        Ok((state, crank_rewards))
+    }
+
     #[inline]
     pub fn check_flags(&self) -> DexResult {
         let flags = BitFlags::from_bits(self.account_flags)
             .map_err(|_| DexErrorCode::InvalidMarketFlags)?;
         let required_flags = AccountFlag::Initialized | AccountFlag::Market;
-        if flags != required_flags {
+        let optional_flags =
+            AccountFlag::LayoutV2 | AccountFlag::CoinFees | AccountFlag::HasTriggerOrders;
+        if flags & !optional_flags != required_flags {
             Err(DexErrorCode::InvalidMarketFlags)?
         }
         Ok(())
     }
 
+    #[inline]
+    pub fn has_trigger_orders(&self) -> bool {
+        self.account_flags & (AccountFlag::HasTriggerOrders as u64) != 0
+    }
+
+    #[inline]
+    pub fn fee_currency(&self) -> FeeCurrency {
+        if self.account_flags & (AccountFlag::CoinFees as u64) != 0 {
+            FeeCurrency::Coin
+        } else {
+            FeeCurrency::Pc
+        }
+    }
+
+    /// The native amount an order has to lock: the pc it could spend,
+    /// including the taker fee when fees are paid in pc, for a bid, or the
+    /// coin it could sell for an ask.
+    fn native_qty_to_lock(
+        &self,
+        side: Side,
+        limit_price: NonZeroU64,
+        max_qty: NonZeroU64,
+        fee_tier: FeeTier,
+    ) -> DexResult<u64> {
+        match side {
+            Side::Bid => {
+                let native_lock_qty_before_fee = max_qty
+                    .get()
+                    .checked_mul(limit_price.get())
+                    .and_then(|lots| lots.checked_mul(self.pc_lot_size))
+                    .ok_or(DexErrorCode::InsufficientFunds)?;
+                Ok(match self.fee_currency() {
+                    FeeCurrency::Pc => native_lock_qty_before_fee
+                        .checked_add(fee_tier.taker_fee(native_lock_qty_before_fee))
+                        .ok_or(DexErrorCode::InsufficientFunds)?,
+                    FeeCurrency::Coin => native_lock_qty_before_fee,
+                })
+            }
+            Side::Ask => Ok(max_qty
+                .get()
+                .checked_mul(self.coin_lot_size)
+                .ok_or(DexErrorCode::InsufficientFunds)?),
+        }
+    }
+
+    #[inline]
+    pub fn layout_version(&self) -> u8 {
+        if self.account_flags & (AccountFlag::LayoutV2 as u64) != 0 {
+            2
+        } else {
+            1
+        }
+    }
+
     pub fn load_orders_mut<'a>(
         &self,
         orders_account: &'a AccountInfo,
         owner_account: Option<&AccountInfo>,
         program_id: &Pubkey,
         rent: Option<Rent>,
-    ) -> DexResult<RefMut<'a, OpenOrders>> {
+    ) -> DexResult<OpenOrdersMut<'a>> {
         check_assert_eq!(orders_account.owner, program_id)?;
-        let mut open_orders: RefMut<'a, OpenOrders>;
 
         let open_orders_data_len = orders_account.data_len();
         let open_orders_lamports = orders_account.lamports();
         let (_, data) = strip_header::<[u8; 0], u8>(orders_account, true)?;
-        open_orders = RefMut::map(data, |data| from_bytes_mut(data));
+        let data_len = data.len();
+        let delegate = OpenOrdersV2::delegate(&data)?;
+        let mut open_orders = OpenOrdersMut::from_data(data)?;
 
         if open_orders.account_flags == 0 {
             let rent = rent.ok_or(DexErrorCode::RentNotProvided)?;
@@ -250,16 +464,58 @@ impl MarketState {
             open_orders.init(&self.own_address, &owner_account.key.to_aligned_bytes())?;
         }
         open_orders.check_flags()?;
+        if open_orders.is_referred() {
+            check_assert!(data_len >= size_of::<ReferredOpenOrders>())?;
+        }
         check_assert_eq!(&open_orders.market, &self.own_address)
             .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
         if let Some(owner) = owner_account {
-            check_assert_eq!(&open_orders.owner, &owner.key.to_aligned_bytes())
-                .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
+            let signer = owner.key.to_aligned_bytes();
+            if open_orders.owner != signer && delegate != Some(signer) {
+                Err(DexErrorCode::WrongOrdersAccount)?
+            }
         }
 
         Ok(open_orders)
     }
 
+    fn load_referrer<'a>(
+        &self,
+        referrer_account: &'a AccountInfo,
+        program_id: &Pubkey,
+    ) -> DexResult<RefMut<'a, Referrer>> {
+        check_assert_eq!(referrer_account.owner, program_id)
+            .map_err(|_| DexErrorCode::WrongReferrerAccount)?;
+        let (_, data) = strip_header::<[u8; 0], u8>(referrer_account, false)?;
+        check_assert_eq!(data.len(), size_of::<Referrer>())
+            .map_err(|_| DexErrorCode::WrongReferrerAccount)?;
+        let referrer: RefMut<'a, Referrer> = RefMut::map(data, |data| from_bytes_mut(data));
+        referrer.check_flags()?;
+        if referrer.market != self.own_address {
+            Err(DexErrorCode::WrongReferrerAccount)?
+        }
+        Ok(referrer)
+    }
+
+    // Only the fee sweeping authority can create the trigger orders of a
+    // market, once, so the account of the market is the only one there is.
+    fn load_trigger_orders_mut<'a>(
+        &self,
+        trigger_orders: &'a AccountInfo,
+        program_id: &Pubkey,
+    ) -> DexResult<TriggerOrders<'a>> {
+        check_assert_eq!(trigger_orders.owner, program_id)
+            .map_err(|_| DexErrorCode::WrongTriggerOrdersAccount)?;
+        let (header, orders) =
+            strip_header::<TriggerOrdersHeader, TriggerOrder>(trigger_orders, false)?;
+        if header.account_flags != (AccountFlag::Initialized | AccountFlag::TriggerOrders).bits()
+            || header.market != self.own_address
+        {
+            Err(DexErrorCode::WrongTriggerOrdersAccount)?
+        }
+        Ok(TriggerOrders { header, orders })
+    }
+
     fn load_bids_mut<'a>(&self, bids: &'a AccountInfo) -> DexResult<RefMut<'a, Slab>> {
         check_assert_eq!(&bids.key.to_aligned_bytes(), &self.bids)
             .map_err(|_| DexErrorCode::WrongBidsAccount)?;
@@ -377,6 +633,34 @@ impl MarketState {
     }
 }
 
+impl MarketStateV2 {
+    #[inline]
+    pub fn load<'a>(
+        market_account: &'a AccountInfo,
+        program_id: &Pubkey,
+    ) -> DexResult<RefMut<'a, Self>> {
+        check_assert_eq!(market_account.owner, program_id)?;
+        let mut account_data: RefMut<'a, [u8]>;
+        let state: RefMut<'a, Self>;
+
+        account_data = RefMut::map(market_account.try_borrow_mut_data()?, |data| *data);
+        let data_len = size_of::<u64>() * check_account_padding(&mut account_data)?.len();
+        if data_len < size_of::<Self>() {
+            Err(DexErrorCode::InvalidMarketLayoutVersion)?
+        }
+        state = RefMut::map(account_data, |data| {
+            let words = check_account_padding(data).unwrap_or_else(|_| unreachable!());
+            from_bytes_mut(&mut cast_slice_mut::<u64, u8>(words)[..size_of::<Self>()])
+        });
+
+        state.check_flags()?;
+        if state.layout_version() != 2 {
+            Err(DexErrorCode::InvalidMarketLayoutVersion)?
+        }
+        Ok(state)
+    }
+}
+
 #[cfg_attr(feature = "fuzz", derive(Debug))]
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -401,17 +685,250 @@ pub struct OpenOrders {
 unsafe impl Pod for OpenOrders {}
 unsafe impl Zeroable for OpenOrders {}
 
+/// The layout of OpenOrders accounts created with a referrer, which are
+/// flagged `Referred`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ReferredOpenOrders {
+    pub inner: OpenOrders,
+    pub referrer: [u64; 4],
+}
+unsafe impl Pod for ReferredOpenOrders {}
+unsafe impl Zeroable for ReferredOpenOrders {}
+
+impl ReferredOpenOrders {
+    /// The referrer of an initialized OpenOrders account, if it has one.
+    fn load_referrer(orders_account: &AccountInfo) -> DexResult<Option<[u64; 4]>> {
+        let (_, data) = strip_header::<[u8; 0], u8>(orders_account, false)?;
+        let referred: &Self = match data.get(..size_of::<Self>()) {
+            Some(data) => try_from_bytes(data).or(check_unreachable!())?,
+            None => return Ok(None),
+        };
+        Ok(if referred.inner.is_referred() {
+            Some(referred.referrer)
+        } else {
+            None
+        })
+    }
+}
+
+/// The layout of OpenOrders accounts that can have a delegate. The referrer
+/// is only set if the account is flagged `Referred`, and the index and nonce
+/// if it is flagged `Derived`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct OpenOrdersV2 {
+    pub inner: OpenOrders,
+    pub referrer: [u64; 4],
+    // All zeros when the account has no delegate.
+    pub delegate: [u64; 4],
+    // The seeds InitOpenOrders derived the account's address from.
+    pub index: u64,
+    pub nonce: u64,
+}
+unsafe impl Pod for OpenOrdersV2 {}
+unsafe impl Zeroable for OpenOrdersV2 {}
+
+impl OpenOrdersV2 {
+    /// The delegate of an OpenOrders account's data, if it has one.
+    fn delegate(data: &[u8]) -> DexResult<Option<[u64; 4]>> {
+        let open_orders: &Self = match data.get(..size_of::<Self>()) {
+            Some(data) => try_from_bytes(data).or(check_unreachable!())?,
+            None => return Ok(None),
+        };
+        Ok(Some(open_orders.delegate).filter(|&delegate| delegate != [0; 4]))
+    }
+}
+
+/// Slots an OpenOrders account has beyond the 128 of `OpenOrders`, stored in
+/// chunks after `OpenOrdersV2`. The number of chunks is chosen when creating
+/// the account, by its data length.
+#[cfg_attr(feature = "fuzz", derive(Debug))]
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct OpenOrdersSlots {
+    pub free_slot_bits: u64,
+    pub is_bid_bits: u64,
+    pub orders: [u128; 64],
+    pub client_order_ids: [u64; 64],
+}
+unsafe impl Pod for OpenOrdersSlots {}
+unsafe impl Zeroable for OpenOrdersSlots {}
+
+// Orders and events refer to slots by a u8.
+pub const MAX_OPEN_ORDERS_SLOTS: usize = 256;
+
+impl OpenOrdersSlots {
+    /// The number of chunks of extra slots held by OpenOrders data of this
+    /// length, if it is a valid length for an `OpenOrdersV2` account.
+    pub fn chunks(data_len: usize) -> Option<usize> {
+        let extra_len = data_len.checked_sub(size_of::<OpenOrdersV2>())?;
+        if extra_len % size_of::<Self>() != 0 {
+            return None;
+        }
+        let chunks = extra_len / size_of::<Self>();
+        if 128 + 64 * chunks > MAX_OPEN_ORDERS_SLOTS {
+            return None;
+        }
+        Some(chunks)
+    }
+
+    /// The data length of an `OpenOrdersV2` account with this many slots,
+    /// which must be 128 plus a multiple of 64.
+    pub fn open_orders_len(slots: usize) -> Option<usize> {
+        let extra_slots = slots.checked_sub(128)?;
+        if extra_slots % 64 != 0 || slots > MAX_OPEN_ORDERS_SLOTS {
+            return None;
+        }
+        Some(size_of::<OpenOrdersV2>() + extra_slots / 64 * size_of::<Self>())
+    }
+}
+
+/// A loaded OpenOrders account. The order slots are only reachable through
+/// it, since some of them live outside of `OpenOrders`.
+pub struct OpenOrdersMut<'a> {
+    inner: RefMut<'a, OpenOrders>,
+    extra_slots: RefMut<'a, [OpenOrdersSlots]>,
+}
+
+impl<'a> Deref for OpenOrdersMut<'a> {
+    type Target = OpenOrders;
+
+    #[inline]
+    fn deref(&self) -> &OpenOrders {
+        &self.inner
+    }
+}
+
+impl<'a> DerefMut for OpenOrdersMut<'a> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut OpenOrders {
+        &mut self.inner
+    }
+}
+
+/// A read-only OpenOrders account, along with its extra slots.
+#[derive(Copy, Clone)]
+pub struct OpenOrdersView<'a> {
+    pub inner: &'a OpenOrders,
+    pub extra_slots: &'a [OpenOrdersSlots],
+}
+
+impl<'a> Deref for OpenOrdersView<'a> {
+    type Target = OpenOrders;
+
+    #[inline]
+    fn deref(&self) -> &OpenOrders {
+        self.inner
+    }
+}
+
+impl<'a> OpenOrdersView<'a> {
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        128 + 64 * self.extra_slots.len()
+    }
+
+    pub fn slots(&self) -> RangeInclusive<u8> {
+        0..=(self.capacity() - 1) as u8
+    }
+
+    /// Whether the slot is free and whether it holds a bid, or `None` if the
+    /// account doesn't have the slot.
+    pub fn slot_bits(&self, slot: u8) -> Option<(bool, bool)> {
+        let slot = slot as usize;
+        if slot < 128 {
+            let slot_mask = 1u128 << slot;
+            Some((
+                self.inner.free_slot_bits & slot_mask != 0,
+                self.inner.is_bid_bits & slot_mask != 0,
+            ))
+        } else {
+            let chunk = self.extra_slots.get((slot - 128) / 64)?;
+            let slot_mask = 1u64 << (slot % 64);
+            Some((
+                chunk.free_slot_bits & slot_mask != 0,
+                chunk.is_bid_bits & slot_mask != 0,
+            ))
+        }
+    }
+
+    /// The order id of a slot, or `None` if the account doesn't have it.
+    pub fn order_id(&self, slot: u8) -> Option<u128> {
+        let slot = slot as usize;
+        if slot < 128 {
+            return Some(self.inner.orders[slot]);
+        }
+        let chunk = self.extra_slots.get((slot - 128) / 64)?;
+        Some(chunk.orders[slot % 64])
+    }
+
+    /// The client order id of a slot, or zero if the account doesn't have it.
+    pub fn client_order_id(&self, slot: u8) -> u64 {
+        let slot = slot as usize;
+        if slot < 128 {
+            return self.inner.client_order_ids[slot];
+        }
+        self.extra_slots
+            .get((slot - 128) / 64)
+            .map_or(0, |chunk| chunk.client_order_ids[slot % 64])
+    }
+
+    pub fn slot_is_free(&self, slot: u8) -> bool {
+        self.slot_bits(slot).map_or(false, |(is_free, _)| is_free)
+    }
+}
+
+/// A referrer registered on a market, paid part of the taker fees of the
+/// OpenOrders accounts created with it.
+#[cfg_attr(feature = "fuzz", derive(Debug))]
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Referrer {
+    pub account_flags: u64, // Initialized, Referrer
+    pub market: [u64; 4],
+    pub owner: [u64; 4],
+    pub pc_wallet: [u64; 4],
+    // In basis points of the taker fee, at most `fees::MAX_REFERRER_REBATE_BPS`.
+    pub rebate_bps: u64,
+}
+unsafe impl Pod for Referrer {}
+unsafe impl Zeroable for Referrer {}
+
+impl Referrer {
+    fn check_flags(&self) -> DexResult {
+        let flags = BitFlags::from_bits(self.account_flags)
+            .map_err(|_| DexErrorCode::WrongReferrerAccount)?;
+        let required_flags = AccountFlag::Initialized | AccountFlag::Referrer;
+        if flags != required_flags {
+            Err(DexErrorCode::WrongReferrerAccount)?
+        }
+        Ok(())
+    }
+}
+
 impl OpenOrders {
     fn check_flags(&self) -> DexResult {
         let flags = BitFlags::from_bits(self.account_flags)
             .map_err(|_| DexErrorCode::InvalidMarketFlags)?;
         let required_flags = AccountFlag::Initialized | AccountFlag::OpenOrders;
-        if flags != required_flags {
+        let optional_flags = AccountFlag::Referred | AccountFlag::Derived;
+        if flags & !optional_flags != required_flags {
             Err(DexErrorCode::WrongOrdersAccount)?
         }
         Ok(())
     }
 
+    #[inline]
+    pub fn is_referred(&self) -> bool {
+        self.account_flags & (AccountFlag::Referred as u64) != 0
+    }
+
+    #[inline]
+    pub fn is_derived(&self) -> bool {
+        self.account_flags & (AccountFlag::Derived as u64) != 0
+    }
+
     fn init(&mut self, market: &[u64; 4], owner: &[u64; 4]) -> DexResult<()> {
         check_assert_eq!(&self.account_flags, &0)?;
         self.account_flags = (AccountFlag::Initialized | AccountFlag::OpenOrders).bits();
@@ -446,54 +963,157 @@ impl OpenOrders {
     fn lock_free_pc(&mut self, native_pc_amount: u64) {
         self.native_pc_free = self.native_pc_free.checked_sub(native_pc_amount).unwrap();
     }
+}
+
+impl<'a> OpenOrdersMut<'a> {
+    /// Loads an initialized OpenOrders account of any layout, without
+    /// checking which market or owner it belongs to.
+    pub fn load(orders_account: &'a AccountInfo, program_id: &Pubkey) -> DexResult<Self> {
+        check_assert_eq!(orders_account.owner, program_id)?;
+        let (_, data) = strip_header::<[u8; 0], u8>(orders_account, false)?;
+        let open_orders = Self::from_data(data)?;
+        open_orders.check_flags()?;
+        Ok(open_orders)
+    }
+
+    fn from_data(data: RefMut<'a, [u8]>) -> DexResult<Self> {
+        let data_len = data.len();
+        check_assert!(
+            data_len == size_of::<OpenOrders>()
+                || data_len == size_of::<ReferredOpenOrders>()
+                || OpenOrdersSlots::chunks(data_len).is_some()
+        )?;
+        let (inner, rest): (RefMut<'a, OpenOrders>, RefMut<'a, [u8]>) =
+            RefMut::map_split(data, |data| {
+                let (inner, rest) = data.split_at_mut(size_of::<OpenOrders>());
+                (from_bytes_mut(inner), rest)
+            });
+        let extra_slots = RefMut::map(rest, |rest| {
+            match rest.get_mut(size_of::<OpenOrdersV2>() - size_of::<OpenOrders>()..) {
+                Some(chunks) => cast_slice_mut(chunks),
+                None => &mut [],
+            }
+        });
+        Ok(OpenOrdersMut { inner, extra_slots })
+    }
+
+    fn init(&mut self, market: &[u64; 4], owner: &[u64; 4]) -> DexResult<()> {
+        self.inner.init(market, owner)?;
+        for chunk in self.extra_slots.iter_mut() {
+            chunk.free_slot_bits = std::u64::MAX;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn view(&self) -> OpenOrdersView {
+        OpenOrdersView {
+            inner: &self.inner,
+            extra_slots: &self.extra_slots,
+        }
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.view().capacity()
+    }
+
+    pub fn free_slots(&self) -> usize {
+        let extra_free_slots: u32 = self
+            .extra_slots
+            .iter()
+            .map(|chunk| chunk.free_slot_bits.count_ones())
+            .sum();
+        (self.inner.free_slot_bits.count_ones() + extra_free_slots) as usize
+    }
+
+    /// Fills the slot with an order on `side`, or frees it if `side` is `None`.
+    fn set_slot(
+        &mut self,
+        slot: u8,
+        side: Option<Side>,
+        order_id: u128,
+        client_order_id: u64,
+    ) -> DexResult {
+        let slot = slot as usize;
+        if slot < 128 {
+            let slot_mask = 1u128 << slot;
+            let open_orders = self.inner.deref_mut();
+            open_orders.free_slot_bits &= !slot_mask;
+            open_orders.is_bid_bits &= !slot_mask;
+            match side {
+                None => open_orders.free_slot_bits |= slot_mask,
+                Some(Side::Bid) => open_orders.is_bid_bits |= slot_mask,
+                Some(Side::Ask) => (),
+            }
+            open_orders.orders[slot] = order_id;
+            open_orders.client_order_ids[slot] = client_order_id;
+        } else {
+            let chunk = self
+                .extra_slots
+                .get_mut((slot - 128) / 64)
+                .ok_or(assertion_error!())?;
+            let slot_mask = 1u64 << (slot % 64);
+            chunk.free_slot_bits &= !slot_mask;
+            chunk.is_bid_bits &= !slot_mask;
+            match side {
+                None => chunk.free_slot_bits |= slot_mask,
+                Some(Side::Bid) => chunk.is_bid_bits |= slot_mask,
+                Some(Side::Ask) => (),
+            }
+            chunk.orders[slot % 64] = order_id;
+            chunk.client_order_ids[slot % 64] = client_order_id;
+        }
+        Ok(())
+    }
+
+    pub fn order_id(&self, slot: u8) -> DexResult<u128> {
+        let order_id = self.view().order_id(slot).ok_or(assertion_error!())?;
+        Ok(order_id)
+    }
+
+    /// The client order id of a slot, or zero if the account doesn't have it.
+    pub fn client_order_id(&self, slot: u8) -> u64 {
+        self.view().client_order_id(slot)
+    }
+
+    fn slots(&self) -> RangeInclusive<u8> {
+        self.view().slots()
+    }
+
+    /// The slot of the first live order with this client id.
+    fn client_order_id_slot(&self, client_order_id: NonZeroU64) -> Option<u8> {
+        self.slots().find(|&slot| {
+            self.client_order_id(slot) == client_order_id.get() && !self.slot_is_free(slot)
+        })
+    }
 
     fn slot_is_free(&self, slot: u8) -> bool {
-        let slot_mask = 1u128 << slot;
-        self.free_slot_bits & slot_mask != 0
+        self.view().slot_is_free(slot)
     }
 
     pub fn slot_side(&self, slot: u8) -> Option<Side> {
-        let slot_mask = 1u128 << slot;
-        if self.free_slot_bits & slot_mask != 0 {
-            None
-        } else if self.is_bid_bits & slot_mask != 0 {
-            Some(Side::Bid)
-        } else {
-            Some(Side::Ask)
+        match self.view().slot_bits(slot)? {
+            (true, _) => None,
+            (false, true) => Some(Side::Bid),
+            (false, false) => Some(Side::Ask),
         }
     }
 
     fn remove_order(&mut self, slot: u8) -> DexResult {
-        check_assert!(slot < 128)?;
+        check_assert!((slot as usize) < self.capacity())?;
         check_assert!(!self.slot_is_free(slot))?;
 
-        let slot_mask = 1u128 << slot;
-        self.orders[slot as usize] = 0;
-        self.client_order_ids[slot as usize] = 0;
-        self.free_slot_bits |= slot_mask;
-        self.is_bid_bits &= !slot_mask;
-
-        Ok(())
+        self.set_slot(slot, None, 0, 0)
     }
 
-    fn add_order(&mut self, id: u128, side: Side) -> DexResult<u8> {
-        if self.free_slot_bits == 0 {
-            Err(DexErrorCode::TooManyOpenOrders)?;
-        }
-        let slot = self.free_slot_bits.trailing_zeros();
-        check_assert!(self.slot_is_free(slot as u8))?;
-        let slot_mask = 1u128 << slot;
-        self.free_slot_bits &= !slot_mask;
-        match side {
-            Side::Bid => {
-                self.is_bid_bits |= slot_mask;
-            }
-            Side::Ask => {
-                self.is_bid_bits &= !slot_mask;
-            }
-        };
-        self.orders[slot as usize] = id;
-        Ok(slot as u8)
+    fn add_order(&mut self, id: u128, client_order_id: u64, side: Side) -> DexResult<u8> {
+        let slot = self
+            .slots()
+            .find(|&slot| self.slot_is_free(slot))
+            .ok_or(DexErrorCode::TooManyOpenOrders)?;
+        self.set_slot(slot, Some(side), id, client_order_id)?;
+        Ok(slot)
     }
 }
 
@@ -534,6 +1154,11 @@ impl<'a, H: QueueHeader> Queue<'a, H> {
         self.header.count() == 0
     }
 
+    #[inline]
+    pub fn capacity(&self) -> u64 {
+        self.buf.len() as u64
+    }
+
     #[inline]
     pub fn push_back(&mut self, value: H::Item) -> Result<(), H::Item> {
         if self.full() {
@@ -583,6 +1208,33 @@ impl<'a, H: QueueHeader> Queue<'a, H> {
         Ok(())
     }
 
+    /// Removes the items among the first `len` that `remove` picks, given
+    /// their index and the item, leaving the others in order at the front of
+    /// the queue. Returns how many were removed.
+    pub fn remove_front_where(
+        &mut self,
+        len: u64,
+        mut remove: impl FnMut(usize, &H::Item) -> bool,
+    ) -> DexResult<u64> {
+        check_assert!(len <= self.header.count())?;
+        let head = self.header.head() as usize;
+        let buf_len = self.buf.len();
+        // walk back from the end of the window, moving the kept items past
+        // the removed ones
+        let mut removed = 0;
+        for i in (0..len as usize).rev() {
+            let slot = (head + i) % buf_len;
+            if remove(i, &self.buf[slot]) {
+                removed += 1;
+            } else if removed > 0 {
+                self.buf[(head + i + removed) % buf_len] = self.buf[slot];
+            }
+        }
+        *self.header.count_mut() -= removed as u64;
+        *self.header.head_mut() = ((head + removed) % buf_len) as u64;
+        Ok(removed as u64)
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &H::Item> {
         QueueIterator {
             queue: self,
@@ -645,7 +1297,7 @@ impl QueueHeader for RequestQueueHeader {
 pub type RequestQueue<'a> = Queue<'a, RequestQueueHeader>;
 
 impl RequestQueue<'_> {
-    fn gen_order_id(&mut self, limit_price: u64, side: Side) -> u128 {
+    pub(crate) fn gen_order_id(&mut self, limit_price: u64, side: Side) -> u128 {
         let seq_num = self.gen_seq_num();
         let upper = (limit_price as u128) << 64;
         let lower = match side {
@@ -671,6 +1323,7 @@ enum RequestFlag {
     PostOnly = 0x08,
     ImmediateOrCancel = 0x10,
     DecrementTakeOnSelfTrade = 0x20,
+    PostOnlySlide = 0x40,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -736,6 +1389,7 @@ impl Request {
                 }
                 match order_type {
                     OrderType::PostOnly => flags |= RequestFlag::PostOnly,
+                    OrderType::PostOnlySlide => flags |= RequestFlag::PostOnlySlide,
                     OrderType::ImmediateOrCancel => flags |= RequestFlag::ImmediateOrCancel,
                     OrderType::Limit => (),
                 };
@@ -781,6 +1435,13 @@ impl Request {
         }
     }
 
+    /// The same request for another order id.
+    #[inline]
+    pub(crate) fn with_order_id(mut self, order_id: u128) -> Self {
+        self.order_id = order_id;
+        self
+    }
+
     #[inline(always)]
     pub fn as_view(&self) -> DexResult<RequestView> {
         let flags = BitFlags::from_bits(self.request_flags).unwrap();
@@ -792,16 +1453,18 @@ impl Request {
         if flags.contains(RequestFlag::NewOrder) {
             let allowed_flags = {
                 use RequestFlag::*;
-                NewOrder | Bid | PostOnly | ImmediateOrCancel
+                NewOrder | Bid | PostOnly | ImmediateOrCancel | PostOnlySlide
             };
             check_assert!(allowed_flags.contains(flags))?;
             let post_only = flags.contains(RequestFlag::PostOnly);
             let ioc = flags.contains(RequestFlag::ImmediateOrCancel);
-            let order_type = match (post_only, ioc) {
-                (true, false) => OrderType::PostOnly,
-                (false, true) => OrderType::ImmediateOrCancel,
-                (false, false) => OrderType::Limit,
-                (true, true) => unreachable!(),
+            let post_slide = flags.contains(RequestFlag::PostOnlySlide);
+            let order_type = match (post_only, ioc, post_slide) {
+                (true, false, false) => OrderType::PostOnly,
+                (false, true, false) => OrderType::ImmediateOrCancel,
+                (false, false, true) => OrderType::PostOnlySlide,
+                (false, false, false) => OrderType::Limit,
+                _ => unreachable!(),
             };
             let fee_tier = FeeTier::try_from_primitive(self.fee_tier).or(check_unreachable!())?;
             let self_trade_behavior =
@@ -884,6 +1547,7 @@ enum EventFlag {
     Out = 0x2,
     Bid = 0x4,
     Maker = 0x8,
+    Triggered = 0x10,
 }
 
 impl EventFlag {
@@ -996,6 +1660,34 @@ impl Event {
                     client_order_id: client_order_id.map_or(0, NonZeroU64::get),
                 }
             }
+
+            EventView::Triggered {
+                side,
+                order_id,
+                previous_order_id,
+                owner,
+                owner_slot,
+                client_order_id,
+            } => {
+                debug_assert_eq!(previous_order_id >> 64, *order_id >> 64);
+                let event_flags = (EventFlag::from_side(side) | EventFlag::Triggered).bits();
+                Event {
+                    event_flags,
+                    owner_slot,
+                    fee_tier: 0,
+
+                    _padding: Zeroable::zeroed(),
+
+                    native_qty_released: 0,
+                    // the previous order id has the same price
+                    native_qty_paid: previous_order_id as u64,
+                    native_fee_or_rebate: 0,
+
+                    order_id: *order_id,
+                    owner: *owner,
+                    client_order_id: client_order_id.map_or(0, NonZeroU64::get),
+                }
+            }
         }
     }
 
@@ -1026,6 +1718,23 @@ impl Event {
                 client_order_id,
             });
         }
+        if flags.contains(EventFlag::Triggered) {
+            let allowed_flags = {
+                use EventFlag::*;
+                Triggered | Bid
+            };
+            check_assert!(allowed_flags.contains(flags))?;
+
+            let price = self.order_id >> 64;
+            return Ok(EventView::Triggered {
+                side,
+                order_id: &self.order_id,
+                previous_order_id: (price << 64) | self.native_qty_paid as u128,
+                owner: &self.owner,
+                owner_slot: self.owner_slot,
+                client_order_id,
+            });
+        }
         let allowed_flags = {
             use EventFlag::*;
             Out | Bid | Maker
@@ -1068,13 +1777,147 @@ pub enum EventView<'a> {
         owner_slot: u8,
         client_order_id: Option<NonZeroU64>,
     },
+    /// A trigger order was moved to the request queue under a new order id,
+    /// which its `OpenOrders` slot takes over from `previous_order_id`.
+    Triggered {
+        side: Side,
+        order_id: &'a u128,
+        previous_order_id: u128,
+        owner: &'a [u64; 4],
+        owner_slot: u8,
+        client_order_id: Option<NonZeroU64>,
+    },
 }
 
 impl<'a> EventView<'a> {
     fn side(&self) -> Side {
         match self {
-            &EventView::Fill { side, .. } | &EventView::Out { side, .. } => side,
+            &EventView::Fill { side, .. }
+            | &EventView::Out { side, .. }
+            | &EventView::Triggered { side, .. } => side,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TriggerOrdersHeader {
+    account_flags: u64, // Initialized, TriggerOrders
+    pub market: [u64; 4],
+    count: u64,
+    // In pc lots, or 0 before the first fill since the account was created.
+    pub last_price: u64,
+    padding: u64,
+}
+unsafe impl Zeroable for TriggerOrdersHeader {}
+unsafe impl Pod for TriggerOrdersHeader {}
+
+impl TriggerOrdersHeader {
+    #[inline]
+    pub fn is_initialized(&self) -> bool {
+        self.account_flags == (AccountFlag::Initialized | AccountFlag::TriggerOrders).bits()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+}
+
+/// A new order request kept out of the request queue until the last traded
+/// price reaches its trigger price.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TriggerOrder {
+    request: Request,
+    trigger_price: u64,
+    trigger_direction: u64,
+}
+unsafe impl Zeroable for TriggerOrder {}
+unsafe impl Pod for TriggerOrder {}
+
+impl TriggerOrder {
+    pub fn new(
+        request: Request,
+        trigger_price: NonZeroU64,
+        trigger_direction: TriggerDirection,
+    ) -> Self {
+        TriggerOrder {
+            request,
+            trigger_price: trigger_price.get(),
+            trigger_direction: u8::from(trigger_direction).into(),
+        }
+    }
+
+    #[inline]
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    pub fn is_triggered(&self, last_price: u64) -> DexResult<bool> {
+        if last_price == 0 {
+            return Ok(false);
+        }
+        let trigger_direction = TriggerDirection::try_from_primitive(self.trigger_direction as u8)
+            .or(check_unreachable!())?;
+        Ok(match trigger_direction {
+            TriggerDirection::Above => last_price >= self.trigger_price,
+            TriggerDirection::Below => last_price <= self.trigger_price,
+        })
+    }
+}
+
+/// The trigger orders of a market, unordered.
+pub struct TriggerOrders<'a> {
+    header: RefMut<'a, TriggerOrdersHeader>,
+    orders: RefMut<'a, [TriggerOrder]>,
+}
+
+impl TriggerOrders<'_> {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.header.count as usize
+    }
+
+    #[inline]
+    pub fn last_price(&self) -> u64 {
+        self.header.last_price
+    }
+
+    #[inline]
+    pub fn set_last_price(&mut self, last_price: u64) {
+        self.header.last_price = last_price;
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&TriggerOrder> {
+        self.orders[..self.len()].get(index)
+    }
+
+    pub fn push(&mut self, order: TriggerOrder) -> DexResult {
+        let count = self.len();
+        if count == self.orders.len() {
+            Err(DexErrorCode::TriggerOrdersFull)?
         }
+        self.orders[count] = order;
+        self.header.count += 1;
+        Ok(())
+    }
+
+    /// Removes the order at `index`, moving the last order into its place.
+    pub fn swap_remove(&mut self, index: usize) -> DexResult<TriggerOrder> {
+        let count = self.len();
+        check_assert!(index < count)?;
+        let order = self.orders[index];
+        self.orders[index] = self.orders[count - 1];
+        self.header.count -= 1;
+        Ok(order)
+    }
+
+    pub fn position(&self, order_id: &u128) -> Option<usize> {
+        self.orders[..self.len()]
+            .iter()
+            .position(|order| order.request.order_id == *order_id)
     }
 }
 
@@ -1088,7 +1931,7 @@ unsafe impl Pod for OrderBookStateHeader {}
 
 pub enum State {}
 
-fn gen_vault_signer_seeds<'a>(nonce: &'a u64, market: &'a Pubkey) -> [&'a [u8]; 2] {
+pub fn gen_vault_signer_seeds<'a>(nonce: &'a u64, market: &'a Pubkey) -> [&'a [u8]; 2] {
     [market.as_ref(), bytes_of(nonce)]
 }
 
@@ -1113,6 +1956,103 @@ pub fn gen_vault_signer_key(
     Ok(Pubkey::default())
 }
 
+fn gen_open_orders_seeds<'a>(
+    market: &'a Pubkey,
+    owner: &'a Pubkey,
+    index: &'a u64,
+    nonce: &'a u64,
+) -> [&'a [u8]; 4] {
+    [
+        market.as_ref(),
+        owner.as_ref(),
+        bytes_of(index),
+        bytes_of(nonce),
+    ]
+}
+
+#[inline]
+pub fn gen_open_orders_key(
+    nonce: u64,
+    index: u64,
+    market: &Pubkey,
+    owner: &Pubkey,
+    program_id: &Pubkey,
+) -> Result<Pubkey, ProgramError> {
+    let seeds = gen_open_orders_seeds(market, owner, &index, &nonce);
+    Ok(Pubkey::create_program_address(&seeds, program_id)?)
+}
+
+/// Returns the first nonce yielding a valid OpenOrders address for
+/// `(market, owner, index)`, along with that address.
+pub fn find_open_orders_key(
+    index: u64,
+    market: &Pubkey,
+    owner: &Pubkey,
+    program_id: &Pubkey,
+) -> Option<(u64, Pubkey)> {
+    (0..100).find_map(|nonce| {
+        gen_open_orders_key(nonce, index, market, owner, program_id)
+            .ok()
+            .map(|key| (nonce, key))
+    })
+}
+
+/// Creates an `OpenOrdersV2` account with `slots` order slots at the address
+/// derived from `open_orders_seeds`.
+#[cfg(feature = "program")]
+pub(crate) fn create_open_orders_account<'a, 'b: 'a>(
+    open_orders: &'a AccountInfo<'b>,
+    payer: account_parser::SignerAccount<'a, 'b>,
+    system_program: account_parser::SystemProgram<'a, 'b>,
+    rent: &Rent,
+    program_id: &Pubkey,
+    open_orders_seeds: &[&[u8]],
+    slots: usize,
+) -> DexResult {
+    let open_orders_len =
+        OpenOrdersSlots::open_orders_len(slots).ok_or(DexErrorCode::InvalidOpenOrdersSlots)?;
+    let space = open_orders_len + ACCOUNT_HEAD_PADDING.len() + ACCOUNT_TAIL_PADDING.len();
+    let accounts: &[AccountInfo] = &[
+        payer.inner().clone(),
+        open_orders.clone(),
+        system_program.inner().clone(),
+    ];
+    let lamports = open_orders.lamports();
+    if lamports == 0 {
+        let create_instruction = solana_sdk::system_instruction::create_account(
+            payer.inner().key,
+            open_orders.key,
+            rent.minimum_balance(space),
+            space as u64,
+            program_id,
+        );
+        invoke_system_program(&create_instruction, accounts, &[open_orders_seeds])
+            .map_err(|_| DexErrorCode::CreateAccountFailed)?;
+        return Ok(());
+    }
+
+    // Anyone can send lamports to the address before it is created, which
+    // would make CreateAccount fail, so top it up and allocate it instead.
+    let required_lamports = rent.minimum_balance(space).saturating_sub(lamports);
+    if required_lamports > 0 {
+        let transfer_instruction = solana_sdk::system_instruction::transfer(
+            payer.inner().key,
+            open_orders.key,
+            required_lamports,
+        );
+        invoke_system_program(&transfer_instruction, accounts, &[])
+            .map_err(|_| DexErrorCode::CreateAccountFailed)?;
+    }
+    let allocate_instruction =
+        solana_sdk::system_instruction::allocate(open_orders.key, space as u64);
+    let assign_instruction = solana_sdk::system_instruction::assign(open_orders.key, program_id);
+    for instruction in &[allocate_instruction, assign_instruction] {
+        invoke_system_program(instruction, accounts, &[open_orders_seeds])
+            .map_err(|_| DexErrorCode::CreateAccountFailed)?;
+    }
+    Ok(())
+}
+
 #[cfg(not(feature = "fuzz"))]
 #[cfg(feature = "program")]
 fn invoke_spl_token(
@@ -1151,6 +2091,167 @@ fn invoke_spl_token(
     Ok(())
 }
 
+#[cfg(not(any(test, feature = "fuzz")))]
+#[cfg(feature = "program")]
+fn invoke_system_program(
+    instruction: &solana_sdk::instruction::Instruction,
+    account_infos: &[AccountInfo],
+    signers_seeds: &[&[&[u8]]],
+) -> solana_sdk::entrypoint::ProgramResult {
+    solana_sdk::program::invoke_signed(instruction, account_infos, signers_seeds)
+}
+
+#[cfg(all(test, not(feature = "fuzz")))]
+thread_local! {
+    /// The system instructions invoked by this thread, with their signer seeds.
+    pub(crate) static SYSTEM_PROGRAM_INVOCATIONS: std::cell::RefCell<
+        Vec<(solana_sdk::instruction::Instruction, Vec<Vec<Vec<u8>>>)>,
+    > = std::cell::RefCell::new(Vec::new());
+}
+
+/// Records the instruction, and moves the lamports of transfers as the system
+/// program would.
+#[cfg(all(test, not(feature = "fuzz")))]
+fn invoke_system_program(
+    instruction: &solana_sdk::instruction::Instruction,
+    account_infos: &[AccountInfo],
+    signers_seeds: &[&[&[u8]]],
+) -> solana_sdk::entrypoint::ProgramResult {
+    use solana_sdk::system_instruction::SystemInstruction;
+
+    if let Ok(SystemInstruction::Transfer { lamports }) = bincode::deserialize(&instruction.data) {
+        let account = |index: usize| {
+            account_infos
+                .iter()
+                .find(|info| *info.key == instruction.accounts[index].pubkey)
+                .unwrap()
+        };
+        let from_lamports = account(0)
+            .lamports()
+            .checked_sub(lamports)
+            .ok_or(ProgramError::InsufficientFunds)?;
+        **account(0).lamports.borrow_mut() = from_lamports;
+        **account(1).lamports.borrow_mut() += lamports;
+    }
+    let signers_seeds = signers_seeds
+        .iter()
+        .map(|seeds| seeds.iter().map(|seed| seed.to_vec()).collect())
+        .collect();
+    SYSTEM_PROGRAM_INVOCATIONS.with(|invocations| {
+        invocations
+            .borrow_mut()
+            .push((instruction.clone(), signers_seeds))
+    });
+    Ok(())
+}
+
+/// Account infos can't be resized or reassigned outside of the runtime, so
+/// accounts being created must already have their final owner and size and
+/// only the lamports move.
+#[cfg(feature = "fuzz")]
+fn invoke_system_program(
+    instruction: &solana_sdk::instruction::Instruction,
+    account_infos: &[AccountInfo],
+    _signers_seeds: &[&[&[u8]]],
+) -> solana_sdk::entrypoint::ProgramResult {
+    use solana_sdk::entrypoint::ProgramResult;
+    use solana_sdk::system_instruction::SystemInstruction;
+
+    assert_eq!(instruction.program_id, solana_sdk::system_program::ID);
+    let account_infos: Vec<AccountInfo> = instruction
+        .accounts
+        .iter()
+        .map(|meta| {
+            account_infos
+                .iter()
+                .find(|info| *info.key == meta.pubkey)
+                .unwrap()
+                .clone()
+        })
+        .collect();
+    fn transfer(from: &AccountInfo, to: &AccountInfo, lamports: u64) -> ProgramResult {
+        let from_lamports = from
+            .lamports()
+            .checked_sub(lamports)
+            .ok_or(ProgramError::InsufficientFunds)?;
+        **from.lamports.borrow_mut() = from_lamports;
+        **to.lamports.borrow_mut() = to.lamports().checked_add(lamports).unwrap();
+        Ok(())
+    }
+    fn check_allocated(account: &AccountInfo, space: u64, owner: Option<&Pubkey>) -> ProgramResult {
+        if account.data_len() as u64 != space || owner.map_or(false, |o| account.owner != o) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(())
+    }
+    match bincode::deserialize(&instruction.data)
+        .map_err(|_| ProgramError::InvalidInstructionData)?
+    {
+        SystemInstruction::CreateAccount {
+            lamports,
+            space,
+            owner,
+        } => {
+            if account_infos[1].lamports() != 0 {
+                return Err(ProgramError::AccountAlreadyInitialized);
+            }
+            check_allocated(&account_infos[1], space, Some(&owner))?;
+            transfer(&account_infos[0], &account_infos[1], lamports)
+        }
+        SystemInstruction::Transfer { lamports } => {
+            transfer(&account_infos[0], &account_infos[1], lamports)
+        }
+        SystemInstruction::Allocate { space } => check_allocated(&account_infos[0], space, None),
+        SystemInstruction::Assign { owner } => check_allocated(
+            &account_infos[0],
+            account_infos[0].data_len() as u64,
+            Some(&owner),
+        ),
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
+
+/// Pulls `native_amount` from `wallet` into `vault`, signed by the wallet's
+/// owner.
+#[cfg(feature = "program")]
+fn deposit_from_wallet<'a, 'b: 'a>(
+    native_amount: u64,
+    wallet: account_parser::TokenAccount<'a, 'b>,
+    vault: account_parser::TokenAccount<'a, 'b>,
+    owner: account_parser::SignerAccount<'a, 'b>,
+    spl_token_program: account_parser::SplTokenProgram<'a, 'b>,
+) -> DexResult {
+    let deposit_instruction = spl_token::instruction::transfer(
+        &spl_token::ID,
+        wallet.inner().key,
+        vault.inner().key,
+        owner.inner().key,
+        &[],
+        native_amount,
+    )
+    .unwrap();
+    assert_eq!(*spl_token_program.inner().key, spl_token::ID);
+
+    invoke_spl_token(
+        &deposit_instruction,
+        &[
+            wallet.inner().clone(),
+            vault.inner().clone(),
+            owner.inner().clone(),
+            spl_token_program.inner().clone(),
+        ],
+        &[],
+    )
+    .map_err(|err| match err {
+        ProgramError::Custom(i) => match TokenError::from_u32(i) {
+            Some(TokenError::InsufficientFunds) => DexErrorCode::InsufficientFunds,
+            _ => DexErrorCode::TransferFailed,
+        },
+        _ => DexErrorCode::TransferFailed,
+    })?;
+    Ok(())
+}
+
 #[cfg(not(feature = "client"))]
 fn send_from_vault<'a, 'b: 'a>(
     native_amount: u64,
@@ -1179,6 +2280,82 @@ fn send_from_vault<'a, 'b: 'a>(
     Ok(())
 }
 
+/// Creates the temporary wrapped SOL account, funded with `native_amount`
+/// lamports from the native wallet and owned by the vault signer.
+#[cfg(feature = "program")]
+fn create_wrapped_sol_account<'a, 'b: 'a>(
+    native_amount: u64,
+    native_sol: account_parser::NativeSolAccounts<'a, 'b>,
+    spl_token_program: account_parser::SplTokenProgram<'a, 'b>,
+) -> DexResult {
+    let wallet = native_sol.wallet.inner();
+    let temp_account = native_sol.temp_account.inner();
+    let rent_sysvar = native_sol.rent_sysvar.inner();
+    let rent = Rent::from_account_info(rent_sysvar).or(check_unreachable!())?;
+    let space = spl_token::state::Account::LEN;
+    let lamports = native_amount
+        .checked_add(rent.minimum_balance(space))
+        .ok_or(DexErrorCode::InsufficientFunds)?;
+
+    let create_instruction = solana_sdk::system_instruction::create_account(
+        wallet.key,
+        temp_account.key,
+        lamports,
+        space as u64,
+        &spl_token::ID,
+    );
+    let accounts: &[AccountInfo] = &[
+        wallet.clone(),
+        temp_account.clone(),
+        native_sol.system_program.inner().clone(),
+    ];
+    invoke_system_program(&create_instruction, accounts, &[])
+        .map_err(|_| DexErrorCode::InsufficientFunds)?;
+
+    let init_instruction = spl_token::instruction::initialize_account(
+        &spl_token::ID,
+        temp_account.key,
+        native_sol.native_mint.inner().key,
+        native_sol.vault_signer.inner().key,
+    )?;
+    let accounts: &[AccountInfo] = &[
+        temp_account.clone(),
+        native_sol.native_mint.inner().clone(),
+        native_sol.vault_signer.inner().clone(),
+        rent_sysvar.clone(),
+        spl_token_program.inner().clone(),
+    ];
+    invoke_spl_token(&init_instruction, accounts, &[])
+        .map_err(|_| DexErrorCode::CreateAccountFailed)?;
+    Ok(())
+}
+
+/// Closes the temporary wrapped SOL account, returning all of its lamports
+/// to the native wallet.
+#[cfg(feature = "program")]
+fn close_wrapped_sol_account<'a, 'b: 'a>(
+    native_sol: account_parser::NativeSolAccounts<'a, 'b>,
+    spl_token_program: account_parser::SplTokenProgram<'a, 'b>,
+    vault_signer_seeds: &[&[u8]],
+) -> DexResult {
+    let close_instruction = spl_token::instruction::close_account(
+        &spl_token::ID,
+        native_sol.temp_account.inner().key,
+        native_sol.wallet.inner().key,
+        native_sol.vault_signer.inner().key,
+        &[],
+    )?;
+    let accounts: &[AccountInfo] = &[
+        native_sol.temp_account.inner().clone(),
+        native_sol.wallet.inner().clone(),
+        native_sol.vault_signer.inner().clone(),
+        spl_token_program.inner().clone(),
+    ];
+    invoke_spl_token(&close_instruction, accounts, &[vault_signer_seeds])
+        .map_err(|_| DexErrorCode::TransferFailed)?;
+    Ok(())
+}
+
 pub mod account_parser {
     use super::*;
 
@@ -1265,6 +2442,16 @@ pub mod account_parser {
         Ok(())
     });
 
+    declare_validated_account_wrapper!(SystemProgram, |account: &AccountInfo| {
+        check_assert_eq!(*account.key, system_program::ID)?;
+        Ok(())
+    });
+
+    declare_validated_account_wrapper!(NativeMint, |account: &AccountInfo| {
+        check_assert_eq!(*account.key, spl_token::native_mint::ID)?;
+        Ok(())
+    });
+
     declare_validated_account_wrapper!(SigningFeeSweeper, |account: &AccountInfo| {
         check_assert!(account.is_signer)?;
         check_assert_eq!(account.key, &fee_sweeper::ID)?;
@@ -1349,24 +2536,146 @@ pub mod account_parser {
         }
     }
 
-    pub struct InitializeMarketArgs<'a, 'b: 'a> {
-        pub program_id: &'a Pubkey,
-        pub instruction: &'a InitializeMarketInstruction,
-        serum_dex_accounts: &'a [AccountInfo<'b>; 5],
-        pub coin_vault_and_mint: TokenAccountAndMint<'a, 'b>,
-        pub pc_vault_and_mint: TokenAccountAndMint<'a, 'b>,
+    /// The accounts used to pay from, or settle to, a native SOL wallet
+    /// through a temporary wrapped SOL account.
+    #[derive(Copy, Clone)]
+    pub struct NativeSolAccounts<'a, 'b: 'a> {
+        pub wallet: SignerAccount<'a, 'b>,
+        pub temp_account: SignerAccount<'a, 'b>,
+        pub native_mint: NativeMint<'a, 'b>,
+        pub vault_signer: VaultSigner<'a, 'b>,
+        pub system_program: SystemProgram<'a, 'b>,
+        pub rent_sysvar: RentSysvarAccount<'a, 'b>,
     }
 
-    impl<'a, 'b: 'a> InitializeMarketArgs<'a, 'b> {
-        pub fn new(
-            program_id: &'a Pubkey,
-            instruction: &'a InitializeMarketInstruction,
-            accounts: &'a [AccountInfo<'b>],
+    impl<'a, 'b: 'a> NativeSolAccounts<'a, 'b> {
+        #[inline]
+        fn is_native_wallet(account: &AccountInfo) -> bool {
+            *account.owner == system_program::ID
+        }
+
+        fn new(
+            wallet: &'a AccountInfo<'b>,
+            temp_account: &'a AccountInfo<'b>,
+            native_mint: &'a AccountInfo<'b>,
+            vault_signer: VaultSigner<'a, 'b>,
+            system_program: &'a AccountInfo<'b>,
+            rent_sysvar: &'a AccountInfo<'b>,
         ) -> DexResult<Self> {
-            check_assert_eq!(accounts.len(), 9)?;
-            let accounts = array_ref![accounts, 0, 9];
-            let (unchecked_serum_dex_accounts, unchecked_vaults, unchecked_mints) =
-                array_refs![accounts, 5, 2, 2];
+            Ok(NativeSolAccounts {
+                wallet: SignerAccount::new(wallet)
+                    .map_err(|_| DexErrorCode::WrongNativeSolAccounts)?,
+                temp_account: SignerAccount::new(temp_account)
+                    .map_err(|_| DexErrorCode::WrongNativeSolAccounts)?,
+                native_mint: NativeMint::new(native_mint)
+                    .map_err(|_| DexErrorCode::WrongNativeSolAccounts)?,
+                vault_signer,
+                system_program: SystemProgram::new(system_program)
+                    .map_err(|_| DexErrorCode::WrongNativeSolAccounts)?,
+                rent_sysvar: RentSysvarAccount::new(rent_sysvar)?,
+            })
+        }
+
+        /// The temporary account, once `create_wrapped_sol_account` has run.
+        pub fn temp_token_account(self) -> DexResult<TokenAccount<'a, 'b>> {
+            TokenAccount::new(self.temp_account.inner())
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    pub enum OrderPayer<'a, 'b: 'a> {
+        Token(TokenAccount<'a, 'b>),
+        NativeSol(NativeSolAccounts<'a, 'b>),
+    }
+
+    /// Moves the crank reward deposits of new orders from their owner's
+    /// system account to the market. The deposits pay for the cranking every
+    /// order needs, cancelled or not, so they are forfeited: cancelling or
+    /// settling the orders doesn't refund them.
+    pub struct CrankDeposit<'a, 'b: 'a> {
+        pub crank_rewards: RefMut<'a, CrankRewards>,
+        pub market_acc: &'a AccountInfo<'b>,
+        pub owner: SignerAccount<'a, 'b>,
+        pub system_program: Option<SystemProgram<'a, 'b>>,
+    }
+
+    impl<'a, 'b: 'a> CrankDeposit<'a, 'b> {
+        #[cfg(feature = "program")]
+        pub fn collect(&mut self, orders: u64) -> DexResult {
+            let lamports = self
+                .crank_rewards
+                .lamports_per_order
+                .checked_mul(orders)
+                .ok_or(DexErrorCode::InsufficientCrankDeposit)?;
+            if lamports == 0 {
+                return Ok(());
+            }
+            let system_program = self
+                .system_program
+                .ok_or(DexErrorCode::SystemProgramNotProvided)?;
+            let owner = self.owner.inner();
+            if owner.lamports() < lamports {
+                Err(DexErrorCode::InsufficientCrankDeposit)?
+            }
+            let transfer_instruction =
+                solana_sdk::system_instruction::transfer(owner.key, self.market_acc.key, lamports);
+            let accounts: &[AccountInfo] = &[
+                owner.clone(),
+                self.market_acc.clone(),
+                system_program.inner().clone(),
+            ];
+            invoke_system_program(&transfer_instruction, accounts, &[])
+                .map_err(|_| DexErrorCode::TransferFailed)?;
+            self.crank_rewards.deposit(lamports);
+            Ok(())
+        }
+    }
+
+    /// Pays the crank of a market out of the crank rewards it holds.
+    pub struct CrankPayout<'a, 'b: 'a> {
+        pub crank_rewards: RefMut<'a, CrankRewards>,
+        pub market_acc: &'a AccountInfo<'b>,
+        pub recipient: &'a AccountInfo<'b>,
+    }
+
+    impl<'a, 'b: 'a> CrankPayout<'a, 'b> {
+        fn new(
+            crank_rewards: Option<RefMut<'a, CrankRewards>>,
+            market_acc: &'a AccountInfo<'b>,
+            recipient: Option<&'a AccountInfo<'b>>,
+        ) -> Option<Self> {
+            Some(CrankPayout {
+                crank_rewards: crank_rewards?,
+                market_acc,
+                recipient: recipient?,
+            })
+        }
+
+        pub fn pay(&mut self, lamports: u64) -> DexResult {
+            **self.market_acc.try_borrow_mut_lamports()? -= lamports;
+            **self.recipient.try_borrow_mut_lamports()? += lamports;
+            Ok(())
+        }
+    }
+
+    pub struct InitializeMarketArgs<'a, 'b: 'a> {
+        pub program_id: &'a Pubkey,
+        pub instruction: &'a InitializeMarketInstructionV2,
+        serum_dex_accounts: &'a [AccountInfo<'b>; 5],
+        pub coin_vault_and_mint: TokenAccountAndMint<'a, 'b>,
+        pub pc_vault_and_mint: TokenAccountAndMint<'a, 'b>,
+    }
+
+    impl<'a, 'b: 'a> InitializeMarketArgs<'a, 'b> {
+        pub fn new(
+            program_id: &'a Pubkey,
+            instruction: &'a InitializeMarketInstructionV2,
+            accounts: &'a [AccountInfo<'b>],
+        ) -> DexResult<Self> {
+            check_assert_eq!(accounts.len(), 9)?;
+            let accounts = array_ref![accounts, 0, 9];
+            let (unchecked_serum_dex_accounts, unchecked_vaults, unchecked_mints) =
+                array_refs![accounts, 5, 2, 2];
             let mut checked_vaults = [None, None];
             for account in unchecked_serum_dex_accounts {
                 check_assert_eq!(account.owner, program_id)?;
@@ -1440,30 +2749,41 @@ pub mod account_parser {
     }
 
     pub struct NewOrderArgs<'a, 'b: 'a> {
-        pub instruction: &'a NewOrderInstructionV2,
+        pub instruction: &'a NewOrderInstructionV3,
         pub market: &'a mut MarketState,
-        pub open_orders: &'a mut OpenOrders,
+        pub open_orders: OpenOrdersMut<'a>,
         pub open_orders_address: &'a [u64; 4],
         pub owner: SignerAccount<'a, 'b>,
         pub req_q: RequestQueue<'a>,
-        pub payer: TokenAccount<'a, 'b>,
+        pub payer: OrderPayer<'a, 'b>,
         pub coin_vault: CoinVault<'a, 'b>,
         pub pc_vault: PcVault<'a, 'b>,
         pub spl_token_program: SplTokenProgram<'a, 'b>,
         pub fee_tier: FeeTier,
+        pub crank_deposit: Option<CrankDeposit<'a, 'b>>,
+        // The instruction's limit price, or the price a PostOnlySlide order
+        // slides to.
+        pub limit_price: NonZeroU64,
     }
     impl<'a, 'b: 'a> NewOrderArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
-            instruction: &'a NewOrderInstructionV2,
+            instruction: &'a NewOrderInstructionV3,
             accounts: &'a [AccountInfo<'b>],
             f: impl FnOnce(NewOrderArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert!(accounts.len() == 9 || accounts.len() == 10)?;
-            let (fixed_accounts, fee_discount_account): (
+            check_assert!(accounts.len() >= 9 && accounts.len() <= 16)?;
+            let (fixed_accounts, optional_accounts): (
                 &'a [AccountInfo<'b>; 9],
                 &'a [AccountInfo<'b>],
             ) = array_refs![accounts, 9; .. ;];
+            let (book_accounts, optional_accounts) = match instruction.order_type {
+                OrderType::PostOnlySlide => {
+                    check_assert!(optional_accounts.len() >= 2)?;
+                    optional_accounts.split_at(2)
+                }
+                _ => (&[][..], optional_accounts),
+            };
             let &[
                 ref market_acc,
                 ref open_orders_acc,
@@ -1475,13 +2795,30 @@ pub mod account_parser {
                 ref spl_token_program_acc,
                 ref rent_sysvar_acc,
             ]: &'a [AccountInfo<'b>; 9] = fixed_accounts;
+            let pays_native_sol = NativeSolAccounts::is_native_wallet(payer_acc);
+            // Orders paid in native SOL already pass the system program that
+            // crank deposits are taken through.
+            let (optional_accounts, crank_system_program_acc) = match optional_accounts.split_last()
+            {
+                Some((last, rest)) if !pays_native_sol && *last.key == system_program::ID => {
+                    (rest, Some(last))
+                }
+                _ => (optional_accounts, None),
+            };
+            let (fee_discount_account, native_sol_accounts) = if pays_native_sol {
+                check_assert!(optional_accounts.len() >= 4)?;
+                optional_accounts.split_at(optional_accounts.len() - 4)
+            } else {
+                (optional_accounts, &[][..])
+            };
             let srm_or_msrm_account = match fee_discount_account {
                 &[] => None,
                 &[ref account] => Some(TokenAccount::new(account)?),
                 _ => check_unreachable!()?,
             };
 
-            let mut market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
+            let (mut market, crank_rewards) =
+                MarketState::load_with_crank_rewards(market_acc, program_id)?;
             let rent = {
                 let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
                 Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
@@ -1489,7 +2826,7 @@ pub mod account_parser {
             let owner = SignerAccount::new(owner_acc)?;
             let fee_tier =
                 market.load_fee_tier(&owner.inner().key.to_aligned_bytes(), srm_or_msrm_account)?;
-            let mut open_orders = market.load_orders_mut(
+            let open_orders = market.load_orders_mut(
                 open_orders_acc,
                 Some(owner.inner()),
                 program_id,
@@ -1498,19 +2835,75 @@ pub mod account_parser {
             let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
             let req_q = market.load_request_queue_mut(req_q_acc)?;
 
-            let payer = TokenAccount::new(payer_acc)?;
-            match instruction.side {
-                Side::Bid => market.check_pc_payer(payer).or(check_unreachable!())?,
-                Side::Ask => market.check_coin_payer(payer).or(check_unreachable!())?,
+            let limit_price = match book_accounts {
+                &[] => instruction.limit_price,
+                &[ref bids_acc, ref asks_acc] => {
+                    let mut bids = market.load_bids_mut(bids_acc)?;
+                    let mut asks = market.load_asks_mut(asks_acc)?;
+                    let order_book_state = OrderBookState {
+                        bids: bids.deref_mut(),
+                        asks: asks.deref_mut(),
+                        market_state: market.deref_mut(),
+                    };
+                    order_book_state
+                        .slide_price(instruction.side, instruction.limit_price.get())
+                        .unwrap_or(instruction.limit_price)
+                }
+                _ => check_unreachable!()?,
+            };
+
+            let payer = match native_sol_accounts {
+                &[] => {
+                    let payer = TokenAccount::new(payer_acc)?;
+                    match instruction.side {
+                        Side::Bid => market.check_pc_payer(payer).or(check_unreachable!())?,
+                        Side::Ask => market.check_coin_payer(payer).or(check_unreachable!())?,
+                    };
+                    OrderPayer::Token(payer)
+                }
+                &[ref temp_acc, ref native_mint_acc, ref vault_signer_acc, ref system_program_acc] =>
+                {
+                    check_assert_eq!(payer_acc.key, owner_acc.key)
+                        .map_err(|_| DexErrorCode::WrongNativeSolAccounts)?;
+                    let native_mint = spl_token::native_mint::ID.to_aligned_bytes();
+                    let paid_mint = match instruction.side {
+                        Side::Bid => market.pc_mint,
+                        Side::Ask => market.coin_mint,
+                    };
+                    check_assert_eq!(paid_mint, native_mint)
+                        .map_err(|_| DexErrorCode::WrongNativeSolAccounts)?;
+                    let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
+                    OrderPayer::NativeSol(NativeSolAccounts::new(
+                        payer_acc,
+                        temp_acc,
+                        native_mint_acc,
+                        vault_signer,
+                        system_program_acc,
+                        rent_sysvar_acc,
+                    )?)
+                }
+                _ => check_unreachable!()?,
             };
             let coin_vault = CoinVault::from_account(coin_vault_acc, &market)?;
             let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
             market.check_enabled()?;
             let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
+            let crank_system_program = match payer {
+                OrderPayer::NativeSol(native_sol) => Some(native_sol.system_program),
+                OrderPayer::Token(_) => crank_system_program_acc
+                    .map(SystemProgram::new)
+                    .transpose()?,
+            };
+            let crank_deposit = crank_rewards.map(|crank_rewards| CrankDeposit {
+                crank_rewards,
+                market_acc,
+                owner,
+                system_program: crank_system_program,
+            });
             let args = NewOrderArgs {
                 instruction,
                 market: market.deref_mut(),
-                open_orders: open_orders.deref_mut(),
+                open_orders,
                 open_orders_address,
                 owner,
                 req_q,
@@ -1519,19 +2912,163 @@ pub mod account_parser {
                 pc_vault,
                 spl_token_program,
                 fee_tier,
+                crank_deposit,
+                limit_price,
+            };
+            f(args)
+        }
+    }
+
+    pub struct NewOrdersArgs<'a, 'b: 'a> {
+        pub instruction: &'a NewOrdersInstruction,
+        pub market: &'a mut MarketState,
+        pub open_orders: OpenOrdersMut<'a>,
+        pub open_orders_address: &'a [u64; 4],
+        pub owner: SignerAccount<'a, 'b>,
+        pub req_q: RequestQueue<'a>,
+        pub coin_wallet: CoinWallet<'a, 'b>,
+        pub pc_wallet: PcWallet<'a, 'b>,
+        pub coin_vault: CoinVault<'a, 'b>,
+        pub pc_vault: PcVault<'a, 'b>,
+        pub spl_token_program: SplTokenProgram<'a, 'b>,
+        pub fee_tier: FeeTier,
+        pub crank_deposit: Option<CrankDeposit<'a, 'b>>,
+    }
+    impl<'a, 'b: 'a> NewOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a NewOrdersInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(NewOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            if instruction.order_type == OrderType::PostOnlySlide {
+                Err(DexErrorCode::PostOnlySlideUnsupported)?
+            }
+            check_assert!(accounts.len() >= 10 && accounts.len() <= 12)?;
+            let (fixed_accounts, optional_accounts): (
+                &'a [AccountInfo<'b>; 10],
+                &'a [AccountInfo<'b>],
+            ) = array_refs![accounts, 10; .. ;];
+            let (fee_discount_account, system_program_acc) = match optional_accounts.split_last() {
+                Some((last, rest)) if *last.key == system_program::ID => (rest, Some(last)),
+                _ => (optional_accounts, None),
+            };
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref req_q_acc,
+                ref coin_wallet_acc,
+                ref pc_wallet_acc,
+                ref owner_acc,
+                ref coin_vault_acc,
+                ref pc_vault_acc,
+                ref spl_token_program_acc,
+                ref rent_sysvar_acc,
+            ]: &'a [AccountInfo<'b>; 10] = fixed_accounts;
+            let srm_or_msrm_account = match fee_discount_account {
+                &[] => None,
+                &[ref account] => Some(TokenAccount::new(account)?),
+                _ => check_unreachable!()?,
+            };
+
+            let (mut market, crank_rewards) =
+                MarketState::load_with_crank_rewards(market_acc, program_id)?;
+            let rent = {
+                let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+                Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
+            };
+            let owner = SignerAccount::new(owner_acc)?;
+            let fee_tier =
+                market.load_fee_tier(&owner.inner().key.to_aligned_bytes(), srm_or_msrm_account)?;
+            let open_orders = market.load_orders_mut(
+                open_orders_acc,
+                Some(owner.inner()),
+                program_id,
+                Some(rent),
+            )?;
+            let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let coin_wallet = CoinWallet::from_account(coin_wallet_acc, &market)?;
+            let pc_wallet = PcWallet::from_account(pc_wallet_acc, &market)?;
+            let coin_vault = CoinVault::from_account(coin_vault_acc, &market)?;
+            let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
+            market.check_enabled()?;
+            let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
+            let system_program = system_program_acc.map(SystemProgram::new).transpose()?;
+            let crank_deposit = crank_rewards.map(|crank_rewards| CrankDeposit {
+                crank_rewards,
+                market_acc,
+                owner,
+                system_program,
+            });
+            let args = NewOrdersArgs {
+                instruction,
+                market: market.deref_mut(),
+                open_orders,
+                open_orders_address,
+                owner,
+                req_q,
+                coin_wallet,
+                pc_wallet,
+                coin_vault,
+                pc_vault,
+                spl_token_program,
+                fee_tier,
+                crank_deposit,
             };
             f(args)
         }
     }
 
-    pub struct MatchOrdersArgs<'a> {
+    pub struct NewTriggerOrderArgs<'a, 'b: 'a> {
+        pub trigger_price: NonZeroU64,
+        pub trigger_direction: TriggerDirection,
+        pub trigger_orders: TriggerOrders<'a>,
+        pub new_order: NewOrderArgs<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> NewTriggerOrderArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a NewOrderInstructionV4,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(NewTriggerOrderArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            if instruction.order_type == OrderType::PostOnlySlide {
+                Err(DexErrorCode::PostOnlySlideUnsupported)?
+            }
+            let (trigger_orders_acc, new_order_accounts) =
+                accounts.split_last().ok_or(assertion_error!())?;
+            let new_order_instruction = instruction.clone().remove_trigger();
+            NewOrderArgs::with_parsed_args(
+                program_id,
+                &new_order_instruction,
+                new_order_accounts,
+                |new_order| {
+                    let trigger_orders = new_order
+                        .market
+                        .load_trigger_orders_mut(trigger_orders_acc, program_id)?;
+                    let args = NewTriggerOrderArgs {
+                        trigger_price: instruction.trigger_price,
+                        trigger_direction: instruction.trigger_direction,
+                        trigger_orders,
+                        new_order,
+                    };
+                    f(args)
+                },
+            )
+        }
+    }
+
+    pub struct MatchOrdersArgs<'a, 'b: 'a> {
         pub limit: u16,
         pub order_book_state: OrderBookState<'a>,
         pub req_q: RequestQueue<'a>,
         pub event_q: EventQueue<'a>,
+        pub trigger_orders: Option<TriggerOrders<'a>>,
+        pub crank_payout: Option<CrankPayout<'a, 'b>>,
     }
-    impl<'a> MatchOrdersArgs<'a> {
-        pub fn with_parsed_args<'b, T>(
+    impl<'a, 'b: 'a> MatchOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
             accounts: &'a [AccountInfo<'b>],
             limit: u16,
@@ -1546,7 +3083,9 @@ pub mod account_parser {
                 ref bids_acc,
                 ref asks_acc
             ] = array_ref![accounts, 0, 5];
-            let mut market = MarketState::load(market_acc, program_id).or(check_unreachable!())?;
+            let (mut market, crank_rewards) =
+                MarketState::load_with_crank_rewards(market_acc, program_id)
+                    .or(check_unreachable!())?;
             let req_q = market
                 .load_request_queue_mut(req_q_acc)
                 .or(check_unreachable!())?;
@@ -1555,6 +3094,16 @@ pub mod account_parser {
                 .or(check_unreachable!())?;
             let mut bids = market.load_bids_mut(bids_acc).or(check_unreachable!())?;
             let mut asks = market.load_asks_mut(asks_acc).or(check_unreachable!())?;
+            let trigger_orders = if market.has_trigger_orders() {
+                let trigger_orders_acc = accounts
+                    .get(7)
+                    .ok_or(DexErrorCode::WrongTriggerOrdersAccount)?;
+                Some(market.load_trigger_orders_mut(trigger_orders_acc, program_id)?)
+            } else {
+                None
+            };
+            let crank_reward_recipient = accounts.get(7 + trigger_orders.is_some() as usize);
+            let crank_payout = CrankPayout::new(crank_rewards, market_acc, crank_reward_recipient);
 
             let order_book_state = OrderBookState {
                 bids: bids.deref_mut(),
@@ -1567,6 +3116,8 @@ pub mod account_parser {
                 order_book_state,
                 req_q,
                 event_q,
+                trigger_orders,
+                crank_payout,
             };
             f(args)
         }
@@ -1578,6 +3129,7 @@ pub mod account_parser {
         pub open_orders_accounts: &'a [AccountInfo<'b>],
         pub market: &'a mut MarketState,
         pub event_q: EventQueue<'a>,
+        pub crank_payout: Option<CrankPayout<'a, 'b>>,
     }
     impl<'a, 'b: 'a> ConsumeEventsArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
@@ -1595,14 +3147,24 @@ pub mod account_parser {
                 &[ref event_q_acc],
                 _unused
             ) = array_refs![accounts, 0; .. ; 1, 1, 2];
-            let mut market = MarketState::load(market_acc, program_id)?;
+            // OpenOrders accounts are owned by the dex, unlike the account
+            // paid the crank reward
+            let (crank_reward_recipient, open_orders_accounts) =
+                match open_orders_accounts.split_first() {
+                    Some((first, rest)) if first.owner != program_id => (Some(first), rest),
+                    _ => (None, open_orders_accounts),
+                };
+            let (mut market, crank_rewards) =
+                MarketState::load_with_crank_rewards(market_acc, program_id)?;
             let event_q = market.load_event_queue_mut(event_q_acc)?;
+            let crank_payout = CrankPayout::new(crank_rewards, market_acc, crank_reward_recipient);
             let args = ConsumeEventsArgs {
                 limit,
                 program_id,
                 open_orders_accounts,
                 market: market.deref_mut(),
                 event_q,
+                crank_payout,
             };
             f(args)
         }
@@ -1610,7 +3172,7 @@ pub mod account_parser {
 
     pub struct CancelOrderArgs<'a, 'b: 'a> {
         pub instruction: &'a CancelOrderInstruction,
-        pub open_orders: &'a mut OpenOrders,
+        pub open_orders: OpenOrdersMut<'a>,
         pub open_orders_address: &'a [u64; 4],
         pub req_q: RequestQueue<'a>,
         pub orders_owner: SignerAccount<'a, 'b>,
@@ -1632,13 +3194,13 @@ pub mod account_parser {
             ] = array_ref![accounts, 0, 4];
             let market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
             let owner = SignerAccount::new(owner_acc)?;
-            let mut open_orders =
+            let open_orders =
                 market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
             let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
             let req_q = market.load_request_queue_mut(req_q_acc)?;
             let args = CancelOrderArgs {
                 instruction,
-                open_orders: open_orders.deref_mut(),
+                open_orders,
                 open_orders_address,
                 req_q,
                 orders_owner: owner,
@@ -1649,7 +3211,7 @@ pub mod account_parser {
 
     pub struct CancelOrderByClientIdArgs<'a, 'b: 'a> {
         pub client_order_id: NonZeroU64,
-        pub open_orders: &'a mut OpenOrders,
+        pub open_orders: OpenOrdersMut<'a>,
         pub open_orders_address: &'a [u64; 4],
         pub req_q: RequestQueue<'a>,
         pub orders_owner: SignerAccount<'a, 'b>,
@@ -1672,13 +3234,52 @@ pub mod account_parser {
             ] = array_ref![accounts, 0, 4];
             let market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
             let owner = SignerAccount::new(owner_acc)?;
-            let mut open_orders =
+            let open_orders =
                 market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
             let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
             let req_q = market.load_request_queue_mut(req_q_acc)?;
             let args = CancelOrderByClientIdArgs {
                 client_order_id,
-                open_orders: open_orders.deref_mut(),
+                open_orders,
+                open_orders_address,
+                req_q,
+                orders_owner: owner,
+            };
+            f(args)
+        }
+    }
+
+    pub struct CancelOrdersByClientIdsArgs<'a, 'b: 'a> {
+        pub client_order_ids: &'a [u64; MAX_CANCEL_CLIENT_IDS],
+        pub open_orders: OpenOrdersMut<'a>,
+        pub open_orders_address: &'a [u64; 4],
+        pub req_q: RequestQueue<'a>,
+        pub orders_owner: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> CancelOrdersByClientIdsArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            client_order_ids: &'a [u64; MAX_CANCEL_CLIENT_IDS],
+            f: impl FnOnce(CancelOrdersByClientIdsArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 4)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref req_q_acc,
+                ref owner_acc
+            ] = array_ref![accounts, 0, 4];
+            let market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let open_orders =
+                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let args = CancelOrdersByClientIdsArgs {
+                client_order_ids,
+                open_orders,
                 open_orders_address,
                 req_q,
                 orders_owner: owner,
@@ -1690,21 +3291,39 @@ pub mod account_parser {
     pub struct SettleFundsArgs<'a, 'b: 'a> {
         pub market: &'a mut MarketState,
         pub open_orders: &'a mut OpenOrders,
+        pub native_coin_amount: u64,
+        pub native_pc_amount: u64,
         pub coin_vault: CoinVault<'a, 'b>,
         pub pc_vault: PcVault<'a, 'b>,
-        pub coin_wallet: CoinWallet<'a, 'b>,
-        pub pc_wallet: PcWallet<'a, 'b>,
+        // At most one of the wallets is a native SOL wallet, in which case
+        // it is `None` and `native_sol` is set.
+        pub coin_wallet: Option<CoinWallet<'a, 'b>>,
+        pub pc_wallet: Option<PcWallet<'a, 'b>>,
+        pub native_sol: Option<NativeSolAccounts<'a, 'b>>,
         pub vault_signer: VaultSigner<'a, 'b>,
         pub spl_token_program: SplTokenProgram<'a, 'b>,
-        pub referrer: Option<PcWallet<'a, 'b>>,
+        pub referrer: SettleReferrer<'a, 'b>,
+    }
+
+    /// Where the referrer rebates of the OpenOrders account being settled go.
+    pub enum SettleReferrer<'a, 'b: 'a> {
+        /// Any pc wallet passed by whoever settles, or else the market's fees.
+        Unregistered(Option<PcWallet<'a, 'b>>),
+        /// The payout wallet of the account's referrer, and its rebate in
+        /// basis points of the taker fee.
+        Registered(PcWallet<'a, 'b>, u64),
+        /// The account's referrer wasn't passed, so the rebates are kept.
+        Withheld,
     }
     impl<'a, 'b: 'a> SettleFundsArgs<'a, 'b> {
+        /// Settles all free funds, unless `amounts` says how much to settle.
         pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
             accounts: &'a [AccountInfo<'b>],
+            amounts: Option<&'a SettleFundsPartialInstruction>,
             f: impl FnOnce(SettleFundsArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert!(accounts.len() == 9 || accounts.len() == 10)?;
+            check_assert!(accounts.len() >= 9 && accounts.len() <= 15)?;
             #[rustfmt::skip]
             let (&[
                 ref market_acc,
@@ -1724,31 +3343,124 @@ pub mod account_parser {
             let coin_vault =
                 CoinVault::from_account(coin_vault_acc, &market).or(check_unreachable!())?;
             let pc_vault = PcVault::from_account(pc_vault_acc, &market).or(check_unreachable!())?;
-            let coin_wallet =
-                CoinWallet::from_account(coin_wallet_acc, &market).or(check_unreachable!())?;
-            let pc_wallet =
-                PcWallet::from_account(pc_wallet_acc, &market).or(check_unreachable!())?;
+            let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
+            let orders_owner = market
+                .load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?
+                .owner;
+            // a delegate can settle, but only to the wallets of the owner
+            let signed_by_owner = owner_acc.key.to_aligned_bytes() == orders_owner;
+
+            let native_coin = NativeSolAccounts::is_native_wallet(coin_wallet_acc);
+            let native_pc = NativeSolAccounts::is_native_wallet(pc_wallet_acc);
+            let (remaining_accounts, native_sol) = if native_coin || native_pc {
+                check_assert!(!(native_coin && native_pc))?;
+                check_assert!(remaining_accounts.len() >= 4)?;
+                let (remaining_accounts, native_sol_accounts) =
+                    remaining_accounts.split_at(remaining_accounts.len() - 4);
+                let native_wallet_acc = if native_coin {
+                    coin_wallet_acc
+                } else {
+                    pc_wallet_acc
+                };
+                check_assert_eq!(native_wallet_acc.key.to_aligned_bytes(), orders_owner)
+                    .map_err(|_| DexErrorCode::WrongNativeSolAccounts)?;
+                let settled_mint = if native_coin {
+                    market.coin_mint
+                } else {
+                    market.pc_mint
+                };
+                check_assert_eq!(settled_mint, spl_token::native_mint::ID.to_aligned_bytes())
+                    .map_err(|_| DexErrorCode::WrongNativeSolAccounts)?;
+                #[rustfmt::skip]
+                let &[
+                    ref temp_acc,
+                    ref native_mint_acc,
+                    ref system_program_acc,
+                    ref rent_sysvar_acc,
+                ] = array_ref![native_sol_accounts, 0, 4];
+                let native_sol = NativeSolAccounts::new(
+                    native_wallet_acc,
+                    temp_acc,
+                    native_mint_acc,
+                    vault_signer,
+                    system_program_acc,
+                    rent_sysvar_acc,
+                )?;
+                (remaining_accounts, Some(native_sol))
+            } else {
+                (remaining_accounts, None)
+            };
 
-            let referrer = match remaining_accounts {
-                &[] => None,
-                &[ref referrer_acc] => {
-                    Some(PcWallet::from_account(referrer_acc, &market).or(check_unreachable!())?)
+            let coin_wallet = if native_coin {
+                None
+            } else {
+                Some(CoinWallet::from_account(coin_wallet_acc, &market).or(check_unreachable!())?)
+            };
+            let pc_wallet = if native_pc {
+                None
+            } else {
+                Some(PcWallet::from_account(pc_wallet_acc, &market).or(check_unreachable!())?)
+            };
+            if !signed_by_owner {
+                let wallets = coin_wallet
+                    .map(CoinWallet::token_account)
+                    .into_iter()
+                    .chain(pc_wallet.map(PcWallet::token_account));
+                for wallet in wallets {
+                    if &wallet.inner().try_borrow_data()?[32..64]
+                        != transmute_to_bytes(&orders_owner)
+                    {
+                        Err(DexErrorCode::WrongDelegateSettleWallet)?
+                    }
+                }
+            }
+
+            let orders_referrer = ReferredOpenOrders::load_referrer(open_orders_acc)?;
+            let referrer = match (remaining_accounts, orders_referrer) {
+                (&[], None) => SettleReferrer::Unregistered(None),
+                (&[ref referrer_wallet_acc], None) => SettleReferrer::Unregistered(Some(
+                    PcWallet::from_account(referrer_wallet_acc, &market)
+                        .or(check_unreachable!())?,
+                )),
+                (&[], Some(_)) => SettleReferrer::Withheld,
+                (&[ref referrer_wallet_acc, ref referrer_acc], Some(referrer_key)) => {
+                    if referrer_acc.key.to_aligned_bytes() != referrer_key {
+                        Err(DexErrorCode::WrongReferrerAccount)?
+                    }
+                    let referrer = market.load_referrer(referrer_acc, program_id)?;
+                    if referrer_wallet_acc.key.to_aligned_bytes() != referrer.pc_wallet {
+                        Err(DexErrorCode::WrongReferrerWallet)?
+                    }
+                    let pc_wallet = PcWallet::from_account(referrer_wallet_acc, &market)
+                        .or(check_unreachable!())?;
+                    SettleReferrer::Registered(pc_wallet, referrer.rebate_bps)
                 }
+                (&[_], Some(_)) | (&[_, _], None) => Err(DexErrorCode::WrongReferrerAccount)?,
                 _ => check_unreachable!()?,
             };
 
-            let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
-
             let mut open_orders =
                 market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            let (native_coin_amount, native_pc_amount) = match amounts {
+                Some(amounts) => (amounts.native_coin_amount, amounts.native_pc_amount),
+                None => (open_orders.native_coin_free, open_orders.native_pc_free),
+            };
+            if native_coin_amount > open_orders.native_coin_free
+                || native_pc_amount > open_orders.native_pc_free
+            {
+                Err(DexErrorCode::SettleAmountExceedsFree)?
+            }
 
             let args = SettleFundsArgs {
                 market: market.deref_mut(),
                 open_orders: open_orders.deref_mut(),
+                native_coin_amount,
+                native_pc_amount,
                 coin_vault,
                 pc_vault,
                 coin_wallet,
                 pc_wallet,
+                native_sol,
                 vault_signer,
                 spl_token_program,
                 referrer,
@@ -1780,13 +3492,32 @@ pub mod account_parser {
         }
     }
 
+    pub enum FeeVault<'a, 'b: 'a> {
+        Coin(CoinVault<'a, 'b>),
+        Pc(PcVault<'a, 'b>),
+    }
+
+    /// Where the swept fees go, in the currency of the vault swept.
+    pub enum FeeReceivers<'a, 'b: 'a> {
+        /// Markets without a fee split pay all of their fees to an account
+        /// the fee sweeping authority picks.
+        Authorized {
+            fee_receiver: TokenAccount<'a, 'b>,
+            authorization: SigningFeeSweeper<'a, 'b>,
+        },
+        Split {
+            fee_split: RefMut<'a, FeeSplit>,
+            treasury: TokenAccount<'a, 'b>,
+            referrer: TokenAccount<'a, 'b>,
+        },
+    }
+
     pub struct SweepFeesArgs<'a, 'b: 'a> {
-        pub market: &'a mut MarketState,
-        pub pc_vault: PcVault<'a, 'b>,
-        pub fee_receiver: PcWallet<'a, 'b>,
+        pub market: RefMut<'a, MarketState>,
+        pub fee_vault: FeeVault<'a, 'b>,
+        pub fee_receivers: FeeReceivers<'a, 'b>,
         pub vault_signer: VaultSigner<'a, 'b>,
         pub spl_token_program: SplTokenProgram<'a, 'b>,
-        pub authorization: SigningFeeSweeper<'a, 'b>,
     }
     impl<'a, 'b: 'a> SweepFeesArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
@@ -1798,66 +3529,624 @@ pub mod account_parser {
             #[rustfmt::skip]
             let &[
                 ref market_acc,
-                ref pc_vault_acc,
+                ref vault_acc,
                 ref sweep_authority_acc,
-                ref pc_wallet_acc,
+                ref fee_receiver_acc,
                 ref vault_signer_acc,
                 ref spl_token_program
             ] = array_ref![accounts, 0, 6];
 
-            let mut market = MarketState::load(market_acc, program_id)?;
-            let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
-            let fee_receiver = PcWallet::from_account(pc_wallet_acc, &market)?;
+            let layout_version = MarketState::load(market_acc, program_id)?.layout_version();
+            let (market, fee_split) = if layout_version == 2 {
+                let (market, fee_split) =
+                    RefMut::map_split(MarketStateV2::load(market_acc, program_id)?, |market| {
+                        (&mut market.inner, &mut market.fee_split)
+                    });
+                (
+                    market,
+                    Some(fee_split).filter(|fee_split| fee_split.is_set()),
+                )
+            } else {
+                (MarketState::load(market_acc, program_id)?, None)
+            };
+
+            let is_coin_vault = vault_acc.key.to_aligned_bytes() == market.coin_vault;
+            let fee_vault = if is_coin_vault {
+                FeeVault::Coin(CoinVault::from_account(vault_acc, &market)?)
+            } else {
+                FeeVault::Pc(PcVault::from_account(vault_acc, &market)?)
+            };
+            let fee_wallet = |account: &'a AccountInfo<'b>| -> DexResult<TokenAccount<'a, 'b>> {
+                Ok(if is_coin_vault {
+                    CoinWallet::from_account(account, &market)?.token_account()
+                } else {
+                    PcWallet::from_account(account, &market)?.token_account()
+                })
+            };
+            let fee_receivers = match fee_split {
+                None => FeeReceivers::Authorized {
+                    fee_receiver: fee_wallet(fee_receiver_acc)?,
+                    authorization: SigningFeeSweeper::new(sweep_authority_acc)?,
+                },
+                Some(fee_split) => {
+                    // Nobody needs to sign, so the treasury and referrer
+                    // wallets take the places of the authority and receiver.
+                    let (treasury_acc, referrer_acc) = (sweep_authority_acc, fee_receiver_acc);
+                    let (treasury_wallet, referrer_wallet) = if is_coin_vault {
+                        (
+                            fee_split.treasury_coin_wallet,
+                            fee_split.referrer_coin_wallet,
+                        )
+                    } else {
+                        (fee_split.treasury_pc_wallet, fee_split.referrer_pc_wallet)
+                    };
+                    if treasury_acc.key.to_aligned_bytes() != treasury_wallet
+                        || referrer_acc.key.to_aligned_bytes() != referrer_wallet
+                    {
+                        Err(DexErrorCode::WrongFeeWallet)?
+                    }
+                    FeeReceivers::Split {
+                        treasury: fee_wallet(treasury_acc)?,
+                        referrer: fee_wallet(referrer_acc)?,
+                        fee_split,
+                    }
+                }
+            };
             let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
             let spl_token_program = SplTokenProgram::new(spl_token_program)?;
-            let authorization = SigningFeeSweeper::new(sweep_authority_acc)?;
 
             let args = SweepFeesArgs {
-                market: market.deref_mut(),
-                pc_vault,
-                fee_receiver,
+                market,
+                fee_vault,
+                fee_receivers,
                 vault_signer,
                 spl_token_program,
-                authorization,
             };
             f(args)
         }
     }
-}
-
-#[inline]
-fn remove_slop<T: Pod>(bytes: &[u8]) -> &[T] {
-    let slop = bytes.len() % size_of::<T>();
-    let new_len = bytes.len() - slop;
-    cast_slice(&bytes[..new_len])
-}
-
-#[inline]
-fn remove_slop_mut<T: Pod>(bytes: &mut [u8]) -> &mut [T] {
-    let slop = bytes.len() % size_of::<T>();
-    let new_len = bytes.len() - slop;
-    cast_slice_mut(&mut bytes[..new_len])
-}
 
-#[cfg_attr(not(feature = "program"), allow(unused))]
-impl State {
+    pub struct InitOpenOrdersArgs<'a, 'b: 'a> {
+        pub program_id: &'a Pubkey,
+        pub instruction: &'a InitOpenOrdersInstruction,
+        pub market: &'a MarketState,
+        // The address the market account is at now, which differs from its
+        // `own_address` once it has been migrated.
+        pub market_address: &'a Pubkey,
+        pub open_orders_acc: &'a AccountInfo<'b>,
+        pub owner: SignerAccount<'a, 'b>,
+        pub payer: SignerAccount<'a, 'b>,
+        pub system_program: SystemProgram<'a, 'b>,
+        pub rent: Rent,
+        pub referrer: Option<[u64; 4]>,
+    }
+    impl<'a, 'b: 'a> InitOpenOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a InitOpenOrdersInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(InitOpenOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 6 || accounts.len() == 7)?;
+            #[rustfmt::skip]
+            let (&[
+                ref open_orders_acc,
+                ref owner_acc,
+                ref market_acc,
+                ref payer_acc,
+                ref system_program_acc,
+                ref rent_sysvar_acc,
+            ], referrer_accs) = array_refs![accounts, 6; ..;];
+
+            let market = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let payer = SignerAccount::new(payer_acc)?;
+            let system_program = SystemProgram::new(system_program_acc)?;
+            let rent = {
+                let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+                Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
+            };
+
+            let referrer = match referrer_accs {
+                &[] => None,
+                &[ref referrer_acc] => {
+                    market.load_referrer(referrer_acc, program_id)?;
+                    Some(referrer_acc.key.to_aligned_bytes())
+                }
+                _ => check_unreachable!()?,
+            };
+
+            let args = InitOpenOrdersArgs {
+                program_id,
+                instruction,
+                market: market.deref(),
+                market_address: market_acc.key,
+                open_orders_acc,
+                owner,
+                payer,
+                system_program,
+                rent,
+                referrer,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SetOpenOrdersDelegateArgs<'a> {
+        pub open_orders: RefMut<'a, OpenOrdersV2>,
+        pub delegate: Option<[u64; 4]>,
+    }
+    impl<'a> SetOpenOrdersDelegateArgs<'a> {
+        pub fn with_parsed_args<'b, T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SetOpenOrdersDelegateArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 3 || accounts.len() == 4)?;
+            #[rustfmt::skip]
+            let (&[
+                ref open_orders_acc,
+                ref owner_acc,
+                ref market_acc,
+            ], delegate_accs) = array_refs![accounts, 3; ..;];
+
+            let market = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            // unlike the delegate, only the owner can change the delegate
+            let orders_owner = market
+                .load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?
+                .owner;
+            check_assert_eq!(orders_owner, owner_acc.key.to_aligned_bytes())
+                .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
+
+            let (_, data) = strip_header::<[u8; 0], u8>(open_orders_acc, false)?;
+            if data.len() < size_of::<OpenOrdersV2>() {
+                Err(DexErrorCode::DelegateUnsupported)?
+            }
+            let open_orders = RefMut::map(data, |data| {
+                from_bytes_mut(&mut data[..size_of::<OpenOrdersV2>()])
+            });
+            let delegate = match delegate_accs {
+                &[] => None,
+                &[ref delegate_acc] => Some(delegate_acc.key.to_aligned_bytes()),
+                _ => check_unreachable!()?,
+            };
+            let args = SetOpenOrdersDelegateArgs {
+                open_orders,
+                delegate,
+            };
+            f(args)
+        }
+    }
+
+    pub struct InitReferrerArgs<'a, 'b: 'a> {
+        pub rebate_bps: u64,
+        pub referrer: &'a mut Referrer,
+        pub market: &'a MarketState,
+        pub owner: SignerAccount<'a, 'b>,
+        pub pc_wallet: PcWallet<'a, 'b>,
+        pub authorization: SigningFeeSweeper<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> InitReferrerArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            rebate_bps: u16,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(InitReferrerArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 6)?;
+            #[rustfmt::skip]
+            let &[
+                ref referrer_acc,
+                ref market_acc,
+                ref owner_acc,
+                ref pc_wallet_acc,
+                ref authority_acc,
+                ref rent_sysvar_acc,
+            ] = array_ref![accounts, 0, 6];
+
+            let market = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let pc_wallet = PcWallet::from_account(pc_wallet_acc, &market)?;
+            let authorization = SigningFeeSweeper::new(authority_acc)?;
+            let rent = {
+                let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+                Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
+            };
+
+            let rebate_bps = rebate_bps as u64;
+            if rebate_bps > fees::MAX_REFERRER_REBATE_BPS {
+                Err(DexErrorCode::InvalidReferrerRebate)?
+            }
+
+            check_assert_eq!(referrer_acc.owner, program_id)
+                .map_err(|_| DexErrorCode::WrongReferrerAccount)?;
+            if !rent.is_exempt(referrer_acc.lamports(), referrer_acc.data_len()) {
+                Err(DexErrorCode::ReferrerNotRentExempt)?
+            }
+            let (_, data) = strip_header::<[u8; 0], u8>(referrer_acc, true)?;
+            check_assert_eq!(data.len(), size_of::<Referrer>())
+                .map_err(|_| DexErrorCode::WrongReferrerAccount)?;
+            let mut referrer: RefMut<Referrer> = RefMut::map(data, |data| from_bytes_mut(data));
+            if referrer.account_flags != 0 {
+                Err(DexErrorCode::AlreadyInitialized)?
+            }
+
+            let args = InitReferrerArgs {
+                rebate_bps,
+                referrer: referrer.deref_mut(),
+                market: market.deref(),
+                owner,
+                pc_wallet,
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct InitTriggerOrdersArgs<'a, 'b: 'a> {
+        pub trigger_orders: RefMut<'a, TriggerOrdersHeader>,
+        pub market: &'a mut MarketState,
+        pub authorization: SigningFeeSweeper<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> InitTriggerOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(InitTriggerOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 4)?;
+            #[rustfmt::skip]
+            let &[
+                ref trigger_orders_acc,
+                ref market_acc,
+                ref authority_acc,
+                ref rent_sysvar_acc,
+            ] = array_ref![accounts, 0, 4];
+
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authorization = SigningFeeSweeper::new(authority_acc)?;
+            let rent = {
+                let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+                Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
+            };
+            if market.has_trigger_orders() {
+                Err(DexErrorCode::AlreadyInitialized)?
+            }
+
+            check_assert_eq!(trigger_orders_acc.owner, program_id)
+                .map_err(|_| DexErrorCode::WrongTriggerOrdersAccount)?;
+            let min_len = ACCOUNT_HEAD_PADDING.len()
+                + size_of::<TriggerOrdersHeader>()
+                + size_of::<TriggerOrder>()
+                + ACCOUNT_TAIL_PADDING.len();
+            if trigger_orders_acc.data_len() < min_len {
+                Err(DexErrorCode::WrongTriggerOrdersAccount)?
+            }
+            if !rent.is_exempt(trigger_orders_acc.lamports(), trigger_orders_acc.data_len()) {
+                Err(DexErrorCode::TriggerOrdersNotRentExempt)?
+            }
+            let (trigger_orders, _) =
+                strip_header::<TriggerOrdersHeader, TriggerOrder>(trigger_orders_acc, true)?;
+            if trigger_orders.account_flags != 0 {
+                Err(DexErrorCode::AlreadyInitialized)?
+            }
+
+            let args = InitTriggerOrdersArgs {
+                trigger_orders,
+                market: market.deref_mut(),
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SetCrankRewardArgs<'a, 'b: 'a> {
+        pub lamports_per_order: u64,
+        pub market: RefMut<'a, MarketStateV2>,
+        pub authorization: SigningFeeSweeper<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> SetCrankRewardArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            lamports_per_order: u64,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SetCrankRewardArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 2)?;
+            let &[ref market_acc, ref authority_acc] = array_ref![accounts, 0, 2];
+            let market = MarketStateV2::load(market_acc, program_id)?;
+            let authorization = SigningFeeSweeper::new(authority_acc)?;
+
+            let args = SetCrankRewardArgs {
+                lamports_per_order,
+                market,
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SetFeeSplitArgs<'a, 'b: 'a> {
+        pub instruction: &'a SetFeeSplitInstruction,
+        pub market: RefMut<'a, MarketStateV2>,
+        pub authorization: SigningFeeSweeper<'a, 'b>,
+        pub treasury_coin_wallet: CoinWallet<'a, 'b>,
+        pub treasury_pc_wallet: PcWallet<'a, 'b>,
+        pub referrer_coin_wallet: CoinWallet<'a, 'b>,
+        pub referrer_pc_wallet: PcWallet<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> SetFeeSplitArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a SetFeeSplitInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SetFeeSplitArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 6)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref authority_acc,
+                ref treasury_coin_wallet_acc,
+                ref treasury_pc_wallet_acc,
+                ref referrer_coin_wallet_acc,
+                ref referrer_pc_wallet_acc,
+            ] = array_ref![accounts, 0, 6];
+            let market = MarketStateV2::load(market_acc, program_id)?;
+            let authorization = SigningFeeSweeper::new(authority_acc)?;
+            let treasury_coin_wallet = CoinWallet::from_account(treasury_coin_wallet_acc, &market)?;
+            let treasury_pc_wallet = PcWallet::from_account(treasury_pc_wallet_acc, &market)?;
+            let referrer_coin_wallet = CoinWallet::from_account(referrer_coin_wallet_acc, &market)?;
+            let referrer_pc_wallet = PcWallet::from_account(referrer_pc_wallet_acc, &market)?;
+
+            let args = SetFeeSplitArgs {
+                instruction,
+                market,
+                authorization,
+                treasury_coin_wallet,
+                treasury_pc_wallet,
+                referrer_coin_wallet,
+                referrer_pc_wallet,
+            };
+            f(args)
+        }
+    }
+
+    pub struct MigrateMarketArgs<'a, 'b: 'a> {
+        pub market: RefMut<'a, MarketState>,
+        pub new_market: RefMut<'a, MarketStateV2>,
+        pub authorization: SigningFeeSweeper<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> MigrateMarketArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(MigrateMarketArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 4)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref new_market_acc,
+                ref authority_acc,
+                ref rent_sysvar_acc,
+            ] = array_ref![accounts, 0, 4];
+
+            let market = MarketState::load(market_acc, program_id)?;
+            if market.layout_version() != 1 {
+                Err(DexErrorCode::InvalidMarketLayoutVersion)?
+            }
+            let authorization = SigningFeeSweeper::new(authority_acc)?;
+            let rent = {
+                let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+                Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
+            };
+
+            check_assert_eq!(new_market_acc.owner, program_id)?;
+            let min_len = ACCOUNT_HEAD_PADDING.len()
+                + size_of::<MarketStateV2>()
+                + ACCOUNT_TAIL_PADDING.len();
+            if new_market_acc.data_len() < min_len {
+                Err(DexErrorCode::MarketAccountTooSmall)?
+            }
+            if !rent.is_exempt(new_market_acc.lamports(), new_market_acc.data_len()) {
+                Err(DexErrorCode::MarketNotRentExempt)?
+            }
+            let (new_market, _) = strip_header::<MarketStateV2, u8>(new_market_acc, true)?;
+            if new_market.account_flags != 0 {
+                Err(DexErrorCode::AlreadyInitialized)?
+            }
+
+            let args = MigrateMarketArgs {
+                market,
+                new_market,
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct RouteMarket<'a, 'b: 'a> {
+        pub market: RefMut<'a, MarketState>,
+        pub req_q: RequestQueue<'a>,
+        pub event_q: EventQueue<'a>,
+        pub bids: RefMut<'a, Slab>,
+        pub asks: RefMut<'a, Slab>,
+        pub coin_vault: CoinVault<'a, 'b>,
+        pub pc_vault: PcVault<'a, 'b>,
+        pub vault_signer: VaultSigner<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> RouteMarket<'a, 'b> {
+        fn new(program_id: &'a Pubkey, accounts: &'a [AccountInfo<'b>; 8]) -> DexResult<Self> {
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref req_q_acc,
+                ref event_q_acc,
+                ref bids_acc,
+                ref asks_acc,
+                ref coin_vault_acc,
+                ref pc_vault_acc,
+                ref vault_signer_acc,
+            ] = accounts;
+            let market = MarketState::load(market_acc, program_id)?;
+            market.check_enabled()?;
+            if market.has_trigger_orders() {
+                Err(DexErrorCode::TriggerOrdersUnsupported)?
+            }
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let event_q = market.load_event_queue_mut(event_q_acc)?;
+            let bids = market.load_bids_mut(bids_acc)?;
+            let asks = market.load_asks_mut(asks_acc)?;
+            let coin_vault = CoinVault::from_account(coin_vault_acc, &market)?;
+            let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
+            let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
+            Ok(RouteMarket {
+                market,
+                req_q,
+                event_q,
+                bids,
+                asks,
+                coin_vault,
+                pc_vault,
+                vault_signer,
+            })
+        }
+
+        fn check_input_wallet(&self, side: Side, wallet: TokenAccount) -> DexResult {
+            match side {
+                Side::Bid => self.market.check_pc_payer(wallet),
+                Side::Ask => self.market.check_coin_payer(wallet),
+            }
+        }
+
+        fn check_output_wallet(&self, side: Side, wallet: TokenAccount) -> DexResult {
+            match side {
+                Side::Bid => self.market.check_coin_payer(wallet),
+                Side::Ask => self.market.check_pc_payer(wallet),
+            }
+        }
+    }
+
+    pub struct RouteArgs<'a, 'b: 'a> {
+        pub instruction: &'a RouteInstruction,
+        pub first_market: RouteMarket<'a, 'b>,
+        pub second_market: RouteMarket<'a, 'b>,
+        pub owner: SignerAccount<'a, 'b>,
+        pub source_wallet: TokenAccount<'a, 'b>,
+        pub intermediate_wallet: TokenAccount<'a, 'b>,
+        pub destination_wallet: TokenAccount<'a, 'b>,
+        pub spl_token_program: SplTokenProgram<'a, 'b>,
+        pub fee_tier: FeeTier,
+    }
+    impl<'a, 'b: 'a> RouteArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a RouteInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(RouteArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 21 || accounts.len() == 22)?;
+            #[rustfmt::skip]
+            let (first_market_accs, second_market_accs, &[
+                ref owner_acc,
+                ref source_wallet_acc,
+                ref intermediate_wallet_acc,
+                ref destination_wallet_acc,
+                ref spl_token_program_acc,
+            ], fee_discount_account) = array_refs![accounts, 8, 8, 5; ..;];
+
+            let first_market = RouteMarket::new(program_id, first_market_accs)?;
+            let second_market = RouteMarket::new(program_id, second_market_accs)?;
+            let owner = SignerAccount::new(owner_acc)?;
+
+            let source_wallet = TokenAccount::new(source_wallet_acc)?;
+            first_market.check_input_wallet(instruction.first_side, source_wallet)?;
+            let intermediate_wallet = TokenAccount::new(intermediate_wallet_acc)?;
+            first_market.check_output_wallet(instruction.first_side, intermediate_wallet)?;
+            second_market.check_input_wallet(instruction.second_side, intermediate_wallet)?;
+            let destination_wallet = TokenAccount::new(destination_wallet_acc)?;
+            second_market.check_output_wallet(instruction.second_side, destination_wallet)?;
+
+            let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
+            let srm_or_msrm_account = match fee_discount_account {
+                &[] => None,
+                &[ref account] => Some(TokenAccount::new(account)?),
+                _ => check_unreachable!()?,
+            };
+            let fee_tier = first_market
+                .market
+                .load_fee_tier(&owner.inner().key.to_aligned_bytes(), srm_or_msrm_account)?;
+
+            let args = RouteArgs {
+                instruction,
+                first_market,
+                second_market,
+                owner,
+                source_wallet,
+                intermediate_wallet,
+                destination_wallet,
+                spl_token_program,
+                fee_tier,
+            };
+            f(args)
+        }
+    }
+}
+
+#[inline]
+pub(crate) fn remove_slop<T: Pod>(bytes: &[u8]) -> &[T] {
+    let slop = bytes.len() % size_of::<T>();
+    let new_len = bytes.len() - slop;
+    cast_slice(&bytes[..new_len])
+}
+
+#[inline]
+fn remove_slop_mut<T: Pod>(bytes: &mut [u8]) -> &mut [T] {
+    let slop = bytes.len() % size_of::<T>();
+    let new_len = bytes.len() - slop;
+    cast_slice_mut(&mut bytes[..new_len])
+}
+
+#[cfg_attr(not(feature = "program"), allow(unused))]
+impl State {
     #[cfg(feature = "program")]
     pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> DexResult {
         let instruction = MarketInstruction::unpack(input).ok_or(ProgramError::InvalidArgument)?;
         match instruction {
-            MarketInstruction::InitializeMarket(ref inner) => Self::process_initialize_market(
+            MarketInstruction::InitializeMarket(ref inner) => {
+                let initialize_market_v2 = inner.clone().add_fee_currency(FeeCurrency::Pc);
+                Self::process_initialize_market(account_parser::InitializeMarketArgs::new(
+                    program_id,
+                    &initialize_market_v2,
+                    accounts,
+                )?)?
+            }
+            MarketInstruction::InitializeMarketV2(ref inner) => Self::process_initialize_market(
                 account_parser::InitializeMarketArgs::new(program_id, inner, accounts)?,
             )?,
             MarketInstruction::NewOrder(inner) => {
-                let new_order_v2 = inner.add_self_trade_behavior(SelfTradeBehavior::DecrementTake);
+                let new_order_v3 = inner
+                    .add_self_trade_behavior(SelfTradeBehavior::DecrementTake)
+                    .add_reject_duplicate_client_id(false);
+                account_parser::NewOrderArgs::with_parsed_args(
+                    program_id,
+                    &new_order_v3,
+                    accounts,
+                    Self::process_new_order,
+                )?
+            }
+            MarketInstruction::NewOrderV2(inner) => {
+                let new_order_v3 = inner.add_reject_duplicate_client_id(false);
                 account_parser::NewOrderArgs::with_parsed_args(
                     program_id,
-                    &new_order_v2,
+                    &new_order_v3,
                     accounts,
                     Self::process_new_order,
                 )?
             }
-            MarketInstruction::NewOrderV2(ref inner) => {
+            MarketInstruction::NewOrderV3(ref inner) => {
                 account_parser::NewOrderArgs::with_parsed_args(
                     program_id,
                     inner,
@@ -1865,6 +4154,36 @@ impl State {
                     Self::process_new_order,
                 )?
             }
+            MarketInstruction::NewOrderV4(ref inner) => {
+                account_parser::NewTriggerOrderArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_new_trigger_order,
+                )?
+            }
+            MarketInstruction::InitTriggerOrders => {
+                account_parser::InitTriggerOrdersArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_init_trigger_orders,
+                )?
+            }
+            MarketInstruction::NewOrders(ref inner) => {
+                account_parser::NewOrdersArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_new_orders,
+                )?
+            }
+            MarketInstruction::SetOpenOrdersDelegate => {
+                account_parser::SetOpenOrdersDelegateArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_set_open_orders_delegate,
+                )?
+            }
             MarketInstruction::MatchOrders(limit) => {
                 account_parser::MatchOrdersArgs::with_parsed_args(
                     program_id,
@@ -1881,6 +4200,14 @@ impl State {
                     Self::process_consume_events,
                 )?
             }
+            MarketInstruction::ConsumeEventsPermissive(limit) => {
+                account_parser::ConsumeEventsArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    limit,
+                    Self::process_consume_events_permissive,
+                )?
+            }
             MarketInstruction::CancelOrder(ref inner) => {
                 account_parser::CancelOrderArgs::with_parsed_args(
                     program_id,
@@ -1892,8 +4219,17 @@ impl State {
             MarketInstruction::SettleFunds => account_parser::SettleFundsArgs::with_parsed_args(
                 program_id,
                 accounts,
+                None,
                 Self::process_settle_funds,
             )?,
+            MarketInstruction::SettleFundsPartial(ref inner) => {
+                account_parser::SettleFundsArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Some(inner),
+                    Self::process_settle_funds,
+                )?
+            }
             MarketInstruction::CancelOrderByClientId(client_id) => {
                 account_parser::CancelOrderByClientIdArgs::with_parsed_args(
                     program_id,
@@ -1914,6 +4250,59 @@ impl State {
                 accounts,
                 Self::process_sweep_fees,
             )?,
+            MarketInstruction::MigrateMarket => {
+                account_parser::MigrateMarketArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_migrate_market,
+                )?
+            }
+            MarketInstruction::InitOpenOrders(ref inner) => {
+                account_parser::InitOpenOrdersArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_init_open_orders,
+                )?
+            }
+            MarketInstruction::Route(ref inner) => account_parser::RouteArgs::with_parsed_args(
+                program_id,
+                inner,
+                accounts,
+                Self::process_route,
+            )?,
+            MarketInstruction::CancelOrdersByClientIds(ref client_ids) => {
+                account_parser::CancelOrdersByClientIdsArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    client_ids,
+                    Self::process_cancel_orders_by_client_ids,
+                )?
+            }
+            MarketInstruction::InitReferrer(rebate_bps) => {
+                account_parser::InitReferrerArgs::with_parsed_args(
+                    program_id,
+                    rebate_bps,
+                    accounts,
+                    Self::process_init_referrer,
+                )?
+            }
+            MarketInstruction::SetCrankReward(lamports_per_order) => {
+                account_parser::SetCrankRewardArgs::with_parsed_args(
+                    program_id,
+                    lamports_per_order,
+                    accounts,
+                    Self::process_set_crank_reward,
+                )?
+            }
+            MarketInstruction::SetFeeSplit(ref inner) => {
+                account_parser::SetFeeSplitArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_set_fee_split,
+                )?
+            }
         };
         Ok(())
     }
@@ -1923,23 +4312,23 @@ impl State {
         let account_parser::SettleFundsArgs {
             market,
             mut open_orders,
+            native_coin_amount,
+            native_pc_amount,
             coin_vault,
             pc_vault,
             coin_wallet,
             pc_wallet,
+            native_sol,
             vault_signer,
             spl_token_program,
             referrer,
         } = args;
 
-        let native_coin_amount = open_orders.native_coin_free;
-        let native_pc_amount = open_orders.native_pc_free;
-
         market.coin_deposits_total -= native_coin_amount;
         market.pc_deposits_total -= native_pc_amount;
 
-        open_orders.native_coin_free = 0;
-        open_orders.native_pc_free = 0;
+        open_orders.native_coin_free -= native_coin_amount;
+        open_orders.native_pc_free -= native_pc_amount;
 
         open_orders.native_coin_total = open_orders
             .native_coin_total
@@ -1952,17 +4341,17 @@ impl State {
 
         let token_infos: [(
             u64,
-            account_parser::TokenAccount,
+            Option<account_parser::TokenAccount>,
             account_parser::TokenAccount,
         ); 2] = [
             (
                 native_coin_amount,
-                coin_wallet.token_account(),
+                coin_wallet.map(|wallet| wallet.token_account()),
                 coin_vault.token_account(),
             ),
             (
                 native_pc_amount,
-                pc_wallet.token_account(),
+                pc_wallet.map(|wallet| wallet.token_account()),
                 pc_vault.token_account(),
             ),
         ];
@@ -1971,32 +4360,59 @@ impl State {
         let vault_signer_seeds = gen_vault_signer_seeds(&market.vault_signer_nonce, &market_pubkey);
 
         for &(token_amount, wallet_account, vault) in token_infos.iter() {
-            send_from_vault(
-                token_amount,
-                wallet_account,
-                vault,
-                spl_token_program,
-                vault_signer,
-                &vault_signer_seeds,
-            )?;
+            match (wallet_account, native_sol) {
+                (Some(wallet_account), _) => send_from_vault(
+                    token_amount,
+                    wallet_account,
+                    vault,
+                    spl_token_program,
+                    vault_signer,
+                    &vault_signer_seeds,
+                )?,
+                (None, Some(native_sol)) if token_amount > 0 => {
+                    // unwrap into the owner's system account through a temporary account
+                    create_wrapped_sol_account(0, native_sol, spl_token_program)?;
+                    send_from_vault(
+                        token_amount,
+                        native_sol.temp_token_account()?,
+                        vault,
+                        spl_token_program,
+                        vault_signer,
+                        &vault_signer_seeds,
+                    )?;
+                    close_wrapped_sol_account(native_sol, spl_token_program, &vault_signer_seeds)?;
+                }
+                (None, _) => (),
+            }
         }
 
-        match referrer {
-            Some(referrer_pc_wallet) if open_orders.referrer_rebates_accrued > 0 => {
+        let referrer_rebates = open_orders.referrer_rebates_accrued;
+        let referrer_payout = match referrer {
+            account_parser::SettleReferrer::Unregistered(pc_wallet) => {
+                pc_wallet.map(|pc_wallet| (pc_wallet, referrer_rebates))
+            }
+            account_parser::SettleReferrer::Registered(pc_wallet, rebate_bps) => Some((
+                pc_wallet,
+                fees::registered_referrer_rebate(referrer_rebates, rebate_bps),
+            )),
+            account_parser::SettleReferrer::Withheld => return Ok(()),
+        };
+        let paid_rebates = match referrer_payout {
+            Some((referrer_pc_wallet, rebate)) if rebate > 0 => {
                 send_from_vault(
-                    open_orders.referrer_rebates_accrued,
+                    rebate,
                     referrer_pc_wallet.token_account(),
                     pc_vault.token_account(),
                     spl_token_program,
                     vault_signer,
                     &vault_signer_seeds,
                 )?;
+                rebate
             }
-            _ => {
-                market.pc_fees_accrued += open_orders.referrer_rebates_accrued;
-            }
+            _ => 0,
         };
-        market.referrer_rebates_accrued -= open_orders.referrer_rebates_accrued;
+        market.pc_fees_accrued += referrer_rebates - paid_rebates;
+        market.referrer_rebates_accrued -= referrer_rebates;
         open_orders.referrer_rebates_accrued = 0;
 
         Ok(())
@@ -2012,18 +4428,13 @@ impl State {
             mut req_q,
             orders_owner: _,
         } = args;
-        let mut slot = None;
-        for (i, ith_client_order_id) in open_orders.client_order_ids.iter().enumerate() {
-            if *ith_client_order_id == client_order_id.get() && !open_orders.slot_is_free(i as u8) {
-                slot = Some(i);
-                break;
-            }
-        }
-        let expected_open_orders_slot = slot.ok_or(DexErrorCode::ClientIdNotFound)? as u8;
+        let expected_open_orders_slot = open_orders
+            .client_order_id_slot(client_order_id)
+            .ok_or(DexErrorCode::ClientIdNotFound)?;
         let side = open_orders
             .slot_side(expected_open_orders_slot)
             .ok_or(DexErrorCode::ClientIdNotFound)?;
-        let ref order_id = open_orders.orders[expected_open_orders_slot as usize];
+        let ref order_id = open_orders.order_id(expected_open_orders_slot)?;
         let request = Request::new(RequestView::CancelOrder {
             cancel_id: req_q.gen_seq_num(),
             expected_owner: open_orders_address,
@@ -2038,14 +4449,57 @@ impl State {
         Ok(())
     }
 
+    fn process_cancel_orders_by_client_ids(
+        args: account_parser::CancelOrdersByClientIdsArgs,
+    ) -> DexResult {
+        let account_parser::CancelOrdersByClientIdsArgs {
+            client_order_ids,
+            open_orders,
+            open_orders_address,
+            mut req_q,
+            orders_owner: _,
+        } = args;
+        for client_order_id in client_order_ids.iter().copied().filter_map(NonZeroU64::new) {
+            // orders placed without the duplicate check may share a client id
+            for slot in open_orders.slots() {
+                if open_orders.client_order_id(slot) != client_order_id.get() {
+                    continue;
+                }
+                let side = match open_orders.slot_side(slot) {
+                    Some(side) => side,
+                    None => continue,
+                };
+                let request = Request::new(RequestView::CancelOrder {
+                    cancel_id: req_q.gen_seq_num(),
+                    expected_owner: open_orders_address,
+                    expected_owner_slot: slot,
+                    order_id: &open_orders.order_id(slot)?,
+                    side,
+                    client_order_id: Some(client_order_id),
+                });
+                req_q
+                    .push_back(request)
+                    .map_err(|_| DexErrorCode::RequestQueueFull)?;
+            }
+        }
+        Ok(())
+    }
+
     fn process_cancel_order(args: account_parser::CancelOrderArgs) -> DexResult {
         let account_parser::CancelOrderArgs {
             instruction,
-            open_orders: _,
+            open_orders,
             open_orders_address,
             mut req_q,
             orders_owner: _,
         } = args;
+        // A triggered order keeps its previous id here until its `Triggered`
+        // event is consumed, and the matching engine maps it to the new one.
+        if open_orders.slot_side(instruction.owner_slot) != Some(instruction.side)
+            || open_orders.order_id(instruction.owner_slot)? != instruction.order_id
+        {
+            Err(DexErrorCode::OrderIdNotFound)?
+        }
 
         let request = Request::new(RequestView::CancelOrder {
             cancel_id: req_q.gen_seq_num(),
@@ -2068,113 +4522,186 @@ impl State {
             open_orders_accounts,
             market,
             mut event_q,
+            crank_payout,
         } = args;
 
+        let fee_currency = market.fee_currency();
+        let mut events_consumed = 0;
         for _i in 0u16..limit {
             let event = match event_q.peek_front() {
                 None => break,
                 Some(e) => e,
             };
 
-            let view = event.as_view()?;
             let owner: [u64; 4] = event.owner;
             let owner_index: Result<usize, usize> = open_orders_accounts
                 .binary_search_by_key(&owner, |account_info| account_info.key.to_aligned_bytes());
-            let mut open_orders: RefMut<OpenOrders> = match owner_index {
+            let mut open_orders: OpenOrdersMut = match owner_index {
                 Err(_) => break,
                 Ok(i) => {
                     market.load_orders_mut(&open_orders_accounts[i], None, program_id, None)?
                 }
             };
+            Self::consume_event(&mut open_orders, event, fee_currency)?;
 
-            check_assert!(event.owner_slot < 128)?;
-            check_assert_eq!(&open_orders.slot_side(event.owner_slot), &Some(view.side()))?;
-            check_assert_eq!(
-                &open_orders.orders[event.owner_slot as usize],
-                &event.order_id
-            )?;
+            event_q
+                .pop_front()
+                .map_err(|()| DexErrorCode::ConsumeEventsQueueFailure)?;
+            events_consumed += 1;
+        }
+        Self::pay_consume_reward(crank_payout, events_consumed, event_q.len())
+    }
 
-            match event.as_view()? {
-                EventView::Fill {
-                    side,
-                    maker,
-                    native_qty_paid,
-                    native_qty_received,
-                    native_fee_or_rebate,
-                    fee_tier: _,
-                    order_id: _,
-                    owner: _,
-                    owner_slot,
-                    client_order_id,
-                } => {
-                    match side {
-                        Side::Bid => {
-                            open_orders.native_pc_total -= native_qty_paid;
-                            open_orders.native_coin_total += native_qty_received;
-                            open_orders.native_coin_free += native_qty_received;
-
-                            if maker {
-                                open_orders.native_pc_free += native_fee_or_rebate;
-                            }
-                        }
-                        Side::Ask => {
-                            open_orders.native_coin_total -= native_qty_paid;
-                            open_orders.native_pc_total += native_qty_received;
-                            open_orders.native_pc_free += native_qty_received;
+    fn process_consume_events_permissive(args: account_parser::ConsumeEventsArgs) -> DexResult {
+        let account_parser::ConsumeEventsArgs {
+            limit,
+            program_id,
+            open_orders_accounts,
+            market,
+            mut event_q,
+            crank_payout,
+        } = args;
+
+        let fee_currency = market.fee_currency();
+        let window = event_q.len().min(limit as u64);
+        let owner_index = |event: &Event| {
+            open_orders_accounts
+                .binary_search_by_key(&event.owner, |account_info| {
+                    account_info.key.to_aligned_bytes()
+                })
+                .ok()
+        };
+        for event in event_q.iter().take(window as usize) {
+            if let Some(i) = owner_index(event) {
+                let mut open_orders =
+                    market.load_orders_mut(&open_orders_accounts[i], None, program_id, None)?;
+                Self::consume_event(&mut open_orders, event, fee_currency)?;
+            }
+        }
+        let events_consumed =
+            event_q.remove_front_where(window, |_, event| owner_index(event).is_some())?;
+        Self::pay_consume_reward(crank_payout, events_consumed, event_q.len())
+    }
+
+    fn pay_consume_reward(
+        crank_payout: Option<account_parser::CrankPayout>,
+        events_consumed: u64,
+        events_pending: u64,
+    ) -> DexResult {
+        if let Some(mut crank_payout) = crank_payout {
+            let reward = CrankRewards::take_reward(
+                &mut crank_payout.crank_rewards.consume_pool,
+                events_consumed,
+                events_pending,
+            );
+            crank_payout.pay(reward)?;
+        }
+        Ok(())
+    }
+
+    /// Applies an event to the OpenOrders account that owns it.
+    fn consume_event(
+        open_orders: &mut OpenOrdersMut,
+        event: &Event,
+        fee_currency: FeeCurrency,
+    ) -> DexResult {
+        let view = event.as_view()?;
+        check_assert!((event.owner_slot as usize) < open_orders.capacity())?;
+        check_assert_eq!(&open_orders.slot_side(event.owner_slot), &Some(view.side()))?;
+        let slot_order_id = match view {
+            EventView::Triggered {
+                previous_order_id, ..
+            } => previous_order_id,
+            _ => event.order_id,
+        };
+        check_assert_eq!(&open_orders.order_id(event.owner_slot)?, &slot_order_id)?;
+
+        match event.as_view()? {
+            EventView::Fill {
+                side,
+                maker,
+                native_qty_paid,
+                native_qty_received,
+                native_fee_or_rebate,
+                fee_tier: _,
+                order_id: _,
+                owner: _,
+                owner_slot,
+                client_order_id,
+            } => {
+                match side {
+                    Side::Bid => {
+                        open_orders.native_pc_total -= native_qty_paid;
+                        open_orders.native_coin_total += native_qty_received;
+                        open_orders.native_coin_free += native_qty_received;
+
+                        if maker {
+                            open_orders.native_pc_free += native_fee_or_rebate;
                         }
-                    };
-                    if !maker {
-                        let referrer_rebate = fees::referrer_rebate(native_fee_or_rebate);
-                        open_orders.referrer_rebates_accrued += referrer_rebate;
                     }
-                    if let Some(client_id) = client_order_id {
-                        debug_assert_eq!(
-                            client_id.get(),
-                            open_orders.client_order_ids[owner_slot as usize]
-                        );
+                    Side::Ask => {
+                        open_orders.native_coin_total -= native_qty_paid;
+                        open_orders.native_pc_total += native_qty_received;
+                        open_orders.native_pc_free += native_qty_received;
+
+                        if maker && fee_currency == FeeCurrency::Coin {
+                            open_orders.native_coin_free += native_fee_or_rebate;
+                        }
                     }
+                };
+                // Referrer rebates are paid out of the pc vault, so fees
+                // taken in coin don't earn any.
+                let fee_in_pc = side == Side::Ask || fee_currency == FeeCurrency::Pc;
+                if !maker && fee_in_pc {
+                    let referrer_rebate = fees::referrer_rebate(native_fee_or_rebate);
+                    open_orders.referrer_rebates_accrued += referrer_rebate;
                 }
-                EventView::Out {
-                    side,
-                    native_qty_unlocked,
-                    native_qty_still_locked,
-                    order_id: _,
-                    owner: _,
-                    owner_slot,
-                    client_order_id,
-                } => {
-                    let fully_out = native_qty_still_locked == 0;
-
-                    match side {
-                        Side::Bid => {
-                            open_orders.native_pc_free += native_qty_unlocked;
-                            check_assert!(
-                                open_orders.native_pc_free <= open_orders.native_pc_total
-                            )?;
-                        }
-                        Side::Ask => {
-                            open_orders.native_coin_free += native_qty_unlocked;
-                            check_assert!(
-                                open_orders.native_coin_free <= open_orders.native_coin_total
-                            )?;
-                        }
-                    };
-                    if let Some(client_id) = client_order_id {
-                        debug_assert_eq!(
-                            client_id.get(),
-                            open_orders.client_order_ids[owner_slot as usize]
-                        );
+                if let Some(client_id) = client_order_id {
+                    debug_assert_eq!(client_id.get(), open_orders.client_order_id(owner_slot));
+                }
+            }
+            EventView::Out {
+                side,
+                native_qty_unlocked,
+                native_qty_still_locked,
+                order_id: _,
+                owner: _,
+                owner_slot,
+                client_order_id,
+            } => {
+                let fully_out = native_qty_still_locked == 0;
+
+                match side {
+                    Side::Bid => {
+                        open_orders.native_pc_free += native_qty_unlocked;
+                        check_assert!(open_orders.native_pc_free <= open_orders.native_pc_total)?;
                     }
-                    if fully_out {
-                        open_orders.remove_order(owner_slot)?;
+                    Side::Ask => {
+                        open_orders.native_coin_free += native_qty_unlocked;
+                        check_assert!(
+                            open_orders.native_coin_free <= open_orders.native_coin_total
+                        )?;
                     }
+                };
+                if let Some(client_id) = client_order_id {
+                    debug_assert_eq!(client_id.get(), open_orders.client_order_id(owner_slot));
+                }
+                if fully_out {
+                    open_orders.remove_order(owner_slot)?;
                 }
-            };
-
-            event_q
-                .pop_front()
-                .map_err(|()| DexErrorCode::ConsumeEventsQueueFailure)?;
-        }
+            }
+            EventView::Triggered {
+                side,
+                order_id,
+                previous_order_id: _,
+                owner: _,
+                owner_slot,
+                client_order_id: _,
+            } => {
+                let client_order_id = open_orders.client_order_id(owner_slot);
+                open_orders.set_slot(owner_slot, Some(side), *order_id, client_order_id)?;
+            }
+        };
         Ok(())
     }
 
@@ -2183,17 +4710,186 @@ impl State {
             mut order_book_state,
             mut req_q,
             mut event_q,
+            mut trigger_orders,
             limit,
+            crank_payout,
         } = args;
-        order_book_state.process_requests(&mut req_q, &mut event_q, limit)
+        let requests_processed = order_book_state.process_requests_with_triggers(
+            &mut req_q,
+            &mut event_q,
+            trigger_orders.as_mut(),
+            limit,
+        )?;
+        if let Some(mut crank_payout) = crank_payout {
+            let reward = CrankRewards::take_reward(
+                &mut crank_payout.crank_rewards.match_pool,
+                requests_processed,
+                req_q.len(),
+            );
+            crank_payout.pay(reward)?;
+        }
+        Ok(())
     }
 
     #[cfg(feature = "program")]
     fn process_new_order(args: account_parser::NewOrderArgs) -> DexResult {
+        let (request, mut req_q) = Self::lock_new_order(args)?;
+        req_q
+            .push_back(request)
+            .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_new_trigger_order(args: account_parser::NewTriggerOrderArgs) -> DexResult {
+        let account_parser::NewTriggerOrderArgs {
+            trigger_price,
+            trigger_direction,
+            mut trigger_orders,
+            new_order,
+        } = args;
+        let (request, _) = Self::lock_new_order(new_order)?;
+        trigger_orders.push(TriggerOrder::new(request, trigger_price, trigger_direction))
+    }
+
+    #[cfg(feature = "program")]
+    fn process_new_orders(args: account_parser::NewOrdersArgs) -> DexResult {
+        let account_parser::NewOrdersArgs {
+            instruction,
+            market,
+            mut open_orders,
+            open_orders_address,
+            mut req_q,
+            coin_wallet,
+            pc_wallet,
+            owner,
+            coin_vault,
+            pc_vault,
+            spl_token_program,
+            fee_tier,
+            crank_deposit,
+        } = args;
+
+        let free_slots = open_orders.free_slots();
+        let free_requests = (req_q.capacity() - req_q.len()) as usize;
+        let orders = &instruction.orders[..];
+        if instruction.all_or_nothing {
+            if orders.len() > free_slots {
+                Err(DexErrorCode::TooManyOpenOrders)?;
+            }
+            if orders.len() > free_requests {
+                Err(DexErrorCode::RequestQueueFull)?;
+            }
+        }
+        let placed = orders.len().min(free_slots).min(free_requests);
+        if placed == 0 {
+            if free_slots == 0 {
+                Err(DexErrorCode::TooManyOpenOrders)?;
+            }
+            Err(DexErrorCode::RequestQueueFull)?;
+        }
+        if placed < orders.len() {
+            info!(&format!("placing {} of {} orders", placed, orders.len()));
+        }
+        let orders = &orders[..placed];
+        if let Some(mut crank_deposit) = crank_deposit {
+            crank_deposit.collect(orders.len() as u64)?;
+        }
+
+        let mut native_locks = Vec::with_capacity(orders.len());
+        let mut pc_qty_to_lock = 0u64;
+        let mut coin_qty_to_lock = 0u64;
+        for order in orders {
+            let native_lock = market.native_qty_to_lock(
+                order.side,
+                order.limit_price,
+                order.max_qty,
+                fee_tier,
+            )?;
+            let total = match order.side {
+                Side::Bid => &mut pc_qty_to_lock,
+                Side::Ask => &mut coin_qty_to_lock,
+            };
+            *total = total
+                .checked_add(native_lock)
+                .ok_or(DexErrorCode::InsufficientFunds)?;
+            native_locks.push(native_lock);
+        }
+
+        let free_pc_to_lock = pc_qty_to_lock.min(open_orders.native_pc_free);
+        let pc_deposit_amount = pc_qty_to_lock - free_pc_to_lock;
+        open_orders.lock_free_pc(free_pc_to_lock);
+        open_orders.credit_locked_pc(pc_deposit_amount);
+        market.pc_deposits_total = market
+            .pc_deposits_total
+            .checked_add(pc_deposit_amount)
+            .unwrap();
+
+        let free_coin_to_lock = coin_qty_to_lock.min(open_orders.native_coin_free);
+        let coin_deposit_amount = coin_qty_to_lock - free_coin_to_lock;
+        open_orders.lock_free_coin(free_coin_to_lock);
+        open_orders.credit_locked_coin(coin_deposit_amount);
+        market.coin_deposits_total = market
+            .coin_deposits_total
+            .checked_add(coin_deposit_amount)
+            .unwrap();
+
+        if pc_deposit_amount > 0 {
+            deposit_from_wallet(
+                pc_deposit_amount,
+                pc_wallet.token_account(),
+                pc_vault.token_account(),
+                owner,
+                spl_token_program,
+            )?;
+        }
+        if coin_deposit_amount > 0 {
+            deposit_from_wallet(
+                coin_deposit_amount,
+                coin_wallet.token_account(),
+                coin_vault.token_account(),
+                owner,
+                spl_token_program,
+            )?;
+        }
+
+        for (order, native_lock) in orders.iter().zip(native_locks) {
+            let order_id = req_q.gen_order_id(order.limit_price.get(), order.side);
+            let owner_slot = open_orders.add_order(order_id, order.client_id, order.side)?;
+            let native_pc_qty_locked = match order.side {
+                Side::Bid => Some(NonZeroU64::new(native_lock).unwrap()),
+                Side::Ask => None,
+            };
+            let request = Request::new(RequestView::NewOrder {
+                side: order.side,
+                order_type: instruction.order_type,
+                order_id: &order_id,
+                fee_tier,
+                self_trade_behavior: instruction.self_trade_behavior,
+                owner: open_orders_address,
+                owner_slot,
+                max_coin_qty: order.max_qty,
+                native_pc_qty_locked,
+                client_order_id: NonZeroU64::new(order.client_id),
+            });
+            req_q
+                .push_back(request)
+                .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        }
+        Ok(())
+    }
+
+    /// Locks the funds of a new order in its OpenOrders account, depositing
+    /// whatever isn't free there yet, and records the order. Returns the
+    /// request placing the order in the book.
+    #[cfg(feature = "program")]
+    fn lock_new_order<'a>(
+        args: account_parser::NewOrderArgs<'a, '_>,
+    ) -> DexResult<(Request, RequestQueue<'a>)> {
         let account_parser::NewOrderArgs {
             instruction,
             market,
-            open_orders,
+            mut open_orders,
             open_orders_address,
             mut req_q,
             payer,
@@ -2202,25 +4898,30 @@ impl State {
             pc_vault,
             spl_token_program,
             fee_tier,
+            crank_deposit,
+            limit_price,
         } = args;
 
+        if instruction.reject_duplicate_client_id {
+            if let Some(client_id) = NonZeroU64::new(instruction.client_id) {
+                if open_orders.client_order_id_slot(client_id).is_some() {
+                    Err(DexErrorCode::DuplicateClientOrderId)?;
+                }
+            }
+        }
+
         let deposit_amount;
         let deposit_vault;
 
         let native_pc_qty_locked;
+        let lock_qty_native = market.native_qty_to_lock(
+            instruction.side,
+            limit_price,
+            instruction.max_qty,
+            fee_tier,
+        )?;
         match instruction.side {
             Side::Bid => {
-                let lock_qty_lots = instruction
-                    .max_qty
-                    .get()
-                    .checked_mul(instruction.limit_price.get())
-                    .ok_or(DexErrorCode::InsufficientFunds)?;
-                let native_lock_qty_before_fee = lock_qty_lots
-                    .checked_mul(market.pc_lot_size)
-                    .ok_or(DexErrorCode::InsufficientFunds)?;
-                let lock_qty_native = native_lock_qty_before_fee
-                    .checked_add(fee_tier.taker_fee(native_lock_qty_before_fee))
-                    .ok_or(DexErrorCode::InsufficientFunds)?;
                 native_pc_qty_locked = Some(NonZeroU64::new(lock_qty_native).unwrap());
                 let free_qty_to_lock = lock_qty_native.min(open_orders.native_pc_free);
                 deposit_amount = lock_qty_native - free_qty_to_lock;
@@ -2233,11 +4934,6 @@ impl State {
                     .unwrap();
             }
             Side::Ask => {
-                let lock_qty_native = instruction
-                    .max_qty
-                    .get()
-                    .checked_mul((*market).coin_lot_size)
-                    .ok_or(DexErrorCode::InsufficientFunds)?;
                 let free_qty_to_lock = lock_qty_native.min(open_orders.native_coin_free);
                 deposit_amount = lock_qty_native - free_qty_to_lock;
                 deposit_vault = coin_vault.token_account();
@@ -2251,41 +4947,44 @@ impl State {
             }
         };
 
-        // pull balances from payer, signed by owner
-        let deposit_instruction = spl_token::instruction::transfer(
-            &spl_token::ID,
-            payer.inner().key,
-            deposit_vault.inner().key,
-            owner.inner().key,
-            &[],
-            deposit_amount,
-        )
-        .unwrap();
-        assert_eq!(*spl_token_program.inner().key, spl_token::ID);
-
-        // pull the deposited funds
-        invoke_spl_token(
-            &deposit_instruction,
-            &[
-                payer.inner().clone(),
-                deposit_vault.inner().clone(),
-                owner.inner().clone(),
-                spl_token_program.inner().clone(),
-            ],
-            &[],
-        )
-        .map_err(|err| match err {
-            ProgramError::Custom(i) => match TokenError::from_u32(i) {
-                Some(TokenError::InsufficientFunds) => DexErrorCode::InsufficientFunds,
-                _ => DexErrorCode::TransferFailed,
-            },
-            _ => DexErrorCode::TransferFailed,
-        })?;
+        match payer {
+            account_parser::OrderPayer::Token(payer) => {
+                deposit_from_wallet(
+                    deposit_amount,
+                    payer,
+                    deposit_vault,
+                    owner,
+                    spl_token_program,
+                )?;
+            }
+            account_parser::OrderPayer::NativeSol(native_sol) if deposit_amount > 0 => {
+                // wrap the deposit into a temporary account owned by the vault
+                // signer, move it into the vault, then refund the rent
+                let market_pubkey = market.pubkey();
+                let vault_signer_seeds =
+                    gen_vault_signer_seeds(&market.vault_signer_nonce, &market_pubkey);
+                create_wrapped_sol_account(deposit_amount, native_sol, spl_token_program)?;
+                send_from_vault(
+                    deposit_amount,
+                    deposit_vault,
+                    native_sol.temp_token_account()?,
+                    spl_token_program,
+                    native_sol.vault_signer,
+                    &vault_signer_seeds,
+                )?;
+                close_wrapped_sol_account(native_sol, spl_token_program, &vault_signer_seeds)?;
+            }
+            account_parser::OrderPayer::NativeSol(_) => (),
+        }
+
+        if let Some(mut crank_deposit) = crank_deposit {
+            crank_deposit.collect(1)?;
+        }
 
         // record the open order in the user account
-        let order_id = req_q.gen_order_id(instruction.limit_price.get(), instruction.side);
-        let owner_slot = open_orders.add_order(order_id, instruction.side)?;
-        open_orders.client_order_ids[owner_slot as usize] = instruction.client_id;
+        let order_id = req_q.gen_order_id(limit_price.get(), instruction.side);
+        let owner_slot =
+            open_orders.add_order(order_id, instruction.client_id, instruction.side)?;
 
         // add the request to the queue
         let request = Request::new(RequestView::NewOrder {
@@ -2300,11 +4999,7 @@ impl State {
             native_pc_qty_locked,
             client_order_id: NonZeroU64::new(instruction.client_id),
         });
-
-        req_q
-            .push_back(request)
-            .map_err(|_| DexErrorCode::RequestQueueFull)?;
-        Ok(())
+        Ok((request, req_q))
     }
 
     fn process_disable_market(args: account_parser::DisableMarketArgs) -> DexResult {
@@ -2320,34 +5015,372 @@ impl State {
     fn process_sweep_fees(args: account_parser::SweepFeesArgs) -> DexResult {
         let account_parser::SweepFeesArgs {
             mut market,
-            pc_vault,
-            fee_receiver,
+            fee_vault,
+            fee_receivers,
             vault_signer,
             spl_token_program,
-            authorization: _,
         } = args;
-        let token_amount = market.pc_fees_accrued;
-        market.pc_fees_accrued = 0;
+        let (token_amount, vault, is_coin_vault) = match fee_vault {
+            account_parser::FeeVault::Coin(coin_vault) => {
+                let token_amount = market.coin_fees_accrued;
+                market.coin_fees_accrued = 0;
+                (token_amount, coin_vault.token_account(), true)
+            }
+            account_parser::FeeVault::Pc(pc_vault) => {
+                let token_amount = market.pc_fees_accrued;
+                market.pc_fees_accrued = 0;
+                (token_amount, pc_vault.token_account(), false)
+            }
+        };
 
         let market_pubkey = market.pubkey();
         let vault_signer_seeds = gen_vault_signer_seeds(&market.vault_signer_nonce, &market_pubkey);
+        let send = |native_amount, recipient| {
+            send_from_vault(
+                native_amount,
+                recipient,
+                vault,
+                spl_token_program,
+                vault_signer,
+                &vault_signer_seeds,
+            )
+        };
+        match fee_receivers {
+            account_parser::FeeReceivers::Authorized {
+                fee_receiver,
+                authorization: _,
+            } => send(token_amount, fee_receiver),
+            account_parser::FeeReceivers::Split {
+                mut fee_split,
+                treasury,
+                referrer,
+            } => {
+                let route_staking_to_treasury = fee_split.route_staking_to_treasury != 0;
+                let (staking_amount, mut treasury_amount, referrer_amount) =
+                    fee_split.split(token_amount);
+                let staking_fees = if is_coin_vault {
+                    &mut fee_split.staking_coin_fees
+                } else {
+                    &mut fee_split.staking_pc_fees
+                };
+                *staking_fees += staking_amount;
+                if route_staking_to_treasury {
+                    treasury_amount += *staking_fees;
+                    *staking_fees = 0;
+                }
+                send(treasury_amount, treasury)?;
+                send(referrer_amount, referrer)
+            }
+        }
+    }
+
+    #[cfg(feature = "program")]
+    fn process_init_open_orders(args: account_parser::InitOpenOrdersArgs) -> DexResult {
+        let account_parser::InitOpenOrdersArgs {
+            program_id,
+            instruction,
+            market,
+            market_address,
+            open_orders_acc,
+            owner,
+            payer,
+            system_program,
+            rent,
+            referrer,
+        } = args;
+
+        let open_orders_seeds = gen_open_orders_seeds(
+            market_address,
+            owner.inner().key,
+            &instruction.index,
+            &instruction.nonce,
+        );
+        // The address is only checked here: nothing but this program can
+        // write the account's data, so it can't reappear at another address.
+        let open_orders_key = Pubkey::create_program_address(&open_orders_seeds, program_id)
+            .map_err(|_| DexErrorCode::BadOpenOrdersNonce)?;
+        check_assert_eq!(&open_orders_key, open_orders_acc.key)
+            .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
+
+        if open_orders_acc.owner != program_id {
+            create_open_orders_account(
+                open_orders_acc,
+                payer,
+                system_program,
+                &rent,
+                program_id,
+                &open_orders_seeds,
+                instruction.slots as usize,
+            )?;
+        }
+        {
+            let (_, data) = strip_header::<[u8; 0], u8>(open_orders_acc, true)?;
+            let open_orders: &OpenOrders = data
+                .get(..size_of::<OpenOrders>())
+                .and_then(|data| try_from_bytes(data).ok())
+                .ok_or(DexErrorCode::WrongOrdersAccount)?;
+            if open_orders.account_flags != 0 {
+                Err(DexErrorCode::AlreadyInitialized)?
+            }
+        }
+        market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, Some(rent))?;
+        let (_, mut data) = strip_header::<[u8; 0], u8>(open_orders_acc, false)?;
+        let open_orders: &mut OpenOrdersV2 = data
+            .get_mut(..size_of::<OpenOrdersV2>())
+            .and_then(|data| try_from_bytes_mut(data).ok())
+            .ok_or(DexErrorCode::WrongOrdersAccount)?;
+        open_orders.inner.account_flags |= AccountFlag::Derived as u64;
+        open_orders.index = instruction.index;
+        open_orders.nonce = instruction.nonce;
+        if let Some(referrer) = referrer {
+            open_orders.inner.account_flags |= AccountFlag::Referred as u64;
+            open_orders.referrer = referrer;
+        }
+        Ok(())
+    }
+
+    fn process_set_open_orders_delegate(
+        args: account_parser::SetOpenOrdersDelegateArgs,
+    ) -> DexResult {
+        let account_parser::SetOpenOrdersDelegateArgs {
+            mut open_orders,
+            delegate,
+        } = args;
+        open_orders.delegate = delegate.unwrap_or([0; 4]);
+        Ok(())
+    }
+
+    fn process_init_referrer(args: account_parser::InitReferrerArgs) -> DexResult {
+        let account_parser::InitReferrerArgs {
+            rebate_bps,
+            referrer,
+            market,
+            owner,
+            pc_wallet,
+            authorization: _,
+        } = args;
+        referrer.account_flags = (AccountFlag::Initialized | AccountFlag::Referrer).bits();
+        referrer.market = market.own_address;
+        referrer.owner = owner.inner().key.to_aligned_bytes();
+        referrer.pc_wallet = pc_wallet.token_account().inner().key.to_aligned_bytes();
+        referrer.rebate_bps = rebate_bps;
+        Ok(())
+    }
+
+    fn process_init_trigger_orders(args: account_parser::InitTriggerOrdersArgs) -> DexResult {
+        let account_parser::InitTriggerOrdersArgs {
+            mut trigger_orders,
+            market,
+            authorization: _,
+        } = args;
+        *trigger_orders = TriggerOrdersHeader {
+            account_flags: (AccountFlag::Initialized | AccountFlag::TriggerOrders).bits(),
+            market: market.own_address,
+            count: 0,
+            last_price: 0,
+            padding: 0,
+        };
+        market.account_flags |= AccountFlag::HasTriggerOrders as u64;
+        Ok(())
+    }
+
+    fn process_set_crank_reward(args: account_parser::SetCrankRewardArgs) -> DexResult {
+        let account_parser::SetCrankRewardArgs {
+            lamports_per_order,
+            mut market,
+            authorization: _,
+        } = args;
+        market.crank_rewards.lamports_per_order = lamports_per_order;
+        Ok(())
+    }
+
+    fn process_set_fee_split(args: account_parser::SetFeeSplitArgs) -> DexResult {
+        let account_parser::SetFeeSplitArgs {
+            instruction,
+            mut market,
+            authorization: _,
+            treasury_coin_wallet,
+            treasury_pc_wallet,
+            referrer_coin_wallet,
+            referrer_pc_wallet,
+        } = args;
+        let total_bps = instruction.staking_bps as u32
+            + instruction.treasury_bps as u32
+            + instruction.referrer_bps as u32;
+        if total_bps != 10_000 {
+            Err(DexErrorCode::InvalidFeeSplit)?
+        }
+        let fee_split = &mut market.fee_split;
+        fee_split.staking_bps = instruction.staking_bps;
+        fee_split.treasury_bps = instruction.treasury_bps;
+        fee_split.referrer_bps = instruction.referrer_bps;
+        fee_split.route_staking_to_treasury = instruction.route_staking_to_treasury as u16;
+        fee_split.treasury_coin_wallet = treasury_coin_wallet.account().key.to_aligned_bytes();
+        fee_split.treasury_pc_wallet = treasury_pc_wallet.account().key.to_aligned_bytes();
+        fee_split.referrer_coin_wallet = referrer_coin_wallet.account().key.to_aligned_bytes();
+        fee_split.referrer_pc_wallet = referrer_pc_wallet.account().key.to_aligned_bytes();
+        Ok(())
+    }
+
+    fn process_migrate_market(args: account_parser::MigrateMarketArgs) -> DexResult {
+        let account_parser::MigrateMarketArgs {
+            mut market,
+            mut new_market,
+            authorization: _,
+        } = args;
+        // The copy keeps the market's `own_address`, which its vault signer,
+        // OpenOrders accounts and referrers are tied to, as well as its queue,
+        // book and vault addresses.
+        new_market.inner = *market;
+        new_market.crank_rewards = CrankRewards::zeroed();
+        new_market.fee_split = FeeSplit::zeroed();
+        new_market.padding = [0; 106];
+        new_market.account_flags |= AccountFlag::LayoutV2 as u64;
+        market.account_flags |= AccountFlag::Disabled as u64;
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_route(args: account_parser::RouteArgs) -> DexResult {
+        let account_parser::RouteArgs {
+            instruction,
+            mut first_market,
+            mut second_market,
+            owner,
+            source_wallet,
+            intermediate_wallet,
+            destination_wallet,
+            spl_token_program,
+            fee_tier,
+        } = args;
+
+        let first_proceeds = Self::take_on_route_market(
+            &mut first_market,
+            instruction.first_side,
+            instruction.native_amount_in,
+            fee_tier,
+            instruction.limit,
+        )?;
+        let second_proceeds = Self::take_on_route_market(
+            &mut second_market,
+            instruction.second_side,
+            first_proceeds.native_qty_received,
+            fee_tier,
+            instruction.limit,
+        )?;
+        if second_proceeds.native_qty_received < instruction.min_native_amount_out {
+            Err(DexErrorCode::RouteOutputTooSmall)?
+        }
+
+        Self::settle_route_market(
+            &first_market,
+            instruction.first_side,
+            first_proceeds,
+            source_wallet,
+            intermediate_wallet,
+            owner,
+            spl_token_program,
+        )?;
+        Self::settle_route_market(
+            &second_market,
+            instruction.second_side,
+            second_proceeds,
+            intermediate_wallet,
+            destination_wallet,
+            owner,
+            spl_token_program,
+        )
+    }
+
+    #[cfg(feature = "program")]
+    fn take_on_route_market(
+        route_market: &mut account_parser::RouteMarket,
+        side: Side,
+        native_amount_in: u64,
+        fee_tier: FeeTier,
+        limit: u16,
+    ) -> DexResult<TakeProceeds> {
+        let account_parser::RouteMarket {
+            market,
+            req_q,
+            event_q,
+            bids,
+            asks,
+            ..
+        } = route_market;
+        let mut order_book_state = OrderBookState {
+            bids: bids.deref_mut(),
+            asks: asks.deref_mut(),
+            market_state: market.deref_mut(),
+        };
+        // orders queued earlier take priority
+        order_book_state.process_requests(req_q, event_q, limit)?;
+        if !req_q.empty() {
+            Err(DexErrorCode::RequestQueueNotEmpty)?
+        }
+
+        let (max_coin_qty, max_native_pc_qty) = match side {
+            Side::Bid => (std::u64::MAX, native_amount_in),
+            Side::Ask => (
+                native_amount_in / order_book_state.market_state.coin_lot_size,
+                0,
+            ),
+        };
+        order_book_state.send_take(
+            side,
+            max_coin_qty,
+            max_native_pc_qty,
+            fee_tier,
+            event_q,
+            limit,
+        )
+    }
+
+    #[cfg(feature = "program")]
+    fn settle_route_market(
+        route_market: &account_parser::RouteMarket,
+        side: Side,
+        proceeds: TakeProceeds,
+        payer: account_parser::TokenAccount,
+        recipient: account_parser::TokenAccount,
+        owner: account_parser::SignerAccount,
+        spl_token_program: account_parser::SplTokenProgram,
+    ) -> DexResult {
+        let coin_vault = route_market.coin_vault.token_account();
+        let pc_vault = route_market.pc_vault.token_account();
+        let (deposit_vault, payout_vault) = match side {
+            Side::Bid => (pc_vault, coin_vault),
+            Side::Ask => (coin_vault, pc_vault),
+        };
+        deposit_from_wallet(
+            proceeds.native_qty_paid,
+            payer,
+            deposit_vault,
+            owner,
+            spl_token_program,
+        )?;
+
+        let market_pubkey = route_market.market.pubkey();
+        let vault_signer_seeds =
+            gen_vault_signer_seeds(&route_market.market.vault_signer_nonce, &market_pubkey);
         send_from_vault(
-            token_amount,
-            fee_receiver.token_account(),
-            pc_vault.token_account(),
+            proceeds.native_qty_received,
+            recipient,
+            payout_vault,
             spl_token_program,
-            vault_signer,
+            route_market.vault_signer,
             &vault_signer_seeds,
         )
     }
 
     fn process_initialize_market(args: account_parser::InitializeMarketArgs) -> DexResult {
-        let &InitializeMarketInstruction {
+        let &InitializeMarketInstructionV2 {
             coin_lot_size,
             pc_lot_size,
             fee_rate_bps,
             vault_signer_nonce,
             pc_dust_threshold,
+            fee_currency,
         } = args.instruction;
 
         let market = args.get_market();
@@ -2413,13 +5446,32 @@ impl State {
         // initialize market
         let mut market_data = market.try_borrow_mut_data()?;
         let market_view = init_account_padding(&mut market_data)?;
+        let market_bytes: &mut [u8] = cast_slice_mut(market_view);
+        if market_bytes.len() < size_of::<MarketState>() {
+            Err(DexErrorCode::MarketAccountTooSmall)?
+        }
+        let mut market_flags = if market_bytes.len() >= size_of::<MarketStateV2>() {
+            let market_v2: &mut MarketStateV2 =
+                try_from_bytes_mut(&mut market_bytes[..size_of::<MarketStateV2>()])
+                    .or(check_unreachable!())?;
+            market_v2.crank_rewards = CrankRewards::zeroed();
+            market_v2.fee_split = FeeSplit::zeroed();
+            market_v2.padding = [0; 106];
+            AccountFlag::Initialized | AccountFlag::Market | AccountFlag::LayoutV2
+        } else {
+            AccountFlag::Initialized | AccountFlag::Market
+        };
+        if fee_currency == FeeCurrency::Coin {
+            market_flags |= AccountFlag::CoinFees;
+        }
         let market_hdr: &mut MarketState =
-            try_from_bytes_mut(cast_slice_mut(market_view)).or(check_unreachable!())?;
+            try_from_bytes_mut(&mut market_bytes[..size_of::<MarketState>()])
+                .or(check_unreachable!())?;
         *market_hdr = MarketState {
             coin_lot_size,
             pc_lot_size,
             own_address: market.key.to_aligned_bytes(),
-            account_flags: (AccountFlag::Initialized | AccountFlag::Market).bits(),
+            account_flags: market_flags.bits(),
 
             coin_mint: coin_mint.key.to_aligned_bytes(),
             coin_vault: coin_vault.key.to_aligned_bytes(),
@@ -2,6 +2,7 @@ use std::mem::size_of;
 use std::num::NonZeroU64;
 
 use bumpalo::{collections::Vec as BumpVec, vec as bump_vec, Bump};
+use bytemuck::Zeroable;
 use rand::prelude::*;
 use safe_transmute::to_bytes::{transmute_to_bytes, transmute_to_bytes_mut};
 use solana_sdk::bpf_loader;
@@ -13,10 +14,22 @@ use solana_sdk::sysvar;
 use solana_sdk::sysvar::Sysvar;
 use spl_token::state::{Account, AccountState, Mint};
 
-use instruction::{initialize_market, MarketInstruction, NewOrderInstructionV1};
+use client::decode_orders;
+use error::{DexError, DexErrorCode, DexResult};
+use fees::FeeTier;
+use instruction::{
+    fee_sweeper, init_open_orders, init_open_orders_with_referrer, init_referrer,
+    init_trigger_orders, initialize_market_with_fee_currency, migrate_market, set_crank_reward,
+    CancelOrderInstruction, FeeCurrency, MarketInstruction, NewOrderInstructionV1, NewOrdersEntry,
+    NewOrdersInstruction, RouteInstruction, SelfTradeBehavior, SetFeeSplitInstruction,
+    SettleFundsPartialInstruction, TriggerDirection, MAX_CANCEL_CLIENT_IDS,
+};
 use matching::{OrderType, Side};
-use state::gen_vault_signer_key;
-use state::{MarketState, OpenOrders, State, ToAlignedBytes};
+use state::{find_open_orders_key, gen_vault_signer_key};
+use state::{
+    AccountFlag, FeeSplit, MarketState, MarketStateV2, OpenOrders, OpenOrdersSlots, OpenOrdersV2,
+    Referrer, State, ToAlignedBytes, TriggerOrder, TriggerOrdersHeader,
+};
 
 use super::*;
 
@@ -32,6 +45,7 @@ struct MarketAccounts<'bump> {
     asks: AccountInfo<'bump>,
     coin_vault: AccountInfo<'bump>,
     pc_vault: AccountInfo<'bump>,
+    vault_signer: AccountInfo<'bump>,
     coin_mint: AccountInfo<'bump>,
     pc_mint: AccountInfo<'bump>,
     rent_sysvar: AccountInfo<'bump>,
@@ -158,17 +172,63 @@ fn new_spl_token_program<'bump>(bump: &'bump Bump) -> AccountInfo<'bump> {
     )
 }
 
+fn new_system_program<'bump>(bump: &'bump Bump) -> AccountInfo<'bump> {
+    AccountInfo::new(
+        &system_program::ID,
+        false,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &bpf_loader::ID,
+        false,
+        Epoch::default(),
+    )
+}
+
 fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts<'bump> {
+    setup_market_with(rng, size_of::<MarketStateV2>(), FeeCurrency::Pc, bump)
+}
+
+/// A market with the version 1 layout, as created before `MarketStateV2`.
+fn setup_market_v1<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts<'bump> {
+    setup_market_with(rng, size_of::<MarketState>(), FeeCurrency::Pc, bump)
+}
+
+fn setup_market_with<'bump, R: Rng>(
+    rng: &mut R,
+    market_len: usize,
+    fee_currency: FeeCurrency,
+    bump: &'bump Bump,
+) -> MarketAccounts<'bump> {
     let program_id = random_pubkey(rng, bump);
-    let market = new_dex_owned_account(rng, size_of::<MarketState>(), program_id, bump);
+    let coin_mint = new_token_mint(rng, bump);
+    let pc_mint = new_token_mint(rng, bump);
+    setup_market_with_mints(
+        rng,
+        program_id,
+        market_len,
+        fee_currency,
+        coin_mint,
+        pc_mint,
+        bump,
+    )
+}
+
+fn setup_market_with_mints<'bump, R: Rng>(
+    rng: &mut R,
+    program_id: &'bump Pubkey,
+    market_len: usize,
+    fee_currency: FeeCurrency,
+    coin_mint: AccountInfo<'bump>,
+    pc_mint: AccountInfo<'bump>,
+    bump: &'bump Bump,
+) -> MarketAccounts<'bump> {
+    let market = new_dex_owned_account(rng, market_len, program_id, bump);
     let bids = new_dex_owned_account(rng, 1 << 23, program_id, bump);
     let asks = new_dex_owned_account(rng, 1 << 23, program_id, bump);
     let req_q = new_dex_owned_account(rng, 640, program_id, bump);
     let event_q = new_dex_owned_account(rng, 65536, program_id, bump);
 
-    let coin_mint = new_token_mint(rng, bump);
-    let pc_mint = new_token_mint(rng, bump);
-
     let rent_sysvar = new_rent_sysvar_account(100000, Rent::default(), bump);
 
     let mut i = 0;
@@ -182,13 +242,23 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
 
     let coin_vault = new_token_account(rng, &coin_mint.key, vault_signer_pk, bump);
     let pc_vault = new_token_account(rng, &pc_mint.key, vault_signer_pk, bump);
+    let vault_signer = AccountInfo::new(
+        vault_signer_pk,
+        false,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
 
     let coin_lot_size = 1_000;
     let pc_lot_size = 1;
 
     let pc_dust_threshold = 5;
 
-    let init_instruction = initialize_market(
+    let init_instruction = initialize_market_with_fee_currency(
         &market.key,
         &program_id,
         &coin_mint.key,
@@ -203,6 +273,7 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
         pc_lot_size,
         vault_signer_nonce,
         pc_dust_threshold,
+        fee_currency,
     )
     .unwrap();
 
@@ -230,6 +301,7 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
         asks,
         coin_vault,
         pc_vault,
+        vault_signer,
         coin_mint,
         pc_mint,
         rent_sysvar,
@@ -241,7 +313,247 @@ fn test_initialize_market() {
     let mut rng = StdRng::seed_from_u64(0);
     let bump = Bump::new();
 
-    setup_market(&mut rng, &bump);
+    let accounts = setup_market(&mut rng, &bump);
+    let market = MarketState::load(&accounts.market, accounts.market.owner).unwrap();
+    assert_eq!(market.layout_version(), 2);
+}
+
+#[test]
+fn test_migrate_market() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let bump = Bump::new();
+
+    // A market created before the v2 layout existed
+    let accounts = setup_market_v1(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    assert_eq!(
+        MarketState::load(&accounts.market, dex_program_id)
+            .unwrap()
+            .layout_version(),
+        1
+    );
+    assert!(MarketStateV2::load(&accounts.market, dex_program_id).is_err());
+
+    // An OpenOrders account with a resting bid from before the migration
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let new_bid = |market| {
+        let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(100_000).unwrap(),
+            max_qty: NonZeroU64::new(5).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+        })
+        .pack();
+        let mut instruction_accounts =
+            new_order_accounts(&bump, &accounts, &orders_account, &pc_account, &owner);
+        instruction_accounts[0] = market;
+        State::process(
+            dex_program_id,
+            instruction_accounts.into_bump_slice(),
+            &instruction_data,
+        )
+        .unwrap();
+    };
+    let match_orders = |market| {
+        State::process(
+            dex_program_id,
+            bump_vec![in &bump;
+                market,
+                accounts.req_q.clone(),
+                accounts.event_q.clone(),
+                accounts.bids.clone(),
+                accounts.asks.clone(),
+                coin_account.clone(),
+                pc_account.clone(),
+            ]
+            .into_bump_slice(),
+            &MarketInstruction::MatchOrders(5).pack(),
+        )
+        .unwrap();
+    };
+    new_bid(accounts.market.clone());
+    match_orders(accounts.market.clone());
+
+    let sweep_authority = AccountInfo::new(
+        &fee_sweeper::ID,
+        true,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    fn migrate<'a>(
+        program_id: &Pubkey,
+        market: &AccountInfo<'a>,
+        new_market: &AccountInfo<'a>,
+        authority: &AccountInfo<'a>,
+        rent_sysvar: &AccountInfo<'a>,
+    ) -> DexResult {
+        let instruction = migrate_market(market.key, new_market.key, program_id).unwrap();
+        let instruction_accounts = [
+            market.clone(),
+            new_market.clone(),
+            authority.clone(),
+            rent_sysvar.clone(),
+        ];
+        State::process(program_id, &instruction_accounts, &instruction.data)
+    }
+
+    // The new account has to fit the v2 layout
+    let small_market =
+        new_dex_owned_account(&mut rng, size_of::<MarketState>(), dex_program_id, &bump);
+    assert_eq!(
+        migrate(
+            dex_program_id,
+            &accounts.market,
+            &small_market,
+            &sweep_authority,
+            &accounts.rent_sysvar,
+        ),
+        Err(DexError::ErrorCode(DexErrorCode::MarketAccountTooSmall))
+    );
+
+    let new_market =
+        new_dex_owned_account(&mut rng, size_of::<MarketStateV2>(), dex_program_id, &bump);
+    migrate(
+        dex_program_id,
+        &accounts.market,
+        &new_market,
+        &sweep_authority,
+        &accounts.rent_sysvar,
+    )
+    .unwrap();
+    {
+        let market = MarketStateV2::load(&new_market, dex_program_id).unwrap();
+        assert_eq!(market.layout_version(), 2);
+        assert_eq!(market.own_address, accounts.market.key.to_aligned_bytes());
+        assert_eq!(market.coin_lot_size, 1_000);
+        assert_eq!(market.req_q, accounts.req_q.key.to_aligned_bytes());
+        assert_eq!(
+            market.coin_vault,
+            accounts.coin_vault.key.to_aligned_bytes()
+        );
+    }
+
+    // The old account is disabled, and neither account can be migrated again
+    assert_eq!(
+        MarketState::load(&accounts.market, dex_program_id).err(),
+        Some(DexError::ErrorCode(DexErrorCode::InvalidMarketFlags))
+    );
+    let another_market =
+        new_dex_owned_account(&mut rng, size_of::<MarketStateV2>(), dex_program_id, &bump);
+    assert_eq!(
+        migrate(
+            dex_program_id,
+            &accounts.market,
+            &another_market,
+            &sweep_authority,
+            &accounts.rent_sysvar,
+        ),
+        Err(DexError::ErrorCode(DexErrorCode::InvalidMarketFlags))
+    );
+    assert_eq!(
+        migrate(
+            dex_program_id,
+            &new_market,
+            &another_market,
+            &sweep_authority,
+            &accounts.rent_sysvar,
+        ),
+        Err(DexError::ErrorCode(
+            DexErrorCode::InvalidMarketLayoutVersion
+        ))
+    );
+
+    // The migrated market still trades against the old queues and vaults,
+    // with the OpenOrders accounts of the old market
+    new_bid(new_market.clone());
+    match_orders(new_market.clone());
+    let first_order_id = {
+        let open_orders = MarketStateV2::load(&new_market, dex_program_id)
+            .unwrap()
+            .load_orders_mut(&orders_account, Some(&owner), dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders.market, accounts.market.key.to_aligned_bytes());
+        assert_eq!(open_orders.native_pc_total, 2 * 501_100);
+        assert_eq!(open_orders.native_pc_free, 0);
+        open_orders.orders[0]
+    };
+    assert_eq!(
+        decode_orders(&accounts.bids.try_borrow_data().unwrap(), Side::Bid)
+            .unwrap()
+            .len(),
+        2
+    );
+
+    // The bid placed before the migration is cancelled and settled through
+    // the new account
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            new_market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            owner.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::CancelOrder(CancelOrderInstruction {
+            side: Side::Bid,
+            order_id: first_order_id,
+            owner: [0; 4],
+            owner_slot: 0,
+        })
+        .pack(),
+    )
+    .unwrap();
+    match_orders(new_market.clone());
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            orders_account.clone(),
+            new_market.clone(),
+            accounts.event_q.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::ConsumeEvents(5).pack(),
+    )
+    .unwrap();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            new_market.clone(),
+            orders_account.clone(),
+            owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+            accounts.vault_signer.clone(),
+            new_spl_token_program(&bump),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::SettleFunds.pack(),
+    )
+    .unwrap();
+    {
+        let open_orders = MarketStateV2::load(&new_market, dex_program_id)
+            .unwrap()
+            .load_orders_mut(&orders_account, Some(&owner), dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders.free_slot_bits, std::u128::MAX - 0b10);
+        // Only the resting bid's pc is still locked, without its fee
+        assert_eq!(open_orders.native_pc_total, 500_000);
+        assert_eq!(open_orders.native_pc_free, 0);
+    }
 }
 
 #[test]
@@ -411,3 +723,2422 @@ fn test_new_order() {
         assert_eq!(open_orders_seller.native_pc_total, 399_120);
     }
 }
+
+#[test]
+fn test_init_open_orders() {
+    let mut rng = StdRng::seed_from_u64(2);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let payer = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let system_program = new_system_program(&bump);
+
+    let (open_orders_key, instruction) = init_open_orders(
+        dex_program_id,
+        accounts.market.key,
+        owner.key,
+        payer.key,
+        1,
+        128,
+    )
+    .unwrap();
+    let (_, expected_key) =
+        find_open_orders_key(1, accounts.market.key, owner.key, dex_program_id).unwrap();
+    assert_eq!(open_orders_key, expected_key);
+    let (other_key, _) = init_open_orders(
+        dex_program_id,
+        accounts.market.key,
+        owner.key,
+        payer.key,
+        2,
+        128,
+    )
+    .unwrap();
+    assert_ne!(open_orders_key, other_key);
+
+    // The runtime has already allocated the account, as the system program would
+    let open_orders_acc = AccountInfo::new(
+        bump.alloc(open_orders_key),
+        false,
+        true,
+        bump.alloc(100_000_000),
+        allocate_dex_owned_account(size_of::<OpenOrdersV2>(), &bump),
+        dex_program_id,
+        false,
+        Epoch::default(),
+    );
+    let instruction_accounts = bump_vec![in &bump;
+        open_orders_acc.clone(),
+        owner.clone(),
+        accounts.market.clone(),
+        payer.clone(),
+        system_program.clone(),
+        accounts.rent_sysvar.clone(),
+    ]
+    .into_bump_slice();
+    State::process(dex_program_id, instruction_accounts, &instruction.data).unwrap();
+    {
+        let open_orders = MarketState::load(&accounts.market, dex_program_id)
+            .unwrap()
+            .load_orders_mut(&open_orders_acc, Some(&owner), dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders.owner, owner.key.to_aligned_bytes());
+        assert_eq!(open_orders.free_slot_bits, std::u128::MAX);
+        assert!(open_orders.is_derived());
+    }
+
+    let result = State::process(dex_program_id, instruction_accounts, &instruction.data);
+    assert_eq!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::AlreadyInitialized))
+    );
+
+    // An address derived for a different owner is rejected
+    let instruction_accounts = bump_vec![in &bump;
+        open_orders_acc.clone(),
+        payer.clone(),
+        accounts.market.clone(),
+        payer.clone(),
+        system_program.clone(),
+        accounts.rent_sysvar.clone(),
+    ]
+    .into_bump_slice();
+    assert!(State::process(dex_program_id, instruction_accounts, &instruction.data).is_err());
+}
+
+#[test]
+fn test_init_open_orders_creates_account() {
+    let mut rng = StdRng::seed_from_u64(2);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let system_program = new_system_program(&bump);
+    let open_orders_len = OpenOrdersSlots::open_orders_len(256).unwrap();
+
+    let (open_orders_key, instruction) = init_open_orders(
+        dex_program_id,
+        accounts.market.key,
+        owner.key,
+        owner.key,
+        3,
+        256,
+    )
+    .unwrap();
+    let open_orders_acc = AccountInfo::new(
+        bump.alloc(open_orders_key),
+        false,
+        true,
+        bump.alloc(0),
+        allocate_dex_owned_account(open_orders_len, &bump),
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    let instruction_accounts = bump_vec![in &bump;
+        open_orders_acc.clone(),
+        owner.clone(),
+        accounts.market.clone(),
+        owner.clone(),
+        system_program.clone(),
+        accounts.rent_sysvar.clone(),
+    ]
+    .into_bump_slice();
+
+    state::SYSTEM_PROGRAM_INVOCATIONS.with(|invocations| invocations.borrow_mut().clear());
+    // The account info can't be reassigned outside of the runtime, so only
+    // the system program invocation is checked.
+    let _ = State::process(dex_program_id, instruction_accounts, &instruction.data);
+    let invocations =
+        state::SYSTEM_PROGRAM_INVOCATIONS.with(|invocations| invocations.replace(Vec::new()));
+    assert_eq!(invocations.len(), 1);
+    let (create_instruction, signers_seeds) = &invocations[0];
+
+    let space = open_orders_len + 12;
+    let rent = Rent::from_account_info(&accounts.rent_sysvar).unwrap();
+    assert_eq!(
+        create_instruction,
+        &solana_sdk::system_instruction::create_account(
+            owner.key,
+            &open_orders_key,
+            rent.minimum_balance(space),
+            space as u64,
+            dex_program_id,
+        )
+    );
+    assert_eq!(signers_seeds.len(), 1);
+    let seeds: Vec<&[u8]> = signers_seeds[0]
+        .iter()
+        .map(|seed| seed.as_slice())
+        .collect();
+    assert_eq!(
+        Pubkey::create_program_address(&seeds, dex_program_id).unwrap(),
+        open_orders_key
+    );
+
+    // Slot counts OpenOrders accounts can't hold are rejected
+    let (_, instruction) = init_open_orders(
+        dex_program_id,
+        accounts.market.key,
+        owner.key,
+        owner.key,
+        3,
+        200,
+    )
+    .unwrap();
+    assert_eq!(
+        State::process(dex_program_id, instruction_accounts, &instruction.data),
+        Err(DexError::ErrorCode(DexErrorCode::InvalidOpenOrdersSlots))
+    );
+}
+
+#[test]
+fn test_coin_fees() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let bump = Bump::new();
+
+    let accounts = setup_market_with(
+        &mut rng,
+        size_of::<MarketStateV2>(),
+        FeeCurrency::Coin,
+        &bump,
+    );
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account_buyer =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let orders_account_seller =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let orders = [
+        (
+            &orders_account_seller,
+            &coin_account,
+            Side::Ask,
+            NonZeroU64::new(99_000).unwrap(),
+            NonZeroU64::new(4).unwrap(),
+        ),
+        (
+            &orders_account_buyer,
+            &pc_account,
+            Side::Bid,
+            NonZeroU64::new(100_000).unwrap(),
+            NonZeroU64::new(5).unwrap(),
+        ),
+    ];
+    for &(orders_account, payer, side, limit_price, max_qty) in orders.iter() {
+        let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+            side,
+            limit_price,
+            max_qty,
+            order_type: OrderType::Limit,
+            client_id: 0,
+        })
+        .pack();
+        let instruction_accounts = bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            payer.clone(),
+            owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            spl_token_program.clone(),
+            accounts.rent_sysvar.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data).unwrap();
+
+        let instruction_data = MarketInstruction::MatchOrders(5).pack();
+        State::process(
+            dex_program_id,
+            bump_vec![in &bump;
+                accounts.market.clone(),
+                accounts.req_q.clone(),
+                accounts.event_q.clone(),
+                accounts.bids.clone(),
+                accounts.asks.clone(),
+                coin_account.clone(),
+                pc_account.clone(),
+            ]
+            .into_bump_slice(),
+            &instruction_data,
+        )
+        .unwrap();
+    }
+    {
+        // no fee is locked up front for bids
+        let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+        assert_eq!(market.pc_deposits_total, 500_000);
+        assert_eq!(market.pc_fees_accrued, 0);
+        assert_eq!(market.referrer_rebates_accrued, 0);
+        assert_eq!(market.coin_fees_accrued, 8);
+        assert_eq!(market.coin_deposits_total, 3_992);
+    }
+
+    {
+        let crank_accounts = bump_vec![in &bump;
+            orders_account_buyer.clone(),
+            orders_account_seller.clone(),
+            accounts.market.clone(),
+            accounts.event_q.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice_mut();
+        crank_accounts[0..2].sort_by_key(|account_info| account_info.key.to_aligned_bytes());
+        let instruction_data = MarketInstruction::ConsumeEvents(200).pack();
+        State::process(dex_program_id, crank_accounts, &instruction_data).unwrap();
+    }
+    {
+        let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+        let open_orders_buyer = market
+            .load_orders_mut(&orders_account_buyer, None, &dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders_buyer.native_coin_free, 3_991);
+        assert_eq!(open_orders_buyer.native_coin_total, 3_991);
+        assert_eq!(open_orders_buyer.native_pc_free, 4_000);
+        assert_eq!(open_orders_buyer.native_pc_total, 104_000);
+        assert_eq!(open_orders_buyer.referrer_rebates_accrued, 0);
+        let open_orders_seller = market
+            .load_orders_mut(&orders_account_seller, None, &dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders_seller.native_coin_free, 1);
+        assert_eq!(open_orders_seller.native_coin_total, 1);
+        assert_eq!(open_orders_seller.native_pc_free, 396_000);
+        assert_eq!(open_orders_seller.native_pc_total, 396_000);
+    }
+
+    let sweep_authority = AccountInfo::new(
+        &fee_sweeper::ID,
+        true,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    let fee_receiver = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let vault_signer = AccountInfo::new(
+        bump.alloc(Pubkey::default()),
+        false,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    let instruction_data = MarketInstruction::SweepFees.pack();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            accounts.coin_vault.clone(),
+            sweep_authority,
+            fee_receiver,
+            vault_signer,
+            spl_token_program.clone(),
+        ]
+        .into_bump_slice(),
+        &instruction_data,
+    )
+    .unwrap();
+    {
+        let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+        assert_eq!(market.coin_fees_accrued, 0);
+        assert_eq!(market.coin_deposits_total, 3_992);
+    }
+}
+
+#[test]
+fn test_fee_split() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account_buyer =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let orders_account_seller =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    // A trade, so that pc fees accrue
+    let orders = [
+        (&orders_account_seller, &coin_account, Side::Ask, 99_000, 4),
+        (&orders_account_buyer, &pc_account, Side::Bid, 100_000, 5),
+    ];
+    for &(orders_account, payer, side, limit_price, max_qty) in orders.iter() {
+        let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+            side,
+            limit_price: NonZeroU64::new(limit_price).unwrap(),
+            max_qty: NonZeroU64::new(max_qty).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+        })
+        .pack();
+        let instruction_accounts = bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            payer.clone(),
+            owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            spl_token_program.clone(),
+            accounts.rent_sysvar.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data).unwrap();
+        State::process(
+            dex_program_id,
+            bump_vec![in &bump;
+                accounts.market.clone(),
+                accounts.req_q.clone(),
+                accounts.event_q.clone(),
+                accounts.bids.clone(),
+                accounts.asks.clone(),
+                coin_account.clone(),
+                pc_account.clone(),
+            ]
+            .into_bump_slice(),
+            &MarketInstruction::MatchOrders(5).pack(),
+        )
+        .unwrap();
+    }
+    let pc_fees = MarketState::load(&accounts.market, &dex_program_id)
+        .unwrap()
+        .pc_fees_accrued;
+    assert!(pc_fees > 0);
+
+    let treasury_owner = random_pubkey(&mut rng, &bump);
+    let referrer_owner = random_pubkey(&mut rng, &bump);
+    let treasury_coin_wallet =
+        new_token_account(&mut rng, accounts.coin_mint.key, treasury_owner, &bump);
+    let treasury_pc_wallet =
+        new_token_account(&mut rng, accounts.pc_mint.key, treasury_owner, &bump);
+    let referrer_coin_wallet =
+        new_token_account(&mut rng, accounts.coin_mint.key, referrer_owner, &bump);
+    let referrer_pc_wallet =
+        new_token_account(&mut rng, accounts.pc_mint.key, referrer_owner, &bump);
+    let sweep_authority = AccountInfo::new(
+        &fee_sweeper::ID,
+        true,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    let set_fee_split_accounts = bump_vec![in &bump;
+        accounts.market.clone(),
+        sweep_authority.clone(),
+        treasury_coin_wallet.clone(),
+        treasury_pc_wallet.clone(),
+        referrer_coin_wallet.clone(),
+        referrer_pc_wallet.clone(),
+    ]
+    .into_bump_slice();
+    let set_fee_split = |staking_bps, treasury_bps, referrer_bps, route_staking_to_treasury| {
+        let instruction = MarketInstruction::SetFeeSplit(SetFeeSplitInstruction {
+            staking_bps,
+            treasury_bps,
+            referrer_bps,
+            route_staking_to_treasury,
+        });
+        State::process(dex_program_id, set_fee_split_accounts, &instruction.pack())
+    };
+    // The same accounts sweep without a signer once the market has a split
+    let sweep_accounts = bump_vec![in &bump;
+        accounts.market.clone(),
+        accounts.pc_vault.clone(),
+        treasury_pc_wallet.clone(),
+        referrer_pc_wallet.clone(),
+        accounts.vault_signer.clone(),
+        spl_token_program.clone(),
+    ]
+    .into_bump_slice();
+    let sweep = || {
+        State::process(
+            dex_program_id,
+            sweep_accounts,
+            &MarketInstruction::SweepFees.pack(),
+        )
+    };
+
+    // Without a split, sweeping still needs the fee sweeping authority
+    assert!(sweep().is_err());
+    assert_eq!(
+        set_fee_split(5_000, 5_000, 1, false),
+        Err(DexError::ErrorCode(DexErrorCode::InvalidFeeSplit))
+    );
+    {
+        let mut unsigned_accounts = set_fee_split_accounts.to_vec();
+        unsigned_accounts[1].is_signer = false;
+        let instruction = MarketInstruction::SetFeeSplit(SetFeeSplitInstruction {
+            staking_bps: 2_000,
+            treasury_bps: 5_000,
+            referrer_bps: 3_000,
+            route_staking_to_treasury: false,
+        });
+        assert!(State::process(dex_program_id, &unsigned_accounts, &instruction.pack()).is_err());
+    }
+    set_fee_split(2_000, 5_000, 3_000, false).unwrap();
+    {
+        let market = MarketStateV2::load(&accounts.market, &dex_program_id).unwrap();
+        assert_eq!(
+            market.fee_split.treasury_pc_wallet,
+            treasury_pc_wallet.key.to_aligned_bytes()
+        );
+        assert_eq!(
+            market.fee_split.referrer_coin_wallet,
+            referrer_coin_wallet.key.to_aligned_bytes()
+        );
+    }
+
+    // Only the recorded wallets, in the currency of the vault, can be paid
+    let mut swapped_accounts = sweep_accounts.to_vec();
+    swapped_accounts.swap(2, 3);
+    assert_eq!(
+        State::process(
+            dex_program_id,
+            &swapped_accounts,
+            &MarketInstruction::SweepFees.pack()
+        ),
+        Err(DexError::ErrorCode(DexErrorCode::WrongFeeWallet))
+    );
+    let mut coin_wallet_accounts = sweep_accounts.to_vec();
+    coin_wallet_accounts[2] = treasury_coin_wallet.clone();
+    assert!(State::process(
+        dex_program_id,
+        &coin_wallet_accounts,
+        &MarketInstruction::SweepFees.pack()
+    )
+    .is_err());
+
+    // Anyone can sweep; the staking share stays in the vault
+    assert!(!sweep_accounts[2..4].iter().any(|account| account.is_signer));
+    sweep().unwrap();
+    let staking_fees = pc_fees * 2_000 / 10_000;
+    {
+        let market = MarketStateV2::load(&accounts.market, &dex_program_id).unwrap();
+        assert_eq!(market.pc_fees_accrued, 0);
+        assert_eq!(market.fee_split.staking_pc_fees, staking_fees);
+        assert_eq!(market.fee_split.staking_coin_fees, 0);
+    }
+
+    // Routing the staking share to the treasury pays out what was held
+    set_fee_split(2_000, 5_000, 3_000, true).unwrap();
+    sweep().unwrap();
+    {
+        let market = MarketStateV2::load(&accounts.market, &dex_program_id).unwrap();
+        assert_eq!(market.fee_split.route_staking_to_treasury, 1);
+        assert_eq!(market.fee_split.staking_pc_fees, 0);
+    }
+}
+
+#[test]
+fn test_fee_split_arithmetic() {
+    let fee_split = FeeSplit {
+        staking_bps: 2_000,
+        treasury_bps: 5_000,
+        referrer_bps: 3_000,
+        ..FeeSplit::zeroed()
+    };
+    // The treasury takes what rounding leaves over
+    assert_eq!(fee_split.split(0), (0, 0, 0));
+    assert_eq!(fee_split.split(999), (199, 501, 299));
+    assert_eq!(fee_split.split(10_000), (2_000, 5_000, 3_000));
+    let (staking, treasury, referrer) = fee_split.split(u64::MAX);
+    assert_eq!(staking, u64::MAX / 5);
+    assert_eq!(staking + treasury + referrer, u64::MAX);
+
+    let treasury_only = FeeSplit {
+        treasury_bps: 10_000,
+        ..FeeSplit::zeroed()
+    };
+    assert_eq!(treasury_only.split(12_345), (0, 12_345, 0));
+    assert!(treasury_only.is_set());
+    assert!(!FeeSplit::zeroed().is_set());
+}
+
+/// Two markets quoted in the same currency. The first has a resting bid for 5
+/// lots at 100_000 and the second an ask for 4 lots at 50_000, both still in
+/// the request queues.
+fn setup_route_markets<'bump, R: Rng>(
+    rng: &mut R,
+    bump: &'bump Bump,
+) -> (MarketAccounts<'bump>, MarketAccounts<'bump>) {
+    let program_id = random_pubkey(rng, bump);
+    let first_coin_mint = new_token_mint(rng, bump);
+    let second_coin_mint = new_token_mint(rng, bump);
+    let pc_mint = new_token_mint(rng, bump);
+    let first = setup_market_with_mints(
+        rng,
+        program_id,
+        size_of::<MarketStateV2>(),
+        FeeCurrency::Pc,
+        first_coin_mint,
+        pc_mint.clone(),
+        bump,
+    );
+    let second = setup_market_with_mints(
+        rng,
+        program_id,
+        size_of::<MarketStateV2>(),
+        FeeCurrency::Pc,
+        second_coin_mint,
+        pc_mint,
+        bump,
+    );
+
+    let maker = new_sol_account(rng, 1_000_000_000, bump);
+    let spl_token_program = new_spl_token_program(bump);
+    let orders = [
+        (&first, Side::Bid, 100_000, 5),
+        (&second, Side::Ask, 50_000, 4),
+    ];
+    for &(accounts, side, limit_price, max_qty) in orders.iter() {
+        let orders_account = new_dex_owned_account(rng, size_of::<OpenOrders>(), program_id, bump);
+        let payer_mint = match side {
+            Side::Bid => accounts.pc_mint.key,
+            Side::Ask => accounts.coin_mint.key,
+        };
+        let payer = new_token_account(rng, payer_mint, maker.key, bump);
+        let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+            side,
+            limit_price: NonZeroU64::new(limit_price).unwrap(),
+            max_qty: NonZeroU64::new(max_qty).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+        })
+        .pack();
+        let instruction_accounts = bump_vec![in bump;
+            accounts.market.clone(),
+            orders_account,
+            accounts.req_q.clone(),
+            payer,
+            maker.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            spl_token_program.clone(),
+            accounts.rent_sysvar.clone(),
+        ]
+        .into_bump_slice();
+        State::process(program_id, instruction_accounts, &instruction_data).unwrap();
+    }
+    (first, second)
+}
+
+#[test]
+fn test_route() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let bump = Bump::new();
+
+    // Sell 3 lots of the first coin, then spend the proceeds on the second.
+    for &(min_native_amount_out, succeeds) in &[(4_001, false), (4_000, true)] {
+        let (first, second) = setup_route_markets(&mut rng, &bump);
+        let dex_program_id = first.market.owner;
+
+        let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+        let source_account = new_token_account(&mut rng, first.coin_mint.key, owner.key, &bump);
+        let intermediate_account = new_token_account(&mut rng, first.pc_mint.key, owner.key, &bump);
+        let destination_account =
+            new_token_account(&mut rng, second.coin_mint.key, owner.key, &bump);
+
+        let instruction_data = MarketInstruction::Route(RouteInstruction {
+            first_side: Side::Ask,
+            second_side: Side::Bid,
+            native_amount_in: 3_000,
+            min_native_amount_out,
+            limit: 10,
+        })
+        .pack();
+        let instruction_accounts = bump_vec![in &bump;
+            first.market.clone(),
+            first.req_q.clone(),
+            first.event_q.clone(),
+            first.bids.clone(),
+            first.asks.clone(),
+            first.coin_vault.clone(),
+            first.pc_vault.clone(),
+            first.vault_signer.clone(),
+            second.market.clone(),
+            second.req_q.clone(),
+            second.event_q.clone(),
+            second.bids.clone(),
+            second.asks.clone(),
+            second.coin_vault.clone(),
+            second.pc_vault.clone(),
+            second.vault_signer.clone(),
+            owner.clone(),
+            source_account.clone(),
+            intermediate_account.clone(),
+            destination_account.clone(),
+            new_spl_token_program(&bump),
+        ]
+        .into_bump_slice();
+        let result = State::process(dex_program_id, instruction_accounts, &instruction_data);
+        if !succeeds {
+            assert_eq!(
+                result,
+                Err(DexError::ErrorCode(DexErrorCode::RouteOutputTooSmall))
+            );
+            continue;
+        }
+        result.unwrap();
+
+        {
+            // 300_000 received, less the 660 taker fee
+            let market = MarketState::load(&first.market, dex_program_id).unwrap();
+            assert_eq!(market.coin_deposits_total, 3_000);
+            assert_eq!(market.pc_deposits_total, 201_190);
+            assert_eq!(market.pc_fees_accrued, 570);
+        }
+        {
+            // 299_340 available, but only 4 lots on offer
+            let market = MarketState::load(&second.market, dex_program_id).unwrap();
+            assert_eq!(market.coin_deposits_total, 0);
+            assert_eq!(market.pc_deposits_total, 200_060);
+            assert_eq!(market.pc_fees_accrued, 380);
+        }
+    }
+}
+
+#[test]
+fn test_referrer() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let referrer_owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let referrer_wallet =
+        new_token_account(&mut rng, accounts.pc_mint.key, referrer_owner.key, &bump);
+    let referrer_acc =
+        new_dex_owned_account(&mut rng, size_of::<Referrer>(), dex_program_id, &bump);
+    let sweep_authority = AccountInfo::new(
+        &fee_sweeper::ID,
+        true,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    let init_referrer_accounts = bump_vec![in &bump;
+        referrer_acc.clone(),
+        accounts.market.clone(),
+        referrer_owner.clone(),
+        referrer_wallet.clone(),
+        sweep_authority.clone(),
+        accounts.rent_sysvar.clone(),
+    ]
+    .into_bump_slice();
+
+    // The referrer can't be paid more than the share set aside for referrers
+    let instruction = init_referrer(
+        dex_program_id,
+        referrer_acc.key,
+        accounts.market.key,
+        referrer_owner.key,
+        referrer_wallet.key,
+        2_001,
+    )
+    .unwrap();
+    let result = State::process(dex_program_id, init_referrer_accounts, &instruction.data);
+    assert_eq!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::InvalidReferrerRebate))
+    );
+    let instruction = init_referrer(
+        dex_program_id,
+        referrer_acc.key,
+        accounts.market.key,
+        referrer_owner.key,
+        referrer_wallet.key,
+        1_000,
+    )
+    .unwrap();
+    State::process(dex_program_id, init_referrer_accounts, &instruction.data).unwrap();
+    let result = State::process(dex_program_id, init_referrer_accounts, &instruction.data);
+    assert_eq!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::AlreadyInitialized))
+    );
+
+    // The taker's OpenOrders account is created with the referrer
+    let (open_orders_key, instruction) = init_open_orders_with_referrer(
+        dex_program_id,
+        accounts.market.key,
+        owner.key,
+        owner.key,
+        0,
+        128,
+        referrer_acc.key,
+    )
+    .unwrap();
+    let orders_account_seller = AccountInfo::new(
+        bump.alloc(open_orders_key),
+        false,
+        true,
+        bump.alloc(100_000_000),
+        allocate_dex_owned_account(size_of::<OpenOrdersV2>(), &bump),
+        dex_program_id,
+        false,
+        Epoch::default(),
+    );
+    let instruction_accounts = bump_vec![in &bump;
+        orders_account_seller.clone(),
+        owner.clone(),
+        accounts.market.clone(),
+        owner.clone(),
+        new_system_program(&bump),
+        accounts.rent_sysvar.clone(),
+        referrer_acc.clone(),
+    ]
+    .into_bump_slice();
+    State::process(dex_program_id, instruction_accounts, &instruction.data).unwrap();
+
+    let orders_account_buyer =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+    let orders = [
+        (&orders_account_buyer, &pc_account, Side::Bid, 100_000, 5),
+        (&orders_account_seller, &coin_account, Side::Ask, 99_000, 4),
+    ];
+    for &(orders_account, payer, side, limit_price, max_qty) in orders.iter() {
+        let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+            side,
+            limit_price: NonZeroU64::new(limit_price).unwrap(),
+            max_qty: NonZeroU64::new(max_qty).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+        })
+        .pack();
+        let instruction_accounts = bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            payer.clone(),
+            owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            spl_token_program.clone(),
+            accounts.rent_sysvar.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data).unwrap();
+        let instruction_data = MarketInstruction::MatchOrders(5).pack();
+        let instruction_accounts = bump_vec![in &bump;
+            accounts.market.clone(),
+            accounts.req_q.clone(),
+            accounts.event_q.clone(),
+            accounts.bids.clone(),
+            accounts.asks.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data).unwrap();
+    }
+    {
+        let crank_accounts = bump_vec![in &bump;
+            orders_account_buyer.clone(),
+            orders_account_seller.clone(),
+            accounts.market.clone(),
+            accounts.event_q.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice_mut();
+        crank_accounts[0..2].sort_by_key(|account_info| account_info.key.to_aligned_bytes());
+        let instruction_data = MarketInstruction::ConsumeEvents(200).pack();
+        State::process(dex_program_id, crank_accounts, &instruction_data).unwrap();
+    }
+
+    let settle = |referrer_accounts| {
+        let mut instruction_accounts = bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account_seller.clone(),
+            owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+            accounts.vault_signer.clone(),
+            spl_token_program.clone(),
+        ];
+        instruction_accounts
+            .extend(<[&AccountInfo]>::iter(referrer_accounts).map(|&account| account.clone()));
+        let instruction_data = MarketInstruction::SettleFunds.pack();
+        State::process(
+            dex_program_id,
+            instruction_accounts.into_bump_slice(),
+            &instruction_data,
+        )
+    };
+    let load_referrer_rebates = || {
+        MarketState::load(&accounts.market, dex_program_id)
+            .unwrap()
+            .load_orders_mut(&orders_account_seller, None, dex_program_id, None)
+            .unwrap()
+            .referrer_rebates_accrued
+    };
+
+    // Only the registered referrer can be paid
+    assert_eq!(
+        settle(&[&pc_account]),
+        Err(DexError::ErrorCode(DexErrorCode::WrongReferrerAccount))
+    );
+    assert_eq!(
+        settle(&[&pc_account, &referrer_acc]),
+        Err(DexError::ErrorCode(DexErrorCode::WrongReferrerWallet))
+    );
+
+    // Without the referrer the rebates are kept for later
+    settle(&[]).unwrap();
+    assert_eq!(load_referrer_rebates(), 176);
+    {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        assert_eq!(market.referrer_rebates_accrued, 176);
+        assert_eq!(market.pc_fees_accrued, 584);
+    }
+
+    // The referrer is paid half of the rebates set aside, and the market
+    // keeps the rest
+    settle(&[&referrer_wallet, &referrer_acc]).unwrap();
+    assert_eq!(load_referrer_rebates(), 0);
+    {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        assert_eq!(market.referrer_rebates_accrued, 0);
+        assert_eq!(market.pc_fees_accrued, 672);
+    }
+}
+
+#[test]
+fn test_client_order_ids() {
+    let mut rng = StdRng::seed_from_u64(6);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+    let new_order_accounts = bump_vec![in &bump;
+        accounts.market.clone(),
+        orders_account.clone(),
+        accounts.req_q.clone(),
+        pc_account.clone(),
+        owner.clone(),
+        accounts.coin_vault.clone(),
+        accounts.pc_vault.clone(),
+        spl_token_program.clone(),
+        accounts.rent_sysvar.clone(),
+    ]
+    .into_bump_slice();
+    let bid = |client_id| NewOrderInstructionV1 {
+        side: Side::Bid,
+        limit_price: NonZeroU64::new(100_000).unwrap(),
+        max_qty: NonZeroU64::new(1).unwrap(),
+        order_type: OrderType::Limit,
+        client_id,
+    };
+
+    // Without the check, client ids may repeat
+    for _ in 0..2 {
+        let instruction_data = MarketInstruction::NewOrder(bid(7)).pack();
+        State::process(dex_program_id, new_order_accounts, &instruction_data).unwrap();
+    }
+    let instruction_data = MarketInstruction::NewOrderV3(
+        bid(7)
+            .add_self_trade_behavior(SelfTradeBehavior::DecrementTake)
+            .add_reject_duplicate_client_id(true),
+    )
+    .pack();
+    let result = State::process(dex_program_id, new_order_accounts, &instruction_data);
+    assert_eq!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::DuplicateClientOrderId))
+    );
+    let instruction_data = MarketInstruction::NewOrderV3(
+        bid(9)
+            .add_self_trade_behavior(SelfTradeBehavior::DecrementTake)
+            .add_reject_duplicate_client_id(true),
+    )
+    .pack();
+    State::process(dex_program_id, new_order_accounts, &instruction_data).unwrap();
+
+    // Both orders with client id 7 are cancelled, unknown ids are ignored
+    let mut client_ids = [0; MAX_CANCEL_CLIENT_IDS];
+    client_ids[0] = 7;
+    client_ids[1] = 8;
+    let instruction_data = MarketInstruction::CancelOrdersByClientIds(client_ids).pack();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            owner.clone(),
+        ]
+        .into_bump_slice(),
+        &instruction_data,
+    )
+    .unwrap();
+
+    let instruction_data = MarketInstruction::MatchOrders(5).pack();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            accounts.req_q.clone(),
+            accounts.event_q.clone(),
+            accounts.bids.clone(),
+            accounts.asks.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice(),
+        &instruction_data,
+    )
+    .unwrap();
+    let instruction_data = MarketInstruction::ConsumeEvents(200).pack();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            orders_account.clone(),
+            accounts.market.clone(),
+            accounts.event_q.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice(),
+        &instruction_data,
+    )
+    .unwrap();
+
+    let open_orders = MarketState::load(&accounts.market, &dex_program_id)
+        .unwrap()
+        .load_orders_mut(&orders_account, None, &dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.free_slot_bits.count_zeros(), 1);
+    let live_slot = (!open_orders.free_slot_bits).trailing_zeros() as usize;
+    assert_eq!(open_orders.client_order_ids[live_slot], 9);
+}
+
+fn new_order_accounts<'bump>(
+    bump: &'bump Bump,
+    accounts: &MarketAccounts<'bump>,
+    orders_account: &AccountInfo<'bump>,
+    payer: &AccountInfo<'bump>,
+    owner: &AccountInfo<'bump>,
+) -> BumpVec<'bump, AccountInfo<'bump>> {
+    bump_vec![in bump;
+        accounts.market.clone(),
+        orders_account.clone(),
+        accounts.req_q.clone(),
+        payer.clone(),
+        owner.clone(),
+        accounts.coin_vault.clone(),
+        accounts.pc_vault.clone(),
+        new_spl_token_program(bump),
+        accounts.rent_sysvar.clone(),
+    ]
+}
+
+#[test]
+fn test_trigger_orders() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account_buyer =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let orders_account_seller =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let orders_account_taker =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let trigger_orders_acc = new_dex_owned_account(
+        &mut rng,
+        size_of::<TriggerOrdersHeader>() + 4 * size_of::<TriggerOrder>(),
+        dex_program_id,
+        &bump,
+    );
+    let sweep_authority = AccountInfo::new(
+        &fee_sweeper::ID,
+        true,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+
+    let instruction =
+        init_trigger_orders(dex_program_id, trigger_orders_acc.key, accounts.market.key).unwrap();
+    let init_accounts = bump_vec![in &bump;
+        trigger_orders_acc.clone(),
+        accounts.market.clone(),
+        sweep_authority.clone(),
+        accounts.rent_sysvar.clone(),
+    ]
+    .into_bump_slice();
+    State::process(dex_program_id, init_accounts, &instruction.data).unwrap();
+    let result = State::process(dex_program_id, init_accounts, &instruction.data);
+    assert_eq!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::AlreadyInitialized))
+    );
+
+    let new_order = |orders_account, payer, instruction: MarketInstruction| {
+        let mut instruction_accounts =
+            new_order_accounts(&bump, &accounts, orders_account, payer, &owner);
+        if let MarketInstruction::NewOrderV4(_) = instruction {
+            instruction_accounts.push(trigger_orders_acc.clone());
+        }
+        State::process(
+            dex_program_id,
+            instruction_accounts.into_bump_slice(),
+            &instruction.pack(),
+        )
+        .unwrap();
+    };
+    let order = |side, limit_price, max_qty, order_type, client_id| NewOrderInstructionV1 {
+        side,
+        limit_price: NonZeroU64::new(limit_price).unwrap(),
+        max_qty: NonZeroU64::new(max_qty).unwrap(),
+        order_type,
+        client_id,
+    };
+    let trigger_order = |instruction: NewOrderInstructionV1, trigger_price, trigger_direction| {
+        MarketInstruction::NewOrderV4(
+            instruction
+                .add_self_trade_behavior(SelfTradeBehavior::DecrementTake)
+                .add_reject_duplicate_client_id(false)
+                .add_trigger(NonZeroU64::new(trigger_price).unwrap(), trigger_direction),
+        )
+    };
+    let match_accounts = bump_vec![in &bump;
+        accounts.market.clone(),
+        accounts.req_q.clone(),
+        accounts.event_q.clone(),
+        accounts.bids.clone(),
+        accounts.asks.clone(),
+        coin_account.clone(),
+        pc_account.clone(),
+        trigger_orders_acc.clone(),
+    ]
+    .into_bump_slice();
+    let match_orders = || {
+        let instruction_data = MarketInstruction::MatchOrders(20).pack();
+        State::process(dex_program_id, match_accounts, &instruction_data).unwrap();
+    };
+
+    // A resting bid, and a stop-loss ask that sells into it once the price
+    // trades down to 100_000
+    new_order(
+        &orders_account_buyer,
+        &pc_account,
+        MarketInstruction::NewOrder(order(Side::Bid, 100_000, 5, OrderType::Limit, 0)),
+    );
+    new_order(
+        &orders_account_seller,
+        &coin_account,
+        trigger_order(
+            order(Side::Ask, 99_000, 2, OrderType::ImmediateOrCancel, 0),
+            100_000,
+            TriggerDirection::Below,
+        ),
+    );
+    let instruction_data = MarketInstruction::MatchOrders(20).pack();
+    let result = State::process(dex_program_id, &match_accounts[..7], &instruction_data);
+    assert_eq!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongTriggerOrdersAccount))
+    );
+    match_orders();
+    {
+        let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+        let open_orders_seller = market
+            .load_orders_mut(&orders_account_seller, None, &dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders_seller.native_coin_free, 0);
+        assert_eq!(open_orders_seller.native_coin_total, 2_000);
+        let open_orders_buyer = market
+            .load_orders_mut(&orders_account_buyer, None, &dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders_buyer.native_coin_total, 0);
+    }
+
+    // A take-profit ask that is cancelled before it triggers
+    new_order(
+        &orders_account_seller,
+        &coin_account,
+        trigger_order(
+            order(Side::Ask, 200_000, 1, OrderType::Limit, 42),
+            200_000,
+            TriggerDirection::Above,
+        ),
+    );
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account_seller.clone(),
+            accounts.req_q.clone(),
+            owner.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::CancelOrderByClientId(42).pack(),
+    )
+    .unwrap();
+
+    // A fill at 100_000 triggers the stop-loss, which fills in the same crank
+    new_order(
+        &orders_account_taker,
+        &coin_account,
+        MarketInstruction::NewOrder(order(Side::Ask, 100_000, 1, OrderType::Limit, 0)),
+    );
+    match_orders();
+    let consume_events = || {
+        let crank_accounts = bump_vec![in &bump;
+            orders_account_buyer.clone(),
+            orders_account_seller.clone(),
+            orders_account_taker.clone(),
+            accounts.market.clone(),
+            accounts.event_q.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice_mut();
+        crank_accounts[0..3].sort_by_key(|account_info| account_info.key.to_aligned_bytes());
+        let instruction_data = MarketInstruction::ConsumeEvents(200).pack();
+        State::process(dex_program_id, crank_accounts, &instruction_data).unwrap();
+    };
+    consume_events();
+    {
+        let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+        let open_orders_buyer = market
+            .load_orders_mut(&orders_account_buyer, None, &dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders_buyer.native_coin_free, 3_000);
+        let open_orders_seller = market
+            .load_orders_mut(&orders_account_seller, None, &dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders_seller.native_coin_free, 1_000);
+        assert_eq!(open_orders_seller.native_coin_total, 1_000);
+        assert!(open_orders_seller.native_pc_free > 0);
+        assert_eq!(open_orders_seller.free_slot_bits, std::u128::MAX);
+    }
+
+    // A take-profit ask placed before another ask at its price queues behind
+    // it once it's triggered, and can still be cancelled by client id before
+    // its slot has caught up with its new order id.
+    new_order(
+        &orders_account_seller,
+        &coin_account,
+        trigger_order(
+            order(Side::Ask, 150_000, 1, OrderType::Limit, 7),
+            101_000,
+            TriggerDirection::Above,
+        ),
+    );
+    new_order(
+        &orders_account_taker,
+        &coin_account,
+        MarketInstruction::NewOrder(order(Side::Ask, 150_000, 2, OrderType::Limit, 0)),
+    );
+    new_order(
+        &orders_account_buyer,
+        &pc_account,
+        MarketInstruction::NewOrder(order(Side::Bid, 150_000, 1, OrderType::Limit, 0)),
+    );
+    match_orders();
+    let load_asks = || decode_orders(&accounts.asks.try_borrow_data().unwrap(), Side::Ask).unwrap();
+    {
+        let asks = load_asks();
+        let ask_owners: Vec<[u64; 4]> = asks.iter().map(|order| *order.owner()).collect();
+        assert_eq!(
+            ask_owners,
+            vec![
+                orders_account_taker.key.to_aligned_bytes(),
+                orders_account_seller.key.to_aligned_bytes(),
+            ]
+        );
+        let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+        let open_orders_seller = market
+            .load_orders_mut(&orders_account_seller, None, &dex_program_id, None)
+            .unwrap();
+        let triggered_order_id = *asks[1].order_id();
+        assert_ne!(open_orders_seller.orders[0], triggered_order_id);
+        assert_eq!(open_orders_seller.orders[0] >> 64, triggered_order_id >> 64);
+    }
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account_seller.clone(),
+            accounts.req_q.clone(),
+            owner.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::CancelOrderByClientId(7).pack(),
+    )
+    .unwrap();
+    match_orders();
+    consume_events();
+
+    assert_eq!(load_asks().len(), 1);
+    {
+        let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+        let open_orders_seller = market
+            .load_orders_mut(&orders_account_seller, None, &dex_program_id, None)
+            .unwrap();
+        assert_eq!(
+            open_orders_seller.native_coin_free,
+            open_orders_seller.native_coin_total
+        );
+        assert_eq!(open_orders_seller.free_slot_bits, std::u128::MAX);
+    }
+
+    // The same race with a cancel by the order id the slot still holds
+    new_order(
+        &orders_account_seller,
+        &coin_account,
+        trigger_order(
+            order(Side::Ask, 160_000, 1, OrderType::Limit, 8),
+            151_000,
+            TriggerDirection::Above,
+        ),
+    );
+    new_order(
+        &orders_account_buyer,
+        &pc_account,
+        MarketInstruction::NewOrder(order(Side::Bid, 155_000, 2, OrderType::Limit, 0)),
+    );
+    new_order(
+        &orders_account_taker,
+        &coin_account,
+        MarketInstruction::NewOrder(order(Side::Ask, 155_000, 1, OrderType::Limit, 0)),
+    );
+    match_orders();
+    let previous_order_id = {
+        let asks = load_asks();
+        assert_eq!(asks.len(), 1);
+        assert_eq!(
+            *asks[0].owner(),
+            orders_account_seller.key.to_aligned_bytes()
+        );
+        let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+        let open_orders_seller = market
+            .load_orders_mut(&orders_account_seller, None, &dex_program_id, None)
+            .unwrap();
+        assert_ne!(open_orders_seller.orders[0], *asks[0].order_id());
+        open_orders_seller.orders[0]
+    };
+    let cancel_accounts = bump_vec![in &bump;
+        accounts.market.clone(),
+        orders_account_seller.clone(),
+        accounts.req_q.clone(),
+        owner.clone(),
+    ]
+    .into_bump_slice();
+    let cancel_by_order_id = MarketInstruction::CancelOrder(CancelOrderInstruction {
+        side: Side::Ask,
+        order_id: previous_order_id,
+        owner: [0; 4],
+        owner_slot: 0,
+    })
+    .pack();
+    State::process(dex_program_id, cancel_accounts, &cancel_by_order_id).unwrap();
+    match_orders();
+    consume_events();
+
+    assert_eq!(load_asks().len(), 0);
+    {
+        let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+        let open_orders_seller = market
+            .load_orders_mut(&orders_account_seller, None, &dex_program_id, None)
+            .unwrap();
+        assert_eq!(
+            open_orders_seller.native_coin_free,
+            open_orders_seller.native_coin_total
+        );
+        assert_eq!(open_orders_seller.free_slot_bits, std::u128::MAX);
+    }
+
+    // Once the slot is free, the id isn't found anymore
+    assert_eq!(
+        State::process(dex_program_id, cancel_accounts, &cancel_by_order_id),
+        Err(DexError::ErrorCode(DexErrorCode::OrderIdNotFound))
+    );
+}
+
+#[test]
+fn test_new_orders() {
+    let mut rng = StdRng::seed_from_u64(8);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+    let new_orders_accounts = bump_vec![in &bump;
+        accounts.market.clone(),
+        orders_account.clone(),
+        accounts.req_q.clone(),
+        coin_account.clone(),
+        pc_account.clone(),
+        owner.clone(),
+        accounts.coin_vault.clone(),
+        accounts.pc_vault.clone(),
+        spl_token_program.clone(),
+        accounts.rent_sysvar.clone(),
+    ]
+    .into_bump_slice();
+
+    // Four bids below 100_000 and four asks above it, one more than the
+    // request queue holds
+    let entry = |side, price: u64, client_id| NewOrdersEntry {
+        side,
+        limit_price: NonZeroU64::new(price).unwrap(),
+        max_qty: NonZeroU64::new(1).unwrap(),
+        client_id,
+    };
+    let mut orders = vec![];
+    for i in 1..=4 {
+        orders.push(entry(Side::Bid, 100_000 - 1_000 * i, i));
+    }
+    for i in 1..=4 {
+        orders.push(entry(Side::Ask, 100_000 + 1_000 * i, 4 + i));
+    }
+    let new_orders = |all_or_nothing| {
+        MarketInstruction::NewOrders(NewOrdersInstruction {
+            order_type: OrderType::Limit,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            all_or_nothing,
+            orders: orders.clone(),
+        })
+        .pack()
+    };
+
+    let result = State::process(dex_program_id, new_orders_accounts, &new_orders(true));
+    assert_eq!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::RequestQueueFull))
+    );
+
+    // Without all_or_nothing the last ask is dropped
+    State::process(dex_program_id, new_orders_accounts, &new_orders(false)).unwrap();
+    {
+        let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+        let open_orders = market
+            .load_orders_mut(&orders_account, None, &dex_program_id, None)
+            .unwrap();
+        let pc_locked: u64 = orders[..4]
+            .iter()
+            .map(|order| {
+                let price = order.limit_price.get();
+                price + FeeTier::Base.taker_fee(price)
+            })
+            .sum();
+        assert_eq!(open_orders.native_pc_total, pc_locked);
+        assert_eq!(open_orders.native_coin_total, 3_000);
+        assert_eq!(open_orders.free_slot_bits.count_zeros(), 7);
+        assert!(open_orders.client_order_ids.contains(&7));
+        assert!(!open_orders.client_order_ids.contains(&8));
+        assert_eq!(market.pc_deposits_total, pc_locked);
+        assert_eq!(market.coin_deposits_total, 3_000);
+    }
+
+    // Now the request queue is full, so none of them fit
+    let result = State::process(dex_program_id, new_orders_accounts, &new_orders(false));
+    assert_eq!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::RequestQueueFull))
+    );
+
+    // None of the orders cross, so they all rest on the book
+    let instruction_data = MarketInstruction::MatchOrders(10).pack();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            accounts.req_q.clone(),
+            accounts.event_q.clone(),
+            accounts.bids.clone(),
+            accounts.asks.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice(),
+        &instruction_data,
+    )
+    .unwrap();
+    let open_orders = MarketState::load(&accounts.market, &dex_program_id)
+        .unwrap()
+        .load_orders_mut(&orders_account, None, &dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.free_slot_bits.count_zeros(), 7);
+    assert_eq!(open_orders.native_coin_free, 0);
+    assert_eq!(open_orders.native_pc_free, 0);
+}
+
+#[test]
+fn test_open_orders_delegate() {
+    let mut rng = StdRng::seed_from_u64(9);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let delegate = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrdersV2>(), dex_program_id, &bump);
+    let v1_orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let delegate_coin_account =
+        new_token_account(&mut rng, accounts.coin_mint.key, delegate.key, &bump);
+    let delegate_pc_account =
+        new_token_account(&mut rng, accounts.pc_mint.key, delegate.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let new_order = |orders_account, payer, signer, client_id| {
+        let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(100_000).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id,
+        })
+        .pack();
+        State::process(
+            dex_program_id,
+            new_order_accounts(&bump, &accounts, orders_account, payer, signer).into_bump_slice(),
+            &instruction_data,
+        )
+    };
+    let set_delegate = |orders_account, signer, delegate| {
+        let mut instruction_accounts = bump_vec![in &bump;
+            AccountInfo::clone(orders_account),
+            AccountInfo::clone(signer),
+            accounts.market.clone(),
+        ];
+        if let Some(delegate) = delegate {
+            instruction_accounts.push(AccountInfo::clone(delegate));
+        }
+        State::process(
+            dex_program_id,
+            instruction_accounts.into_bump_slice(),
+            &MarketInstruction::SetOpenOrdersDelegate.pack(),
+        )
+    };
+    let settle = |coin_wallet, pc_wallet| {
+        State::process(
+            dex_program_id,
+            bump_vec![in &bump;
+                accounts.market.clone(),
+                orders_account.clone(),
+                delegate.clone(),
+                accounts.coin_vault.clone(),
+                accounts.pc_vault.clone(),
+                AccountInfo::clone(coin_wallet),
+                AccountInfo::clone(pc_wallet),
+                accounts.vault_signer.clone(),
+                spl_token_program.clone(),
+            ]
+            .into_bump_slice(),
+            &MarketInstruction::SettleFunds.pack(),
+        )
+    };
+
+    new_order(&orders_account, &pc_account, &owner, 1).unwrap();
+    new_order(&v1_orders_account, &pc_account, &owner, 1).unwrap();
+
+    // Only the owner sets the delegate, and only on accounts with room for it
+    assert_eq!(
+        set_delegate(&orders_account, &delegate, Some(&delegate)),
+        Err(DexError::ErrorCode(DexErrorCode::WrongOrdersAccount))
+    );
+    assert_eq!(
+        set_delegate(&v1_orders_account, &owner, Some(&delegate)),
+        Err(DexError::ErrorCode(DexErrorCode::DelegateUnsupported))
+    );
+    assert_eq!(
+        new_order(&orders_account, &delegate_pc_account, &delegate, 2),
+        Err(DexError::ErrorCode(DexErrorCode::WrongOrdersAccount))
+    );
+    set_delegate(&orders_account, &owner, Some(&delegate)).unwrap();
+
+    // The delegate trades, but settles only to the owner's wallets
+    new_order(&orders_account, &delegate_pc_account, &delegate, 2).unwrap();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            delegate.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::CancelOrderByClientId(1).pack(),
+    )
+    .unwrap();
+    assert_eq!(
+        set_delegate(&orders_account, &delegate, None),
+        Err(DexError::ErrorCode(DexErrorCode::WrongOrdersAccount))
+    );
+    assert_eq!(
+        settle(&delegate_coin_account, &pc_account),
+        Err(DexError::ErrorCode(DexErrorCode::WrongDelegateSettleWallet))
+    );
+    assert_eq!(
+        settle(&coin_account, &delegate_pc_account),
+        Err(DexError::ErrorCode(DexErrorCode::WrongDelegateSettleWallet))
+    );
+    settle(&coin_account, &pc_account).unwrap();
+
+    // Once removed, the delegate can't trade
+    set_delegate(&orders_account, &owner, None).unwrap();
+    assert_eq!(
+        new_order(&orders_account, &delegate_pc_account, &delegate, 3),
+        Err(DexError::ErrorCode(DexErrorCode::WrongOrdersAccount))
+    );
+    assert_eq!(
+        settle(&coin_account, &pc_account),
+        Err(DexError::ErrorCode(DexErrorCode::WrongOrdersAccount))
+    );
+}
+
+#[test]
+fn test_consume_events_permissive() {
+    let mut rng = StdRng::seed_from_u64(10);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account_first =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let orders_account_second =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let orders_account_taker =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+
+    let new_order = |orders_account, payer, side, limit_price, max_qty| {
+        let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+            side,
+            limit_price: NonZeroU64::new(limit_price).unwrap(),
+            max_qty: NonZeroU64::new(max_qty).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+        })
+        .pack();
+        State::process(
+            dex_program_id,
+            new_order_accounts(&bump, &accounts, orders_account, payer, &owner).into_bump_slice(),
+            &instruction_data,
+        )
+        .unwrap();
+    };
+    let consume_events = |orders_accounts, permissive| {
+        let mut crank_accounts = BumpVec::new_in(&bump);
+        crank_accounts
+            .extend(<[&AccountInfo]>::iter(orders_accounts).map(|&account| account.clone()));
+        crank_accounts.sort_by_key(|account_info| account_info.key.to_aligned_bytes());
+        crank_accounts.extend(bump_vec![in &bump;
+            accounts.market.clone(),
+            accounts.event_q.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]);
+        let instruction = if permissive {
+            MarketInstruction::ConsumeEventsPermissive(20)
+        } else {
+            MarketInstruction::ConsumeEvents(20)
+        };
+        State::process(
+            dex_program_id,
+            crank_accounts.into_bump_slice(),
+            &instruction.pack(),
+        )
+        .unwrap();
+    };
+    let load_coin_free = |orders_account| {
+        MarketState::load(&accounts.market, &dex_program_id)
+            .unwrap()
+            .load_orders_mut(orders_account, None, &dex_program_id, None)
+            .unwrap()
+            .native_coin_free
+    };
+
+    // The taker fills the first bid, then the second
+    new_order(&orders_account_first, &pc_account, Side::Bid, 100_000, 1);
+    new_order(&orders_account_second, &pc_account, Side::Bid, 99_000, 1);
+    new_order(&orders_account_taker, &coin_account, Side::Ask, 99_000, 2);
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            accounts.req_q.clone(),
+            accounts.event_q.clone(),
+            accounts.bids.clone(),
+            accounts.asks.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::MatchOrders(5).pack(),
+    )
+    .unwrap();
+
+    // ConsumeEvents stops at the events of the first bid
+    consume_events(&[&orders_account_second], false);
+    assert_eq!(load_coin_free(&orders_account_second), 0);
+
+    consume_events(&[&orders_account_second], true);
+    assert_eq!(load_coin_free(&orders_account_second), 1_000);
+    assert_eq!(load_coin_free(&orders_account_first), 0);
+
+    // The events left are still in order for the other accounts
+    consume_events(&[&orders_account_first, &orders_account_taker], false);
+    assert_eq!(load_coin_free(&orders_account_first), 1_000);
+    assert_eq!(load_coin_free(&orders_account_second), 1_000);
+    for orders_account in &[&orders_account_first, &orders_account_second] {
+        let open_orders = MarketState::load(&accounts.market, &dex_program_id)
+            .unwrap()
+            .load_orders_mut(orders_account, None, &dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders.free_slot_bits, std::u128::MAX);
+    }
+}
+
+#[test]
+fn test_crank_rewards() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let cranker = new_sol_account(&mut rng, 0, &bump);
+    let orders_account_bid =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let orders_account_ask =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let sweep_authority = AccountInfo::new(
+        &fee_sweeper::ID,
+        true,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+
+    let instruction = set_crank_reward(dex_program_id, accounts.market.key, 1_000_000).unwrap();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump; accounts.market.clone(), sweep_authority.clone()].into_bump_slice(),
+        &instruction.data,
+    )
+    .unwrap();
+
+    let system_program = new_system_program(&bump);
+    let new_order = |orders_account, payer, side, with_system_program| {
+        let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+            side,
+            limit_price: NonZeroU64::new(100_000).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+        })
+        .pack();
+        let mut instruction_accounts =
+            new_order_accounts(&bump, &accounts, orders_account, payer, &owner);
+        if with_system_program {
+            instruction_accounts.push(system_program.clone());
+        }
+        State::process(
+            dex_program_id,
+            instruction_accounts.into_bump_slice(),
+            &instruction_data,
+        )
+    };
+    let match_orders = || {
+        State::process(
+            dex_program_id,
+            bump_vec![in &bump;
+                accounts.market.clone(),
+                accounts.req_q.clone(),
+                accounts.event_q.clone(),
+                accounts.bids.clone(),
+                accounts.asks.clone(),
+                coin_account.clone(),
+                pc_account.clone(),
+                cranker.clone(),
+            ]
+            .into_bump_slice(),
+            &MarketInstruction::MatchOrders(5).pack(),
+        )
+        .unwrap();
+    };
+    let consume_events = || {
+        let mut orders_accounts = bump_vec![in &bump;
+            orders_account_bid.clone(),
+            orders_account_ask.clone(),
+        ];
+        orders_accounts.sort_by_key(|account_info| account_info.key.to_aligned_bytes());
+        let mut crank_accounts = bump_vec![in &bump; cranker.clone()];
+        crank_accounts.extend(orders_accounts);
+        crank_accounts.extend(bump_vec![in &bump;
+            accounts.market.clone(),
+            accounts.event_q.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]);
+        State::process(
+            dex_program_id,
+            crank_accounts.into_bump_slice(),
+            &MarketInstruction::ConsumeEvents(20).pack(),
+        )
+        .unwrap();
+    };
+
+    // The deposit is transferred from the owner through the system program
+    assert_eq!(
+        new_order(&orders_account_bid, &pc_account, Side::Bid, false),
+        Err(DexError::ErrorCode(DexErrorCode::SystemProgramNotProvided))
+    );
+    let owner_lamports = owner.lamports();
+    **owner.lamports.borrow_mut() = 999_999;
+    assert_eq!(
+        new_order(&orders_account_bid, &pc_account, Side::Bid, true),
+        Err(DexError::ErrorCode(DexErrorCode::InsufficientCrankDeposit))
+    );
+    **owner.lamports.borrow_mut() = owner_lamports;
+
+    let orders_lamports = orders_account_bid.lamports();
+    let market_lamports = accounts.market.lamports();
+    new_order(&orders_account_bid, &pc_account, Side::Bid, true).unwrap();
+    new_order(&orders_account_ask, &coin_account, Side::Ask, true).unwrap();
+    assert_eq!(owner.lamports(), owner_lamports - 2_000_000);
+    assert_eq!(orders_account_bid.lamports(), orders_lamports);
+    assert_eq!(orders_account_ask.lamports(), orders_lamports);
+    assert_eq!(accounts.market.lamports(), market_lamports + 2_000_000);
+
+    // Matching both requests earns the whole match pool
+    match_orders();
+    assert_eq!(cranker.lamports(), 1_000_000);
+    assert_eq!(accounts.market.lamports(), market_lamports + 1_000_000);
+
+    // Consuming every event earns the whole consume pool
+    consume_events();
+    assert_eq!(cranker.lamports(), 2_000_000);
+    assert_eq!(accounts.market.lamports(), market_lamports);
+
+    // A cancelled order's deposit isn't refunded: it pays for cranking the
+    // order and its cancel, and settling doesn't return it either
+    new_order(&orders_account_bid, &pc_account, Side::Bid, true).unwrap();
+    let owner_lamports = owner.lamports();
+    let order_id = MarketState::load(&accounts.market, dex_program_id)
+        .unwrap()
+        .load_orders_mut(&orders_account_bid, None, dex_program_id, None)
+        .unwrap()
+        .orders[0];
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account_bid.clone(),
+            accounts.req_q.clone(),
+            owner.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::CancelOrder(CancelOrderInstruction {
+            side: Side::Bid,
+            order_id,
+            owner: [0; 4],
+            owner_slot: 0,
+        })
+        .pack(),
+    )
+    .unwrap();
+    match_orders();
+    consume_events();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account_bid.clone(),
+            owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+            accounts.vault_signer.clone(),
+            new_spl_token_program(&bump),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::SettleFunds.pack(),
+    )
+    .unwrap();
+    assert_eq!(owner.lamports(), owner_lamports);
+    assert_eq!(cranker.lamports(), 3_000_000);
+    assert_eq!(accounts.market.lamports(), market_lamports);
+}
+
+#[test]
+fn test_open_orders_capacity() {
+    let mut rng = StdRng::seed_from_u64(12);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account = new_dex_owned_account(
+        &mut rng,
+        OpenOrdersSlots::open_orders_len(256).unwrap(),
+        dex_program_id,
+        &bump,
+    );
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let match_orders = || {
+        State::process(
+            dex_program_id,
+            bump_vec![in &bump;
+                accounts.market.clone(),
+                accounts.req_q.clone(),
+                accounts.event_q.clone(),
+                accounts.bids.clone(),
+                accounts.asks.clone(),
+                coin_account.clone(),
+                pc_account.clone(),
+            ]
+            .into_bump_slice(),
+            &MarketInstruction::MatchOrders(5).pack(),
+        )
+        .unwrap();
+    };
+
+    // The account holds more than the 128 orders of `OpenOrders`
+    for client_id in 1..=200 {
+        let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(1_000 + client_id).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id,
+        })
+        .pack();
+        State::process(
+            dex_program_id,
+            new_order_accounts(&bump, &accounts, &orders_account, &pc_account, &owner)
+                .into_bump_slice(),
+            &instruction_data,
+        )
+        .unwrap();
+        match_orders();
+    }
+    {
+        let open_orders = MarketState::load(&accounts.market, &dex_program_id)
+            .unwrap()
+            .load_orders_mut(&orders_account, None, &dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders.capacity(), 256);
+        assert_eq!(open_orders.free_slots(), 56);
+        assert_eq!(open_orders.free_slot_bits, 0);
+        assert_eq!(open_orders.slot_side(199), Some(Side::Bid));
+        assert_eq!(open_orders.client_order_id(199), 200);
+    }
+
+    // Orders in the extra slots are cancelled like any other
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            owner.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::CancelOrderByClientId(200).pack(),
+    )
+    .unwrap();
+    match_orders();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            orders_account.clone(),
+            accounts.market.clone(),
+            accounts.event_q.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::ConsumeEvents(200).pack(),
+    )
+    .unwrap();
+
+    let open_orders = MarketState::load(&accounts.market, &dex_program_id)
+        .unwrap()
+        .load_orders_mut(&orders_account, None, &dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.free_slots(), 57);
+    assert_eq!(open_orders.slot_side(199), None);
+    assert_eq!(open_orders.client_order_id(199), 0);
+}
+
+#[test]
+fn test_settle_funds_partial() {
+    let mut rng = StdRng::seed_from_u64(13);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    // Cancelling a bid leaves its pc free
+    let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+        side: Side::Bid,
+        limit_price: NonZeroU64::new(100_000).unwrap(),
+        max_qty: NonZeroU64::new(1).unwrap(),
+        order_type: OrderType::Limit,
+        client_id: 1,
+    })
+    .pack();
+    State::process(
+        dex_program_id,
+        new_order_accounts(&bump, &accounts, &orders_account, &pc_account, &owner)
+            .into_bump_slice(),
+        &instruction_data,
+    )
+    .unwrap();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            owner.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::CancelOrderByClientId(1).pack(),
+    )
+    .unwrap();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            accounts.req_q.clone(),
+            accounts.event_q.clone(),
+            accounts.bids.clone(),
+            accounts.asks.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::MatchOrders(5).pack(),
+    )
+    .unwrap();
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            orders_account.clone(),
+            accounts.market.clone(),
+            accounts.event_q.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::ConsumeEvents(200).pack(),
+    )
+    .unwrap();
+
+    let load_free = || {
+        let open_orders = MarketState::load(&accounts.market, &dex_program_id)
+            .unwrap()
+            .load_orders_mut(&orders_account, None, &dex_program_id, None)
+            .unwrap();
+        (open_orders.native_coin_free, open_orders.native_pc_free)
+    };
+    let settle = |instruction: MarketInstruction| {
+        State::process(
+            dex_program_id,
+            bump_vec![in &bump;
+                accounts.market.clone(),
+                orders_account.clone(),
+                owner.clone(),
+                accounts.coin_vault.clone(),
+                accounts.pc_vault.clone(),
+                coin_account.clone(),
+                pc_account.clone(),
+                accounts.vault_signer.clone(),
+                spl_token_program.clone(),
+            ]
+            .into_bump_slice(),
+            &instruction.pack(),
+        )
+    };
+    let partial = |native_coin_amount, native_pc_amount| {
+        MarketInstruction::SettleFundsPartial(SettleFundsPartialInstruction {
+            native_coin_amount,
+            native_pc_amount,
+        })
+    };
+
+    let (coin_free, pc_free) = load_free();
+    assert_eq!(coin_free, 0);
+    assert!(pc_free > 40_000);
+
+    // Only what is free can be settled
+    assert_eq!(
+        settle(partial(1, 0)),
+        Err(DexError::ErrorCode(DexErrorCode::SettleAmountExceedsFree))
+    );
+    assert_eq!(
+        settle(partial(0, pc_free + 1)),
+        Err(DexError::ErrorCode(DexErrorCode::SettleAmountExceedsFree))
+    );
+
+    settle(partial(0, 40_000)).unwrap();
+    assert_eq!(load_free(), (0, pc_free - 40_000));
+
+    settle(MarketInstruction::SettleFunds).unwrap();
+    assert_eq!(load_free(), (0, 0));
+}
+
+#[test]
+fn test_cancel_priority() {
+    let mut rng = StdRng::seed_from_u64(14);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account_maker =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let orders_account_taker =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+
+    let new_order = |orders_account, payer, side, client_id| {
+        let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+            side,
+            limit_price: NonZeroU64::new(100_000).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id,
+        })
+        .pack();
+        State::process(
+            dex_program_id,
+            new_order_accounts(&bump, &accounts, orders_account, payer, &owner).into_bump_slice(),
+            &instruction_data,
+        )
+        .unwrap();
+    };
+    let cancel = |orders_account, client_id: u64| {
+        State::process(
+            dex_program_id,
+            bump_vec![in &bump;
+                accounts.market.clone(),
+                AccountInfo::clone(orders_account),
+                accounts.req_q.clone(),
+                owner.clone(),
+            ]
+            .into_bump_slice(),
+            &MarketInstruction::CancelOrderByClientId(client_id).pack(),
+        )
+        .unwrap();
+    };
+    let match_orders = || {
+        State::process(
+            dex_program_id,
+            bump_vec![in &bump;
+                accounts.market.clone(),
+                accounts.req_q.clone(),
+                accounts.event_q.clone(),
+                accounts.bids.clone(),
+                accounts.asks.clone(),
+                coin_account.clone(),
+                pc_account.clone(),
+            ]
+            .into_bump_slice(),
+            &MarketInstruction::MatchOrders(5).pack(),
+        )
+        .unwrap();
+    };
+    let load_orders = |orders_account| {
+        let open_orders = MarketState::load(&accounts.market, &dex_program_id)
+            .unwrap()
+            .load_orders_mut(orders_account, None, &dex_program_id, None)
+            .unwrap();
+        (
+            open_orders.native_coin_free,
+            open_orders.native_pc_free,
+            open_orders.free_slot_bits.count_zeros(),
+        )
+    };
+
+    // A cancel queued behind a new order of the same account waits for it
+    new_order(&orders_account_maker, &pc_account, Side::Bid, 1);
+    cancel(&orders_account_maker, 1);
+    new_order(&orders_account_maker, &pc_account, Side::Bid, 2);
+    match_orders();
+
+    // The taker queued first doesn't fill the bid cancelled after it
+    new_order(&orders_account_taker, &coin_account, Side::Ask, 3);
+    cancel(&orders_account_maker, 2);
+    match_orders();
+
+    let mut crank_accounts = bump_vec![in &bump;
+        orders_account_maker.clone(),
+        orders_account_taker.clone(),
+    ];
+    crank_accounts.sort_by_key(|account_info| account_info.key.to_aligned_bytes());
+    crank_accounts.extend(bump_vec![in &bump;
+        accounts.market.clone(),
+        accounts.event_q.clone(),
+        coin_account.clone(),
+        pc_account.clone(),
+    ]);
+    State::process(
+        dex_program_id,
+        crank_accounts.into_bump_slice(),
+        &MarketInstruction::ConsumeEvents(20).pack(),
+    )
+    .unwrap();
+
+    let (maker_coin_free, maker_pc_free, maker_orders) = load_orders(&orders_account_maker);
+    assert_eq!(maker_coin_free, 0);
+    assert!(maker_pc_free > 0);
+    assert_eq!(maker_orders, 0);
+    let (taker_coin_free, taker_pc_free, taker_orders) = load_orders(&orders_account_taker);
+    assert_eq!(taker_coin_free, 0);
+    assert_eq!(taker_pc_free, 0);
+    assert_eq!(taker_orders, 1);
+}
+
+#[test]
+fn test_post_only_slide() {
+    let mut rng = StdRng::seed_from_u64(15);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account_maker =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let orders_account_slider =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+
+    let new_order = |orders_account, payer, side, limit_price, order_type, client_id| {
+        let instruction_data = MarketInstruction::NewOrder(NewOrderInstructionV1 {
+            side,
+            limit_price: NonZeroU64::new(limit_price).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type,
+            client_id,
+        })
+        .pack();
+        let mut order_accounts =
+            new_order_accounts(&bump, &accounts, orders_account, payer, &owner);
+        if order_type == OrderType::PostOnlySlide {
+            order_accounts.extend(bump_vec![in &bump;
+                accounts.bids.clone(),
+                accounts.asks.clone(),
+            ]);
+        }
+        State::process(
+            dex_program_id,
+            order_accounts.into_bump_slice(),
+            &instruction_data,
+        )
+    };
+    let match_and_consume = || {
+        State::process(
+            dex_program_id,
+            bump_vec![in &bump;
+                accounts.market.clone(),
+                accounts.req_q.clone(),
+                accounts.event_q.clone(),
+                accounts.bids.clone(),
+                accounts.asks.clone(),
+                coin_account.clone(),
+                pc_account.clone(),
+            ]
+            .into_bump_slice(),
+            &MarketInstruction::MatchOrders(5).pack(),
+        )
+        .unwrap();
+        let mut crank_accounts = bump_vec![in &bump;
+            orders_account_maker.clone(),
+            orders_account_slider.clone(),
+        ];
+        crank_accounts.sort_by_key(|account_info| account_info.key.to_aligned_bytes());
+        crank_accounts.extend(bump_vec![in &bump;
+            accounts.market.clone(),
+            accounts.event_q.clone(),
+            coin_account.clone(),
+            pc_account.clone(),
+        ]);
+        State::process(
+            dex_program_id,
+            crank_accounts.into_bump_slice(),
+            &MarketInstruction::ConsumeEvents(20).pack(),
+        )
+        .unwrap();
+    };
+    let load_orders = |orders_account| {
+        let open_orders = MarketState::load(&accounts.market, &dex_program_id)
+            .unwrap()
+            .load_orders_mut(orders_account, None, &dex_program_id, None)
+            .unwrap();
+        (
+            open_orders.native_coin_free,
+            open_orders.native_pc_free,
+            open_orders.native_pc_total,
+            open_orders.orders[0],
+        )
+    };
+
+    new_order(
+        &orders_account_maker,
+        &coin_account,
+        Side::Ask,
+        100_000,
+        OrderType::Limit,
+        1,
+    )
+    .unwrap();
+    match_and_consume();
+
+    // A crossing bid is placed one tick behind the best ask instead of filling
+    new_order(
+        &orders_account_slider,
+        &pc_account,
+        Side::Bid,
+        101_000,
+        OrderType::PostOnlySlide,
+        3,
+    )
+    .unwrap();
+    let (_, slider_pc_free, slider_pc_total, slider_order_id) = load_orders(&orders_account_slider);
+    assert_eq!((slider_order_id >> 64) as u64, 99_999);
+    assert!(slider_pc_total - slider_pc_free < 101_000);
+    match_and_consume();
+
+    let (maker_coin_free, maker_pc_free, _, _) = load_orders(&orders_account_maker);
+    assert_eq!(maker_coin_free, 0);
+    assert_eq!(maker_pc_free, 0);
+    let (_, slider_pc_free, slider_pc_total, slider_order_id) = load_orders(&orders_account_slider);
+    assert_eq!((slider_order_id >> 64) as u64, 99_999);
+    assert_eq!(slider_pc_total - slider_pc_free, 99_999);
+
+    // The slot has the order id on the book, so a cancel right away finds it
+    State::process(
+        dex_program_id,
+        bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account_slider.clone(),
+            accounts.req_q.clone(),
+            owner.clone(),
+        ]
+        .into_bump_slice(),
+        &MarketInstruction::CancelOrderByClientId(3).pack(),
+    )
+    .unwrap();
+    match_and_consume();
+
+    let (_, slider_pc_free, slider_pc_total, slider_order_id) = load_orders(&orders_account_slider);
+    assert_eq!(slider_pc_free, slider_pc_total);
+    assert_eq!(slider_order_id, 0);
+
+    // A bid with no price one tick behind the best ask is dropped
+    new_order(
+        &orders_account_maker,
+        &coin_account,
+        Side::Ask,
+        1,
+        OrderType::Limit,
+        4,
+    )
+    .unwrap();
+    match_and_consume();
+    new_order(
+        &orders_account_slider,
+        &pc_account,
+        Side::Bid,
+        1,
+        OrderType::PostOnlySlide,
+        5,
+    )
+    .unwrap();
+    match_and_consume();
+
+    let (_, slider_pc_free, slider_pc_total, slider_order_id) = load_orders(&orders_account_slider);
+    assert_eq!(slider_pc_free, slider_pc_total);
+    assert_eq!(slider_order_id, 0);
+}